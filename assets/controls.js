@@ -0,0 +1,262 @@
+// Drives the /player page: its buttons and sliders call the player API, shortcuts and media keys
+// do the same, and what's playing comes in from /api/player/events.
+(() => {
+	const player = document.getElementById("player");
+	if (!player) {
+		return;
+	}
+	const SEEK_STEP_MS = 10_000;
+	const VOLUME_STEP = 5;
+
+	const $ = (id) => document.getElementById(`player-${id}`);
+	const device = $("device");
+	const artwork = $("artwork");
+	const title = $("title");
+	const artists = $("artists");
+	const album = $("album");
+	const progress = $("progress");
+	const duration = $("duration");
+	const seek = $("seek");
+	const toggle = $("toggle");
+	const volume = $("volume");
+	const status = $("status");
+
+	const minutes = (ms) => {
+		const seconds = Math.floor(ms / 1000);
+		return `${Math.floor(seconds / 60)}:${String(seconds % 60).padStart(2, "0")}`;
+	};
+
+	let playing = player.dataset.playing === "true";
+	let durationMs = Number(player.dataset.durationMs);
+	let progressMs = Number(player.dataset.progressMs);
+	// When `progressMs` was last known, to advance it between updates.
+	let progressAt = performance.now();
+
+	const renderProgress = () => {
+		seek.max = durationMs;
+		seek.value = progressMs;
+		progress.textContent = minutes(progressMs);
+		duration.textContent = minutes(durationMs);
+		seek.setAttribute(
+			"aria-valuetext",
+			`${minutes(progressMs)} of ${minutes(durationMs)}`,
+		);
+	};
+
+	const renderPlaying = () => {
+		toggle.textContent = playing ? "Pause" : "Play";
+		toggle.setAttribute("aria-pressed", String(playing));
+		if (mediaSession) {
+			mediaSession.playbackState = playing ? "playing" : "paused";
+		}
+	};
+
+	// Media keys and lock screen controls. Browsers only show them for tabs playing audio, as this
+	// one does once the Web Playback SDK device in the layout is the active one.
+	const mediaSession = "mediaSession" in navigator ? navigator.mediaSession : null;
+	const ART_WIDTHS = [64, 300, 640];
+
+	const renderMediaSession = (track) => {
+		if (!mediaSession) {
+			return;
+		}
+		if (!track) {
+			mediaSession.metadata = null;
+			mediaSession.playbackState = "none";
+			return;
+		}
+		const image = track.album.images[0];
+		const local = image?.url.replace("https://i.scdn.co/image/", "/art/");
+		mediaSession.metadata = new MediaMetadata({
+			title: track.name,
+			artist: track.artists.map((a) => a.name).join(", "),
+			album: track.album.name,
+			artwork: local?.startsWith("/art/")
+				? ART_WIDTHS.map((w) => ({
+						src: `${local}?w=${w}`,
+						sizes: `${w}x${w}`,
+					}))
+				: local
+					? [{ src: local }]
+					: [],
+		});
+	};
+
+	const renderPosition = () => {
+		if (!mediaSession?.setPositionState || durationMs === 0) {
+			return;
+		}
+		mediaSession.setPositionState({
+			duration: durationMs / 1000,
+			position: Math.min(progressMs, durationMs) / 1000,
+			playbackRate: 1,
+		});
+	};
+
+	const render = (nowPlaying) => {
+		if (!nowPlaying) {
+			device.textContent =
+				"No device is active. Start playing on one to control it here.";
+			playing = false;
+			durationMs = 0;
+			progressMs = 0;
+			title.textContent = artists.textContent = album.textContent = "";
+			artwork.hidden = true;
+			volume.disabled = true;
+			renderMediaSession(null);
+		} else {
+			const { track } = nowPlaying;
+			device.textContent = `Playing on ${nowPlaying.device}`;
+			playing = nowPlaying.is_playing;
+			durationMs = track?.duration_ms ?? 0;
+			progressMs = nowPlaying.progress_ms ?? 0;
+			title.textContent = track?.name ?? "";
+			artists.textContent = track?.artists.map((a) => a.name).join(", ") ?? "";
+			album.textContent = track?.album.name ?? "";
+			const image = track?.album.images[0];
+			artwork.hidden = !image;
+			if (image) {
+				// Served from our origin, as on the rendered page.
+				artwork.src = image.url.replace("https://i.scdn.co/image/", "/art/");
+			}
+			volume.disabled = nowPlaying.volume_percent == null;
+			// Don't move the slider from under someone dragging it.
+			if (nowPlaying.volume_percent != null && document.activeElement !== volume) {
+				volume.value = nowPlaying.volume_percent;
+			}
+			renderMediaSession(track);
+		}
+		progressAt = performance.now();
+		renderPlaying();
+		renderProgress();
+		renderPosition();
+	};
+
+	const send = async (method, path, body) => {
+		const response = await fetch(`/api/player/${path}`, {
+			method,
+			headers: body ? { "Content-Type": "application/json" } : {},
+			body: body ? JSON.stringify(body) : undefined,
+		});
+		if (response.status === 402) {
+			status.textContent = "Controlling playback needs Spotify Premium.";
+		} else if (!response.ok) {
+			status.textContent = "Spotify didn't take that, try again.";
+		}
+		return response.ok;
+	};
+
+	const actions = {
+		toggle: async () => {
+			if (await send("PUT", playing ? "pause" : "play")) {
+				playing = !playing;
+				progressAt = performance.now();
+				renderPlaying();
+				status.textContent = playing ? "Playing" : "Paused";
+			}
+		},
+		previous: async () => {
+			if (await send("POST", "previous")) {
+				status.textContent = "Previous track";
+			}
+		},
+		next: async () => {
+			if (await send("POST", "next")) {
+				status.textContent = "Next track";
+			}
+		},
+		seekTo: async (ms) => {
+			const position_ms = Math.max(0, Math.min(durationMs, Math.round(ms)));
+			if (await send("PUT", "seek", { position_ms })) {
+				progressMs = position_ms;
+				progressAt = performance.now();
+				renderProgress();
+				renderPosition();
+			}
+		},
+		volumeTo: async (percent) => {
+			const volume_percent = Math.max(0, Math.min(100, percent));
+			if (await send("PUT", "volume", { volume_percent })) {
+				volume.value = volume_percent;
+				status.textContent = `Volume ${volume_percent}%`;
+			}
+		},
+	};
+
+	toggle.addEventListener("click", actions.toggle);
+	$("previous").addEventListener("click", actions.previous);
+	$("next").addEventListener("click", actions.next);
+	seek.addEventListener("change", () => actions.seekTo(Number(seek.value)));
+	volume.addEventListener("change", () => actions.volumeTo(Number(volume.value)));
+
+	if (mediaSession) {
+		const handlers = {
+			play: () => !playing && actions.toggle(),
+			pause: () => playing && actions.toggle(),
+			previoustrack: actions.previous,
+			nexttrack: actions.next,
+			seekbackward: ({ seekOffset }) =>
+				actions.seekTo(progressMs - (seekOffset ?? SEEK_STEP_MS / 1000) * 1000),
+			seekforward: ({ seekOffset }) =>
+				actions.seekTo(progressMs + (seekOffset ?? SEEK_STEP_MS / 1000) * 1000),
+			seekto: ({ seekTime }) => actions.seekTo(seekTime * 1000),
+		};
+		for (const [action, handler] of Object.entries(handlers)) {
+			try {
+				mediaSession.setActionHandler(action, handler);
+			} catch {
+				// Browsers throw for actions they don't support.
+			}
+		}
+		renderPlaying();
+	}
+
+	// Controls handle their own keys: Space presses a focused button, arrows move a focused slider.
+	const ownsKeys = (target) =>
+		target instanceof HTMLElement &&
+		(target.isContentEditable ||
+			target.closest("input, textarea, select, button, a, summary"));
+
+	document.addEventListener("keydown", (event) => {
+		if (event.defaultPrevented || event.ctrlKey || event.metaKey || event.altKey) {
+			return;
+		}
+		if (ownsKeys(event.target)) {
+			return;
+		}
+		const action = {
+			" ": () => actions.toggle(),
+			k: () => actions.toggle(),
+			K: () => actions.toggle(),
+			ArrowLeft: () =>
+				event.shiftKey ? actions.previous() : actions.seekTo(progressMs - SEEK_STEP_MS),
+			ArrowRight: () =>
+				event.shiftKey ? actions.next() : actions.seekTo(progressMs + SEEK_STEP_MS),
+			ArrowUp: () => actions.volumeTo(Number(volume.value) + VOLUME_STEP),
+			ArrowDown: () => actions.volumeTo(Number(volume.value) - VOLUME_STEP),
+		}[event.key];
+		const changesVolume = event.key === "ArrowUp" || event.key === "ArrowDown";
+		if (!action || (changesVolume && volume.disabled)) {
+			return;
+		}
+		// Keeps Space and the arrows from scrolling the page.
+		event.preventDefault();
+		action();
+	});
+
+	// Advances the position between updates, which only come when playback changes.
+	setInterval(() => {
+		if (!playing || document.activeElement === seek) {
+			return;
+		}
+		const now = performance.now();
+		progressMs = Math.min(durationMs, progressMs + (now - progressAt));
+		progressAt = now;
+		renderProgress();
+	}, 1000);
+
+	const events = new EventSource("/api/player/events");
+	events.addEventListener("now_playing", (event) => render(JSON.parse(event.data)));
+	// htmx swaps the body when navigating away; the stream shouldn't outlive the page.
+	document.addEventListener("htmx:beforeSwap", () => events.close(), { once: true });
+})();
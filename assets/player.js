@@ -0,0 +1,17 @@
+window.onSpotifyWebPlaybackSDKReady = async () => {
+	console.log("hello");
+	const token = await fetch("/token");
+	const player = new window.Spotify.Player({
+		getOAuthToken: (cb) => cb(token),
+		name: "Web Playback SDK Quick Start Player",
+	});
+	player.addListener("ready", ({ device_id }) => {
+		console.log("Ready with Device ID", device_id);
+	});
+
+	player.addListener("not_ready", ({ device_id }) => {
+		console.log("Device ID has gone offline", device_id);
+	});
+
+	player.connect();
+};
@@ -0,0 +1,45 @@
+(() => {
+	const list = document.getElementById("playlist-items");
+	const status = document.getElementById("playlist-status");
+	// Sorted or filtered pages don't show the playlist's order.
+	if (!list || list.dataset.reorderable !== "true") {
+		return;
+	}
+	// Rows are numbered from the start of the page, moves from the start of the playlist.
+	const offset = Number(list.dataset.offset);
+
+	Sortable.create(list, {
+		onEnd: async ({ oldIndex, newIndex }) => {
+			if (oldIndex === newIndex) {
+				return;
+			}
+			// Spotify inserts before `insert_before` in the list as it was *before* the move.
+			const insertBefore = offset + (newIndex > oldIndex ? newIndex + 1 : newIndex);
+			const response = await fetch(
+				`/api/playlists/${list.dataset.playlistId}/reorder`,
+				{
+					method: "PUT",
+					headers: { "Content-Type": "application/json" },
+					body: JSON.stringify({
+						snapshot_id: list.dataset.snapshotId,
+						range_start: offset + oldIndex,
+						insert_before: insertBefore,
+						range_length: 1,
+					}),
+				},
+			);
+			if (response.status === 409) {
+				status.textContent = "This playlist changed elsewhere, reloading…";
+				window.location.reload();
+				return;
+			}
+			if (!response.ok) {
+				status.textContent = "Couldn't save the new order.";
+				return;
+			}
+			const { snapshot_id } = await response.json();
+			list.dataset.snapshotId = snapshot_id;
+			status.textContent = "";
+		},
+	});
+})();
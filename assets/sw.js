@@ -0,0 +1,99 @@
+// Service worker, served from /sw.js. Keeps the shell of the pages so the installed app opens
+// without a network, and the last known state of what it shows:
+// - pages, scripts and the now-playing widget's JSON go to the network first, falling back to
+//   what was last fetched;
+// - album art, which never changes under the same URL, comes from the cache first;
+// - the API is never cached, as it's per user and controls playback.
+
+// Bumped to drop what older versions cached.
+const VERSION = "v1";
+const SHELL = `shell-${VERSION}`;
+const ART = `art-${VERSION}`;
+// Album art kept at most, oldest first out.
+const ART_KEPT = 200;
+
+const SHELL_URLS = [
+	"/player",
+	"/assets/controls.js",
+	"/assets/player.js",
+	"/manifest.webmanifest",
+];
+
+self.addEventListener("install", (event) => {
+	event.waitUntil(
+		caches
+			.open(SHELL)
+			// `/player` redirects to the login when logged out, which isn't worth keeping.
+			.then((cache) => Promise.allSettled(SHELL_URLS.map((url) => cache.add(url))))
+			.then(() => self.skipWaiting()),
+	);
+});
+
+self.addEventListener("activate", (event) => {
+	event.waitUntil(
+		caches
+			.keys()
+			.then((keys) =>
+				Promise.all(
+					keys
+						.filter((key) => key !== SHELL && key !== ART)
+						.map((key) => caches.delete(key)),
+				),
+			)
+			.then(() => self.clients.claim()),
+	);
+});
+
+const networkFirst = async (request) => {
+	const cache = await caches.open(SHELL);
+	try {
+		const response = await fetch(request);
+		if (response.ok && !response.redirected) {
+			cache.put(request, response.clone());
+		}
+		return response;
+	} catch (e) {
+		const cached = await cache.match(request);
+		if (cached) {
+			return cached;
+		}
+		throw e;
+	}
+};
+
+const cacheFirst = async (request) => {
+	const cache = await caches.open(ART);
+	const cached = await cache.match(request);
+	if (cached) {
+		return cached;
+	}
+	const response = await fetch(request);
+	if (response.ok) {
+		await cache.put(request, response.clone());
+		const keys = await cache.keys();
+		await Promise.all(
+			keys.slice(0, Math.max(0, keys.length - ART_KEPT)).map((key) => cache.delete(key)),
+		);
+	}
+	return response;
+};
+
+self.addEventListener("fetch", (event) => {
+	const { request } = event;
+	const url = new URL(request.url);
+	if (request.method !== "GET" || url.origin !== self.location.origin) {
+		return;
+	}
+	if (url.pathname.startsWith("/api/") || url.pathname.startsWith("/auth/")) {
+		return;
+	}
+	if (url.pathname.startsWith("/art/")) {
+		event.respondWith(cacheFirst(request));
+	} else if (
+		request.mode === "navigate" ||
+		url.pathname.startsWith("/assets/") ||
+		url.pathname.startsWith("/w/")
+	) {
+		event.respondWith(networkFirst(request));
+	}
+});
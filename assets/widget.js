@@ -0,0 +1,59 @@
+// Embeds a now-playing widget where this script is included:
+// <script src="https://…/widget.js" data-slug="…" async></script>
+(() => {
+	const script = document.currentScript;
+	const slug = script?.dataset.slug;
+	if (!slug) {
+		return;
+	}
+	const origin = new URL(script.src).origin;
+	const POLL_MS = 30_000;
+
+	// A shadow root keeps the page's styles out of the widget, and the widget's out of the page.
+	const host = document.createElement("div");
+	script.after(host);
+	const root = host.attachShadow({ mode: "open" });
+	root.innerHTML = `
+		<style>
+			a { display: flex; gap: 0.75em; align-items: center; color: inherit; text-decoration: none; font: 14px/1.3 system-ui, sans-serif; }
+			img { width: 48px; height: 48px; border-radius: 4px; }
+			img[hidden] { display: none; }
+			small { display: block; opacity: 0.7; }
+		</style>
+		<a target="_blank" rel="noopener">
+			<img alt="" hidden />
+			<span><strong></strong><small></small></span>
+		</a>`;
+	const link = root.querySelector("a");
+	const artwork = root.querySelector("img");
+	const title = root.querySelector("strong");
+	const subtitle = root.querySelector("small");
+
+	const render = ({ track }) => {
+		if (!track) {
+			link.removeAttribute("href");
+			artwork.hidden = true;
+			title.textContent = "Not listening to anything";
+			subtitle.textContent = "";
+			return;
+		}
+		link.href = track.url;
+		artwork.hidden = !track.artwork;
+		artwork.src = track.artwork;
+		title.textContent = track.name;
+		subtitle.textContent = `${track.artists} · ${track.album}`;
+	};
+
+	const poll = async () => {
+		try {
+			const response = await fetch(`${origin}/w/${encodeURIComponent(slug)}/now-playing.json`);
+			if (response.ok) {
+				render(await response.json());
+			}
+		} catch {
+			// Keep showing the last state until the next poll.
+		}
+		setTimeout(poll, POLL_MS);
+	};
+	poll();
+})();
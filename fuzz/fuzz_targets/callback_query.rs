@@ -0,0 +1,22 @@
+//! The query string of the login callback, parsed as axum's `Query` does. Whatever it holds, a
+//! successful callback never logs its code or state.
+
+#![no_main]
+
+use blid_test::login_state::SpotifyAuthResponse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|query: &str| {
+    let Ok(callback) = serde_urlencoded::from_str::<SpotifyAuthResponse>(query) else {
+        return;
+    };
+    let logged = format!("{callback:?}");
+    match callback {
+        SpotifyAuthResponse::Success { .. } => {
+            assert_eq!(logged, "Success { code: [redacted], state: [redacted] }");
+        }
+        SpotifyAuthResponse::Error { error, .. } => {
+            assert!(logged.ends_with("state: [redacted] }"), "{error}");
+        }
+    }
+});
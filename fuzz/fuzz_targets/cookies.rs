@@ -0,0 +1,40 @@
+//! `Cookie` headers as clients could send them: any bytes, split into several headers at each
+//! newline. Whatever is found has to be a cookie value out of one of the headers, within the
+//! length limit.
+
+#![no_main]
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use blid_test::cookies;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut headers = HeaderMap::new();
+    for line in data.split(|&b| b == b'\n') {
+        // What hyper would have rejected never reaches handlers.
+        if let Ok(value) = HeaderValue::from_bytes(line) {
+            headers.append(header::COOKIE, value);
+        }
+    }
+
+    for name in [cookies::SESSION, "login_nonce", ""] {
+        let Some(value) = cookies::from_headers(&headers, name) else {
+            continue;
+        };
+        assert!(value.len() <= cookies::MAX_VALUE_LEN);
+        assert!(!value.contains(';'));
+        assert!(headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|line| line.to_str().ok())
+            .any(|line| line.contains(value)));
+    }
+
+    if let Ok(line) = std::str::from_utf8(data) {
+        if let Some(value) = cookies::get(line, cookies::SESSION) {
+            // Borrowed from the header rather than built up.
+            let range = line.as_bytes().as_ptr_range();
+            assert!(range.contains(&value.as_ptr()) || value.is_empty());
+        }
+    }
+});
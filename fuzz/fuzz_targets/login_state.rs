@@ -0,0 +1,28 @@
+//! The OAuth `state` of a login callback and the nonce cookie that comes with it. Made-up states
+//! never verify, and states that were signed only verify with their own nonce.
+
+#![no_main]
+
+use blid_test::login_state::{LoginState, StateKey};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (&str, &str, Option<String>)| {
+    let (state, nonce, next) = input;
+    let key = StateKey::new(b"fuzzing key");
+
+    // Forging a signature is out of the fuzzer's reach, so anything it makes up is rejected.
+    assert!(key.verify(state, nonce).is_err());
+
+    let login = LoginState::new(next.clone());
+    let signed = key.sign(&login).expect("login states serialize");
+    let verified = key
+        .verify(&signed, &login.nonce)
+        .expect("a fresh state verifies with its nonce");
+    assert_eq!(verified.next, next);
+    if nonce != login.nonce {
+        assert!(key.verify(&signed, nonce).is_err());
+    }
+    assert!(StateKey::new(b"another key")
+        .verify(&signed, &login.nonce)
+        .is_err());
+});
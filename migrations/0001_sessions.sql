@@ -0,0 +1,12 @@
+-- Sessions, keyed by the hex SHA-256 of the session ID. `data` is the JSON of `SessionData`,
+-- as in Redis, with `user_id` repeated so a user's sessions can be found without parsing it.
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    data TEXT NOT NULL,
+    -- Unix seconds.
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX sessions_user_id ON sessions (user_id);
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
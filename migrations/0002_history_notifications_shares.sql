@@ -0,0 +1,33 @@
+-- Times are Unix seconds, and nested data is JSON, which both SQLite and Postgres store as text.
+
+CREATE TABLE history_users (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    collecting_since BIGINT NOT NULL
+);
+
+CREATE TABLE plays (
+    user_id TEXT NOT NULL,
+    -- Unix milliseconds, as plays are keyed by when they happened.
+    played_at BIGINT NOT NULL,
+    -- Also in `track`, repeated to find when tracks were last played without parsing it.
+    track_id TEXT NOT NULL,
+    track TEXT NOT NULL,
+    PRIMARY KEY (user_id, played_at)
+);
+
+CREATE TABLE notifications (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    email TEXT NOT NULL,
+    weekly_digest BOOLEAN NOT NULL,
+    new_releases BOOLEAN NOT NULL,
+    last_sent BIGINT NOT NULL,
+    unsubscribe_token TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE shares (
+    id TEXT PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE INDEX shares_owner ON shares (owner);
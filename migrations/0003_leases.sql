@@ -0,0 +1,8 @@
+-- Leases on work only one instance should do at a time, held until `expires_at` (Unix seconds)
+-- unless renewed.
+
+CREATE TABLE leases (
+    name TEXT PRIMARY KEY NOT NULL,
+    holder TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
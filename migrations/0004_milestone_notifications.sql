@@ -0,0 +1,3 @@
+-- Opting in to milestone emails, off for everyone who subscribed before they existed.
+
+ALTER TABLE notifications ADD COLUMN milestones BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,7 @@
+-- How long users keep their history, only for those who don't keep everything.
+
+CREATE TABLE retention (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    -- 30, 90 or 365.
+    keep_days BIGINT NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Smart playlist rules, with their name, criteria and target playlist as JSON.
+
+CREATE TABLE rules (
+    id TEXT PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE INDEX rules_owner ON rules (owner);
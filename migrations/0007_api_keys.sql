@@ -0,0 +1,11 @@
+-- API keys, keyed by the hex SHA-256 of the key like sessions. `data` is the JSON of the rest of
+-- the key, its Spotify token included.
+
+CREATE TABLE api_keys (
+    hash TEXT PRIMARY KEY NOT NULL,
+    id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE INDEX api_keys_user_id ON api_keys (user_id);
@@ -0,0 +1,7 @@
+-- Roles given by admins. Users in `ADMINS` and those with the default role aren't here.
+
+CREATE TABLE roles (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    -- admin, member or guest.
+    role TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Unused invites of invitation-only instances, and the users invites let in for good.
+
+CREATE TABLE invites (
+    code TEXT PRIMARY KEY NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE admitted_users (
+    user_id TEXT PRIMARY KEY NOT NULL
+);
@@ -0,0 +1,7 @@
+-- The `CONSENT_VERSION` each user last consented to.
+
+CREATE TABLE consents (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    version TEXT NOT NULL,
+    given_at BIGINT NOT NULL
+);
@@ -0,0 +1,11 @@
+-- The slugs of public feeds and widgets, one of each per user at most.
+
+CREATE TABLE feeds (
+    slug TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE widgets (
+    slug TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL UNIQUE
+);
@@ -0,0 +1,12 @@
+-- Who opted in to volume normalization, and who shares what they're listening to. A sharing
+-- name is empty when unset.
+
+CREATE TABLE normalization (
+    user_id TEXT PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE sharing (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    visibility TEXT NOT NULL,
+    name TEXT NOT NULL
+);
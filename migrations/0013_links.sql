@@ -0,0 +1,13 @@
+-- Linked accounts, with a row for either side of each link, and the invites to link.
+
+CREATE TABLE links (
+    user_id TEXT NOT NULL,
+    linked_id TEXT NOT NULL,
+    PRIMARY KEY (user_id, linked_id)
+);
+
+CREATE TABLE link_invites (
+    code TEXT PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
@@ -0,0 +1,17 @@
+-- Releases of followed artists seen by the release watcher, and those it found new, with the
+-- album as JSON.
+
+CREATE TABLE seen_releases (
+    user_id TEXT NOT NULL,
+    album_id TEXT NOT NULL,
+    PRIMARY KEY (user_id, album_id)
+);
+
+CREATE TABLE new_releases (
+    user_id TEXT NOT NULL,
+    album_id TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    album TEXT NOT NULL,
+    detected_at BIGINT NOT NULL,
+    PRIMARY KEY (user_id, album_id)
+);
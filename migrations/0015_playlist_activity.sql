@@ -0,0 +1,23 @@
+-- Watched playlists with their last snapshot, the items as JSON, and what changed between
+-- snapshots. The snapshot ID is empty until the first one was seen, and `added_by` when Spotify
+-- doesn't say.
+
+CREATE TABLE watched_playlists (
+    user_id TEXT NOT NULL,
+    playlist_id TEXT NOT NULL,
+    snapshot_id TEXT NOT NULL,
+    items TEXT NOT NULL,
+    PRIMARY KEY (user_id, playlist_id)
+);
+
+CREATE TABLE playlist_activity (
+    user_id TEXT NOT NULL,
+    playlist_id TEXT NOT NULL,
+    change TEXT NOT NULL,
+    track TEXT NOT NULL,
+    added_by TEXT NOT NULL,
+    at BIGINT NOT NULL,
+    ordinal BIGINT NOT NULL
+);
+
+CREATE INDEX playlist_activity_by_playlist ON playlist_activity (user_id, playlist_id, at);
@@ -0,0 +1,28 @@
+-- Webhooks, with their events and playlist IDs as JSON, and their delivery logs. A delivery's
+-- status is 0 when the endpoint didn't answer, its error empty when there was none, and
+-- `delivered` 1 or 0, which unlike a BOOLEAN reads the same from SQLite and Postgres.
+
+CREATE TABLE webhooks (
+    id TEXT PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL,
+    url TEXT NOT NULL,
+    events TEXT NOT NULL,
+    playlist_ids TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX webhooks_owner ON webhooks (owner);
+
+CREATE TABLE webhook_deliveries (
+    id TEXT PRIMARY KEY NOT NULL,
+    webhook_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    attempts BIGINT NOT NULL,
+    status BIGINT NOT NULL,
+    error TEXT NOT NULL,
+    delivered BIGINT NOT NULL
+);
+
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
@@ -0,0 +1,12 @@
+-- Where each user's recent logins were from, with the country empty for networks without one,
+-- and the time in milliseconds.
+
+CREATE TABLE logins (
+    user_id TEXT NOT NULL,
+    country TEXT NOT NULL,
+    asn BIGINT NOT NULL,
+    network TEXT NOT NULL,
+    logged_in_at BIGINT NOT NULL
+);
+
+CREATE INDEX logins_user_id ON logins (user_id);
@@ -0,0 +1,637 @@
+//! Activity feeds of playlists. Spotify only ever shows a playlist as it is now, so for playlists
+//! a user watches, a worker compares each new snapshot with the previous one and records which
+//! tracks were added and removed, and by whom when Spotify says. This is mostly useful for
+//! collaborative playlists.
+//!
+//! Nothing is recorded for the first snapshot of a playlist, which is what it was like when the
+//! user started watching it.
+//!
+//! Watched playlists, their last snapshot and their activity are kept in memory by default, or in
+//! the database with a SQL feature and `DATABASE_URL` set, in which case only the instance holding
+//! the workers lease polls them.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    session_store::SessionData,
+    spotify::{PlaylistItem, Track},
+    AppStateInner,
+};
+
+/// Snapshots are compared first, so an unchanged playlist costs a single small request.
+const POLL_INTERVAL: Duration = Duration::from_mins(5);
+/// Activity entries kept per watched playlist.
+const KEPT: usize = 200;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+}
+
+impl Change {
+    #[cfg(feature = "sql")]
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+        }
+    }
+
+    #[cfg(feature = "sql")]
+    fn parse(change: &str) -> anyhow::Result<Self> {
+        match change {
+            "added" => Ok(Self::Added),
+            "removed" => Ok(Self::Removed),
+            _ => anyhow::bail!("bad change {change:?}"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Activity {
+    pub change: Change,
+    pub track: Track,
+    /// Who added the track, for additions to collaborative playlists.
+    pub by: Option<String>,
+    /// When the change was noticed, which is up to a few minutes after it was made.
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct Watched {
+    /// `None` until the first snapshot was seen.
+    snapshot_id: Option<String>,
+    items: Vec<PlaylistItem>,
+    /// Newest first.
+    activity: VecDeque<Activity>,
+}
+
+pub enum ActivityStore {
+    /// By user ID, then playlist ID.
+    Memory(RwLock<HashMap<String, HashMap<String, Watched>>>),
+    /// Each entry of `playlist_activity` has its ordinal among those recorded at the same time,
+    /// so they're listed in the same order as in memory.
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for ActivityStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl ActivityStore {
+    /// Starts watching a playlist for a user, returning `false` if they already were.
+    pub async fn watch(&self, user_id: &str, playlist_id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(watches) => {
+                let mut watches = watches.write().await;
+                let playlists = watches.entry(user_id.to_owned()).or_default();
+                if playlists.contains_key(playlist_id) {
+                    return Ok(false);
+                }
+                playlists.insert(playlist_id.to_owned(), Watched::default());
+                Ok(true)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let inserted = sqlx::query(
+                    "INSERT INTO watched_playlists (user_id, playlist_id, snapshot_id, items) \
+                     VALUES ($1, $2, '', '[]') ON CONFLICT (user_id, playlist_id) DO NOTHING",
+                )
+                .bind(user_id)
+                .bind(playlist_id)
+                .execute(pool)
+                .await?;
+                Ok(inserted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Stops watching a playlist, dropping its activity. Returns whether it was watched.
+    pub async fn unwatch(&self, user_id: &str, playlist_id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(watches) => Ok(watches
+                .write()
+                .await
+                .get_mut(user_id)
+                .is_some_and(|playlists| playlists.remove(playlist_id).is_some())),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query(
+                    "DELETE FROM playlist_activity WHERE user_id = $1 AND playlist_id = $2",
+                )
+                .bind(user_id)
+                .bind(playlist_id)
+                .execute(&mut *tx)
+                .await?;
+                let deleted = sqlx::query(
+                    "DELETE FROM watched_playlists WHERE user_id = $1 AND playlist_id = $2",
+                )
+                .bind(user_id)
+                .bind(playlist_id)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Activity of a watched playlist, newest first, or `None` if it isn't watched.
+    pub async fn activity(
+        &self,
+        user_id: &str,
+        playlist_id: &str,
+    ) -> anyhow::Result<Option<Vec<Activity>>> {
+        match self {
+            Self::Memory(watches) => Ok(watches
+                .read()
+                .await
+                .get(user_id)
+                .and_then(|playlists| playlists.get(playlist_id))
+                .map(|watched| watched.activity.iter().cloned().collect())),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                if !self
+                    .watched_by(user_id)
+                    .await?
+                    .iter()
+                    .any(|id| id == playlist_id)
+                {
+                    return Ok(None);
+                }
+                let rows: Vec<ActivityRow> = sqlx::query_as(
+                    "SELECT playlist_id, change, track, added_by, at FROM playlist_activity \
+                     WHERE user_id = $1 AND playlist_id = $2 ORDER BY at DESC, ordinal DESC",
+                )
+                .bind(user_id)
+                .bind(playlist_id)
+                .fetch_all(pool)
+                .await?;
+                let activity = rows
+                    .into_iter()
+                    .map(|row| Ok(from_row(row)?.1))
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Some(activity))
+            }
+        }
+    }
+
+    /// Activity of every playlist a user watches, by playlist ID.
+    pub async fn owned_by(&self, user_id: &str) -> anyhow::Result<HashMap<String, Vec<Activity>>> {
+        match self {
+            Self::Memory(watches) => Ok(watches
+                .read()
+                .await
+                .get(user_id)
+                .map(|playlists| {
+                    playlists
+                        .iter()
+                        .map(|(id, watched)| {
+                            (id.clone(), watched.activity.iter().cloned().collect())
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut owned: HashMap<String, Vec<Activity>> = self
+                    .watched_by(user_id)
+                    .await?
+                    .into_iter()
+                    .map(|id| (id, Vec::new()))
+                    .collect();
+                let rows: Vec<ActivityRow> = sqlx::query_as(
+                    "SELECT playlist_id, change, track, added_by, at FROM playlist_activity \
+                     WHERE user_id = $1 ORDER BY at DESC, ordinal DESC",
+                )
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+                for row in rows {
+                    let (playlist_id, activity) = from_row(row)?;
+                    owned.entry(playlist_id).or_default().push(activity);
+                }
+                Ok(owned)
+            }
+        }
+    }
+
+    /// Stops watching everything for a user, returning how many playlists were watched.
+    pub async fn remove_owned_by(&self, user_id: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(watches) => Ok(watches
+                .write()
+                .await
+                .remove(user_id)
+                .map_or(0, |playlists| playlists.len())),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query("DELETE FROM playlist_activity WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                let deleted = sqlx::query("DELETE FROM watched_playlists WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(usize::try_from(deleted.rows_affected())?)
+            }
+        }
+    }
+
+    /// Forgets a user's activity from before `cutoff`, returning how many entries there were.
+    pub async fn prune(&self, user_id: &str, cutoff: DateTime<Utc>) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(watches) => {
+                let mut watches = watches.write().await;
+                let Some(playlists) = watches.get_mut(user_id) else {
+                    return Ok(0);
+                };
+                let mut pruned = 0;
+                for watched in playlists.values_mut() {
+                    let before = watched.activity.len();
+                    // Newest first, so what's old is at the back.
+                    while watched.activity.back().is_some_and(|a| a.at < cutoff) {
+                        watched.activity.pop_back();
+                    }
+                    pruned += before - watched.activity.len();
+                }
+                Ok(pruned)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted =
+                    sqlx::query("DELETE FROM playlist_activity WHERE user_id = $1 AND at < $2")
+                        .bind(user_id)
+                        .bind(cutoff.timestamp())
+                        .execute(pool)
+                        .await?;
+                Ok(usize::try_from(deleted.rows_affected())?)
+            }
+        }
+    }
+
+    /// Whether every instance sees the same watches, so only one of them should poll them.
+    const fn is_shared(&self) -> bool {
+        match self {
+            Self::Memory(_) => false,
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => true,
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(watches) => watches.try_read().ok().map(|w| w.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+
+    async fn watched_by(&self, user_id: &str) -> anyhow::Result<Vec<String>> {
+        match self {
+            Self::Memory(watches) => Ok(watches
+                .read()
+                .await
+                .get(user_id)
+                .map(|playlists| playlists.keys().cloned().collect())
+                .unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => Ok(sqlx::query_scalar(
+                "SELECT playlist_id FROM watched_playlists WHERE user_id = $1",
+            )
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?),
+        }
+    }
+
+    /// Records a snapshot of a playlist, diffing it against the previous one.
+    pub async fn record(
+        &self,
+        user_id: &str,
+        playlist_id: &str,
+        snapshot_id: String,
+        items: Vec<PlaylistItem>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(watches) => {
+                let mut watches = watches.write().await;
+                // Unwatched in the meantime.
+                let Some(playlist) = watches
+                    .get_mut(user_id)
+                    .and_then(|playlists| playlists.get_mut(playlist_id))
+                else {
+                    return Ok(());
+                };
+                if playlist.snapshot_id.as_ref() == Some(&snapshot_id) {
+                    return Ok(());
+                }
+                if playlist.snapshot_id.is_some() {
+                    for activity in diff(&playlist.items, &items, Utc::now()) {
+                        playlist.activity.push_front(activity);
+                    }
+                    playlist.activity.truncate(KEPT);
+                }
+                playlist.snapshot_id = Some(snapshot_id);
+                playlist.items = items;
+                Ok(())
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let previous: Option<(String, String)> = sqlx::query_as(
+                    "SELECT snapshot_id, items FROM watched_playlists \
+                     WHERE user_id = $1 AND playlist_id = $2",
+                )
+                .bind(user_id)
+                .bind(playlist_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+                // Unwatched in the meantime.
+                let Some((previous_id, previous_items)) = previous else {
+                    return Ok(());
+                };
+                if previous_id == snapshot_id {
+                    return Ok(());
+                }
+                // Empty until the first snapshot was seen.
+                if !previous_id.is_empty() {
+                    let before: Vec<PlaylistItem> = serde_json::from_str(&previous_items)?;
+                    let activity = diff(&before, &items, Utc::now());
+                    for (ordinal, activity) in activity.iter().enumerate() {
+                        sqlx::query(
+                            "INSERT INTO playlist_activity \
+                             (user_id, playlist_id, change, track, added_by, at, ordinal) \
+                             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                        )
+                        .bind(user_id)
+                        .bind(playlist_id)
+                        .bind(activity.change.as_str())
+                        .bind(serde_json::to_string(&activity.track)?)
+                        .bind(activity.by.clone().unwrap_or_default())
+                        .bind(activity.at.timestamp())
+                        .bind(i64::try_from(ordinal)?)
+                        .execute(&mut *tx)
+                        .await?;
+                    }
+                    if !activity.is_empty() {
+                        prune_kept(&mut tx, user_id, playlist_id).await?;
+                    }
+                }
+                sqlx::query(
+                    "UPDATE watched_playlists SET snapshot_id = $3, items = $4 \
+                     WHERE user_id = $1 AND playlist_id = $2",
+                )
+                .bind(user_id)
+                .bind(playlist_id)
+                .bind(&snapshot_id)
+                .bind(serde_json::to_string(&items)?)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Playlist ID, change, track JSON, who added it or empty, and when.
+#[cfg(feature = "sql")]
+type ActivityRow = (String, String, String, String, i64);
+
+#[cfg(feature = "sql")]
+fn from_row(
+    (playlist_id, change, track, by, at): ActivityRow,
+) -> anyhow::Result<(String, Activity)> {
+    let activity = Activity {
+        change: Change::parse(&change)?,
+        track: serde_json::from_str(&track)?,
+        by: Some(by).filter(|by| !by.is_empty()),
+        at: DateTime::from_timestamp(at, 0).ok_or_else(|| anyhow::anyhow!("bad time {at}"))?,
+    };
+    Ok((playlist_id, activity))
+}
+
+/// Drops all but the [`KEPT`] newest entries of a playlist's activity.
+#[cfg(feature = "sql")]
+async fn prune_kept(
+    tx: &mut sqlx::Transaction<'_, sqlx::Any>,
+    user_id: &str,
+    playlist_id: &str,
+) -> anyhow::Result<()> {
+    let cutoff: Option<(i64, i64)> = sqlx::query_as(
+        "SELECT at, ordinal FROM playlist_activity WHERE user_id = $1 AND playlist_id = $2 \
+         ORDER BY at DESC, ordinal DESC LIMIT 1 OFFSET $3",
+    )
+    .bind(user_id)
+    .bind(playlist_id)
+    .bind(i64::try_from(KEPT)?)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some((at, ordinal)) = cutoff {
+        sqlx::query(
+            "DELETE FROM playlist_activity WHERE user_id = $1 AND playlist_id = $2 \
+             AND (at < $3 OR (at = $3 AND ordinal <= $4))",
+        )
+        .bind(user_id)
+        .bind(playlist_id)
+        .bind(at)
+        .bind(ordinal)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// What changed from `before` to `after`. Tracks can appear several times in a playlist, so
+/// occurrences are matched up rather than compared as sets: the first occurrences in `after` are
+/// the ones that were already there, and any left over are additions.
+fn diff(before: &[PlaylistItem], after: &[PlaylistItem], at: DateTime<Utc>) -> Vec<Activity> {
+    fn tracks(items: &[PlaylistItem]) -> impl Iterator<Item = (&Track, &PlaylistItem)> {
+        items
+            .iter()
+            .filter_map(|item| Some((item.track.as_ref()?, item)))
+    }
+
+    // Occurrences of each track in `before` not matched in `after` yet.
+    let mut unmatched: HashMap<&str, usize> = HashMap::new();
+    for (track, _) in tracks(before) {
+        *unmatched.entry(&track.id).or_default() += 1;
+    }
+    let mut activity = Vec::new();
+    for (track, item) in tracks(after) {
+        match unmatched.get_mut(track.id.as_str()) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => activity.push(Activity {
+                change: Change::Added,
+                track: track.clone(),
+                by: item.added_by.as_ref().map(|user| user.id.clone()),
+                at,
+            }),
+        }
+    }
+    for (track, _) in tracks(before) {
+        if let Some(count) = unmatched
+            .get_mut(track.id.as_str())
+            .filter(|count| **count > 0)
+        {
+            *count -= 1;
+            activity.push(Activity {
+                change: Change::Removed,
+                track: track.clone(),
+                by: None,
+                at,
+            });
+        }
+    }
+    activity
+}
+
+async fn check(
+    state: &AppStateInner,
+    session: &SessionData,
+    playlist_id: &str,
+) -> anyhow::Result<()> {
+    let (snapshot_id, items) = state
+        .playlist_cache
+        .items(&session.token.access_token, playlist_id)
+        .await?;
+    state
+        .activity
+        .record(&session.user_id, playlist_id, snapshot_id, items)
+        .await
+}
+
+pub fn spawn_worker(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Watches kept in memory are this instance's own, so it polls them whoever leads.
+            if state.activity.is_shared() && !state.leader.holds() {
+                continue;
+            }
+            let sessions = match state.sessions.all().await {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    tracing::error!("Failed to list sessions for playlist activity: {e:#}");
+                    continue;
+                }
+            };
+            let mut seen = HashSet::new();
+            for session in sessions {
+                if !seen.insert(session.user_id.clone()) {
+                    continue;
+                }
+                let watched = match state.activity.watched_by(&session.user_id).await {
+                    Ok(watched) => watched,
+                    Err(e) => {
+                        tracing::error!("Failed to list watched playlists: {e:#}");
+                        continue;
+                    }
+                };
+                for playlist_id in watched {
+                    if let Err(e) = check(&state, &session, &playlist_id).await {
+                        tracing::warn!("Failed to check watched playlist: {e:#}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(ids: &[&str]) -> Vec<PlaylistItem> {
+        ids.iter()
+            .map(|id| {
+                serde_json::from_value(serde_json::json!({
+                    "added_at": null,
+                    "added_by": {"id": "ann"},
+                    "track": {"id": id, "name": id, "uri": format!("spotify:track:{id}"), "duration_ms": 1000},
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    async fn records_changes_between_snapshots(store: &ActivityStore) {
+        assert!(store.watch("ann", "p").await.unwrap());
+        assert!(!store.watch("ann", "p").await.unwrap());
+        store
+            .record("ann", "p", "1".to_owned(), items(&["a", "b"]))
+            .await
+            .unwrap();
+        assert_eq!(store.activity("ann", "p").await.unwrap().unwrap().len(), 0);
+
+        store
+            .record("ann", "p", "2".to_owned(), items(&["b", "c", "c"]))
+            .await
+            .unwrap();
+        let activity = store.activity("ann", "p").await.unwrap().unwrap();
+        let changes: Vec<_> = activity
+            .iter()
+            .map(|a| (a.change, a.track.id.as_str()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (Change::Removed, "a"),
+                (Change::Added, "c"),
+                (Change::Added, "c"),
+            ]
+        );
+        assert_eq!(activity[1].by.as_deref(), Some("ann"));
+        assert_eq!(store.owned_by("ann").await.unwrap()["p"].len(), 3);
+        assert_eq!(
+            store.activity("ann", "q").await.unwrap().map(|a| a.len()),
+            None
+        );
+
+        let later = Utc::now() + chrono::Duration::minutes(1);
+        assert_eq!(store.prune("ann", later).await.unwrap(), 3);
+        assert!(store.unwatch("ann", "p").await.unwrap());
+        assert!(!store.unwatch("ann", "p").await.unwrap());
+        store.watch("ann", "q").await.unwrap();
+        assert_eq!(store.remove_owned_by("ann").await.unwrap(), 1);
+        assert!(store.owned_by("ann").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_changes_between_snapshots_in_memory() {
+        records_changes_between_snapshots(&ActivityStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn records_changes_between_snapshots_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-activity-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        records_changes_between_snapshots(&ActivityStore::Sql(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
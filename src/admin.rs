@@ -0,0 +1,229 @@
+//! Operator endpoints under `/admin`, authenticated with `ADMIN_TOKEN` as a bearer token, or
+//! with the session of an admin (see [`crate::roles`]).
+//!
+//! - `GET /admin/log-level` returns the log filter in effect.
+//! - `PUT /admin/log-level` replaces it with the filter in the body, in `RUST_LOG` syntax, until
+//!   the next restart.
+//! - `GET /admin/roles` lists the users whose role isn't the default one.
+//! - `PUT /admin/roles/:user_id` gives a user the role in the body, `admin`, `member` or `guest`.
+//! - `DELETE /admin/roles/:user_id` gives them the default role again.
+//! - `GET /admin/invites` lists the unused invites of an invitation-only instance.
+//! - `POST /admin/invites` mints one, see [`crate::invites`].
+//! - `DELETE /admin/invites/:code` revokes one.
+//! - `GET /admin/themes` lists the uploaded themes, see [`crate::themes`].
+//! - `PUT /admin/themes/:name` adds or replaces one with the CSS in the body.
+//! - `DELETE /admin/themes/:name` removes one.
+//! - `GET /admin/theme` returns the name of the selected theme.
+//! - `PUT /admin/theme` selects the theme named in the body for every page.
+//! - `DELETE /admin/theme` goes back to the default styles.
+
+use axum::{
+    extract::{FromRequestParts, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{delete, get, put},
+    Json, Router,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    ids::UserId,
+    invites::Invite,
+    logging,
+    roles::{Role, Seeded},
+    session::Session,
+    themes::{self, Theme},
+    token, AppError, AppStateInner,
+};
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/admin/log-level", get(log_level).put(set_log_level))
+        .route("/admin/roles", get(roles))
+        .route("/admin/roles/:user_id", put(set_role).delete(reset_role))
+        .route("/admin/invites", get(invites).post(mint_invite))
+        .route("/admin/invites/:code", delete(revoke_invite))
+        .route("/admin/themes", get(themes))
+        .route("/admin/themes/:name", put(save_theme).delete(remove_theme))
+        .route(
+            "/admin/theme",
+            get(selected_theme).put(select_theme).delete(deselect_theme),
+        )
+}
+
+/// Lets requests with `ADMIN_TOKEN` or the session of an admin through.
+pub async fn authenticate(
+    State(s): State<Arc<AppStateInner>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    if let Ok(session) = Session::from_request_parts(&mut parts, &s).await {
+        match s.roles.role(&session.user_id).await {
+            Ok(Role::Admin) => return next.run(Request::from_parts(parts, body)).await,
+            Ok(_) => {}
+            Err(e) => return AppError(e).into_response(),
+        }
+    }
+    let request = Request::from_parts(parts, body);
+    let Some(expected) = &s.admin_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if token::constant_time_eq(presented.as_bytes(), expected.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+    }
+}
+
+async fn log_level() -> Result<String, AppError> {
+    Ok(logging::filter()?)
+}
+
+/// `400` for a filter that doesn't parse, which leaves the current one in place.
+async fn set_log_level(body: String) -> Response {
+    let directives = body.trim();
+    if let Err(e) = logging::set_filter(directives) {
+        return (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response();
+    }
+    match logging::filter() {
+        Ok(filter) => filter.into_response(),
+        Err(e) => AppError(e).into_response(),
+    }
+}
+
+async fn roles(
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<BTreeMap<String, Role>>, AppError> {
+    Ok(Json(s.roles.all().await?))
+}
+
+/// `400` for an unknown role, `409` for users in `ADMINS`.
+async fn set_role(
+    State(s): State<Arc<AppStateInner>>,
+    UserId(user_id): UserId,
+    body: String,
+) -> Response {
+    let role: Role = match body.trim().parse() {
+        Ok(role) => role,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    };
+    match s.roles.set(&user_id, role).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) if e.is::<Seeded>() => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(e) => AppError(e).into_response(),
+    }
+}
+
+async fn reset_role(State(s): State<Arc<AppStateInner>>, UserId(user_id): UserId) -> Response {
+    match s.roles.reset(&user_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) if e.is::<Seeded>() => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(e) => AppError(e).into_response(),
+    }
+}
+
+async fn invites(State(s): State<Arc<AppStateInner>>) -> Result<Json<Vec<Invite>>, AppError> {
+    Ok(Json(s.invites.list().await?))
+}
+
+#[derive(Serialize)]
+struct MintedInvite {
+    #[serde(flatten)]
+    invite: Invite,
+    /// Where the invitee logs in.
+    url: String,
+}
+
+/// `409` unless `INVITE_ONLY` is set, as an invite wouldn't mean anything.
+async fn mint_invite(State(s): State<Arc<AppStateInner>>) -> Result<Response, AppError> {
+    if !s.invites.enabled() {
+        return Ok((StatusCode::CONFLICT, "INVITE_ONLY isn't set").into_response());
+    }
+    let invite = s.invites.mint().await?;
+    let url = format!("{}/auth?invite={}", s.public_url, invite.code);
+    Ok((StatusCode::CREATED, Json(MintedInvite { invite, url })).into_response())
+}
+
+async fn revoke_invite(
+    State(s): State<Arc<AppStateInner>>,
+    Path(code): Path<String>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.invites.revoke(&code).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+async fn themes(State(s): State<Arc<AppStateInner>>) -> Result<Json<Vec<Theme>>, AppError> {
+    Ok(Json(s.themes.list().await?))
+}
+
+/// `400` for a name other than lowercase letters, digits and dashes, `413` for a bundle larger
+/// than [`themes::MAX_SIZE`].
+async fn save_theme(
+    State(s): State<Arc<AppStateInner>>,
+    Path(name): Path<String>,
+    css: String,
+) -> Result<Response, AppError> {
+    if !themes::valid_name(&name) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "Theme names are lowercase letters, digits and dashes",
+        )
+            .into_response());
+    }
+    if css.len() > themes::MAX_SIZE {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    s.themes.save(&name, &css).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn remove_theme(
+    State(s): State<Arc<AppStateInner>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if !themes::valid_name(&name) || !s.themes.remove(&name).await? {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `404` while the default styles are in use.
+async fn selected_theme(State(s): State<Arc<AppStateInner>>) -> Response {
+    s.themes.selected().map_or_else(
+        || StatusCode::NOT_FOUND.into_response(),
+        IntoResponse::into_response,
+    )
+}
+
+/// `404` for a theme that wasn't uploaded.
+async fn select_theme(
+    State(s): State<Arc<AppStateInner>>,
+    body: String,
+) -> Result<StatusCode, AppError> {
+    if !s.themes.select(Some(body.trim())).await? {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn deselect_theme(State(s): State<Arc<AppStateInner>>) -> Result<StatusCode, AppError> {
+    s.themes.select(None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,2720 @@
+//! JSON API for non-HTML clients. Every route here but `/session` requires a [`Session`].
+
+use axum::{
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+#[cfg(feature = "stats")]
+use chrono::Datelike;
+use chrono::{DateTime, Months, Utc};
+#[cfg(feature = "library")]
+use futures::future;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "library")]
+use std::collections::HashSet;
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use tokio::sync::broadcast;
+
+#[cfg(any(feature = "library", feature = "stats"))]
+use crate::deadline;
+#[cfg(any(feature = "player", feature = "library"))]
+use crate::partials::{ButtonTemplate, WantsFragment};
+use crate::{
+    api_keys::{ApiKey, KeyAuth, Scope},
+    art,
+    consent::Consent,
+    digest::Preferences,
+    export,
+    friends::{Friend, Sharing},
+    history::{self, Stream as StreamedPlay},
+    ids::{SpotifyId, UserId},
+    jobs::{JobEvent, JobKind, JobStatus, Output},
+    json_array::JsonArray,
+    library,
+    playlist_cache::SnapshotConflict,
+    releases::NewRelease,
+    retention::Retention,
+    rules::{self, Criteria, Rule},
+    running,
+    session::{self, Session},
+    spotify::{self, Playlist, PlaylistItem, Track},
+    webhooks::{self, EventKind, Webhook},
+    AppError, AppStateInner,
+};
+#[cfg(feature = "library")]
+use crate::{availability::Availability, discover, library::TaggedTrack};
+#[cfg(feature = "stats")]
+use crate::{
+    collage::{Grid, Period},
+    stats::{self, Milestone, Streak, Wrapped},
+};
+#[cfg(feature = "player")]
+use crate::{library::Ending, widget};
+
+/// Spotify scopes of playback routes, added after the first logins. Older sessions are asked to
+/// log in again to grant them.
+pub const READ_PLAYBACK: &[&str] = &["user-read-playback-state"];
+const MODIFY_PLAYBACK: &[&str] = &["user-modify-playback-state"];
+#[cfg(feature = "library")]
+const MODIFY_LIBRARY: &[&str] = &["user-library-modify"];
+#[cfg(feature = "library")]
+const MODIFY_FOLLOWING: &[&str] = &["user-follow-modify"];
+const UPLOAD_IMAGES: &[&str] = &["ugc-image-upload"];
+/// Extended streaming histories come in files of about 10 MB.
+const HISTORY_IMPORT_LIMIT: usize = 64 * 1024 * 1024;
+/// Photos straight off a phone camera fit.
+const IMAGE_UPLOAD_LIMIT: usize = 20 * 1024 * 1024;
+
+// Every route of the API in one place.
+#[allow(clippy::too_many_lines)]
+pub fn router() -> Router<Arc<AppStateInner>> {
+    let router = Router::new()
+        .route("/version", get(version))
+        .route("/session", get(session_status))
+        .route("/session/token-info", get(token_info))
+        .route("/playlists", get(playlists))
+        .route("/playlists/import", post(import_playlist))
+        .route("/playlists/:id/tracks", get(playlist_tracks))
+        .route("/playlists/:id/dedupe", post(dedupe_playlist))
+        .route("/playlists/:id/reorder", put(reorder_playlist))
+        .route("/playlists/:id/undo", post(undo_playlist_edit))
+        .route(
+            "/playlists/:id/image",
+            put(upload_playlist_image).layer(DefaultBodyLimit::max(IMAGE_UPLOAD_LIMIT)),
+        )
+        .route("/playlists/:id/share", post(share_playlist))
+        .route(
+            "/playlists/:id/watch",
+            put(watch_playlist).delete(unwatch_playlist),
+        )
+        .route("/playlists/:id/activity", get(playlist_activity))
+        .route("/rules", get(list_rules).post(create_rule))
+        .route("/rules/:id", delete(delete_rule))
+        .route("/rules/:id/run", post(run_rule))
+        .route("/generate/forgotten", post(generate_forgotten))
+        .route("/generate/run", post(generate_run))
+        .route("/links", get(list_links))
+        .route("/links/invites", post(create_link_invite))
+        .route("/links/invites/:code", post(accept_link_invite))
+        .route("/links/:user_id", delete(unlink))
+        .route("/friends/activity", get(friend_activity))
+        .route("/me/sharing", get(my_sharing).put(update_my_sharing))
+        .route("/me/consent", get(my_consent))
+        .route("/me/retention", get(my_retention).put(update_my_retention))
+        .route("/releases/new", get(new_releases))
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/:id", delete(delete_webhook))
+        .route("/webhooks/:id/deliveries", get(webhook_deliveries))
+        .route("/keys", get(list_api_keys).post(create_api_key))
+        .route("/keys/:id", delete(delete_api_key))
+        .route(
+            "/me/feed",
+            get(my_feed).put(enable_my_feed).delete(disable_my_feed),
+        )
+        .route(
+            "/me/normalization",
+            get(my_normalization)
+                .put(enable_my_normalization)
+                .delete(disable_my_normalization),
+        )
+        .route(
+            "/me/notifications",
+            get(my_notifications).put(update_my_notifications),
+        )
+        .route("/me/data", delete(delete_my_data))
+        .route("/me/export", get(export_my_data).post(start_export))
+        .route("/me/history", get(my_history))
+        .route(
+            "/me/history/import",
+            post(import_history).layer(DefaultBodyLimit::max(HISTORY_IMPORT_LIMIT)),
+        )
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(job_status))
+        .route("/jobs/:id/result", get(job_result))
+        .route("/jobs/:id/stream", get(job_events));
+    #[cfg(feature = "player")]
+    let router = router
+        .route("/player", get(now_playing))
+        .route("/player/events", get(now_playing_events))
+        .route("/player/upnext", get(up_next))
+        .route("/player/play", put(resume_playback))
+        .route("/player/pause", put(pause_playback))
+        .route("/player/next", post(next_track))
+        .route("/player/previous", post(previous_track))
+        .route("/player/queue/:track_id", post(queue_track))
+        .route("/player/seek", put(seek_playback))
+        .route("/player/volume", put(set_volume))
+        .route("/integrations/homeassistant", get(home_assistant))
+        .route(
+            "/me/widget",
+            get(my_widget)
+                .put(enable_my_widget)
+                .delete(disable_my_widget),
+        );
+    #[cfg(feature = "library")]
+    let router = router
+        .route("/discover/deep-cuts/:artist_id", get(deep_cuts))
+        .route("/tracks/:id/availability", get(track_availability))
+        .route("/library/tracks", get(saved_tracks))
+        .route("/library/tracks/:id", put(save_track).delete(unsave_track))
+        .route("/following", get(followed_artists))
+        .route(
+            "/following/:artist_id",
+            put(follow_artist).delete(unfollow_artist),
+        );
+    #[cfg(feature = "stats")]
+    let router = router
+        .route("/stats/genres", get(genre_breakdown))
+        .route("/stats/collage", get(collage))
+        .route("/stats/wrapped", get(wrapped))
+        .route("/stats/streaks", get(streaks))
+        .route("/stats/compare", get(compare))
+        .route("/stats/compare/blend", post(create_blend));
+    router
+}
+
+/// What an instance runs, to tell instances of a deployment apart. Recorded by `build.rs`.
+#[derive(Serialize)]
+#[allow(clippy::struct_field_names)]
+struct Version {
+    version: &'static str,
+    /// Abbreviated, ending in `-dirty` for builds with uncommitted changes.
+    commit: &'static str,
+    built_at: Option<DateTime<Utc>>,
+    features: Vec<&'static str>,
+    started_at: DateTime<Utc>,
+    uptime_secs: i64,
+}
+
+async fn version(State(s): State<Arc<AppStateInner>>) -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("BUILD_COMMIT"),
+        built_at: env!("BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        started_at: s.started_at,
+        uptime_secs: (Utc::now() - s.started_at).num_seconds(),
+    })
+}
+
+/// Whether the request is logged in, and as whom, for frontends deciding what to show.
+#[derive(Serialize)]
+struct SessionStatus {
+    authenticated: bool,
+    user: Option<spotify::User>,
+    /// Whether playback controls work for the user, so frontends can hide them otherwise.
+    premium: Option<bool>,
+}
+
+async fn session_status(session: Option<Session>) -> Result<Json<SessionStatus>, AppError> {
+    let Some(session) = session else {
+        return Ok(Json(SessionStatus {
+            authenticated: false,
+            user: None,
+            premium: None,
+        }));
+    };
+    let user = spotify::current_user(&session.token.access_token).await?;
+    Ok(Json(SessionStatus {
+        authenticated: true,
+        premium: user.premium(),
+        user: Some(user),
+    }))
+}
+
+/// What the request's Spotify token allows, for debugging permission problems. The token itself
+/// is never shown.
+#[derive(Serialize)]
+struct TokenInfo {
+    /// `session` or `api_key`.
+    auth: &'static str,
+    user: spotify::User,
+    token_type: String,
+    /// Empty when unknown, for tokens granted before scopes were kept.
+    scopes: Vec<String>,
+    /// Scopes the app asks for on login that the token wasn't granted.
+    missing_scopes: Vec<&'static str>,
+    /// Scopes of the API key, when authenticated with one.
+    key_scopes: Option<Vec<Scope>>,
+    expires_at: DateTime<Utc>,
+    /// Seconds until the token expires. It's refreshed a few minutes before that.
+    expires_in: i64,
+}
+
+async fn token_info(
+    session: Session,
+    key: Option<Extension<KeyAuth>>,
+) -> Result<Json<TokenInfo>, AppError> {
+    let user = spotify::current_user(&session.token.access_token).await?;
+    let wanted: Vec<&'static str> = crate::SCOPES.split_whitespace().collect();
+    Ok(Json(TokenInfo {
+        auth: if key.is_some() { "api_key" } else { "session" },
+        user,
+        token_type: session.token.token_type.clone(),
+        scopes: session
+            .token
+            .scope
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+        missing_scopes: session.token.missing_scopes(&wanted),
+        key_scopes: key.map(|Extension(key)| key.scopes),
+        expires_at: session.token_expires_at,
+        expires_in: (session.token_expires_at - Utc::now()).num_seconds().max(0),
+    }))
+}
+
+async fn playlists(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<Playlist>>, AppError> {
+    let mut playlists: Vec<Playlist> = spotify::playlists(&session.token.access_token)
+        .try_collect()
+        .await?;
+    s.art
+        .annotate(playlists.iter_mut().flat_map(|p| &mut p.images))
+        .await;
+    Ok(Json(playlists))
+}
+
+#[derive(Serialize)]
+struct PlaylistTracks {
+    snapshot_id: String,
+    items: Vec<PlaylistItem>,
+}
+
+async fn playlist_tracks(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+) -> Result<Json<PlaylistTracks>, AppError> {
+    let (snapshot_id, mut items) = s
+        .playlist_cache
+        .items(&session.token.access_token, &id)
+        .await?;
+    s.art
+        .annotate(
+            items
+                .iter_mut()
+                .filter_map(|item| item.track.as_mut())
+                .flat_map(|track| &mut track.album.images),
+        )
+        .await;
+    Ok(Json(PlaylistTracks { snapshot_id, items }))
+}
+
+/// Body of playlist mutations: the snapshot the client based its edit on.
+#[derive(Deserialize)]
+struct SnapshotBody {
+    snapshot_id: String,
+}
+
+#[derive(Serialize)]
+struct Deduped {
+    snapshot_id: String,
+    removed: usize,
+}
+
+/// Removes every repeated occurrence of a track, keeping the first one.
+async fn dedupe_playlist(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+    Json(body): Json<SnapshotBody>,
+) -> Result<Json<Deduped>, AppError> {
+    let token = &session.token.access_token;
+    let items = s
+        .playlist_cache
+        .items_at(token, &id, &body.snapshot_id)
+        .await?;
+
+    let mut seen = HashMap::<&str, Vec<usize>>::new();
+    for (position, item) in items.iter().enumerate() {
+        if let Some(track) = &item.track {
+            seen.entry(&track.uri).or_default().push(position);
+        }
+    }
+    let duplicates: Vec<(String, Vec<usize>)> = seen
+        .into_iter()
+        .filter(|(_, positions)| positions.len() > 1)
+        .map(|(uri, positions)| (uri.to_owned(), positions[1..].to_vec()))
+        .collect();
+    let removed = duplicates.iter().map(|(_, p)| p.len()).sum();
+    if removed == 0 {
+        return Ok(Json(Deduped {
+            snapshot_id: body.snapshot_id,
+            removed,
+        }));
+    }
+
+    let snapshot_id =
+        spotify::remove_playlist_items(token, &id, &duplicates, &body.snapshot_id).await?;
+    s.playlist_cache.invalidate(&id).await;
+    s.undo
+        .record(&session.user_id, &id, &items, snapshot_id.clone())
+        .await;
+    Ok(Json(Deduped {
+        snapshot_id,
+        removed,
+    }))
+}
+
+/// Mirrors the parameters of Spotify's reorder API.
+#[derive(Deserialize)]
+struct ReorderBody {
+    snapshot_id: String,
+    range_start: usize,
+    insert_before: usize,
+    #[serde(default = "one")]
+    range_length: usize,
+}
+
+const fn one() -> usize {
+    1
+}
+
+impl ReorderBody {
+    /// Whether the range and its destination are within a playlist of `len` items. The numbers
+    /// come from the client, so the end of the range can overflow.
+    fn in_bounds(&self, len: usize) -> bool {
+        self.range_length > 0
+            && self
+                .range_start
+                .checked_add(self.range_length)
+                .is_some_and(|end| end <= len)
+            && self.insert_before <= len
+    }
+}
+
+#[derive(Serialize)]
+struct Reordered {
+    snapshot_id: String,
+}
+
+async fn reorder_playlist(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+    Json(body): Json<ReorderBody>,
+) -> Result<axum::response::Response, AppError> {
+    let token = &session.token.access_token;
+    let items = s
+        .playlist_cache
+        .items_at(token, &id, &body.snapshot_id)
+        .await?;
+    if !body.in_bounds(items.len()) {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "reorder range is out of bounds",
+        )
+            .into_response());
+    }
+
+    let snapshot_id = spotify::reorder_playlist_items(
+        token,
+        &id,
+        body.range_start,
+        body.insert_before,
+        body.range_length,
+        &body.snapshot_id,
+    )
+    .await?;
+    s.playlist_cache.invalidate(&id).await;
+    s.undo
+        .record(&session.user_id, &id, &items, snapshot_id.clone())
+        .await;
+    Ok(Json(Reordered { snapshot_id }).into_response())
+}
+
+#[derive(Serialize)]
+struct Undone {
+    snapshot_id: String,
+}
+
+/// Puts back the tracks a playlist had before the app's last edit of it. `404` if there's nothing
+/// to undo, `409` if the playlist was changed since.
+async fn undo_playlist_edit(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+) -> Result<axum::response::Response, AppError> {
+    let Some(edit) = s.undo.get(&session.user_id, &id).await else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let token = &session.token.access_token;
+    let current = spotify::playlist_snapshot_id(token, &id).await?;
+    if current != edit.snapshot_id {
+        return Err(SnapshotConflict {
+            snapshot_id: current,
+        }
+        .into());
+    }
+    let snapshot_id = spotify::replace_playlist_items(token, &id, &edit.uris).await?;
+    s.playlist_cache.invalidate(&id).await;
+    s.undo.remove(&session.user_id, &id).await;
+    Ok(Json(Undone { snapshot_id }).into_response())
+}
+
+/// Makes the uploaded image the playlist's cover, cropped square and shrunk to what Spotify takes.
+/// Spotify applies it in the background, hence the `202`. `422` if the body isn't an image.
+async fn upload_playlist_image(
+    session: Session,
+    SpotifyId(id): SpotifyId,
+    body: axum::body::Bytes,
+) -> Result<axum::response::Response, AppError> {
+    session.require(UPLOAD_IMAGES)?;
+    let cover = tokio::task::spawn_blocking(move || art::playlist_cover(&body)).await?;
+    let cover = match cover {
+        Ok(cover) => cover,
+        Err(e) => return Ok((StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}")).into_response()),
+    };
+    spotify::upload_playlist_image(&session.token.access_token, &id, &cover).await?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+async fn list_rules(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<Rule>>, AppError> {
+    Ok(Json(s.rules.owned_by(&session.user_id).await?))
+}
+
+#[derive(Deserialize)]
+struct NewRule {
+    name: String,
+    criteria: Criteria,
+    target_playlist_id: String,
+}
+
+async fn create_rule(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(body): Json<NewRule>,
+) -> Result<(StatusCode, Json<Rule>), AppError> {
+    let rule = s
+        .rules
+        .insert(
+            session.user_id,
+            body.name,
+            body.criteria,
+            body.target_playlist_id,
+        )
+        .await?;
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+async fn delete_rule(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.rules.remove(&session.user_id, &id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+#[derive(Serialize)]
+struct RuleApplied {
+    snapshot_id: String,
+    tracks: usize,
+}
+
+/// Applies a rule right away instead of waiting for the worker.
+async fn run_rule(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> Result<axum::response::Response, AppError> {
+    let Some(rule) = s.rules.get(&session.user_id, &id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let token = &session.token.access_token;
+    let library = library::load(token, &s.genres, &s.features).await?;
+    let (_, before) = s
+        .playlist_cache
+        .items(token, &rule.target_playlist_id)
+        .await?;
+    let (snapshot_id, tracks) = rules::apply(token, &rule, &library).await?;
+    s.playlist_cache.invalidate(&rule.target_playlist_id).await;
+    s.undo
+        .record(
+            &session.user_id,
+            &rule.target_playlist_id,
+            &before,
+            snapshot_id.clone(),
+        )
+        .await;
+    Ok(Json(RuleApplied {
+        snapshot_id,
+        tracks,
+    })
+    .into_response())
+}
+
+#[derive(Deserialize)]
+struct ForgottenQuery {
+    /// Tracks not played for this many months are forgotten.
+    #[serde(default = "six")]
+    months: u32,
+    /// Size of the generated playlist, 1 to [`MAX_FORGOTTEN`].
+    #[serde(default = "fifty")]
+    limit: usize,
+}
+
+/// Spotify's limit on the size of a playlist.
+const MAX_FORGOTTEN: usize = 10_000;
+
+const fn six() -> u32 {
+    6
+}
+
+const fn fifty() -> usize {
+    50
+}
+
+#[derive(Serialize)]
+struct Generated {
+    playlist_id: String,
+    tracks: usize,
+    /// Start of the collected history, for generators based on it. Plays before then are
+    /// unknown, so tracks that were only played before it may still show up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history_since: Option<DateTime<Utc>>,
+}
+
+/// Builds a playlist of saved tracks that haven't been played in a while. `400` for limits out of
+/// range.
+async fn generate_forgotten(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<ForgottenQuery>,
+) -> Result<axum::response::Response, AppError> {
+    if !(1..=MAX_FORGOTTEN).contains(&q.limit) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            format!("limit is 1 to {MAX_FORGOTTEN}"),
+        )
+            .into_response());
+    }
+    let token = &session.token.access_token;
+    let cutoff = Utc::now()
+        .checked_sub_months(Months::new(q.months))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC);
+    let last_played = s.history.last_played(&session.user_id).await?;
+
+    let mut forgotten: Vec<String> = spotify::saved_tracks(token)
+        .try_filter(|saved| {
+            // Tracks saved recently can't have been forgotten yet.
+            let saved_long_ago = saved
+                .added_at
+                .parse::<DateTime<Utc>>()
+                .is_ok_and(|added_at| added_at < cutoff);
+            let not_played = last_played
+                .get(&saved.track.id)
+                .is_none_or(|played_at| *played_at < cutoff);
+            std::future::ready(saved_long_ago && not_played)
+        })
+        .map_ok(|saved| saved.track.uri)
+        .try_collect()
+        .await?;
+    if forgotten.is_empty() {
+        // Rather than leaving an empty playlist in the user's account.
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("No saved tracks have gone unplayed for {} months", q.months),
+        )
+            .into_response());
+    }
+    forgotten.shuffle(&mut rand::thread_rng());
+    forgotten.truncate(q.limit);
+
+    let playlist = spotify::create_playlist(
+        token,
+        &session.user_id,
+        "Forgotten gems",
+        &format!("Saved tracks you haven't played in {} months", q.months),
+    )
+    .await?;
+    spotify::replace_playlist_items(token, &playlist.id, &forgotten).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(Generated {
+            playlist_id: playlist.id,
+            tracks: forgotten.len(),
+            history_since: s.history.collecting_since(&session.user_id).await?,
+        }),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct RunQuery {
+    target_bpm: f32,
+    duration_min: u32,
+}
+
+/// Builds a running playlist around a cadence out of the saved tracks.
+async fn generate_run(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<RunQuery>,
+) -> Result<axum::response::Response, AppError> {
+    if !(60.0..=240.0).contains(&q.target_bpm) || !(1..=600).contains(&q.duration_min) {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "target_bpm must be within 60-240 and duration_min within 1-600",
+        )
+            .into_response());
+    }
+    let token = &session.token.access_token;
+    let library = library::load(token, &s.genres, &s.features).await?;
+    let uris = running::build(&library, q.target_bpm, u64::from(q.duration_min) * 60_000);
+    if uris.is_empty() {
+        // Rather than leaving an empty playlist in the user's account.
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "No saved tracks are close enough to {} BPM",
+                q.target_bpm.round()
+            ),
+        )
+            .into_response());
+    }
+
+    let playlist = spotify::create_playlist(
+        token,
+        &session.user_id,
+        &format!("Running at {} BPM", q.target_bpm.round()),
+        &format!("{} minutes: warm-up, steady and cool-down", q.duration_min),
+    )
+    .await?;
+    spotify::replace_playlist_items(token, &playlist.id, &uris).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(Generated {
+            playlist_id: playlist.id,
+            tracks: uris.len(),
+            history_since: None,
+        }),
+    )
+        .into_response())
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize)]
+struct DeepCutsQuery {
+    #[serde(default = "twenty")]
+    limit: usize,
+}
+
+#[cfg(feature = "library")]
+const fn twenty() -> usize {
+    20
+}
+
+/// An artist's least popular tracks the user hasn't saved or played.
+#[cfg(feature = "library")]
+async fn deep_cuts(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(artist_id): SpotifyId,
+    Query(q): Query<DeepCutsQuery>,
+) -> Result<Json<Vec<Track>>, AppError> {
+    let token = &session.token.access_token;
+    let mut known: HashSet<String> = spotify::saved_tracks(token)
+        .map_ok(|saved| saved.track.id)
+        .try_collect()
+        .await?;
+    known.extend(s.history.last_played(&session.user_id).await?.into_keys());
+    Ok(Json(
+        discover::deep_cuts(token, &artist_id, &known, q.limit).await?,
+    ))
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize)]
+struct AvailabilityQuery {
+    /// Comma-separated ISO 3166-1 alpha-2 country codes to check in detail.
+    markets: Option<String>,
+}
+
+/// At most this many markets can be checked in detail per request, each one being a call to
+/// Spotify.
+#[cfg(feature = "library")]
+const MAX_CHECKED_MARKETS: usize = 20;
+
+#[cfg(feature = "library")]
+async fn track_availability(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+    Query(q): Query<AvailabilityQuery>,
+) -> Result<axum::response::Response, AppError> {
+    let markets: Vec<String> = q
+        .markets
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|m| m.trim().to_uppercase())
+        .filter(|m| !m.is_empty())
+        .unique()
+        .collect();
+    if markets.len() > MAX_CHECKED_MARKETS {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("at most {MAX_CHECKED_MARKETS} markets can be checked at once"),
+        )
+            .into_response());
+    }
+    let availability: Availability = s
+        .availability
+        .check(&session.token.access_token, &id, &markets)
+        .await?;
+    Ok(Json(availability).into_response())
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize)]
+struct LibraryQuery {
+    genre: Option<String>,
+}
+
+/// Streamed, since libraries can have thousands of tracks.
+#[cfg(feature = "library")]
+async fn saved_tracks(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<LibraryQuery>,
+) -> JsonArray<impl Stream<Item = anyhow::Result<TaggedTrack>>> {
+    let tracks = library::tagged_stream(session.token.access_token, s.genres.clone()).try_filter(
+        move |track| {
+            future::ready(
+                q.genre
+                    .as_ref()
+                    .is_none_or(|genre| track.genres.contains(genre)),
+            )
+        },
+    );
+    JsonArray(tracks)
+}
+
+/// Saves a track to the library. With [`WantsFragment`], answers with the button removing it.
+#[cfg(feature = "library")]
+async fn save_track(
+    session: Session,
+    SpotifyId(id): SpotifyId,
+    fragment: WantsFragment,
+) -> Result<axum::response::Response, AppError> {
+    session.require(MODIFY_LIBRARY)?;
+    spotify::set_saved(&session.token.access_token, &id, true).await?;
+    Ok(ButtonTemplate::save(&id, true).respond(fragment))
+}
+
+/// With [`WantsFragment`], answers with the button saving the track again.
+#[cfg(feature = "library")]
+async fn unsave_track(
+    session: Session,
+    SpotifyId(id): SpotifyId,
+    fragment: WantsFragment,
+) -> Result<axum::response::Response, AppError> {
+    session.require(MODIFY_LIBRARY)?;
+    spotify::set_saved(&session.token.access_token, &id, false).await?;
+    Ok(ButtonTemplate::save(&id, false).respond(fragment))
+}
+
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+struct GenreCount {
+    genre: String,
+    tracks: usize,
+}
+
+/// How many saved tracks each genre has, most common first. Out of time, the counts of the
+/// tracks tagged so far.
+#[cfg(feature = "stats")]
+async fn genre_breakdown(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<axum::response::Response, AppError> {
+    let tracks = library::tagged_stream(session.token.access_token, s.genres.clone());
+    let (genres, result) = collect_within_budget(tracks.map_ok(|track| track.genres)).await;
+    let tagged = genres.len();
+    let mut counts: Vec<GenreCount> = genres
+        .into_iter()
+        .flatten()
+        .counts()
+        .into_iter()
+        .map(|(genre, tracks)| GenreCount { genre, tracks })
+        .collect();
+    counts.sort_by(|a, b| b.tracks.cmp(&a.tracks).then_with(|| a.genre.cmp(&b.genre)));
+    match result {
+        Ok(()) => Ok(Json(counts).into_response()),
+        Err(e) if deadline::is_exceeded(&e) => Ok(deadline::response(Some((tagged, counts)))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(feature = "stats")]
+#[derive(Deserialize)]
+struct CollageQuery {
+    #[serde(default)]
+    period: Period,
+    /// Covers across and down, as in `3x3`.
+    size: Option<String>,
+}
+
+/// A JPEG grid of the covers of the albums played most in the period, as an attachment to save
+/// and share. 404 without any plays in it.
+#[cfg(feature = "stats")]
+async fn collage(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<CollageQuery>,
+) -> Result<axum::response::Response, AppError> {
+    let grid = match q.size.as_deref().map(str::parse::<Grid>) {
+        Some(Ok(grid)) => grid,
+        Some(Err(e)) => return Ok((StatusCode::BAD_REQUEST, format!("{e:#}")).into_response()),
+        None => Grid::default(),
+    };
+    let plays = s.history.plays(&session.user_id);
+    let Some(jpeg) = s
+        .collages
+        .get(&s.art, &session.user_id, q.period, grid, plays)
+        .await?
+    else {
+        return Ok((StatusCode::NOT_FOUND, "no plays in this period").into_response());
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/jpeg"),
+            (header::CACHE_CONTROL, "private, max-age=3600"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"blid-collage.jpg\"",
+            ),
+        ],
+        jpeg,
+    )
+        .into_response())
+}
+
+#[cfg(feature = "stats")]
+#[derive(Deserialize)]
+struct WrappedQuery {
+    /// Defaults to the current year.
+    year: Option<i32>,
+}
+
+/// The user's year in review, out of the plays collected during it.
+#[cfg(feature = "stats")]
+async fn wrapped(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<WrappedQuery>,
+) -> Result<Json<Wrapped>, AppError> {
+    let year = q.year.unwrap_or_else(|| Utc::now().year());
+    let plays = s.history.plays(&session.user_id).await?;
+    Ok(Json(
+        stats::wrapped(&s.genres, &session.token.access_token, year, plays).await?,
+    ))
+}
+
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+struct Streaks {
+    /// Zero days when the user didn't listen today or yesterday.
+    current: Streak,
+    longest: Streak,
+    /// Oldest first.
+    milestones: Vec<Milestone>,
+}
+
+/// Listening streaks and milestones, out of the collected history.
+#[cfg(feature = "stats")]
+async fn streaks(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Streaks>, AppError> {
+    let plays = s.history.plays(&session.user_id).await?;
+    let streaks = stats::streaks(plays.iter().map(|(played_at, _)| played_at.date_naive()));
+    Ok(Json(Streaks {
+        current: stats::current_streak(&streaks, Utc::now().date_naive()),
+        longest: stats::longest(&streaks),
+        milestones: stats::milestones(&plays),
+    }))
+}
+
+#[cfg(feature = "stats")]
+#[derive(Deserialize)]
+struct CompareQuery {
+    /// Defaults to the user.
+    a: Option<String>,
+    b: String,
+}
+
+/// The users of `q` if the session can compare them: one of them is its user, and the other is
+/// linked to them.
+#[cfg(feature = "stats")]
+async fn comparable(
+    s: &AppStateInner,
+    session: &Session,
+    q: CompareQuery,
+) -> anyhow::Result<Option<(String, String)>> {
+    let a = q.a.unwrap_or_else(|| session.user_id.clone());
+    let other = if a == session.user_id {
+        &q.b
+    } else if q.b == session.user_id {
+        &a
+    } else {
+        return Ok(None);
+    };
+    if !s.links.are_linked(&session.user_id, other).await? {
+        return Ok(None);
+    }
+    Ok(Some((a, q.b)))
+}
+
+/// How the listening of two linked users overlaps. 403 unless one of them is the user and the
+/// other is linked to them.
+#[cfg(feature = "stats")]
+async fn compare(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<CompareQuery>,
+) -> Result<axum::response::Response, AppError> {
+    let Some((a, b)) = comparable(&s, &session, q).await? else {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+    let (plays_a, plays_b) = (s.history.plays(&a).await?, s.history.plays(&b).await?);
+    let comparison =
+        stats::compare(&s.genres, &session.token.access_token, &plays_a, &plays_b).await?;
+    Ok(Json(comparison).into_response())
+}
+
+/// Most tracks a blend is made of.
+#[cfg(feature = "stats")]
+const MAX_BLEND: usize = 500;
+
+#[cfg(feature = "stats")]
+#[derive(Deserialize)]
+struct BlendQuery {
+    a: Option<String>,
+    b: String,
+    /// Size of the playlist, up to [`MAX_BLEND`].
+    #[serde(default = "fifty")]
+    limit: usize,
+}
+
+/// Creates a playlist mixing what two linked users listen to, in the user's account.
+#[cfg(feature = "stats")]
+async fn create_blend(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<BlendQuery>,
+) -> Result<axum::response::Response, AppError> {
+    let users = CompareQuery { a: q.a, b: q.b };
+    let Some((a, b)) = comparable(&s, &session, users).await? else {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+    let (plays_a, plays_b) = (s.history.plays(&a).await?, s.history.plays(&b).await?);
+    let uris = stats::blend(&plays_a, &plays_b, q.limit.min(MAX_BLEND));
+    if uris.is_empty() {
+        // Rather than leaving an empty playlist in the user's account.
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Neither {a} nor {b} has played anything yet"),
+        )
+            .into_response());
+    }
+    let token = &session.token.access_token;
+    let playlist = spotify::create_playlist(
+        token,
+        &session.user_id,
+        "Blend",
+        &format!("What {a} and {b} both listen to"),
+    )
+    .await?;
+    spotify::replace_playlist_items(token, &playlist.id, &uris).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(Generated {
+            playlist_id: playlist.id,
+            tracks: uris.len(),
+            history_since: s.history.collecting_since(&session.user_id).await?,
+        }),
+    )
+        .into_response())
+}
+
+async fn list_links(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<String>>, AppError> {
+    Ok(Json(s.links.linked(&session.user_id).await?))
+}
+
+#[derive(Serialize)]
+struct LinkInvite {
+    code: String,
+}
+
+/// An invite for another user to link with this one, by accepting the code while logged in.
+async fn create_link_invite(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<(StatusCode, Json<LinkInvite>), AppError> {
+    let code = s.links.invite(&session.user_id).await?;
+    Ok((StatusCode::CREATED, Json(LinkInvite { code })))
+}
+
+#[derive(Serialize)]
+struct Linked {
+    user_id: String,
+}
+
+/// 404 for invites that are unknown, expired, used, or the user's own.
+async fn accept_link_invite(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(code): Path<String>,
+) -> Result<axum::response::Response, AppError> {
+    Ok(s.links.accept(&code, &session.user_id).await?.map_or_else(
+        || StatusCode::NOT_FOUND.into_response(),
+        |user_id| Json(Linked { user_id }).into_response(),
+    ))
+}
+
+async fn unlink(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    UserId(user_id): UserId,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.links.unlink(&session.user_id, &user_id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// What the user's friends are listening to. Empty unless the user shares too.
+async fn friend_activity(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<Friend>>, AppError> {
+    Ok(Json(s.friends.activity(&s, &session.user_id).await?))
+}
+
+async fn my_sharing(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Sharing>, AppError> {
+    Ok(Json(s.friends.get(&session.user_id).await?))
+}
+
+/// Who sees what the user is listening to, and under which name.
+async fn update_my_sharing(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(sharing): Json<Sharing>,
+) -> Result<Json<Sharing>, AppError> {
+    Ok(Json(s.friends.set(&session.user_id, sharing).await?))
+}
+
+/// How long the user's history and playlist activity are kept, `30d`, `90d`, `365d` or
+/// `forever`.
+#[derive(Serialize, Deserialize)]
+struct RetentionSetting {
+    retention: Retention,
+}
+
+async fn my_retention(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<RetentionSetting>, AppError> {
+    Ok(Json(RetentionSetting {
+        retention: s.retention.get(&session.user_id).await?,
+    }))
+}
+
+/// What's older than the new setting is forgotten by the next run of the retention worker.
+async fn update_my_retention(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(body): Json<RetentionSetting>,
+) -> Result<Json<RetentionSetting>, AppError> {
+    s.retention.set(&session.user_id, body.retention).await?;
+    Ok(Json(body))
+}
+
+#[derive(Serialize)]
+struct ConsentStatus {
+    /// The version users consent to on this instance, `null` when nobody is asked.
+    version: Option<String>,
+    /// What the user consented to, and when.
+    given: Option<Consent>,
+}
+
+async fn my_consent(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<ConsentStatus>, AppError> {
+    Ok(Json(ConsentStatus {
+        version: s.consent.version().map(str::to_owned),
+        given: s.consent.get(&session.user_id).await?,
+    }))
+}
+
+/// Out of time, the artists listed so far.
+#[cfg(feature = "library")]
+async fn followed_artists(session: Session) -> Result<axum::response::Response, AppError> {
+    let artists = spotify::followed_artists(&session.token.access_token);
+    let (artists, result) = collect_within_budget(artists).await;
+    match result {
+        Ok(()) => Ok(Json(artists).into_response()),
+        Err(e) if deadline::is_exceeded(&e) => {
+            Ok(deadline::response(Some((artists.len(), artists))))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// With [`WantsFragment`], answers with the button unfollowing the artist.
+#[cfg(feature = "library")]
+async fn follow_artist(
+    session: Session,
+    SpotifyId(artist_id): SpotifyId,
+    fragment: WantsFragment,
+) -> Result<axum::response::Response, AppError> {
+    session.require(MODIFY_FOLLOWING)?;
+    spotify::set_following(&session.token.access_token, &artist_id, true).await?;
+    Ok(ButtonTemplate::follow(&artist_id, true).respond(fragment))
+}
+
+/// With [`WantsFragment`], answers with the button following the artist again.
+#[cfg(feature = "library")]
+async fn unfollow_artist(
+    session: Session,
+    SpotifyId(artist_id): SpotifyId,
+    fragment: WantsFragment,
+) -> Result<axum::response::Response, AppError> {
+    session.require(MODIFY_FOLLOWING)?;
+    spotify::set_following(&session.token.access_token, &artist_id, false).await?;
+    Ok(ButtonTemplate::follow(&artist_id, false).respond(fragment))
+}
+
+/// Collects `items` until they end or fail, returning those collected so far along with the
+/// error if any, so handlers can answer with partial results when out of time.
+#[cfg(any(feature = "library", feature = "stats"))]
+async fn collect_within_budget<T>(
+    items: impl Stream<Item = anyhow::Result<T>>,
+) -> (Vec<T>, anyhow::Result<()>) {
+    let mut items = std::pin::pin!(items);
+    let mut collected = Vec::new();
+    while let Some(item) = items.next().await {
+        match item {
+            Ok(item) => collected.push(item),
+            Err(e) => return (collected, Err(e)),
+        }
+    }
+    (collected, Ok(()))
+}
+
+/// Releases of followed artists found since the user's first check, newest first.
+async fn new_releases(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<NewRelease>>, AppError> {
+    Ok(Json(s.releases.new_releases(&session.user_id).await?))
+}
+
+async fn list_webhooks(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    Ok(Json(s.webhooks.owned_by(&session.user_id).await?))
+}
+
+#[derive(Deserialize)]
+struct NewWebhook {
+    url: String,
+    events: Vec<EventKind>,
+    #[serde(default)]
+    playlist_ids: Vec<String>,
+}
+
+/// A webhook as returned on creation, the only time its secret is shown.
+#[derive(Serialize)]
+struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+async fn create_webhook(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(body): Json<NewWebhook>,
+) -> Result<axum::response::Response, AppError> {
+    if let Err(e) = webhooks::check_destination(&body.url).await {
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}")).into_response());
+    }
+    if body.events.is_empty() {
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, "events must not be empty").into_response());
+    }
+    let webhook = s
+        .webhooks
+        .insert(
+            session.user_id,
+            body.url,
+            body.events.into_iter().unique().collect(),
+            body.playlist_ids,
+        )
+        .await?;
+    let secret = webhook.secret.clone();
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedWebhook { webhook, secret }),
+    )
+        .into_response())
+}
+
+async fn delete_webhook(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.webhooks.remove(&session.user_id, &id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+async fn webhook_deliveries(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> Result<axum::response::Response, AppError> {
+    Ok(s.webhooks
+        .deliveries(&session.user_id, &id)
+        .await?
+        .map_or_else(
+            || StatusCode::NOT_FOUND.into_response(),
+            |log| Json(log).into_response(),
+        ))
+}
+
+#[derive(Serialize)]
+struct Share {
+    id: String,
+    url: String,
+    snapshot_id: String,
+}
+
+/// Freezes the playlist as it is now into a public page.
+async fn share_playlist(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+) -> Result<impl IntoResponse, AppError> {
+    let share = s
+        .shares
+        .create(
+            &session.token.access_token,
+            &s.playlist_cache,
+            session.user_id,
+            &id,
+        )
+        .await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(Share {
+            url: format!("{}/p/{}", s.public_url, share.id),
+            id: share.id,
+            snapshot_id: share.snapshot_id,
+        }),
+    ))
+}
+
+/// Starts recording the activity of a playlist, from its current contents on.
+async fn watch_playlist(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+) -> Result<StatusCode, AppError> {
+    // Fetched right away, which also checks the user can see the playlist at all.
+    let (snapshot_id, items) = s
+        .playlist_cache
+        .items(&session.token.access_token, &id)
+        .await?;
+    if !s.activity.watch(&session.user_id, &id).await? {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    s.activity
+        .record(&session.user_id, &id, snapshot_id, items)
+        .await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn unwatch_playlist(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.activity.unwatch(&session.user_id, &id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Tracks added to and removed from a watched playlist, newest first. `404` for playlists that
+/// aren't watched.
+async fn playlist_activity(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+) -> Result<axum::response::Response, AppError> {
+    Ok(s.activity
+        .activity(&session.user_id, &id)
+        .await?
+        .map_or_else(
+            || StatusCode::NOT_FOUND.into_response(),
+            |activity| Json(activity).into_response(),
+        ))
+}
+
+/// What the active device is playing. Shared with [`crate::client`], which deserializes it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NowPlaying {
+    pub is_playing: bool,
+    pub device: String,
+    /// `None` for devices whose volume can't be controlled.
+    #[serde(default)]
+    pub volume_percent: Option<u32>,
+    pub progress_ms: Option<u64>,
+    /// `None` for episodes and ads.
+    pub track: Option<Track>,
+}
+
+impl From<spotify::PlaybackState> for NowPlaying {
+    fn from(playback: spotify::PlaybackState) -> Self {
+        Self {
+            is_playing: playback.is_playing,
+            device: playback.device.name,
+            volume_percent: playback.device.volume_percent,
+            progress_ms: playback.progress_ms,
+            track: playback.item,
+        }
+    }
+}
+
+/// `204` when no device is active.
+#[cfg(feature = "player")]
+async fn now_playing(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<axum::response::Response, AppError> {
+    session.require(READ_PLAYBACK)?;
+    let Some(playback) = spotify::playback_state(&session.token.access_token).await? else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+    let mut now_playing = NowPlaying::from(playback);
+    if let Some(track) = &mut now_playing.track {
+        s.art.annotate(&mut track.album.images).await;
+    }
+    Ok(Json(now_playing).into_response())
+}
+
+/// A `now_playing` event with the [`NowPlaying`] JSON, or `null` when no device is active, each
+/// time Spotify is polled. Every stream of a user shares the same polling.
+#[cfg(feature = "player")]
+async fn now_playing_events(
+    State(s): State<Arc<AppStateInner>>,
+    session: Session,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    session.require(READ_PLAYBACK)?;
+    let subscription = s
+        .now_playing
+        .subscribe(&session.user_id, &session.token.access_token);
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let update = subscription.next().await?;
+        let event = Event::default()
+            .event("now_playing")
+            .json_data(update)
+            .unwrap_or_else(|_| Event::default().comment("unserializable update"));
+        Some((Ok(event), subscription))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Upcoming tracks whose endings are looked up, each being a call to Spotify the first time.
+#[cfg(feature = "player")]
+const ANALYZED_UPCOMING: usize = 5;
+
+#[cfg(feature = "player")]
+#[derive(Serialize)]
+struct UpNextTrack {
+    #[serde(flatten)]
+    track: Track,
+    /// `None` for tracks without an analysis, and past the first few upcoming ones.
+    ending: Option<Ending>,
+}
+
+#[cfg(feature = "player")]
+#[derive(Serialize)]
+struct UpNext {
+    current: Option<UpNextTrack>,
+    queue: Vec<UpNextTrack>,
+}
+
+/// The queue along with how tracks end, so a frontend can show or prepare transitions.
+#[cfg(feature = "player")]
+async fn up_next(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<UpNext>, AppError> {
+    session.require(READ_PLAYBACK)?;
+    let token = &session.token.access_token;
+    let queue = spotify::queue(token).await?;
+    let analyzed = queue
+        .currently_playing
+        .iter()
+        .chain(queue.queue.iter().take(ANALYZED_UPCOMING));
+    let mut endings: HashMap<String, Ending> =
+        futures::future::try_join_all(analyzed.map(|track| async {
+            let ending = s.endings.resolve(token, &track.id).await?;
+            anyhow::Ok(ending.map(|ending| (track.id.clone(), ending)))
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+    let mut with_ending = |track: Track| UpNextTrack {
+        ending: endings.remove(&track.id),
+        track,
+    };
+    Ok(Json(UpNext {
+        current: queue.currently_playing.map(&mut with_ending),
+        queue: queue.queue.into_iter().map(with_ending).collect(),
+    }))
+}
+
+#[cfg(feature = "player")]
+async fn resume_playback(session: Session) -> Result<StatusCode, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    spotify::resume(&session.token.access_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(feature = "player")]
+async fn pause_playback(session: Session) -> Result<StatusCode, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    spotify::pause(&session.token.access_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(feature = "player")]
+async fn next_track(session: Session) -> Result<StatusCode, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    spotify::skip_to_next(&session.token.access_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(feature = "player")]
+async fn previous_track(session: Session) -> Result<StatusCode, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    spotify::skip_to_previous(&session.token.access_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Adds a track to the end of the queue. With [`WantsFragment`], answers with a disabled
+/// button saying so.
+#[cfg(feature = "player")]
+async fn queue_track(
+    session: Session,
+    SpotifyId(track_id): SpotifyId,
+    fragment: WantsFragment,
+) -> Result<axum::response::Response, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    spotify::add_to_queue(&session.token.access_token, &track_id).await?;
+    Ok(ButtonTemplate::queued().respond(fragment))
+}
+
+#[cfg(feature = "player")]
+#[derive(Deserialize)]
+struct Seek {
+    position_ms: u64,
+}
+
+#[cfg(feature = "player")]
+async fn seek_playback(session: Session, Json(body): Json<Seek>) -> Result<StatusCode, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    spotify::seek(&session.token.access_token, body.position_ms).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(feature = "player")]
+#[derive(Deserialize)]
+struct Volume {
+    volume_percent: u32,
+}
+
+/// `400` for volumes over 100.
+#[cfg(feature = "player")]
+async fn set_volume(
+    session: Session,
+    Json(body): Json<Volume>,
+) -> Result<axum::response::Response, AppError> {
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    if body.volume_percent > 100 {
+        return Ok((StatusCode::BAD_REQUEST, "volume_percent is 0 to 100").into_response());
+    }
+    spotify::set_volume(&session.token.access_token, body.volume_percent).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Playback in the shape of Home Assistant's `media_player` attributes, so a REST sensor or
+/// template media player can use it as is. Durations are in seconds and the volume is 0 to 1.
+#[cfg(feature = "player")]
+#[derive(Serialize, Default)]
+struct HomeAssistantState {
+    /// `playing`, `paused` or `idle`.
+    state: &'static str,
+    media_content_id: Option<String>,
+    media_title: Option<String>,
+    media_artist: Option<String>,
+    media_album_name: Option<String>,
+    media_duration: Option<f64>,
+    media_position: Option<f64>,
+    media_position_updated_at: Option<DateTime<Utc>>,
+    entity_picture: Option<String>,
+    volume_level: Option<f64>,
+    /// Name of the device playing.
+    source: Option<String>,
+}
+
+#[cfg(feature = "player")]
+async fn home_assistant(session: Session) -> Result<Json<HomeAssistantState>, AppError> {
+    session.require(READ_PLAYBACK)?;
+    let Some(playback) = spotify::playback_state(&session.token.access_token).await? else {
+        return Ok(Json(HomeAssistantState {
+            state: "idle",
+            ..HomeAssistantState::default()
+        }));
+    };
+    let volume_level = playback
+        .device
+        .volume_percent
+        .map(|volume| f64::from(volume) / 100.0);
+    let source = Some(playback.device.name);
+    let state = if playback.is_playing {
+        "playing"
+    } else {
+        "paused"
+    };
+    let Some(track) = playback.item else {
+        // An episode or an ad, which we know nothing about.
+        return Ok(Json(HomeAssistantState {
+            state,
+            volume_level,
+            source,
+            ..HomeAssistantState::default()
+        }));
+    };
+    Ok(Json(HomeAssistantState {
+        state,
+        media_content_id: Some(track.uri),
+        media_title: Some(track.name),
+        media_artist: Some(track.artists.iter().map(|a| &a.name).join(", ")),
+        media_duration: Some(seconds(track.duration_ms)),
+        media_position: playback.progress_ms.map(seconds),
+        media_position_updated_at: Some(Utc::now()),
+        entity_picture: track.album.images.first().map(|image| image.url.clone()),
+        media_album_name: Some(track.album.name),
+        volume_level,
+        source,
+    }))
+}
+
+#[cfg(feature = "player")]
+#[allow(clippy::cast_precision_loss)]
+fn seconds(ms: u64) -> f64 {
+    ms as f64 / 1000.0
+}
+
+async fn list_api_keys(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Vec<ApiKey>>, AppError> {
+    Ok(Json(s.api_keys.owned_by(&session.user_id).await?))
+}
+
+#[derive(Deserialize)]
+struct NewApiKey {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<Scope>,
+}
+
+/// An API key as returned on creation, the only time the key itself is shown.
+#[derive(Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    secret: String,
+}
+
+/// Mints a key acting as the current user. Only sessions can do this, so a leaked key can't be
+/// used to make more.
+async fn create_api_key(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(body): Json<NewApiKey>,
+) -> Result<axum::response::Response, AppError> {
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "name must be between 1 and 64 characters",
+        )
+            .into_response());
+    }
+    let Some(id) = &session.id else {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+    let Some(data) = s.sessions.get(&id.hash()).await? else {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let (secret, key) = s
+        .api_keys
+        .mint(
+            data.user_id,
+            name.to_owned(),
+            body.scopes.into_iter().unique().collect(),
+            data.token,
+            data.token_expires_at,
+        )
+        .await?;
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { key, secret })).into_response())
+}
+
+async fn delete_api_key(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.api_keys.remove(&session.user_id, &id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+#[derive(Serialize)]
+struct FeedUrls {
+    json: String,
+    rss: String,
+}
+
+impl FeedUrls {
+    fn new(public_url: &str, slug: &str) -> Self {
+        Self {
+            json: format!("{public_url}/u/{slug}/feed.json"),
+            rss: format!("{public_url}/u/{slug}/feed.xml"),
+        }
+    }
+}
+
+async fn my_feed(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<axum::response::Response, AppError> {
+    Ok(s.feeds.slug(&session.user_id).await?.map_or_else(
+        || StatusCode::NOT_FOUND.into_response(),
+        |slug| Json(FeedUrls::new(&s.public_url, &slug)).into_response(),
+    ))
+}
+
+/// Makes the user's recent listens public.
+async fn enable_my_feed(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<FeedUrls>, AppError> {
+    let slug = s.feeds.enable(&session.user_id).await?;
+    Ok(Json(FeedUrls::new(&s.public_url, &slug)))
+}
+
+async fn disable_my_feed(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.feeds.disable(&session.user_id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// How to embed the widget.
+#[cfg(feature = "player")]
+#[derive(Serialize)]
+struct WidgetEmbed {
+    slug: String,
+    snippet: String,
+}
+
+#[cfg(feature = "player")]
+impl WidgetEmbed {
+    fn new(public_url: &str, slug: String) -> Self {
+        Self {
+            snippet: widget::snippet(public_url, &slug),
+            slug,
+        }
+    }
+}
+
+#[cfg(feature = "player")]
+async fn my_widget(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<axum::response::Response, AppError> {
+    Ok(s.widgets.slug(&session.user_id).await?.map_or_else(
+        || StatusCode::NOT_FOUND.into_response(),
+        |slug| Json(WidgetEmbed::new(&s.public_url, slug)).into_response(),
+    ))
+}
+
+/// Makes what the user is playing public, through the widget.
+#[cfg(feature = "player")]
+async fn enable_my_widget(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<WidgetEmbed>, AppError> {
+    let slug = s.widgets.enable(&session.user_id).await?;
+    Ok(Json(WidgetEmbed::new(&s.public_url, slug)))
+}
+
+#[cfg(feature = "player")]
+async fn disable_my_widget(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.widgets.disable(&session.user_id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+#[derive(Serialize)]
+struct NormalizationStatus {
+    enabled: bool,
+}
+
+async fn my_normalization(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<NormalizationStatus>, AppError> {
+    Ok(Json(NormalizationStatus {
+        enabled: s.normalization.enabled(&session.user_id).await?,
+    }))
+}
+
+/// Starts adjusting the volume of the user's active device to the loudness of each track.
+async fn enable_my_normalization(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<NormalizationStatus>, AppError> {
+    session.require(READ_PLAYBACK)?;
+    session.require(MODIFY_PLAYBACK)?;
+    session.require_premium()?;
+    s.normalization.enable(&session.user_id).await?;
+    Ok(Json(NormalizationStatus { enabled: true }))
+}
+
+async fn disable_my_normalization(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<StatusCode, AppError> {
+    Ok(if s.normalization.disable(&session.user_id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// `None` until the user first subscribes to something.
+async fn my_notifications(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<Json<Option<Preferences>>, AppError> {
+    Ok(Json(s.notifications.get(&session.user_id).await?))
+}
+
+#[derive(Deserialize)]
+struct NotificationUpdate {
+    weekly_digest: bool,
+    #[serde(default)]
+    new_releases: bool,
+    #[serde(default)]
+    milestones: bool,
+}
+
+/// Subscribing sends emails to the address on the user's Spotify account.
+async fn update_my_notifications(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(body): Json<NotificationUpdate>,
+) -> Result<axum::response::Response, AppError> {
+    if !body.weekly_digest && !body.new_releases && !body.milestones {
+        return Ok(Json(s.notifications.unsubscribe(&session.user_id).await?).into_response());
+    }
+    let user = spotify::current_user(&session.token.access_token).await?;
+    let Some(email) = user.email else {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Your Spotify account has no email address",
+        )
+            .into_response());
+    };
+    Ok(Json(Some(
+        s.notifications
+            .subscribe(
+                &session.user_id,
+                email,
+                body.weekly_digest,
+                body.new_releases,
+                body.milestones,
+            )
+            .await?,
+    ))
+    .into_response())
+}
+
+#[derive(Serialize)]
+#[allow(clippy::struct_excessive_bools)]
+struct Purged {
+    sessions: usize,
+    plays: usize,
+    rules: usize,
+    webhooks: usize,
+    shares: usize,
+    api_keys: usize,
+    /// Playlist edits that could still have been undone.
+    undoable_edits: usize,
+    /// Background jobs, with what they made.
+    jobs: usize,
+    /// Whether a public feed was disabled.
+    feed: bool,
+    /// Whether the widget was disabled.
+    #[cfg(feature = "player")]
+    widget: bool,
+    /// Whether volume normalization was disabled.
+    normalization: bool,
+    /// Whether notification settings, and the email address with them, were deleted.
+    notifications: bool,
+    /// Whether the releases seen for the user were forgotten.
+    releases: bool,
+    watched_playlists: usize,
+    /// Users the user was linked with, who are unlinked.
+    links: usize,
+    /// Whether the user shared what they're listening to.
+    sharing: bool,
+    /// Whether the record of the user's consent was deleted, so they're asked again next login.
+    consent: bool,
+    /// Whether a retention setting was deleted.
+    retention: bool,
+    /// Places of recent logins, remembered to notice unusual ones.
+    logins: usize,
+}
+
+/// Deletes everything stored about the user and logs out all of their sessions. Caches of
+/// Spotify catalogue data are shared between users and kept.
+async fn delete_my_data(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<impl IntoResponse, AppError> {
+    let rules = s.rules.remove_owned_by(&session.user_id).await?;
+    let plays = s.history.remove(&session.user_id).await?;
+    let webhooks = s.webhooks.remove_owned_by(&session.user_id).await?;
+    let shares = s.shares.remove_owned_by(&session.user_id).await?;
+    let api_keys = s.api_keys.remove_owned_by(&session.user_id).await?;
+    let undoable_edits = s.undo.remove_owned_by(&session.user_id).await;
+    let jobs = s.jobs.remove_owned_by(&session.user_id).await;
+    let feed = s.feeds.disable(&session.user_id).await?;
+    #[cfg(feature = "player")]
+    let widget = s.widgets.disable(&session.user_id).await?;
+    let normalization = s.normalization.disable(&session.user_id).await?;
+    let notifications = s.notifications.remove(&session.user_id).await?;
+    let releases = s.releases.remove(&session.user_id).await?;
+    let watched_playlists = s.activity.remove_owned_by(&session.user_id).await?;
+    let links = s.links.remove_owned_by(&session.user_id).await?;
+    let sharing = s.friends.remove(&session.user_id).await?;
+    let consent = s.consent.remove(&session.user_id).await?;
+    let retention = s.retention.remove(&session.user_id).await?;
+    let logins = s.logins.remove(&session.user_id).await?;
+    // Sessions go last, so a failure above can be retried with the same session.
+    let sessions = s.sessions.remove_user(&session.user_id).await?;
+    tracing::info!(
+        "Purged data of a user: {sessions} sessions, {plays} plays, {rules} rules, \
+         {webhooks} webhooks, {shares} shares, {api_keys} API keys"
+    );
+    Ok((
+        [(header::SET_COOKIE, session::clear_cookie(&s.session_config))],
+        Json(Purged {
+            sessions,
+            plays,
+            rules,
+            webhooks,
+            shares,
+            api_keys,
+            undoable_edits,
+            jobs,
+            feed,
+            #[cfg(feature = "player")]
+            widget,
+            normalization,
+            notifications,
+            releases,
+            watched_playlists,
+            links,
+            sharing,
+            consent,
+            retention,
+            logins,
+        }),
+    ))
+}
+
+#[derive(Serialize)]
+struct Play {
+    played_at: DateTime<Utc>,
+    track: Track,
+}
+
+/// Every play collected for the user, oldest first. Streamed, since it only ever grows.
+async fn my_history(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<JsonArray<impl Stream<Item = anyhow::Result<Play>>>, AppError> {
+    let plays = s.history.plays(&session.user_id).await?;
+    Ok(JsonArray(
+        stream::iter(plays).map(|(played_at, track)| Ok(Play { played_at, track })),
+    ))
+}
+
+async fn export_my_data(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> Result<impl IntoResponse, AppError> {
+    let body = export::bundle(&s, &session.user_id).await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"blid-export.zip\"",
+            ),
+        ],
+        body,
+    ))
+}
+
+/// A job was started, and can be followed at the `Location` it's returned with.
+fn job_started(status: JobStatus) -> axum::response::Response {
+    (
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/api/jobs/{}", status.id))],
+        Json(status),
+    )
+        .into_response()
+}
+
+/// Like `GET /me/export`, but builds the archive in the background for clients that can't keep
+/// a request open that long.
+async fn start_export(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+) -> axum::response::Response {
+    let state = Arc::clone(&s);
+    let user_id = session.user_id.clone();
+    let status = s
+        .jobs
+        .submit(&session.user_id, JobKind::Export, |reporter| async move {
+            reporter.progress(0, 2).await;
+            reporter.log("Collecting your data").await;
+            let body = export::bundle(&state, &user_id).await?;
+            reporter.progress(1, 2).await;
+            reporter.log("Compressing the archive").await;
+            let data = axum::body::to_bytes(body, usize::MAX).await?;
+            reporter.progress(2, 2).await;
+            reporter
+                .log(format!("Archive is {} KiB", data.len() / 1024))
+                .await;
+            Ok(Output::File {
+                content_type: "application/zip",
+                filename: "blid-export.zip",
+                data,
+            })
+        })
+        .await;
+    job_started(status)
+}
+
+#[derive(Deserialize)]
+struct PlaylistImport {
+    name: String,
+    #[serde(default)]
+    description: String,
+    /// Spotify URIs of the tracks, in order.
+    uris: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PlaylistImported {
+    playlist_id: String,
+    snapshot_id: String,
+    tracks: usize,
+}
+
+/// Creates a playlist of the given tracks, such as one exported from another service.
+async fn import_playlist(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(body): Json<PlaylistImport>,
+) -> axum::response::Response {
+    let token = session.token.access_token.clone();
+    let user_id = session.user_id.clone();
+    let status = s
+        .jobs
+        .submit(
+            &session.user_id,
+            JobKind::PlaylistImport,
+            |reporter| async move {
+                reporter.progress(0, body.uris.len()).await;
+                let playlist =
+                    spotify::create_playlist(&token, &user_id, &body.name, &body.description)
+                        .await?;
+                reporter
+                    .log(format!("Created the playlist \"{}\"", playlist.name))
+                    .await;
+                let snapshot_id =
+                    spotify::replace_playlist_items(&token, &playlist.id, &body.uris).await?;
+                reporter.progress(body.uris.len(), body.uris.len()).await;
+                reporter
+                    .log(format!("Added {} tracks", body.uris.len()))
+                    .await;
+                Ok(Output::Json(serde_json::to_value(PlaylistImported {
+                    playlist_id: playlist.id,
+                    snapshot_id,
+                    tracks: body.uris.len(),
+                })?))
+            },
+        )
+        .await;
+    job_started(status)
+}
+
+#[derive(Serialize)]
+struct HistoryImported {
+    new_plays: usize,
+}
+
+/// Adds plays from one of the `Streaming_History_Audio_*.json` files of Spotify's extended
+/// streaming history to the user's history.
+async fn import_history(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Json(streams): Json<Vec<StreamedPlay>>,
+) -> axum::response::Response {
+    let state = Arc::clone(&s);
+    let token = session.token.access_token.clone();
+    let user_id = session.user_id.clone();
+    let status = s
+        .jobs
+        .submit(
+            &session.user_id,
+            JobKind::HistoryImport,
+            |reporter| async move {
+                let new_plays =
+                    history::import(&state.history, &token, &user_id, streams, &reporter).await?;
+                Ok(Output::Json(serde_json::to_value(HistoryImported {
+                    new_plays,
+                })?))
+            },
+        )
+        .await;
+    job_started(status)
+}
+
+/// The events of a job as it runs: its `status` each time it changes state, `progress` with a
+/// percentage, and `log` lines in plain text. A stream starts with the job's status and log so
+/// far, and ends once the job finished.
+async fn job_events(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let Some((status, log, events)) = s.jobs.subscribe(&session.user_id, &id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let finished = status.state.finished();
+    let backlog = std::iter::once(JobEvent::Status(status))
+        .chain(log.into_iter().map(JobEvent::Log))
+        .map(job_event);
+    let live = stream::unfold((!finished).then_some(events), |events| async move {
+        let mut events = events?;
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let last = matches!(&event, JobEvent::Status(s) if s.state.finished());
+                    return Some((job_event(event), (!last).then_some(events)));
+                }
+                // Progress is overwritten by the next anyway.
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream::iter(backlog).chain(live))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Fallible as an [`Sse`] stream is, though it never fails.
+#[allow(clippy::unnecessary_wraps)]
+fn job_event(event: JobEvent) -> Result<Event, Infallible> {
+    let event = match event {
+        JobEvent::Status(status) => Event::default().event("status").json_data(status),
+        JobEvent::Progress(progress) => Event::default().event("progress").json_data(progress),
+        JobEvent::Log(line) => Ok(Event::default().event("log").data(line)),
+    };
+    Ok(event.unwrap_or_else(|_| Event::default().comment("unserializable event")))
+}
+
+async fn list_jobs(session: Session, State(s): State<Arc<AppStateInner>>) -> Json<Vec<JobStatus>> {
+    Json(s.jobs.owned_by(&session.user_id).await)
+}
+
+async fn job_status(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> Result<Json<JobStatus>, StatusCode> {
+    s.jobs
+        .get(&session.user_id, &id)
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// What a job made. `409` with the job's status until it succeeded.
+async fn job_result(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let Some(status) = s.jobs.get(&session.user_id, &id).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match s.jobs.output(&session.user_id, &id).await {
+        Some(Output::File {
+            content_type,
+            filename,
+            data,
+        }) => (
+            [
+                (header::CONTENT_TYPE, content_type.to_owned()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{filename}\""),
+                ),
+            ],
+            data,
+        )
+            .into_response(),
+        Some(Output::Json(value)) => Json(value).into_response(),
+        None => (StatusCode::CONFLICT, Json(status)).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{self, Fixtures},
+        geoip,
+        session_store::SessionData,
+        share::SharedPlaylist,
+        spotify::SpotifyToken,
+        token::{self, SessionId},
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn session() -> Session {
+        Session {
+            id: None,
+            user_id: "ann".to_owned(),
+            token: SpotifyToken {
+                access_token: "token".to_owned(),
+                refresh_token: String::new(),
+                expires_in: 3600,
+                token_type: "Bearer".to_owned(),
+                scope: String::new(),
+            },
+            token_expires_at: Utc::now() + chrono::Duration::hours(1),
+            premium: None,
+        }
+    }
+
+    /// Replays `fixtures/`, where the library is empty. Creating the playlist isn't recorded, so
+    /// trying to would fail.
+    #[tokio::test]
+    async fn forgetting_nothing_creates_no_playlist() {
+        fixtures::enable(Fixtures::Replay(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures"),
+        ))
+        .unwrap();
+        let response = generate_forgotten(
+            session(),
+            State(AppStateInner::for_tests().await),
+            Query(ForgottenQuery {
+                months: six(),
+                limit: fifty(),
+            }),
+        )
+        .await
+        .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// Also replays `fixtures/`, so a limit that got through would be a `422` rather than a `400`.
+    #[tokio::test]
+    async fn forgetting_zero_tracks_is_refused() {
+        fixtures::enable(Fixtures::Replay(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures"),
+        ))
+        .unwrap();
+        let state = AppStateInner::for_tests().await;
+        for limit in [0, MAX_FORGOTTEN + 1] {
+            let response = generate_forgotten(
+                session(),
+                State(state.clone()),
+                Query(ForgottenQuery {
+                    months: six(),
+                    limit,
+                }),
+            )
+            .await
+            .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{limit}");
+        }
+    }
+
+    /// IDs are decoded before handlers see them, so these would otherwise be sent on to other
+    /// Spotify endpoints than the ones meant.
+    #[tokio::test]
+    async fn ids_that_leave_their_segment_are_refused() {
+        fixtures::enable(Fixtures::Replay(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures"),
+        ))
+        .unwrap();
+        let state = AppStateInner::for_tests().await;
+        let (id, _) = session::start(
+            &state,
+            SessionData {
+                user_id: "ann".to_owned(),
+                token: session().token,
+                token_expires_at: Utc::now() + chrono::Duration::hours(1),
+                created_at: Utc::now(),
+                last_seen: Utc::now(),
+                premium: None,
+                do_not_track: false,
+            },
+        )
+        .await
+        .unwrap();
+        let api = axum::Router::new().nest("/api", router()).with_state(state);
+
+        #[cfg_attr(not(feature = "library"), allow(unused_mut))]
+        let mut requests = vec![
+            (
+                axum::http::Method::PUT,
+                "/api/playlists/..%2Fme%2Fplayer%2Fpause%3F/image",
+            ),
+            (
+                axum::http::Method::GET,
+                "/api/playlists/37i9dQZF1DXcBWIGoYBM5M%3Ffields=id/tracks",
+            ),
+            (axum::http::Method::DELETE, "/api/links/..%2Fme"),
+        ];
+        #[cfg(feature = "library")]
+        requests.push((
+            axum::http::Method::GET,
+            "/api/tracks/..%2Fme%2Fplayer%3F/availability",
+        ));
+        for (method, uri) in requests {
+            let request = axum::http::Request::builder()
+                .method(method.clone())
+                .uri(uri)
+                .header(header::COOKIE, format!("session_id={}", id.as_str()))
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let response = tower::ServiceExt::oneshot(api.clone(), request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{method} {uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn webhooks_to_internal_addresses_are_refused() {
+        let state = AppStateInner::for_tests().await;
+        let create = |url: &str| {
+            create_webhook(
+                session(),
+                State(state.clone()),
+                Json(NewWebhook {
+                    url: url.to_owned(),
+                    events: vec![EventKind::TrackChange],
+                    playlist_ids: Vec::new(),
+                }),
+            )
+        };
+        for url in [
+            "http://127.0.0.1:6379/",
+            "http://localhost:8080/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/",
+            "http://192.168.1.1/",
+            "http://0.0.0.0:5432/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "file:///etc/passwd",
+        ] {
+            assert_eq!(
+                create(url).await.into_response().status(),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{url}"
+            );
+        }
+        assert!(state.webhooks.owned_by("ann").await.unwrap().is_empty());
+        assert_eq!(
+            create("https://93.184.215.14/hook")
+                .await
+                .into_response()
+                .status(),
+            StatusCode::CREATED
+        );
+    }
+
+    /// Like [`forgetting_nothing_creates_no_playlist`], with no saved tracks to run to.
+    #[tokio::test]
+    async fn running_without_tracks_creates_no_playlist() {
+        fixtures::enable(Fixtures::Replay(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures"),
+        ))
+        .unwrap();
+        let response = generate_run(
+            session(),
+            State(AppStateInner::for_tests().await),
+            Query(RunQuery {
+                target_bpm: 170.0,
+                duration_min: 30,
+            }),
+        )
+        .await
+        .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// Linked users who haven't played anything have nothing to blend.
+    #[cfg(feature = "stats")]
+    #[tokio::test]
+    async fn blending_nothing_creates_no_playlist() {
+        let state = AppStateInner::for_tests().await;
+        let code = state.links.invite("bob").await.unwrap();
+        state.links.accept(&code, "ann").await.unwrap().unwrap();
+        let response = create_blend(
+            session(),
+            State(state),
+            Query(BlendQuery {
+                a: None,
+                b: "bob".to_owned(),
+                limit: usize::MAX,
+            }),
+        )
+        .await
+        .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// Where the export keeps each kind of data `DELETE /api/me/data` purges, by the field of its
+    /// response, or `None` for what's only kept for a while to get something done.
+    const EXPORTED: &[(&str, Option<&str>)] = &[
+        ("sessions", Some("sessions.json")),
+        ("plays", Some("history.json")),
+        ("rules", Some("rules.json")),
+        ("webhooks", Some("webhooks.json")),
+        ("shares", Some("shares.json")),
+        ("api_keys", Some("api_keys.json")),
+        ("undoable_edits", None),
+        ("jobs", None),
+        ("feed", Some("account.json")),
+        #[cfg(feature = "player")]
+        ("widget", Some("account.json")),
+        ("normalization", Some("account.json")),
+        ("notifications", Some("account.json")),
+        ("releases", Some("new_releases.json")),
+        ("watched_playlists", Some("playlist_activity.json")),
+        ("links", Some("links.json")),
+        ("sharing", Some("sharing.json")),
+        ("consent", Some("consent.json")),
+        ("retention", Some("retention.json")),
+        ("logins", Some("logins.json")),
+    ];
+
+    /// The files of the export of `user_id`, without what changes between exports.
+    async fn exported(state: &AppStateInner, user_id: &str) -> BTreeMap<String, serde_json::Value> {
+        let export = export::bundle(state, user_id).await.unwrap();
+        let export = axum::body::to_bytes(export, usize::MAX).await.unwrap();
+        let mut export = zip::ZipArchive::new(std::io::Cursor::new(export)).unwrap();
+        let mut files = BTreeMap::new();
+        for i in 0..export.len() {
+            let file = export.by_index(i).unwrap();
+            let name = file.name().to_owned();
+            let mut value: serde_json::Value = serde_json::from_reader(file).unwrap();
+            if name == "account.json" {
+                let account = value.as_object_mut().unwrap();
+                account.remove("user_id");
+                account.remove("exported_at");
+            }
+            files.insert(name, value);
+        }
+        files
+    }
+
+    /// Gives `user_id` something in every store `DELETE /api/me/data` purges.
+    #[allow(clippy::too_many_lines)]
+    async fn seed(state: &Arc<AppStateInner>, user_id: &str) -> SessionId {
+        let track: Track = serde_json::from_value(serde_json::json!({
+            "id": "t1",
+            "name": "Track",
+            "uri": "spotify:track:t1",
+            "duration_ms": 1000,
+        }))
+        .unwrap();
+        let album = |id: &str| {
+            let album = spotify::Album {
+                id: id.to_owned(),
+                ..spotify::Album::default()
+            };
+            ("Artist".to_owned(), album)
+        };
+        let (id, _) = session::start(
+            state,
+            SessionData {
+                user_id: user_id.to_owned(),
+                token: session().token,
+                token_expires_at: Utc::now() + chrono::Duration::hours(1),
+                created_at: Utc::now(),
+                last_seen: Utc::now(),
+                premium: None,
+                do_not_track: false,
+            },
+        )
+        .await
+        .unwrap();
+        let play = spotify::PlayHistory {
+            track: track.clone(),
+            played_at: Utc::now().to_rfc3339(),
+        };
+        state.history.record(user_id, vec![play]).await.unwrap();
+        state
+            .rules
+            .insert(
+                user_id.to_owned(),
+                "Rule".to_owned(),
+                Criteria::default(),
+                "p1".to_owned(),
+            )
+            .await
+            .unwrap();
+        state
+            .webhooks
+            .insert(
+                user_id.to_owned(),
+                "https://example.com/hook".to_owned(),
+                vec![EventKind::PlaylistChange],
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        let share = SharedPlaylist {
+            id: "s1".to_owned(),
+            playlist_id: "p1".to_owned(),
+            snapshot_id: String::new(),
+            name: "Shared".to_owned(),
+            artwork: String::new(),
+            shared_at: Utc::now(),
+            tracks: Vec::new(),
+            owner: user_id.to_owned(),
+        };
+        state.shares.insert(&share).await.unwrap();
+        state
+            .api_keys
+            .mint(
+                user_id.to_owned(),
+                "Key".to_owned(),
+                Vec::new(),
+                session().token,
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+        state.undo.record(user_id, "p1", &[], String::new()).await;
+        state
+            .jobs
+            .submit(user_id, JobKind::Export, |_| async {
+                Ok(Output::Json(serde_json::Value::Null))
+            })
+            .await;
+        state.feeds.enable(user_id).await.unwrap();
+        #[cfg(feature = "player")]
+        state.widgets.enable(user_id).await.unwrap();
+        state.normalization.enable(user_id).await.unwrap();
+        state
+            .notifications
+            .subscribe(user_id, "ann@example.com".to_owned(), true, true, true)
+            .await
+            .unwrap();
+        // Releases are only new once some were seen before them.
+        state
+            .releases
+            .record(user_id, vec![album("a1")])
+            .await
+            .unwrap();
+        state
+            .releases
+            .record(user_id, vec![album("a2")])
+            .await
+            .unwrap();
+        state.activity.watch(user_id, "p1").await.unwrap();
+        let code = state.links.invite("bob").await.unwrap();
+        state.links.accept(&code, user_id).await.unwrap().unwrap();
+        let sharing = Sharing {
+            visibility: crate::friends::Visibility::Linked,
+            name: None,
+        };
+        state.friends.set(user_id, sharing).await.unwrap();
+        state.consent.record(user_id).await.unwrap();
+        state
+            .retention
+            .set(user_id, Retention::Days30)
+            .await
+            .unwrap();
+        let location = geoip::Location {
+            country: Some("CH".to_owned()),
+            asn: 1,
+            network: "Network".to_owned(),
+        };
+        state.logins.record(user_id, location).await.unwrap();
+        id
+    }
+
+    /// Seeds every store, purges it all through the router, and expects an export with nothing
+    /// left in it.
+    #[tokio::test]
+    async fn exports_hold_everything_that_is_purged() {
+        let mut config = crate::config::Config::from_args(std::iter::empty()).unwrap();
+        let dir = std::env::temp_dir().join(format!("blid-test-{}", token::generate(8)));
+        config.art_dir = dir.join("art");
+        config.theme_dir = dir.join("themes");
+        config.consent_version = Some("1".to_owned());
+        let state = Arc::new(AppStateInner::new(&config).await.unwrap());
+        let id = seed(&state, "ann").await;
+
+        let nothing = exported(&state, "nobody").await;
+        let before = exported(&state, "ann").await;
+        for (file, value) in &before {
+            assert_ne!(Some(value), nothing.get(file), "nothing seeded for {file}");
+        }
+
+        let api = axum::Router::new()
+            .nest("/api", router())
+            .with_state(state.clone());
+        let request = axum::http::Request::builder()
+            .method(axum::http::Method::DELETE)
+            .uri("/api/me/data")
+            .header(header::COOKIE, format!("session_id={}", id.as_str()))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let purged = tower::ServiceExt::oneshot(api, request).await.unwrap();
+        assert_eq!(purged.status(), StatusCode::OK);
+        let purged = axum::body::to_bytes(purged.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let purged: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&purged).unwrap();
+        for (field, value) in &purged {
+            assert!(
+                ![serde_json::json!(0), serde_json::json!(false)].contains(value),
+                "nothing purged for {field}"
+            );
+        }
+
+        assert_eq!(exported(&state, "ann").await, nothing);
+        let mapped: BTreeSet<&str> = EXPORTED.iter().map(|(field, _)| *field).collect();
+        assert_eq!(
+            purged.keys().map(String::as_str).collect::<BTreeSet<_>>(),
+            mapped
+        );
+        let files: BTreeSet<&str> = before.keys().map(String::as_str).collect();
+        let exported: BTreeSet<&str> = EXPORTED.iter().filter_map(|(_, file)| *file).collect();
+        assert_eq!(files, exported);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reorder_ranges_past_the_end_are_out_of_bounds() {
+        let body = |range_start, range_length, insert_before| ReorderBody {
+            snapshot_id: String::new(),
+            range_start,
+            insert_before,
+            range_length,
+        };
+        assert!(body(0, 1, 3).in_bounds(3));
+        assert!(body(1, 2, 0).in_bounds(3));
+        assert!(!body(2, 2, 0).in_bounds(3));
+        assert!(!body(0, 0, 0).in_bounds(3));
+        assert!(!body(0, 1, 4).in_bounds(3));
+        assert!(!body(1, usize::MAX, 0).in_bounds(3));
+        assert!(!body(usize::MAX, usize::MAX, 0).in_bounds(3));
+    }
+}
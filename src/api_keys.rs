@@ -0,0 +1,620 @@
+//! Bearer keys for clients that can't hold a session cookie, such as scripts, CLIs and
+//! home-automation tools. A key acts as its user on `/api` when sent as
+//! `Authorization: Bearer <key>`, with a Spotify token of its own that is refreshed as needed, so
+//! it keeps working after the session that made it ends, until revoked.
+//!
+//! Only a hash of each key is kept, like for session IDs, in memory or in the database with a SQL
+//! feature and `DATABASE_URL` set.
+//!
+//! Each key has [`Scope`]s limiting what it can do. Reading library data needs no scope, changes
+//! need the scope [`required`] names for them, and everything else, including account management
+//! (`/keys`, `/me/…`) and routes added without a rule there, is left to sessions.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    session,
+    spotify::{self, SpotifyToken},
+    token::{self, SessionHash},
+    AppStateInner,
+};
+
+/// Prefix of every key, so leaked keys are easy to recognize.
+const PREFIX: &str = "blid_";
+
+/// What a key may do besides reading library data.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// What's playing, and where.
+    ReadPlayback,
+    /// Pausing, skipping and the like.
+    ControlPlayback,
+    /// Changing playlists, directly or through rules and generators.
+    ModifyPlaylists,
+    /// Saving tracks and following artists.
+    ModifyLibrary,
+}
+
+impl Scope {
+    pub const ALL: [Self; 4] = [
+        Self::ReadPlayback,
+        Self::ControlPlayback,
+        Self::ModifyPlaylists,
+        Self::ModifyLibrary,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadPlayback => "read-playback",
+            Self::ControlPlayback => "control-playback",
+            Self::ModifyPlaylists => "modify-playlists",
+            Self::ModifyLibrary => "modify-library",
+        }
+    }
+
+    /// Parses a space-separated list, as in OAuth's `scope` parameter. `None` if any is unknown.
+    pub fn parse_list(list: &str) -> Option<Vec<Self>> {
+        let mut scopes = Vec::new();
+        for name in list.split_whitespace() {
+            let scope = Self::ALL.into_iter().find(|scope| scope.as_str() == name)?;
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        Some(scopes)
+    }
+}
+
+/// What a request to `/api` needs from a key.
+#[derive(Debug, PartialEq, Eq)]
+enum Required {
+    Nothing,
+    Scope(Scope),
+    /// Only a session will do.
+    Session,
+}
+
+/// Library data keys may read without a scope, relative to `/api`.
+const READABLE: [&str; 10] = [
+    "/version",
+    "/session",
+    "/playlists",
+    "/rules",
+    "/discover",
+    "/tracks",
+    "/library",
+    "/stats",
+    "/following",
+    "/releases",
+];
+
+/// Paths are relative to `/api`. Anything not named here needs a session.
+fn required(method: &Method, path: &str) -> Required {
+    let under = |prefix: &str| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    if under("/player") || under("/integrations") {
+        Required::Scope(if method == Method::GET {
+            Scope::ReadPlayback
+        } else {
+            Scope::ControlPlayback
+        })
+    } else if method == Method::GET {
+        if READABLE.iter().any(|prefix| under(prefix)) {
+            Required::Nothing
+        } else {
+            Required::Session
+        }
+    } else if under("/playlists") || under("/generate") || under("/rules") {
+        Required::Scope(Scope::ModifyPlaylists)
+    } else if under("/library") || under("/following") {
+        Required::Scope(Scope::ModifyLibrary)
+    } else {
+        Required::Session
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiKey {
+    /// Identifies the key when listing or revoking it. Not a credential.
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    token: SpotifyToken,
+    #[serde(skip)]
+    token_expires_at: DateTime<Utc>,
+}
+
+/// What's kept of a key in the database besides its hash, ID and user, which have columns.
+#[cfg(feature = "sql")]
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    name: String,
+    scopes: Vec<Scope>,
+    created_at: DateTime<Utc>,
+    last_used: Option<DateTime<Utc>>,
+    token: SpotifyToken,
+    token_expires_at: DateTime<Utc>,
+}
+
+#[cfg(feature = "sql")]
+impl Stored {
+    fn of(key: &ApiKey) -> Self {
+        Self {
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+            created_at: key.created_at,
+            last_used: key.last_used,
+            token: key.token.clone(),
+            token_expires_at: key.token_expires_at,
+        }
+    }
+
+    fn into_key(self, id: String, user_id: String) -> ApiKey {
+        ApiKey {
+            id,
+            user_id,
+            name: self.name,
+            scopes: self.scopes,
+            created_at: self.created_at,
+            last_used: self.last_used,
+            token: self.token,
+            token_expires_at: self.token_expires_at,
+        }
+    }
+}
+
+/// Keys by the hash of their secret. In memory by default, or in the database with a SQL feature
+/// and `DATABASE_URL` set.
+pub enum ApiKeyStore {
+    Memory(RwLock<HashMap<SessionHash, ApiKey>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for ApiKeyStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl ApiKeyStore {
+    /// Creates a key acting as `user_id` with a copy of `token`, returning the key itself. This
+    /// is the only time it's available.
+    pub async fn mint(
+        &self,
+        user_id: String,
+        name: String,
+        scopes: Vec<Scope>,
+        token: SpotifyToken,
+        token_expires_at: DateTime<Utc>,
+    ) -> anyhow::Result<(String, ApiKey)> {
+        let secret = format!("{PREFIX}{}", token::generate(token::API_KEY_BYTES));
+        let hash = SessionHash::of(&secret);
+        let key = ApiKey {
+            id: token::generate(9),
+            user_id,
+            name,
+            scopes,
+            created_at: Utc::now(),
+            last_used: None,
+            token,
+            token_expires_at,
+        };
+        match self {
+            Self::Memory(keys) => {
+                keys.write().await.insert(hash, key.clone());
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO api_keys (hash, id, user_id, data) VALUES ($1, $2, $3, $4)",
+                )
+                .bind(hash.to_hex())
+                .bind(&key.id)
+                .bind(&key.user_id)
+                .bind(serde_json::to_string(&Stored::of(&key))?)
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok((secret, key))
+    }
+
+    async fn get(&self, hash: SessionHash) -> anyhow::Result<Option<ApiKey>> {
+        match self {
+            Self::Memory(keys) => Ok(keys.read().await.get(&hash).cloned()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let row: Option<(String, String, String)> =
+                    sqlx::query_as("SELECT id, user_id, data FROM api_keys WHERE hash = $1")
+                        .bind(hash.to_hex())
+                        .fetch_optional(pool)
+                        .await?;
+                row.map(|(id, user_id, data)| {
+                    Ok(serde_json::from_str::<Stored>(&data)?.into_key(id, user_id))
+                })
+                .transpose()
+            }
+        }
+    }
+
+    /// Writes back a key that was used, unless it was revoked in the meantime, returning whether
+    /// it still exists.
+    async fn update(&self, hash: SessionHash, key: &ApiKey) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(keys) => Ok(keys
+                .write()
+                .await
+                .get_mut(&hash)
+                .map(|stored| stored.clone_from(key))
+                .is_some()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let updated = sqlx::query("UPDATE api_keys SET data = $1 WHERE hash = $2")
+                    .bind(serde_json::to_string(&Stored::of(key))?)
+                    .bind(hash.to_hex())
+                    .execute(pool)
+                    .await?;
+                Ok(updated.rows_affected() == 1)
+            }
+        }
+    }
+
+    /// The user, Spotify token and scopes a key acts with, refreshing the token first when it's
+    /// about to expire. `None` for unknown or revoked keys.
+    pub async fn authenticate(&self, secret: &str) -> anyhow::Result<Option<KeyAuth>> {
+        if !secret.starts_with(PREFIX) {
+            return Ok(None);
+        }
+        let hash = SessionHash::of(secret);
+        let Some(mut key) = self.get(hash).await? else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let stale =
+            key.token_expires_at - now < chrono::Duration::from_std(session::REFRESH_MARGIN)?;
+        if stale {
+            key.token = spotify::refresh(&key.token).await?;
+            key.token_expires_at =
+                now + chrono::Duration::seconds(key.token.expires_in.try_into()?);
+        }
+        key.last_used = Some(now);
+        // Revoked while refreshing.
+        if !self.update(hash, &key).await? {
+            return Ok(None);
+        }
+        Ok(Some(KeyAuth {
+            user_id: key.user_id,
+            token: key.token,
+            token_expires_at: key.token_expires_at,
+            scopes: key.scopes,
+        }))
+    }
+
+    pub async fn owned_by(&self, user_id: &str) -> anyhow::Result<Vec<ApiKey>> {
+        match self {
+            Self::Memory(keys) => Ok(keys
+                .read()
+                .await
+                .values()
+                .filter(|key| key.user_id == user_id)
+                .cloned()
+                .collect()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, String)> =
+                    sqlx::query_as("SELECT id, data FROM api_keys WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_all(pool)
+                        .await?;
+                rows.into_iter()
+                    .map(|(id, data)| {
+                        Ok(serde_json::from_str::<Stored>(&data)?.into_key(id, user_id.to_owned()))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Revokes a key of `user_id`, returning whether there was one.
+    pub async fn remove(&self, user_id: &str, id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(keys) => {
+                let mut keys = keys.write().await;
+                let before = keys.len();
+                keys.retain(|_, key| !(key.user_id == user_id && key.id == id));
+                Ok(before != keys.len())
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM api_keys WHERE user_id = $1 AND id = $2")
+                    .bind(user_id)
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Revokes every key of `user_id`, returning how many there were.
+    pub async fn remove_owned_by(&self, user_id: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(keys) => {
+                let mut keys = keys.write().await;
+                let before = keys.len();
+                keys.retain(|_, key| key.user_id != user_id);
+                Ok(before - keys.len())
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM api_keys WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(keys) => keys.try_read().ok().map(|k| k.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+/// Request extension set by [`authenticate`] on requests made with a valid key.
+#[derive(Clone)]
+pub struct KeyAuth {
+    pub user_id: String,
+    pub token: SpotifyToken,
+    pub token_expires_at: DateTime<Utc>,
+    pub scopes: Vec<Scope>,
+}
+
+/// Middleware for `/api`. Requests with an `Authorization: Bearer` key act as the key's user, and
+/// are rejected right away if the key is unknown or revoked (`401`) or lacks the scope the route
+/// needs (`403`). Requests without one are left to the session cookie.
+pub async fn authenticate(
+    State(state): State<Arc<AppStateInner>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let secret = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|secret| secret.trim().to_owned());
+    let Some(secret) = secret else {
+        return next.run(request).await;
+    };
+    match state.api_keys.authenticate(&secret).await {
+        Ok(Some(auth)) => {
+            let allowed = match required(request.method(), request.uri().path()) {
+                Required::Nothing => true,
+                Required::Scope(scope) => auth.scopes.contains(&scope),
+                Required::Session => false,
+            };
+            if !allowed {
+                return (
+                    StatusCode::FORBIDDEN,
+                    [(
+                        header::WWW_AUTHENTICATE,
+                        "Bearer error=\"insufficient_scope\"",
+                    )],
+                )
+                    .into_response();
+            }
+            request.extensions_mut().insert(auth);
+            next.run(request).await
+        }
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to authenticate API key: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/api` as served, behind key authentication.
+    fn api(state: Arc<AppStateInner>) -> axum::Router {
+        axum::Router::new()
+            .nest(
+                "/api",
+                crate::api::router().layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    authenticate,
+                )),
+            )
+            .with_state(state)
+    }
+
+    async fn send(api: &axum::Router, method: Method, path: &str, key: &str) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {key}"))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        tower::ServiceExt::oneshot(api.clone(), request)
+            .await
+            .unwrap()
+    }
+
+    /// Every change `/api` routes. Keys without scopes must be refused all of them by the
+    /// middleware, before the route is reached.
+    fn changes() -> Vec<(Method, &'static str)> {
+        #[cfg_attr(
+            not(any(feature = "player", feature = "library", feature = "stats")),
+            allow(unused_mut)
+        )]
+        let mut changes = vec![
+            (Method::POST, "/api/playlists/import"),
+            (Method::POST, "/api/playlists/p1/dedupe"),
+            (Method::PUT, "/api/playlists/p1/reorder"),
+            (Method::POST, "/api/playlists/p1/undo"),
+            (Method::PUT, "/api/playlists/p1/image"),
+            (Method::POST, "/api/playlists/p1/share"),
+            (Method::PUT, "/api/playlists/p1/watch"),
+            (Method::DELETE, "/api/playlists/p1/watch"),
+            (Method::POST, "/api/rules"),
+            (Method::DELETE, "/api/rules/r1"),
+            (Method::POST, "/api/rules/r1/run"),
+            (Method::POST, "/api/generate/forgotten"),
+            (Method::POST, "/api/generate/run"),
+            (Method::POST, "/api/links/invites"),
+            (Method::POST, "/api/links/invites/c1"),
+            (Method::DELETE, "/api/links/bob"),
+            (Method::PUT, "/api/me/sharing"),
+            (Method::PUT, "/api/me/retention"),
+            (Method::POST, "/api/webhooks"),
+            (Method::DELETE, "/api/webhooks/w1"),
+            (Method::POST, "/api/keys"),
+            (Method::DELETE, "/api/keys/k1"),
+            (Method::PUT, "/api/me/feed"),
+            (Method::DELETE, "/api/me/feed"),
+            (Method::PUT, "/api/me/normalization"),
+            (Method::DELETE, "/api/me/normalization"),
+            (Method::PUT, "/api/me/notifications"),
+            (Method::DELETE, "/api/me/data"),
+            (Method::POST, "/api/me/export"),
+            (Method::POST, "/api/me/history/import"),
+        ];
+        #[cfg(feature = "player")]
+        changes.extend([
+            (Method::PUT, "/api/player/play"),
+            (Method::PUT, "/api/player/pause"),
+            (Method::POST, "/api/player/next"),
+            (Method::POST, "/api/player/previous"),
+            (Method::POST, "/api/player/queue/t1"),
+            (Method::PUT, "/api/player/seek"),
+            (Method::PUT, "/api/player/volume"),
+            (Method::PUT, "/api/me/widget"),
+            (Method::DELETE, "/api/me/widget"),
+        ]);
+        #[cfg(feature = "library")]
+        changes.extend([
+            (Method::PUT, "/api/library/tracks/t1"),
+            (Method::DELETE, "/api/library/tracks/t1"),
+            (Method::PUT, "/api/following/a1"),
+            (Method::DELETE, "/api/following/a1"),
+        ]);
+        #[cfg(feature = "stats")]
+        changes.push((Method::POST, "/api/stats/compare/blend"));
+        changes
+    }
+
+    #[tokio::test]
+    async fn keys_without_scopes_can_only_read() {
+        let state = AppStateInner::for_tests().await;
+        let token = SpotifyToken {
+            access_token: "token".to_owned(),
+            refresh_token: String::new(),
+            expires_in: 3600,
+            token_type: "Bearer".to_owned(),
+            scope: String::new(),
+        };
+        let (key, _) = state
+            .api_keys
+            .mint(
+                "ann".to_owned(),
+                "read-only".to_owned(),
+                Vec::new(),
+                token,
+                Utc::now() + chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        let api = api(state);
+
+        for (method, path) in changes() {
+            let response = send(&api, method.clone(), path, &key).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{method} {path} is open to keys without scopes"
+            );
+            assert_eq!(
+                response.headers()[header::WWW_AUTHENTICATE],
+                "Bearer error=\"insufficient_scope\"",
+                "{method} {path} was refused by the route rather than for its scope"
+            );
+        }
+        let response = send(&api, Method::GET, "/api/version", &key).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&api, Method::GET, "/api/version", "blid_unknown").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn reads_outside_the_library_need_a_session() {
+        for path in [
+            "/keys",
+            "/me/export",
+            "/jobs/1",
+            "/webhooks",
+            "/friends/activity",
+            "/links",
+        ] {
+            assert_eq!(required(&Method::GET, path), Required::Session, "{path}");
+        }
+        assert_eq!(required(&Method::GET, "/unknown"), Required::Session);
+        assert_eq!(required(&Method::GET, "/library/tracks"), Required::Nothing);
+    }
+
+    #[test]
+    fn changes_need_their_scope() {
+        let cases = [
+            (Method::PUT, "/library/tracks/1", Scope::ModifyLibrary),
+            (Method::DELETE, "/following/1", Scope::ModifyLibrary),
+            (Method::POST, "/playlists/1/dedupe", Scope::ModifyPlaylists),
+            (Method::POST, "/player/queue/1", Scope::ControlPlayback),
+            (Method::GET, "/player", Scope::ReadPlayback),
+        ];
+        for (method, path, scope) in cases {
+            assert_eq!(required(&method, path), Required::Scope(scope), "{path}");
+        }
+        for (method, path) in [
+            (Method::POST, "/webhooks"),
+            (Method::POST, "/stats/compare/blend"),
+            (Method::POST, "/links/invites"),
+        ] {
+            assert_eq!(
+                required(&method, path),
+                Required::Session,
+                "{method} {path}"
+            );
+        }
+    }
+}
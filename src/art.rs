@@ -0,0 +1,579 @@
+//! Album art served from our own origin at `/art/:id`, so pages don't hotlink Spotify's CDN and
+//! server-side rendering has the images at hand. `:id` is what ends a Spotify image URL,
+//! `https://i.scdn.co/image/<id>`, and [`local_url`] turns one into the other.
+//!
+//! Images are fetched once and kept in `ART_DIR`, each in a file named after the SHA-256 of its
+//! content. Spotify serves the same artwork under different IDs, e.g. for every release of an
+//! album, which this stores once. An image ID always refers to the same content, so responses can
+//! be cached by browsers for good. Once the directory grows past `ART_DIR_MAX_MB`, the oldest
+//! files in it are removed, to be fetched or made again if they're asked for.
+//!
+//! `?w=` scales images down to one of a few widths, and browsers that accept AVIF get that instead
+//! of the original JPEG. Variants are made once and kept next to the originals, only a few at a
+//! time since `/art` is open to anyone. WebP isn't
+//! offered, since the encoder we have is lossless only and makes photos larger than the JPEG.
+//!
+//! Each image also gets a [blurhash](https://blurha.sh), a short string frontends can render as a
+//! blurred placeholder while the image loads. It's made when the image is first fetched, and the
+//! images in playlist and track JSON carry it once it's known. One is shared by every size of an
+//! album's art.
+
+use anyhow::Context;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    DynamicImage,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path as FsPath, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio::sync::{Mutex, RwLock, Semaphore};
+
+use crate::{cache::Cache, spotify, token, AppStateInner};
+
+const SPOTIFY_IMAGES: &str = "https://i.scdn.co/image/";
+/// Album art IDs are this prefix, a code for the size, then an ID shared by every size.
+const ALBUM_ART_PREFIX: &str = "ab67616d0000";
+/// The sizes Spotify has album art in, with their codes, smallest first. These are also the
+/// widths images can be scaled to.
+const SIZES: [(u32, &str); 3] = [(64, "4851"), (300, "1e02"), (640, "b273")];
+/// AVIF encoder speed, from 1 (slowest, smallest) to 10. Variants are only made once, but while
+/// a page waits for them.
+const AVIF_SPEED: u8 = 8;
+const AVIF_QUALITY: u8 = 70;
+const JPEG_QUALITY: u8 = 85;
+/// Blurhash components across and down. Album art is square, and more would only make longer
+/// strings for placeholders that are meant to be blurry.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+/// Width images are scaled down to before making their blurhash, which only needs the colours.
+const BLURHASH_WIDTH: u32 = 32;
+/// Images fetched at once in the background to make blurhashes for responses that lack them.
+const BLURHASH_FETCHES: usize = 2;
+/// Variants made at once. Requests for more wait their turn.
+const ENCODES: usize = 2;
+/// Image IDs whose content hash is remembered. Those forgotten are fetched again when asked for.
+const KNOWN_IDS: usize = 100_000;
+/// Largest JPEG Spotify takes as a playlist cover, which it takes base64-encoded in at most
+/// 256 KB.
+const COVER_MAX_BYTES: usize = 256 * 1024 / 4 * 3;
+/// Covers are square, and shown no larger than album art.
+const COVER_SIZE: u32 = 640;
+/// JPEG qualities tried in turn for covers, until one fits in [`COVER_MAX_BYTES`].
+const COVER_QUALITIES: [u8; 4] = [JPEG_QUALITY, 75, 65, 50];
+
+/// What images are sent as.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    /// As Spotify has it, JPEG or PNG.
+    Original,
+    Avif,
+}
+
+impl Encoding {
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let accepts_avif = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .any(|accept| accept.contains("image/avif"));
+        if accepts_avif {
+            Self::Avif
+        } else {
+            Self::Original
+        }
+    }
+}
+
+/// An image as sent, with a tag unique to its content.
+struct Variant {
+    tag: String,
+    content_type: &'static str,
+    data: Bytes,
+}
+
+pub struct ArtStore {
+    dir: PathBuf,
+    /// Content hash of each image fetched, by ID, up to [`KNOWN_IDS`] of them.
+    hashes: RwLock<HashMap<String, String>>,
+    /// Blurhashes, under `blurhash:<key>` with [`blurhash_key`].
+    cache: Arc<dyn Cache>,
+    fetches: Arc<Semaphore>,
+    encodes: Semaphore,
+    /// `ART_DIR_MAX_MB`, in bytes.
+    max_bytes: u64,
+    /// Bytes in `dir`, as of the last prune and the files written since.
+    used: AtomicU64,
+    /// Held while pruning, so only one prune runs at a time.
+    pruning: Mutex<()>,
+}
+
+impl ArtStore {
+    pub fn new(dir: PathBuf, max_bytes: u64, cache: Arc<dyn Cache>) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let used = prune(&dir, max_bytes)?;
+        Ok(Self {
+            dir,
+            hashes: RwLock::default(),
+            cache,
+            fetches: Arc::new(Semaphore::new(BLURHASH_FETCHES)),
+            encodes: Semaphore::new(ENCODES),
+            max_bytes,
+            used: AtomicU64::new(used),
+            pruning: Mutex::default(),
+        })
+    }
+
+    /// Writes `data` to `name` in the directory, pruning it if that takes it past its limit.
+    async fn write(&self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        // Written aside and moved in place, so a half-written file is never served.
+        let partial = self.dir.join(format!("{name}.partial"));
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, self.dir.join(name)).await?;
+        let used = self.used.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+        if used > self.max_bytes {
+            // Whoever is pruning already will get this file too.
+            let Ok(_pruning) = self.pruning.try_lock() else {
+                return Ok(());
+            };
+            let (dir, max_bytes) = (self.dir.clone(), self.max_bytes);
+            let used = tokio::task::spawn_blocking(move || prune(&dir, max_bytes)).await??;
+            self.used.store(used, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Remembers that the image with `id` has the content `hash`, forgetting another if there
+    /// are too many.
+    async fn remember(&self, id: &str, hash: String) {
+        let mut hashes = self.hashes.write().await;
+        if hashes.len() >= KNOWN_IDS && !hashes.contains_key(id) {
+            let forgotten = hashes.keys().next().cloned();
+            if let Some(forgotten) = forgotten {
+                hashes.remove(&forgotten);
+            }
+        }
+        hashes.insert(id.to_owned(), hash);
+    }
+
+    /// Sets the blurhash of each of `images` that's known. Those of Spotify images that aren't
+    /// are made in the background, for later responses to have them. Images are left as they are
+    /// when the cache fails, since they're good without.
+    pub async fn annotate<'a>(
+        self: &Arc<Self>,
+        images: impl IntoIterator<Item = &'a mut spotify::Image>,
+    ) {
+        let mut images: Vec<(&mut spotify::Image, String)> = images
+            .into_iter()
+            .filter_map(|image| {
+                let id = image_id(&image.url)?.to_owned();
+                Some((image, id))
+            })
+            .collect();
+        let keys: Vec<String> = images
+            .iter()
+            .map(|(_, id)| format!("blurhash:{}", blurhash_key(id)))
+            .collect();
+        let hashes = match self.cache.get_many(&keys).await {
+            Ok(hashes) => hashes,
+            Err(e) => return tracing::warn!("Failed to look blurhashes up: {e:#}"),
+        };
+        let mut missing = HashMap::new();
+        for ((image, id), hash) in images.iter_mut().zip(hashes) {
+            if hash.is_some() {
+                image.blurhash = hash;
+            } else {
+                // Any size will do, so the smallest.
+                let smallest = SIZES[0].1;
+                missing
+                    .entry(blurhash_key(id))
+                    .or_insert_with(|| sized(id, smallest));
+            }
+        }
+        for id in missing.into_values() {
+            // Responses don't wait for these, and don't queue up more than we can fetch.
+            let Ok(permit) = Arc::clone(&self.fetches).try_acquire_owned() else {
+                break;
+            };
+            let store = Arc::clone(self);
+            tokio::spawn(async move {
+                if let Err(e) = store.get(&id).await {
+                    tracing::debug!("Failed to fetch art {id} for its blurhash: {e:#}");
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// The image with `id` and its content hash, fetching it from Spotify the first time.
+    async fn get(&self, id: &str) -> anyhow::Result<(String, Bytes)> {
+        let known = self.hashes.read().await.get(id).cloned();
+        if let Some(hash) = known {
+            match tokio::fs::read(self.dir.join(&hash)).await {
+                Ok(data) => return Ok((hash, data.into())),
+                // Pruned, so it's fetched again.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to read cached art {hash}: {e}"),
+            }
+        }
+
+        let data = spotify::image(id).await?;
+        let hash = token::hex(&Sha256::digest(&data));
+        let path = self.dir.join(&hash);
+        if tokio::fs::try_exists(&path).await? {
+            tracing::debug!("Art {id} is a duplicate of {hash}");
+        } else {
+            self.write(&hash, &data).await?;
+        }
+        self.store_blurhash(id, data.clone()).await;
+        self.remember(id, hash.clone()).await;
+        Ok((hash, data))
+    }
+
+    /// The image with `id`, scaled down to `width` if it's wider, in `encoding`.
+    async fn variant(
+        &self,
+        id: &str,
+        width: Option<u32>,
+        encoding: Encoding,
+    ) -> anyhow::Result<Variant> {
+        let (hash, data) = self.get(id).await?;
+        if width.is_none() && encoding == Encoding::Original {
+            return Ok(Variant {
+                tag: hash,
+                content_type: content_type(&data),
+                data,
+            });
+        }
+
+        let extension = match encoding {
+            Encoding::Original if data.starts_with(PNG_MAGIC) => "png",
+            Encoding::Original => "jpg",
+            Encoding::Avif => "avif",
+        };
+        let tag = format!("{hash}-{}.{extension}", width.unwrap_or(0));
+        let content_type = match extension {
+            "png" => "image/png",
+            "jpg" => "image/jpeg",
+            _ => "image/avif",
+        };
+        let path = self.dir.join(&tag);
+        if let Ok(data) = tokio::fs::read(&path).await {
+            return Ok(Variant {
+                tag,
+                content_type,
+                data: data.into(),
+            });
+        }
+        let _encoding = self.encodes.acquire().await?;
+        // Made by whoever held the permit before us.
+        if let Ok(data) = tokio::fs::read(&path).await {
+            return Ok(Variant {
+                tag,
+                content_type,
+                data: data.into(),
+            });
+        }
+        let data: Bytes = tokio::task::spawn_blocking(move || convert(&data, width, extension))
+            .await??
+            .into();
+        self.write(&tag, &data).await?;
+        Ok(Variant {
+            tag,
+            content_type,
+            data,
+        })
+    }
+
+    /// The album art at `url`, a Spotify image URL, in the smallest size at least `width` wide.
+    #[cfg(feature = "stats")]
+    pub async fn cover(&self, url: &str, width: u32) -> anyhow::Result<Bytes> {
+        let id = image_id(url).context("not a Spotify image")?;
+        let id = SIZES
+            .iter()
+            .find(|(size, _)| *size >= width)
+            .map_or_else(|| id.to_owned(), |(_, code)| sized(id, code));
+        Ok(self.get(&id).await?.1)
+    }
+
+    /// Makes and caches the blurhash of `data`, the image with `id`. Only logged on failure, as
+    /// the image is good without it.
+    async fn store_blurhash(&self, id: &str, data: Bytes) {
+        let hash = match tokio::task::spawn_blocking(move || blurhash(&data)).await {
+            Ok(Ok(hash)) => hash,
+            Ok(Err(e)) => return tracing::warn!("Failed to make blurhash of art {id}: {e:#}"),
+            Err(e) => return tracing::warn!("Failed to make blurhash of art {id}: {e}"),
+        };
+        let key = format!("blurhash:{}", blurhash_key(id));
+        if let Err(e) = self.cache.set(&key, hash, None).await {
+            tracing::warn!("Failed to cache blurhash of art {id}: {e:#}");
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.hashes.try_read().ok().map(|h| h.len())
+    }
+}
+
+/// The local URL of a Spotify image, under `base` (empty for a URL relative to our origin).
+/// Other URLs, and empty ones, are returned as they are.
+pub fn local_url(base: &str, url: &str) -> String {
+    image_id(url).map_or_else(|| url.to_owned(), |id| format!("{base}/art/{id}"))
+}
+
+/// The ID of a Spotify image URL.
+fn image_id(url: &str) -> Option<&str> {
+    url.strip_prefix(SPOTIFY_IMAGES).filter(|id| valid_id(id))
+}
+
+fn valid_id(id: &str) -> bool {
+    id.len() == 40 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new().route("/art/:id", get(art))
+}
+
+#[derive(Deserialize)]
+struct ArtQuery {
+    /// Width in pixels to scale the image down to, one of those in [`SIZES`].
+    w: Option<u32>,
+}
+
+async fn art(
+    State(s): State<Arc<AppStateInner>>,
+    Path(id): Path<String>,
+    Query(q): Query<ArtQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !valid_id(&id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let id = match q.w {
+        // Album art comes in these sizes, so the right one is fetched rather than scaled.
+        Some(width) => match SIZES.iter().find(|(size, _)| *size == width) {
+            Some((_, code)) => sized(&id, code),
+            None => return (StatusCode::BAD_REQUEST, "unsupported width").into_response(),
+        },
+        None => id,
+    };
+    let variant = match s.art.variant(&id, q.w, Encoding::negotiate(&headers)).await {
+        Ok(variant) => variant,
+        Err(e) => {
+            tracing::warn!("Failed to get art {id}: {e:#}");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    let etag = format!("\"{}\"", variant.tag);
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (
+            header::CACHE_CONTROL,
+            "public, max-age=31536000, immutable".to_owned(),
+        ),
+        (header::VARY, "Accept".to_owned()),
+    ];
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|tag| tag.as_bytes() == etag.as_bytes())
+    {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (
+        cache_headers,
+        [(header::CONTENT_TYPE, variant.content_type)],
+        variant.data,
+    )
+        .into_response()
+}
+
+/// `id` of album art in the size with `code`.
+fn sized(id: &str, code: &str) -> String {
+    match id.strip_prefix(ALBUM_ART_PREFIX) {
+        Some(rest) if rest.len() > 4 => format!("{ALBUM_ART_PREFIX}{code}{}", &rest[4..]),
+        _ => id.to_owned(),
+    }
+}
+
+/// What the blurhash of the image with `id` is cached by: album art without its size code, so
+/// each size shares one, and other images by their ID.
+fn blurhash_key(id: &str) -> String {
+    match id.strip_prefix(ALBUM_ART_PREFIX) {
+        Some(rest) if rest.len() > 4 => format!("{ALBUM_ART_PREFIX}{}", &rest[4..]),
+        _ => id.to_owned(),
+    }
+}
+
+const PNG_MAGIC: &[u8] = b"\x89PNG";
+
+/// Spotify serves JPEGs, and the odd PNG for images uploaded by users.
+fn content_type(data: &[u8]) -> &'static str {
+    if data.starts_with(PNG_MAGIC) {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Removes the oldest files in `dir` until what's left takes at most nine tenths of `max_bytes`,
+/// if it takes more than `max_bytes`. Returns the bytes left.
+fn prune(dir: &FsPath, max_bytes: u64) -> std::io::Result<u64> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, metadata.len(), entry.path()));
+        }
+    }
+    let mut used: u64 = files.iter().map(|(_, len, _)| len).sum();
+    if used <= max_bytes {
+        return Ok(used);
+    }
+    let target = max_bytes / 10 * 9;
+    files.sort_unstable();
+    for (_, len, path) in files {
+        if used <= target {
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => used -= len,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => used -= len,
+            Err(e) => tracing::warn!("Failed to prune {}: {e}", path.display()),
+        }
+    }
+    tracing::info!("Pruned art down to {used} bytes");
+    Ok(used)
+}
+
+/// Scales `data` down to `width` if it's wider, and encodes it as `extension`.
+fn convert(data: &[u8], width: Option<u32>, extension: &str) -> anyhow::Result<Vec<u8>> {
+    let mut image = image::load_from_memory(data).context("undecodable image")?;
+    match width.filter(|width| *width < image.width()) {
+        Some(width) => image = image.resize(width, u32::MAX, FilterType::Lanczos3),
+        // Not worth losing quality to encode again.
+        None if extension != "avif" => return Ok(data.to_vec()),
+        None => {}
+    }
+    let mut out = Vec::new();
+    match extension {
+        "png" => image.write_with_encoder(PngEncoder::new(&mut out))?,
+        "jpg" => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?,
+        _ => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+            &mut out,
+            AVIF_SPEED,
+            AVIF_QUALITY,
+        ))?,
+    }
+    Ok(out)
+}
+
+/// `data`, an uploaded image, as a playlist cover Spotify accepts: cropped square, scaled down to
+/// [`COVER_SIZE`], and a JPEG of at most [`COVER_MAX_BYTES`], losing quality and then size until
+/// it fits. Runs long enough to belong on a blocking thread.
+pub fn playlist_cover(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(data).context("undecodable image")?;
+    let side = image.width().min(image.height());
+    let mut image = image
+        .crop_imm(
+            (image.width() - side) / 2,
+            (image.height() - side) / 2,
+            side,
+            side,
+        )
+        .to_rgb8();
+    if side > COVER_SIZE {
+        image = image::imageops::resize(&image, COVER_SIZE, COVER_SIZE, FilterType::Lanczos3);
+    }
+    loop {
+        for quality in COVER_QUALITIES {
+            let mut out = Vec::new();
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?;
+            if out.len() <= COVER_MAX_BYTES {
+                return Ok(out);
+            }
+        }
+        // Only noise is this hard to compress, and a few halvings make anything small enough.
+        let side = image.width() / 2;
+        anyhow::ensure!(side > 0, "image can't be made small enough");
+        image = image::imageops::resize(&image, side, side, FilterType::Triangle);
+    }
+}
+
+fn blurhash(data: &[u8]) -> anyhow::Result<String> {
+    let image = image::load_from_memory(data).context("undecodable image")?;
+    let image = image
+        .resize(BLURHASH_WIDTH, u32::MAX, FilterType::Triangle)
+        .to_rgba8();
+    let (x, y) = BLURHASH_COMPONENTS;
+    Ok(blurhash::encode(
+        x,
+        y,
+        image.width(),
+        image.height(),
+        image.as_raw(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::MemoryCache;
+    use std::time::Duration;
+
+    fn store(max_bytes: u64) -> ArtStore {
+        let dir = std::env::temp_dir().join(format!("blid-art-{}", token::generate(8)));
+        ArtStore::new(dir, max_bytes, Arc::new(MemoryCache::default())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn prunes_the_oldest_files_past_the_limit() {
+        let art = store(1000);
+        let start = SystemTime::now() - Duration::from_mins(1);
+        for i in 0..3 {
+            art.write(&format!("f{i}"), &[0; 300]).await.unwrap();
+            let file = std::fs::File::options()
+                .write(true)
+                .open(art.dir.join(format!("f{i}")))
+                .unwrap();
+            file.set_modified(start + Duration::from_secs(i)).unwrap();
+        }
+        assert_eq!(art.used.load(Ordering::Relaxed), 900);
+
+        art.write("f3", &[0; 300]).await.unwrap();
+        let left: Vec<bool> = (0..4)
+            .map(|i| art.dir.join(format!("f{i}")).exists())
+            .collect();
+        assert_eq!(left, [false, true, true, true]);
+        assert_eq!(art.used.load(Ordering::Relaxed), 900);
+        assert_eq!(prune(&art.dir, 1000).unwrap(), 900);
+    }
+
+    #[tokio::test]
+    async fn remembers_a_bounded_number_of_ids() {
+        let art = store(u64::MAX);
+        for i in 0..=KNOWN_IDS {
+            art.remember(&i.to_string(), String::new()).await;
+        }
+        assert_eq!(art.len_hint(), Some(KNOWN_IDS));
+        art.remember("0", "again".to_owned()).await;
+        assert!(art.len_hint() <= Some(KNOWN_IDS));
+    }
+}
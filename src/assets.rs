@@ -0,0 +1,46 @@
+//! Static files under `assets/`. With the `embed-assets` feature they are compiled into the
+//! executable, so a deployment only needs the binary and its environment; otherwise they are
+//! served from `ASSET_DIR`, or `assets/` in the working directory.
+
+use axum::Router;
+use std::path::PathBuf;
+
+#[cfg(feature = "embed-assets")]
+#[allow(clippy::needless_pass_by_value)]
+pub fn router(dir: Option<PathBuf>) -> Router {
+    use axum::{
+        extract::Path,
+        http::{header, StatusCode},
+        response::IntoResponse,
+        routing::get,
+    };
+
+    #[derive(rust_embed::Embed)]
+    #[folder = "assets/"]
+    struct Assets;
+
+    async fn serve(Path(path): Path<String>) -> impl IntoResponse {
+        Assets::get(&path).map_or_else(
+            || StatusCode::NOT_FOUND.into_response(),
+            |file| {
+                (
+                    [(header::CONTENT_TYPE, file.metadata.mimetype().to_owned())],
+                    file.data,
+                )
+                    .into_response()
+            },
+        )
+    }
+
+    if dir.is_some() {
+        tracing::warn!("ASSET_DIR is set, but this build only has its compiled-in assets");
+    }
+    Router::new().route("/*path", get(serve))
+}
+
+#[cfg(not(feature = "embed-assets"))]
+pub fn router(dir: Option<PathBuf>) -> Router {
+    let dir = dir.unwrap_or_else(|| "assets".into());
+    tracing::info!("Serving assets from {}", dir.display());
+    Router::new().route_service("/*path", tower_http::services::ServeDir::new(dir))
+}
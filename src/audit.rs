@@ -0,0 +1,54 @@
+//! The audit log: security events, logged from this module so they can be picked out with the
+//! `blid_test::audit` target, e.g. `RUST_LOG=info,blid_test::audit=info` to keep them when
+//! everything else is quieter.
+
+use std::{fmt, net::IpAddr, time::Duration};
+
+use crate::geoip::Location;
+
+pub enum Event {
+    /// A client presented too many unknown session IDs, and is refused for a while.
+    SessionGuessing {
+        ip: IpAddr,
+        /// How many unknown session IDs it presented.
+        attempts: usize,
+        banned_for: Duration,
+    },
+    /// A session was started from a place unusual for the user, see [`crate::logins`].
+    UnusualLogin {
+        user_id: String,
+        ip: IpAddr,
+        location: Location,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SessionGuessing {
+                ip,
+                attempts,
+                banned_for,
+            } => write!(
+                f,
+                "{ip} presented {attempts} unknown session IDs, banning it for {}s",
+                banned_for.as_secs()
+            ),
+            Self::UnusualLogin {
+                user_id,
+                ip,
+                location,
+            } => write!(
+                f,
+                "{user_id} logged in from {ip}, in {} on AS{} ({}), unlike their recent logins",
+                location.country.as_deref().unwrap_or("an unknown country"),
+                location.asn,
+                location.network
+            ),
+        }
+    }
+}
+
+pub fn record(event: &Event) {
+    tracing::warn!("{event}");
+}
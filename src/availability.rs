@@ -0,0 +1,110 @@
+//! Where a track can be played. Spotify only answers that one market at a time (and may answer
+//! with a relinked release of the track), so results are cached for a day.
+
+use futures::future::try_join_all;
+use serde::Serialize;
+use std::{collections::HashMap, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::spotify;
+
+const TTL: Duration = Duration::from_hours(24);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MarketAvailability {
+    pub market: String,
+    pub playable: bool,
+    /// ID of the release played instead when the track was relinked in this market.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relinked_to: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Availability {
+    pub track_id: String,
+    /// Every market the requested release itself is available in.
+    pub available_markets: Vec<String>,
+    /// Details for the markets that were asked about.
+    pub markets: Vec<MarketAvailability>,
+}
+
+type Entry<T> = (Instant, T);
+
+#[derive(Default)]
+pub struct AvailabilityCache {
+    markets: RwLock<HashMap<String, Entry<Vec<String>>>>,
+    per_market: RwLock<HashMap<(String, String), Entry<MarketAvailability>>>,
+}
+
+impl AvailabilityCache {
+    pub async fn check(
+        &self,
+        access_token: &str,
+        track_id: &str,
+        markets: &[String],
+    ) -> anyhow::Result<Availability> {
+        let available_markets = self.available_markets(access_token, track_id).await?;
+        // Markets are independent of each other, so they're all asked about at once.
+        let markets = try_join_all(
+            markets
+                .iter()
+                .map(|market| self.in_market(access_token, track_id, market)),
+        )
+        .await?;
+        Ok(Availability {
+            track_id: track_id.to_owned(),
+            available_markets,
+            markets,
+        })
+    }
+
+    async fn available_markets(
+        &self,
+        access_token: &str,
+        track_id: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        if let Some((at, markets)) = self.markets.read().await.get(track_id) {
+            if at.elapsed() < TTL {
+                return Ok(markets.clone());
+            }
+        }
+        let markets = spotify::track(access_token, track_id, None)
+            .await?
+            .available_markets
+            .unwrap_or_default();
+        self.markets
+            .write()
+            .await
+            .insert(track_id.to_owned(), (Instant::now(), markets.clone()));
+        Ok(markets)
+    }
+
+    async fn in_market(
+        &self,
+        access_token: &str,
+        track_id: &str,
+        market: &str,
+    ) -> anyhow::Result<MarketAvailability> {
+        let key = (track_id.to_owned(), market.to_owned());
+        if let Some((at, availability)) = self.per_market.read().await.get(&key) {
+            if at.elapsed() < TTL {
+                return Ok(availability.clone());
+            }
+        }
+        let track = spotify::track(access_token, track_id, Some(market)).await?;
+        let availability = MarketAvailability {
+            market: market.to_owned(),
+            playable: track.is_playable.unwrap_or(false),
+            relinked_to: track.linked_from.is_some().then_some(track.id),
+        };
+        self.per_market
+            .write()
+            .await
+            .insert(key, (Instant::now(), availability.clone()));
+        Ok(availability)
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.per_market.try_read().ok().map(|m| m.len())
+    }
+}
@@ -0,0 +1,574 @@
+//! `blid-test backup` and `blid-test restore`: dumping the database behind `DATABASE_URL` to a
+//! `.tar.zst` archive, and loading one back. The archive holds a `manifest.json` and one JSON
+//! Lines file per table, so it can also be inspected with standard tools.
+//!
+//! A backup reads every table in one transaction, so it can be taken from the database of a
+//! running server and still be consistent. Restoring should be done with the server stopped.
+//! Sessions are left out of backups unless asked for, since they're short-lived credentials.
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::{any::AnyArguments, query::Query, Any, AnyConnection, Row};
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::db;
+
+const BACKUP_USAGE: &str = "usage: blid-test backup --out FILE.tar.zst [--include-sessions]";
+const RESTORE_USAGE: &str = "usage: blid-test restore FILE.tar.zst";
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    BigInt,
+    Bool,
+}
+
+struct Table {
+    name: &'static str,
+    columns: &'static [(&'static str, Kind)],
+}
+
+/// Every table the migrations create, with its columns, except `leases`, which only matter to
+/// the instances running at the time.
+const TABLES: &[Table] = &[
+    Table {
+        name: "sessions",
+        columns: &[
+            ("id", Kind::Text),
+            ("user_id", Kind::Text),
+            ("data", Kind::Text),
+            ("expires_at", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "history_users",
+        columns: &[("user_id", Kind::Text), ("collecting_since", Kind::BigInt)],
+    },
+    Table {
+        name: "plays",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("played_at", Kind::BigInt),
+            ("track_id", Kind::Text),
+            ("track", Kind::Text),
+        ],
+    },
+    Table {
+        name: "notifications",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("email", Kind::Text),
+            ("weekly_digest", Kind::Bool),
+            ("new_releases", Kind::Bool),
+            ("milestones", Kind::Bool),
+            ("last_sent", Kind::BigInt),
+            ("unsubscribe_token", Kind::Text),
+        ],
+    },
+    Table {
+        name: "shares",
+        columns: &[
+            ("id", Kind::Text),
+            ("owner", Kind::Text),
+            ("data", Kind::Text),
+        ],
+    },
+    Table {
+        name: "retention",
+        columns: &[("user_id", Kind::Text), ("keep_days", Kind::BigInt)],
+    },
+    Table {
+        name: "rules",
+        columns: &[
+            ("id", Kind::Text),
+            ("owner", Kind::Text),
+            ("data", Kind::Text),
+        ],
+    },
+    Table {
+        name: "api_keys",
+        columns: &[
+            ("hash", Kind::Text),
+            ("id", Kind::Text),
+            ("user_id", Kind::Text),
+            ("data", Kind::Text),
+        ],
+    },
+    Table {
+        name: "roles",
+        columns: &[("user_id", Kind::Text), ("role", Kind::Text)],
+    },
+    Table {
+        name: "invites",
+        columns: &[("code", Kind::Text), ("expires_at", Kind::BigInt)],
+    },
+    Table {
+        name: "admitted_users",
+        columns: &[("user_id", Kind::Text)],
+    },
+    Table {
+        name: "consents",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("version", Kind::Text),
+            ("given_at", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "feeds",
+        columns: &[("slug", Kind::Text), ("user_id", Kind::Text)],
+    },
+    Table {
+        name: "widgets",
+        columns: &[("slug", Kind::Text), ("user_id", Kind::Text)],
+    },
+    Table {
+        name: "normalization",
+        columns: &[("user_id", Kind::Text)],
+    },
+    Table {
+        name: "sharing",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("visibility", Kind::Text),
+            ("name", Kind::Text),
+        ],
+    },
+    Table {
+        name: "links",
+        columns: &[("user_id", Kind::Text), ("linked_id", Kind::Text)],
+    },
+    Table {
+        name: "link_invites",
+        columns: &[
+            ("code", Kind::Text),
+            ("owner", Kind::Text),
+            ("expires_at", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "seen_releases",
+        columns: &[("user_id", Kind::Text), ("album_id", Kind::Text)],
+    },
+    Table {
+        name: "new_releases",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("album_id", Kind::Text),
+            ("artist", Kind::Text),
+            ("album", Kind::Text),
+            ("detected_at", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "watched_playlists",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("playlist_id", Kind::Text),
+            ("snapshot_id", Kind::Text),
+            ("items", Kind::Text),
+        ],
+    },
+    Table {
+        name: "playlist_activity",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("playlist_id", Kind::Text),
+            ("change", Kind::Text),
+            ("track", Kind::Text),
+            ("added_by", Kind::Text),
+            ("at", Kind::BigInt),
+            ("ordinal", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "webhooks",
+        columns: &[
+            ("id", Kind::Text),
+            ("owner", Kind::Text),
+            ("url", Kind::Text),
+            ("events", Kind::Text),
+            ("playlist_ids", Kind::Text),
+            ("secret", Kind::Text),
+            ("created_at", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "webhook_deliveries",
+        columns: &[
+            ("id", Kind::Text),
+            ("webhook_id", Kind::Text),
+            ("event", Kind::Text),
+            ("created_at", Kind::BigInt),
+            ("attempts", Kind::BigInt),
+            ("status", Kind::BigInt),
+            ("error", Kind::Text),
+            ("delivered", Kind::BigInt),
+        ],
+    },
+    Table {
+        name: "logins",
+        columns: &[
+            ("user_id", Kind::Text),
+            ("country", Kind::Text),
+            ("asn", Kind::BigInt),
+            ("network", Kind::Text),
+            ("logged_in_at", Kind::BigInt),
+        ],
+    },
+];
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// Backups are only restored into a database at the same version.
+    schema_version: Option<i64>,
+    created_at: DateTime<Utc>,
+    tables: Vec<String>,
+}
+
+async fn connect() -> anyhow::Result<db::Pool> {
+    let url = env::var("DATABASE_URL")
+        .context("DATABASE_URL is needed, since nothing is persisted without a database")?;
+    db::connect(&url).await
+}
+
+/// Runs `blid-test backup`, with `args` the arguments after `backup`.
+pub async fn backup(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut out = None;
+    let mut include_sessions = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => out = Some(PathBuf::from(args.next().context("--out needs a value")?)),
+            "--include-sessions" => include_sessions = true,
+            other => bail!("unexpected argument `{other}`\n{BACKUP_USAGE}"),
+        }
+    }
+    let out = out.context(BACKUP_USAGE)?;
+    backup_to(&connect().await?, &out, include_sessions).await?;
+    println!("Backed up to {}", out.display());
+    Ok(())
+}
+
+/// Backs the database up to an archive at `out`.
+async fn backup_to(pool: &db::Pool, out: &Path, include_sessions: bool) -> anyhow::Result<()> {
+    let version = db::schema_version(pool).await?;
+    if !version.is_current() {
+        bail!(
+            "the database schema is at version {:?} but this build expects {:?}",
+            version.current,
+            version.expected
+        );
+    }
+    let mut tx = pool.begin().await?;
+    // Postgres otherwise gives each statement its own snapshot.
+    if tx.backend_name() == "PostgreSQL" {
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+            .execute(&mut *tx)
+            .await?;
+    }
+    let mut files = Vec::new();
+    for table in TABLES {
+        if table.name == "sessions" && !include_sessions {
+            continue;
+        }
+        let (rows, data) = dump(&mut tx, table).await?;
+        println!("{}: {rows} rows", table.name);
+        files.push((table.name, data));
+    }
+    tx.rollback().await?;
+
+    let manifest = Manifest {
+        schema_version: version.current,
+        created_at: Utc::now(),
+        tables: files.iter().map(|(name, _)| (*name).to_owned()).collect(),
+    };
+    write_archive(out, &manifest, &files)
+}
+
+/// The rows of `table` as JSON Lines, with how many there are.
+async fn dump(conn: &mut AnyConnection, table: &Table) -> anyhow::Result<(usize, Vec<u8>)> {
+    // Booleans are selected as 1 or 0, since the `Any` driver can't read them from SQLite.
+    let columns = table
+        .columns
+        .iter()
+        .map(|(name, kind)| match kind {
+            Kind::Bool => format!("CASE WHEN {name} THEN 1 ELSE 0 END"),
+            Kind::Text | Kind::BigInt => (*name).to_owned(),
+        })
+        .join(", ");
+    let rows = sqlx::query(&format!("SELECT {columns} FROM {}", table.name))
+        .fetch_all(conn)
+        .await?;
+    let mut data = Vec::new();
+    for row in &rows {
+        let mut object = Map::new();
+        for (i, (name, kind)) in table.columns.iter().enumerate() {
+            let value = match kind {
+                Kind::Text => json!(row.try_get::<String, _>(i)?),
+                Kind::BigInt => json!(row.try_get::<i64, _>(i)?),
+                Kind::Bool => json!(row.try_get::<i64, _>(i)? != 0),
+            };
+            object.insert((*name).to_owned(), value);
+        }
+        serde_json::to_writer(&mut data, &object)?;
+        data.push(b'\n');
+    }
+    Ok((rows.len(), data))
+}
+
+fn write_archive(
+    path: &Path,
+    manifest: &Manifest,
+    files: &[(&str, Vec<u8>)],
+) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+    let mut tar = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+    append(
+        &mut tar,
+        "manifest.json",
+        &serde_json::to_vec_pretty(manifest)?,
+    )?;
+    for (name, data) in files {
+        append(&mut tar, &format!("{name}.jsonl"), data)?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn append(tar: &mut tar::Builder<impl Write>, name: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len().try_into()?);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp().try_into()?);
+    header.set_cksum();
+    tar.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+/// Runs `blid-test restore`, with `args` the arguments after `restore`. Every table in the
+/// backup has its contents replaced; tables it doesn't have, such as sessions usually, are left
+/// alone.
+pub async fn restore(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let (Some(path), None) = (args.next(), args.next()) else {
+        bail!(RESTORE_USAGE);
+    };
+    restore_from(&connect().await?, Path::new(&path)).await?;
+    println!("Restored {path}");
+    Ok(())
+}
+
+/// Replaces the contents of the tables in the archive at `path`.
+async fn restore_from(pool: &db::Pool, path: &Path) -> anyhow::Result<()> {
+    let (manifest, mut files) = read_archive(path)?;
+    db::migrate(pool).await?;
+    let version = db::schema_version(pool).await?;
+    if manifest.schema_version != version.current {
+        bail!(
+            "the backup is of schema version {:?} but the database is at {:?}, restore it with \
+             the version of blid-test that made it",
+            manifest.schema_version,
+            version.current
+        );
+    }
+    // All or nothing, so a bad backup doesn't leave the database half restored.
+    let mut tx = pool.begin().await?;
+    for name in &manifest.tables {
+        let table = TABLES
+            .iter()
+            .find(|table| table.name == name)
+            .with_context(|| format!("unknown table `{name}` in the backup"))?;
+        let data = files
+            .remove(&format!("{name}.jsonl"))
+            .with_context(|| format!("`{name}.jsonl` is missing from the backup"))?;
+        sqlx::query(&format!("DELETE FROM {name}"))
+            .execute(&mut *tx)
+            .await?;
+        let columns = table.columns.iter().map(|(column, _)| column).join(", ");
+        let placeholders = (1..=table.columns.len())
+            .map(|i| format!("${i}"))
+            .join(", ");
+        let insert = format!("INSERT INTO {name} ({columns}) VALUES ({placeholders})");
+        let mut rows = 0;
+        for line in data.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let object: Map<String, Value> = serde_json::from_slice(line)?;
+            let mut query = sqlx::query(&insert);
+            for (column, kind) in table.columns {
+                let value = object.get(*column).unwrap_or(&Value::Null);
+                query = bind(query, *kind, value)
+                    .with_context(|| format!("bad `{column}` in a row of `{name}`"))?;
+            }
+            query.execute(&mut *tx).await?;
+            rows += 1;
+        }
+        println!("{name}: {rows} rows");
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+fn bind<'q>(
+    query: Query<'q, Any, AnyArguments<'q>>,
+    kind: Kind,
+    value: &Value,
+) -> anyhow::Result<Query<'q, Any, AnyArguments<'q>>> {
+    Ok(match kind {
+        Kind::Text => query.bind(value.as_str().context("not a string")?.to_owned()),
+        Kind::BigInt => query.bind(value.as_i64().context("not an integer")?),
+        Kind::Bool => query.bind(value.as_bool().context("not a boolean")?),
+    })
+}
+
+fn read_archive(path: &Path) -> anyhow::Result<(Manifest, HashMap<String, Vec<u8>>)> {
+    let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    let mut files = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(name, data);
+    }
+    let manifest = files
+        .remove("manifest.json")
+        .context("not a blid-test backup, it has no manifest.json")?;
+    Ok((serde_json::from_slice(&manifest)?, files))
+}
+
+#[cfg(all(test, feature = "sqlite-store"))]
+mod tests {
+    use super::*;
+    use crate::{digest::NotificationStore, token};
+
+    /// A migrated database in a temporary file, with the path to remove it by.
+    async fn database() -> (db::Pool, PathBuf) {
+        let path = std::env::temp_dir().join(format!("blid-backup-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        (pool, path)
+    }
+
+    async fn session_ids(pool: &db::Pool) -> Vec<String> {
+        sqlx::query_scalar("SELECT id FROM sessions ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn add_session(pool: &db::Pool, id: &str) {
+        sqlx::query("INSERT INTO sessions (id, user_id, data, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(id)
+            .bind("ann")
+            .bind("{}")
+            .bind(i64::MAX)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_what_was_backed_up() {
+        let (source, source_path) = database().await;
+        let (target, target_path) = database().await;
+        NotificationStore::Sql(source.clone())
+            .subscribe("ann", "ann@example.com".to_owned(), true, false, true)
+            .await
+            .unwrap();
+        add_session(&source, "from-source").await;
+        let notifications = NotificationStore::Sql(target.clone());
+        notifications
+            .subscribe("bob", "bob@example.com".to_owned(), true, true, true)
+            .await
+            .unwrap();
+        add_session(&target, "from-target").await;
+
+        let archive = source_path.with_extension("tar.zst");
+        backup_to(&source, &archive, false).await.unwrap();
+        restore_from(&target, &archive).await.unwrap();
+        assert!(notifications.get("bob").await.unwrap().is_none());
+        let ann = notifications.get("ann").await.unwrap().unwrap();
+        assert_eq!(ann.email, "ann@example.com");
+        assert!(ann.weekly_digest && !ann.new_releases && ann.milestones);
+        // Left out of the backup, so left alone.
+        assert_eq!(session_ids(&target).await, ["from-target"]);
+
+        backup_to(&source, &archive, true).await.unwrap();
+        restore_from(&target, &archive).await.unwrap();
+        assert_eq!(session_ids(&target).await, ["from-source"]);
+
+        for path in [source_path, target_path, archive] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_backups_of_other_schema_versions() {
+        let (pool, path) = database().await;
+        add_session(&pool, "kept").await;
+        let archive = path.with_extension("tar.zst");
+        let manifest = Manifest {
+            schema_version: Some(-1),
+            created_at: Utc::now(),
+            tables: vec!["sessions".to_owned()],
+        };
+        write_archive(&archive, &manifest, &[("sessions", Vec::new())]).unwrap();
+        let e = restore_from(&pool, &archive).await.unwrap_err();
+        assert!(e.to_string().contains("schema version"), "{e:#}");
+        assert_eq!(session_ids(&pool).await, ["kept"]);
+
+        let mut tar =
+            tar::Builder::new(zstd::Encoder::new(File::create(&archive).unwrap(), 0).unwrap());
+        append(&mut tar, "sessions.jsonl", b"").unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        let e = restore_from(&pool, &archive).await.unwrap_err();
+        assert!(e.to_string().contains("no manifest.json"), "{e:#}");
+
+        for path in [path, archive] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// Every table is listed with columns that exist, and booleans come out as booleans.
+    #[tokio::test]
+    async fn dumps_every_table() {
+        let (pool, path) = database().await;
+        NotificationStore::Sql(pool.clone())
+            .subscribe("ann", "ann@example.com".to_owned(), true, false, true)
+            .await
+            .unwrap();
+
+        let tables: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' \
+             AND name NOT LIKE '\\_%' ESCAPE '\\' AND name NOT LIKE 'sqlite%' AND name <> 'leases'",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        for name in &tables {
+            assert!(TABLES.iter().any(|table| table.name == name), "{name}");
+        }
+        let mut conn = pool.acquire().await.unwrap();
+        for table in TABLES {
+            let (rows, data) = dump(&mut conn, table).await.unwrap();
+            if table.name == "notifications" {
+                assert_eq!(rows, 1);
+                let row: Map<String, Value> = serde_json::from_slice(&data).unwrap();
+                assert_eq!(row["weekly_digest"], json!(true));
+                assert_eq!(row["new_releases"], json!(false));
+            }
+        }
+        drop(conn);
+        let _ = std::fs::remove_file(path);
+    }
+}
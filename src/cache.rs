@@ -0,0 +1,288 @@
+//! Key-value caching with expiry, for lookups that are expensive upstream and safe to serve
+//! stale. Entries live in memory, or with the `redis-store` feature and `REDIS_URL` set, in Redis
+//! next to the sessions, so every instance shares them. Callers only see [`Cache`], so where
+//! entries live is decided in [`connect`] alone.
+//!
+//! In memory, at most [`CAPACITY`] entries are kept, dropping the oldest to make room, and
+//! expired ones are swept out every [`SWEEP_INTERVAL`].
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::{config::Config, metrics};
+
+/// Values are strings; `get_json` and `set_json` on `dyn Cache` store anything serializable.
+#[axum::async_trait]
+pub trait Cache: Send + Sync {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+
+    /// Like [`get`](Self::get) for each of `keys`, in the same order.
+    async fn get_many(&self, keys: &[String]) -> anyhow::Result<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key).await?);
+        }
+        Ok(values)
+    }
+
+    /// Stores `value` under `key` for `ttl`, or until invalidated when `ttl` is `None`.
+    async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> anyhow::Result<()>;
+
+    async fn invalidate(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Number of entries, when cheaply known.
+    fn len_hint(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl dyn Cache {
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        let value = self.get(key).await?;
+        Ok(value.map(|v| serde_json::from_str(&v)).transpose()?)
+    }
+
+    pub async fn get_many_json<T: DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> anyhow::Result<Vec<Option<T>>> {
+        self.get_many(keys)
+            .await?
+            .into_iter()
+            .map(|value| Ok(value.map(|v| serde_json::from_str(&v)).transpose()?))
+            .collect()
+    }
+
+    pub async fn set_json<T: Serialize + Sync>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        self.set(key, serde_json::to_string(value)?, ttl).await
+    }
+}
+
+/// The cache to use with `config`.
+#[allow(clippy::unused_async)]
+pub async fn connect(config: &Config) -> anyhow::Result<Arc<dyn Cache>> {
+    #[cfg(feature = "redis-store")]
+    if let Some(url) = &config.redis_url {
+        let client = redis::Client::open(url.as_str())?;
+        tracing::info!("Caching in Redis");
+        return Ok(Arc::new(RedisCache(client.get_connection_manager().await?)));
+    }
+    let _ = config;
+    Ok(Arc::new(MemoryCache::default()))
+}
+
+/// Entries kept in memory at once. Values are small (genres, audio features, blurhashes), so
+/// this is some megabytes at most.
+const CAPACITY: usize = 50_000;
+/// How often setting an entry also sweeps out the expired ones, which reads only skip.
+const SWEEP_INTERVAL: Duration = Duration::from_mins(1);
+
+struct MemoryEntry {
+    value: String,
+    expires_at: Option<Instant>,
+    /// Tells this entry apart from earlier ones under the same key in [`Entries::order`].
+    seq: u64,
+}
+
+struct Entries {
+    values: HashMap<String, MemoryEntry>,
+    /// Keys oldest first, with the `seq` of the entry they were set with. Keys set again or
+    /// invalidated since leave stale pairs behind, which are skipped.
+    order: VecDeque<(String, u64)>,
+    next_seq: u64,
+    swept_at: Instant,
+}
+
+pub struct MemoryCache {
+    entries: RwLock<Entries>,
+}
+
+impl Default for MemoryCache {
+    fn default() -> Self {
+        Self {
+            entries: RwLock::new(Entries {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+                next_seq: 0,
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl MemoryCache {
+    async fn lookup(&self, key: &str, now: Instant) -> Option<String> {
+        metrics::read("cache", &self.entries)
+            .await
+            .values
+            .get(key)
+            .filter(|entry| entry.expires_at.is_none_or(|at| at > now))
+            .map(|entry| entry.value.clone())
+    }
+
+    async fn store(&self, key: &str, value: String, ttl: Option<Duration>, now: Instant) {
+        let mut entries = metrics::write("cache", &self.entries).await;
+        let Entries {
+            values,
+            order,
+            next_seq,
+            swept_at,
+        } = &mut *entries;
+        if now.duration_since(*swept_at) >= SWEEP_INTERVAL {
+            values.retain(|_, entry| entry.expires_at.is_none_or(|at| at > now));
+            *swept_at = now;
+        }
+        let seq = *next_seq;
+        *next_seq += 1;
+        let entry = MemoryEntry {
+            value,
+            expires_at: ttl.map(|ttl| now + ttl),
+            seq,
+        };
+        values.insert(key.to_owned(), entry);
+        order.push_back((key.to_owned(), seq));
+        while values.len() > CAPACITY {
+            let Some((oldest, seq)) = order.pop_front() else {
+                break;
+            };
+            if values.get(&oldest).is_some_and(|entry| entry.seq == seq) {
+                values.remove(&oldest);
+            }
+        }
+        // Stale pairs are only dropped when reached above, so compact once they pile up.
+        if order.len() > 2 * CAPACITY {
+            order.retain(|(key, seq)| values.get(key).is_some_and(|entry| entry.seq == *seq));
+        }
+    }
+}
+
+#[axum::async_trait]
+impl Cache for MemoryCache {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.lookup(key, Instant::now()).await)
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> anyhow::Result<()> {
+        self.store(key, value, ttl, Instant::now()).await;
+        Ok(())
+    }
+
+    async fn invalidate(&self, key: &str) -> anyhow::Result<()> {
+        metrics::write("cache", &self.entries)
+            .await
+            .values
+            .remove(key);
+        Ok(())
+    }
+
+    fn len_hint(&self) -> Option<usize> {
+        self.entries.try_read().ok().map(|e| e.values.len())
+    }
+}
+
+#[cfg(feature = "redis-store")]
+pub struct RedisCache(redis::aio::ConnectionManager);
+
+#[cfg(feature = "redis-store")]
+#[axum::async_trait]
+impl Cache for RedisCache {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(redis::AsyncCommands::get(&mut self.0.clone(), redis_key(key)).await?)
+    }
+
+    async fn get_many(&self, keys: &[String]) -> anyhow::Result<Vec<Option<String>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        // One round trip instead of one per key.
+        Ok(redis::cmd("MGET")
+            .arg(keys.iter().map(|key| redis_key(key)).collect::<Vec<_>>())
+            .query_async(&mut self.0.clone())
+            .await?)
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> anyhow::Result<()> {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(redis_key(key)).arg(value);
+        if let Some(ttl) = ttl {
+            cmd.arg("EX").arg(ttl.as_secs().max(1));
+        }
+        cmd.query_async::<_, ()>(&mut self.0.clone()).await?;
+        Ok(())
+    }
+
+    async fn invalidate(&self, key: &str) -> anyhow::Result<()> {
+        redis::AsyncCommands::del::<_, ()>(&mut self.0.clone(), redis_key(key)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis-store")]
+fn redis_key(key: &str) -> String {
+    format!("cache:{key}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_json_until_invalidated() {
+        let cache: Arc<dyn Cache> = Arc::new(MemoryCache::default());
+        cache.set_json("a", &vec![1, 2], None).await.unwrap();
+        cache.set_json("b", &vec![3], None).await.unwrap();
+        let keys = ["a", "missing", "b"].map(str::to_owned);
+        assert_eq!(
+            cache.get_many_json::<Vec<u8>>(&keys).await.unwrap(),
+            [Some(vec![1, 2]), None, Some(vec![3])]
+        );
+        cache.invalidate("a").await.unwrap();
+        assert_eq!(cache.get_json::<Vec<u8>>("a").await.unwrap(), None);
+        cache.set("c", "not json".to_owned(), None).await.unwrap();
+        assert!(cache.get_json::<Vec<u8>>("c").await.is_err());
+        assert_eq!(cache.len_hint(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn sweeps_expired_entries() {
+        let cache = MemoryCache::default();
+        let now = Instant::now();
+        let ttl = Some(Duration::from_secs(1));
+        cache.store("a", "1".to_owned(), ttl, now).await;
+        cache.store("b", "2".to_owned(), None, now).await;
+        assert_eq!(cache.lookup("a", now).await.as_deref(), Some("1"));
+        assert_eq!(cache.lookup("a", now + Duration::from_secs(1)).await, None);
+
+        let later = now + SWEEP_INTERVAL;
+        cache.store("c", "3".to_owned(), ttl, later).await;
+        assert_eq!(cache.len_hint(), Some(2));
+        assert_eq!(cache.lookup("b", later).await.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_past_capacity() {
+        let cache = MemoryCache::default();
+        let now = Instant::now();
+        for i in 0..CAPACITY {
+            cache.store(&i.to_string(), String::new(), None, now).await;
+        }
+        // Set again, so it's the newest now.
+        cache.store("0", "again".to_owned(), None, now).await;
+        cache.store("new", String::new(), None, now).await;
+        assert_eq!(cache.len_hint(), Some(CAPACITY));
+        assert_eq!(cache.lookup("0", now).await.as_deref(), Some("again"));
+        assert_eq!(cache.lookup("1", now).await, None);
+        assert!(cache.lookup("new", now).await.is_some());
+        assert!(cache.entries.read().await.order.len() <= 2 * CAPACITY);
+    }
+}
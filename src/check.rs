@@ -0,0 +1,198 @@
+//! `--check-config`: loads the configuration, checks it along with what it points at, and prints
+//! a report instead of serving, so a deployment can be verified before it takes traffic. Exits
+//! with an error when anything would keep the app from working; warnings are for what works but
+//! likely isn't what was meant.
+//!
+//! A configuration that doesn't parse fails [`Config::load`] already, with the variable at fault.
+
+use std::fmt::Display;
+
+use crate::{
+    cache,
+    config::{Config, Frontend, Listen},
+    mail::Mailer,
+    session_store::SessionStore,
+    spotify,
+};
+
+/// Shorter state secrets are easier to guess than the random key used without one.
+const MIN_STATE_SECRET_LEN: usize = 32;
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, what: &str, detail: impl Display) {
+        self.passed += 1;
+        println!("ok     {what}: {detail}");
+    }
+
+    fn warn(&mut self, what: &str, detail: impl Display) {
+        self.warnings += 1;
+        println!("warn   {what}: {detail}");
+    }
+
+    fn error(&mut self, what: &str, detail: impl Display) {
+        self.errors += 1;
+        println!("error  {what}: {detail}");
+    }
+
+    /// Reports `result` as ok with `detail`, or as an error.
+    fn check<T>(&mut self, what: &str, result: anyhow::Result<T>, detail: impl Display) {
+        match result {
+            Ok(_) => self.ok(what, detail),
+            Err(e) => self.error(what, format!("{e:#}")),
+        }
+    }
+}
+
+pub async fn run(config: &Config) -> anyhow::Result<()> {
+    let mut report = Report::default();
+
+    match &config.listen {
+        Listen::Tcp(addr) => report.ok("LISTEN", addr),
+        Listen::Unix(path) => match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) if !dir.is_dir() => report.error(
+                "LISTEN",
+                format!("the socket's directory {} doesn't exist", dir.display()),
+            ),
+            _ => report.ok("LISTEN", format!("unix:{}", path.display())),
+        },
+    }
+
+    public_url(&mut report, config);
+
+    match config.state_secret.as_deref() {
+        None => report.warn(
+            "STATE_SECRET",
+            "unset, so logins only complete on the instance they started on",
+        ),
+        Some(secret) if secret.len() < MIN_STATE_SECRET_LEN => report.warn(
+            "STATE_SECRET",
+            format!("shorter than {MIN_STATE_SECRET_LEN} characters"),
+        ),
+        Some(_) => report.ok("STATE_SECRET", "set"),
+    }
+
+    report.check(
+        "Spotify credentials",
+        spotify::check_credentials().await,
+        "accepted by Spotify",
+    );
+
+    stores(&mut report, config).await;
+
+    if config.mail.url.is_none() {
+        report.warn("MAIL_URL", "unset, so emails are only logged");
+    } else {
+        let mailer = Mailer::new(&config.mail);
+        let tested = match &mailer {
+            Ok(mailer) => mailer.test_connection().await,
+            Err(e) => Err(anyhow::anyhow!("{e:#}")),
+        };
+        report.check("MAIL_URL", tested, "the SMTP server accepts us");
+    }
+
+    let probe = config.art_dir.join(".check-config");
+    let writable = std::fs::create_dir_all(&config.art_dir)
+        .and_then(|()| std::fs::write(&probe, b""))
+        .and_then(|()| std::fs::remove_file(&probe));
+    report.check(
+        "ART_DIR",
+        writable.map_err(anyhow::Error::from),
+        format!("{} is writable", config.art_dir.display()),
+    );
+
+    if let Frontend::Spa(dir) = &config.frontend {
+        let index = dir.join("index.html");
+        if index.is_file() {
+            report.ok("FRONTEND", format!("serving {}", dir.display()));
+        } else {
+            report.error("FRONTEND", format!("{} doesn't exist", index.display()));
+        }
+    }
+
+    println!(
+        "\n{} ok, {} errors, {} warnings",
+        report.passed, report.errors, report.warnings
+    );
+    if report.errors > 0 {
+        anyhow::bail!("the configuration has {} errors", report.errors);
+    }
+    Ok(())
+}
+
+fn public_url(report: &mut Report, config: &Config) {
+    let url = match reqwest::Url::parse(&config.public_url) {
+        Ok(url) => url,
+        Err(e) => return report.error("PUBLIC_URL", format!("`{}`: {e}", config.public_url)),
+    };
+    if !matches!(url.scheme(), "http" | "https") || url.query().is_some() {
+        return report.error(
+            "PUBLIC_URL",
+            format!("`{url}` should be an http(s) URL without a query"),
+        );
+    }
+    let local = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if url.scheme() == "http" && config.session.cross_site {
+        report.error(
+            "PUBLIC_URL",
+            "SESSION_COOKIE_CROSS_SITE makes the session cookie Secure, which needs https",
+        );
+    } else if url.scheme() == "http" && !local {
+        report.warn("PUBLIC_URL", format!("{url} isn't https"));
+    } else {
+        report.ok("PUBLIC_URL", &config.public_url);
+    }
+
+    // Spotify only takes plain http redirects to the loopback address, anything else is refused
+    // before the user even gets to log in.
+    let redirect = config.redirect_uri();
+    match reqwest::Url::parse(&redirect) {
+        Ok(uri) if uri.scheme() == "https" || local => report.ok(
+            "Redirect URI",
+            format!("{redirect}, as registered with the Spotify app"),
+        ),
+        Ok(_) => report.error(
+            "Redirect URI",
+            format!("Spotify only sends users back to https or local URIs, not {redirect}"),
+        ),
+        Err(e) => report.error("Redirect URI", format!("`{redirect}`: {e}")),
+    }
+}
+
+async fn stores(report: &mut Report, config: &Config) {
+    report.check(
+        "Session store",
+        SessionStore::connect(config).await,
+        "reachable",
+    );
+    report.check("Cache", cache::connect(config).await, "reachable");
+
+    #[cfg(feature = "sql")]
+    if let Some(url) = &config.database_url {
+        match crate::db::connect(url).await {
+            Ok(pool) => match crate::db::schema_version(&pool).await {
+                Ok(version) if version.is_current() => report.ok("DATABASE_URL", "up to date"),
+                Ok(version) => report.warn(
+                    "DATABASE_URL",
+                    format!(
+                        "schema at {:?}, this build expects {:?}; migrations are applied on \
+                         startup",
+                        version.current, version.expected
+                    ),
+                ),
+                // The migrations table doesn't exist before the first startup.
+                Err(e) => report.warn(
+                    "DATABASE_URL",
+                    format!("reachable, but the schema is unknown: {e:#}"),
+                ),
+            },
+            Err(e) => report.error("DATABASE_URL", format!("{e:#}")),
+        }
+    }
+}
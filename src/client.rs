@@ -0,0 +1,118 @@
+//! `blid-test client <command>`: the same binary as a thin command-line client of a running
+//! server's API, authenticating with an API key. Responses are deserialized into the same types
+//! the server serializes them from.
+
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use reqwest::{Method, Response, StatusCode};
+use std::env;
+
+use crate::api::NowPlaying;
+
+const USAGE: &str = "usage: blid-test client now-playing|pause|next [--server URL] [--key KEY]";
+
+enum Command {
+    NowPlaying,
+    Pause,
+    Next,
+}
+
+struct Client {
+    http: reqwest::Client,
+    server: String,
+    key: String,
+}
+
+impl Client {
+    async fn send(&self, method: Method, path: &str) -> anyhow::Result<Response> {
+        let response = self
+            .http
+            .request(method, format!("{}{path}", self.server))
+            .bearer_auth(&self.key)
+            .send()
+            .await
+            .with_context(|| format!("couldn't reach {}", self.server))?;
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::UNAUTHORIZED => bail!("the server doesn't accept this key"),
+            StatusCode::FORBIDDEN => bail!("this key lacks the scope this command needs"),
+            StatusCode::PAYMENT_REQUIRED => bail!("controlling playback needs Spotify Premium"),
+            status => bail!(
+                "the server responded {status}: {}",
+                response.text().await.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Runs the command given in `args`, the arguments after `client`. `--server` defaults to
+/// `BLID_SERVER`, then `http://localhost:3000`, and `--key` to `BLID_KEY`, which keeps the key
+/// out of shell history.
+pub async fn run(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut command = None;
+    let mut server = env::var("BLID_SERVER").ok();
+    let mut key = env::var("BLID_KEY").ok();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = Some(args.next().context("--server needs a value")?),
+            "--key" => key = Some(args.next().context("--key needs a value")?),
+            "now-playing" if command.is_none() => command = Some(Command::NowPlaying),
+            "pause" if command.is_none() => command = Some(Command::Pause),
+            "next" if command.is_none() => command = Some(Command::Next),
+            other => bail!("unexpected argument `{other}`\n{USAGE}"),
+        }
+    }
+    let command = command.context(USAGE)?;
+    let client = Client {
+        http: reqwest::Client::new(),
+        server: server
+            .as_deref()
+            .unwrap_or("http://localhost:3000")
+            .trim_end_matches('/')
+            .to_owned(),
+        key: key.context("an API key is needed, through --key or BLID_KEY")?,
+    };
+
+    match command {
+        Command::NowPlaying => {
+            let response = client.send(Method::GET, "/api/player").await?;
+            if response.status() == StatusCode::NO_CONTENT {
+                println!("Nothing is playing");
+                return Ok(());
+            }
+            println!("{}", describe(&response.json().await?));
+        }
+        Command::Pause => {
+            client.send(Method::PUT, "/api/player/pause").await?;
+        }
+        Command::Next => {
+            client.send(Method::POST, "/api/player/next").await?;
+        }
+    }
+    Ok(())
+}
+
+fn describe(playing: &NowPlaying) -> String {
+    let state = if playing.is_playing {
+        "Playing"
+    } else {
+        "Paused"
+    };
+    let Some(track) = &playing.track else {
+        return format!("{state} something other than a track on {}", playing.device);
+    };
+    format!(
+        "{state} {} – {} ({} / {}) on {}",
+        track.name,
+        track.artists.iter().map(|a| &a.name).join(", "),
+        clock(playing.progress_ms.unwrap_or_default()),
+        clock(track.duration_ms),
+        playing.device
+    )
+}
+
+/// `m:ss`.
+fn clock(ms: u64) -> String {
+    let seconds = ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
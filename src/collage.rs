@@ -0,0 +1,215 @@
+//! Collages of the covers of the albums a user played most, as a grid to share. Built out of the
+//! collected history, so they only cover what was played since the user started using us or
+//! imported their streaming history.
+//!
+//! Making one fetches up to [`MAX_SIDE`]² covers and encodes a large JPEG, so each is cached for a
+//! while per user, period and grid. New plays show up once it expires.
+
+use anyhow::{bail, Context};
+use axum::body::Bytes;
+use base64::prelude::*;
+use chrono::{DateTime, Duration as TimeDelta, Utc};
+use futures::future::join_all;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, Rgb, RgbImage};
+use itertools::Itertools;
+use serde::Deserialize;
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use crate::{art::ArtStore, cache::Cache, spotify::Track};
+
+const CACHE_TTL: Duration = Duration::from_hours(1);
+/// Side of each cover in the collage, which the 300px size of album art is fetched for.
+const TILE: u32 = 300;
+/// Most covers along either side of a collage.
+const MAX_SIDE: u32 = 5;
+const JPEG_QUALITY: u8 = 85;
+/// Fills the tiles of albums whose cover couldn't be had.
+const BLANK: Rgb<u8> = Rgb([24, 24, 24]);
+
+/// How far back plays count.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Week,
+    #[default]
+    Month,
+    Year,
+    All,
+}
+
+impl Period {
+    fn since(self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Week => Some(now - TimeDelta::days(7)),
+            Self::Month => Some(now - TimeDelta::days(30)),
+            Self::Year => Some(now - TimeDelta::days(365)),
+            Self::All => None,
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Week => "week",
+            Self::Month => "month",
+            Self::Year => "year",
+            Self::All => "all",
+        }
+    }
+}
+
+/// Covers across and down, as in `3x3`.
+#[derive(Clone, Copy)]
+pub struct Grid {
+    columns: u32,
+    rows: u32,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self {
+            columns: 3,
+            rows: 3,
+        }
+    }
+}
+
+impl FromStr for Grid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (columns, rows) = s
+            .split_once(['x', 'X'])
+            .context("size should look like 3x3")?;
+        let grid = Self {
+            columns: columns.parse().context("size should look like 3x3")?,
+            rows: rows.parse().context("size should look like 3x3")?,
+        };
+        for side in [grid.columns, grid.rows] {
+            if !(1..=MAX_SIDE).contains(&side) {
+                bail!("collages are 1 to {MAX_SIDE} covers across and down");
+            }
+        }
+        Ok(grid)
+    }
+}
+
+impl Grid {
+    const fn tiles(self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+}
+
+pub struct CollageCache {
+    cache: Arc<dyn Cache>,
+}
+
+impl CollageCache {
+    pub fn new(cache: Arc<dyn Cache>) -> Self {
+        Self { cache }
+    }
+
+    /// The collage of the user's plays in `period`, as a JPEG, or `None` without any plays in
+    /// it. `plays` is only called when there's none cached.
+    pub async fn get<F>(
+        &self,
+        art: &ArtStore,
+        user_id: &str,
+        period: Period,
+        grid: Grid,
+        plays: F,
+    ) -> anyhow::Result<Option<Bytes>>
+    where
+        F: std::future::Future<Output = anyhow::Result<Vec<(DateTime<Utc>, Track)>>>,
+    {
+        let key = format!(
+            "collage:{user_id}:{}:{}x{}",
+            period.name(),
+            grid.columns,
+            grid.rows
+        );
+        // Made again when the cache fails, which is only slower.
+        match self.cache.get(&key).await {
+            Ok(Some(cached)) => match BASE64_STANDARD.decode(cached) {
+                Ok(jpeg) => return Ok(Some(jpeg.into())),
+                Err(e) => tracing::warn!("Failed to decode cached collage: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to read cached collage: {e:#}"),
+        }
+
+        let covers = top_covers(plays.await?, period.since(Utc::now()), grid.tiles());
+        if covers.is_empty() {
+            return Ok(None);
+        }
+        let covers = join_all(covers.iter().map(|url| async move {
+            art.cover(url, TILE)
+                .await
+                .inspect_err(|e| tracing::warn!("Failed to fetch cover {url} for a collage: {e:#}"))
+                .ok()
+        }))
+        .await;
+        let jpeg: Bytes = tokio::task::spawn_blocking(move || render(&covers, grid))
+            .await??
+            .into();
+
+        if let Err(e) = self
+            .cache
+            .set(&key, BASE64_STANDARD.encode(&jpeg), Some(CACHE_TTL))
+            .await
+        {
+            tracing::warn!("Failed to cache collage: {e:#}");
+        }
+        Ok(Some(jpeg))
+    }
+}
+
+/// URLs of the covers of the `n` albums played most since `since`, most played first. Ties
+/// go to the album played most recently.
+fn top_covers(
+    plays: Vec<(DateTime<Utc>, Track)>,
+    since: Option<DateTime<Utc>>,
+    n: usize,
+) -> Vec<String> {
+    plays
+        .into_iter()
+        .filter(|(played_at, _)| since.is_none_or(|since| *played_at >= since))
+        // Tracks without album art have nothing to show.
+        .filter_map(|(played_at, track)| {
+            let image = track.album.images.into_iter().next()?;
+            Some((track.album.id, (played_at, image.url)))
+        })
+        .into_group_map()
+        .into_values()
+        .filter_map(|plays| {
+            let count = plays.len();
+            let (last_played, url) = plays.into_iter().max_by_key(|(played_at, _)| *played_at)?;
+            Some((count, last_played, url))
+        })
+        .sorted_by(|a, b| b.0.cmp(&a.0).then_with(|| b.1.cmp(&a.1)))
+        .take(n)
+        .map(|(_, _, url)| url)
+        .collect()
+}
+
+/// `covers` laid out in rows, each scaled to a square tile. Albums that ran out leave the end of
+/// the grid blank.
+fn render(covers: &[Option<Bytes>], grid: Grid) -> anyhow::Result<Vec<u8>> {
+    let mut collage = RgbImage::from_pixel(grid.columns * TILE, grid.rows * TILE, BLANK);
+    for (i, cover) in (0..grid.columns * grid.rows).zip(covers) {
+        let Some(cover) = cover else {
+            continue;
+        };
+        let cover = match image::load_from_memory(cover) {
+            Ok(cover) => cover.resize_to_fill(TILE, TILE, FilterType::Lanczos3),
+            Err(e) => {
+                tracing::warn!("Undecodable cover in a collage: {e}");
+                continue;
+            }
+        };
+        let (x, y) = ((i % grid.columns) * TILE, (i / grid.columns) * TILE);
+        image::imageops::replace(&mut collage, &cover.to_rgb8(), x.into(), y.into());
+    }
+    let mut out = Vec::new();
+    collage.write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+    Ok(out)
+}
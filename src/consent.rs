@@ -0,0 +1,444 @@
+//! Consent to what the instance stores, with `CONSENT_VERSION`. After the login callback, users
+//! who haven't consented to that version are shown `/auth/consent` before they get a session,
+//! explaining what's kept about them, or `CONSENT_TEXT` instead. Changing the version asks
+//! everyone again on their next login.
+//!
+//! The login waits on the server, with the user's fresh token, under a one-time code held in a
+//! cookie of the browser that logged in. Consents live in memory by default, or in the database
+//! with a SQL feature and `DATABASE_URL` set; waiting logins are only ever in memory.
+
+use askama_axum::Template;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+    routing::get,
+    Form, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{sync::RwLock, time::Instant};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    cookie_manager::Flash,
+    cookies, login_state,
+    spotify::SpotifyToken,
+    templates::{self, Format, Layout, Page},
+    token, AppError, AppStateInner, LoginErrorTemplate,
+};
+
+/// Name of the cookie holding the code of the login waiting for consent.
+pub const COOKIE: &str = "consent_code";
+
+/// What a user consented to, and when.
+#[derive(Serialize, Clone, Debug)]
+pub struct Consent {
+    pub version: String,
+    pub at: DateTime<Utc>,
+}
+
+/// A login waiting for consent.
+pub struct Pending {
+    pub token: SpotifyToken,
+    pub user_id: String,
+    pub premium: Option<bool>,
+    /// Local path to send the user to once logged in.
+    pub next: String,
+}
+
+pub struct ConsentStore {
+    /// `CONSENT_VERSION`, without which nobody is asked.
+    version: Option<String>,
+    /// `CONSENT_TEXT`, empty for the default explanation.
+    text: String,
+    consents: Consents,
+    /// By code.
+    pending: RwLock<HashMap<String, (Instant, Pending)>>,
+}
+
+enum Consents {
+    /// By user ID.
+    Memory(RwLock<HashMap<String, Consent>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl ConsentStore {
+    pub fn new(version: Option<String>, text: Option<String>) -> Self {
+        Self {
+            version,
+            text: text.unwrap_or_default(),
+            consents: Consents::Memory(RwLock::default()),
+            pending: RwLock::default(),
+        }
+    }
+
+    /// Keeps consents in the database instead of in memory.
+    #[cfg(feature = "sql")]
+    pub fn in_database(self, pool: db::Pool) -> Self {
+        Self {
+            consents: Consents::Sql(pool),
+            ..self
+        }
+    }
+
+    /// The version in effect, if any.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub async fn get(&self, user_id: &str) -> anyhow::Result<Option<Consent>> {
+        match &self.consents {
+            Consents::Memory(consents) => Ok(consents.read().await.get(user_id).cloned()),
+            #[cfg(feature = "sql")]
+            Consents::Sql(pool) => {
+                let row: Option<(String, i64)> =
+                    sqlx::query_as("SELECT version, given_at FROM consents WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_optional(pool)
+                        .await?;
+                Ok(row.map(|(version, at)| Consent {
+                    version,
+                    at: DateTime::from_timestamp(at, 0).unwrap_or_default(),
+                }))
+            }
+        }
+    }
+
+    /// Whether `user_id` has to consent before logging in.
+    pub async fn needed(&self, user_id: &str) -> anyhow::Result<bool> {
+        let Some(version) = &self.version else {
+            return Ok(false);
+        };
+        Ok(self
+            .get(user_id)
+            .await?
+            .is_none_or(|consent| &consent.version != version))
+    }
+
+    /// Keeps a login until the user answers, returning the code to answer with.
+    pub async fn hold(&self, login: Pending) -> String {
+        self.hold_at(login, Instant::now()).await
+    }
+
+    async fn hold_at(&self, login: Pending, now: Instant) -> String {
+        let code = token::generate(token::STATE_BYTES);
+        let mut pending = self.pending.write().await;
+        pending.retain(|_, (expires_at, _)| *expires_at > now);
+        pending.insert(code.clone(), (now + login_state::MAX_AGE, login));
+        code
+    }
+
+    /// Whether `code` belongs to a login still waiting.
+    async fn waiting(&self, code: &str) -> bool {
+        self.pending
+            .read()
+            .await
+            .get(code)
+            .is_some_and(|(expires_at, _)| *expires_at > Instant::now())
+    }
+
+    /// Uses up `code`, returning its login if it's still waiting.
+    async fn take(&self, code: &str) -> Option<Pending> {
+        self.take_at(code, Instant::now()).await
+    }
+
+    async fn take_at(&self, code: &str, now: Instant) -> Option<Pending> {
+        let (expires_at, login) = self.pending.write().await.remove(code)?;
+        (expires_at > now).then_some(login)
+    }
+
+    /// Records that `user_id` consented to the current version, if there's one.
+    pub async fn record(&self, user_id: &str) -> anyhow::Result<()> {
+        let Some(version) = self.version.clone() else {
+            return Ok(());
+        };
+        let consent = Consent {
+            version,
+            at: Utc::now(),
+        };
+        match &self.consents {
+            Consents::Memory(consents) => {
+                consents.write().await.insert(user_id.to_owned(), consent);
+            }
+            #[cfg(feature = "sql")]
+            Consents::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO consents (user_id, version, given_at) VALUES ($1, $2, $3) \
+                     ON CONFLICT (user_id) DO UPDATE SET version = $2, given_at = $3",
+                )
+                .bind(user_id)
+                .bind(&consent.version)
+                .bind(consent.at.timestamp())
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets that a user consented, returning whether they had.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<bool> {
+        match &self.consents {
+            Consents::Memory(consents) => Ok(consents.write().await.remove(user_id).is_some()),
+            #[cfg(feature = "sql")]
+            Consents::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM consents WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.consents {
+            Consents::Memory(consents) => consents.try_read().ok().map(|c| c.len()),
+            #[cfg(feature = "sql")]
+            Consents::Sql(_) => None,
+        }
+    }
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new().route("/consent", get(ask).post(answer))
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "consent.html")]
+struct ConsentTemplate {
+    /// `CONSENT_TEXT`, empty for the default explanation.
+    text: String,
+    layout: Layout,
+}
+
+impl Page for ConsentTemplate {
+    const PATH: &'static str = "consent.html";
+}
+
+fn clear_cookie() -> String {
+    format!("{COOKIE}=; Max-Age=0; Path=/auth/consent; HttpOnly; SameSite=Lax")
+}
+
+async fn ask(
+    State(s): State<Arc<AppStateInner>>,
+    headers: HeaderMap,
+    layout: Layout,
+    format: Format,
+) -> Response {
+    let code = cookies::from_headers(&headers, COOKIE).unwrap_or_default();
+    if !s.consent.waiting(code).await {
+        return Redirect::to("/auth").into_response();
+    }
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        templates::respond(
+            format,
+            ConsentTemplate {
+                text: s.consent.text.clone(),
+                layout,
+            },
+        ),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Answer {
+    Accept,
+    Decline,
+}
+
+#[derive(Deserialize)]
+struct AnswerForm {
+    answer: Answer,
+}
+
+/// Finishes the login on acceptance. The cookie is `SameSite=Lax`, so other sites can't answer
+/// for the user.
+async fn answer(
+    State(s): State<Arc<AppStateInner>>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    layout: Layout,
+    format: Format,
+    Form(form): Form<AnswerForm>,
+) -> Result<Response, AppError> {
+    let code = cookies::from_headers(&headers, COOKIE).unwrap_or_default();
+    let Some(login) = s.consent.take(code).await else {
+        return Ok((StatusCode::NOT_FOUND, "This login has expired").into_response());
+    };
+    if matches!(form.answer, Answer::Decline) {
+        return Ok((
+            [(header::SET_COOKIE, clear_cookie())],
+            templates::respond(
+                format,
+                LoginErrorTemplate {
+                    cancelled: true,
+                    message: "access_denied".to_owned(),
+                    layout,
+                },
+            ),
+        )
+            .into_response());
+    }
+    s.consent.record(&login.user_id).await?;
+    let flash = Flash(format!("Logged in as {}", login.user_id));
+    let session_cookie = crate::log_in(
+        &s,
+        &headers,
+        peer.as_ref(),
+        login.token,
+        login.user_id,
+        login.premium,
+    )
+    .await?;
+    Ok((
+        AppendHeaders(
+            std::iter::once(clear_cookie())
+                .chain(session_cookie)
+                .map(|cookie| (header::SET_COOKIE, cookie)),
+        ),
+        flash,
+        Redirect::to(&login.next),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn login(user_id: &str) -> Pending {
+        Pending {
+            token: SpotifyToken {
+                access_token: "token".to_owned(),
+                refresh_token: String::new(),
+                expires_in: 3600,
+                token_type: "Bearer".to_owned(),
+                scope: String::new(),
+            },
+            user_id: user_id.to_owned(),
+            premium: None,
+            next: "/playlists".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn asks_for_the_current_version_in_memory() {
+        let store = ConsentStore::new(None, None);
+        assert!(!store.needed("ann").await.unwrap());
+        store.record("ann").await.unwrap();
+        assert!(store.get("ann").await.unwrap().is_none());
+
+        let store = ConsentStore::new(Some("1".to_owned()), None);
+        assert!(store.needed("ann").await.unwrap());
+        store.record("ann").await.unwrap();
+        assert!(!store.needed("ann").await.unwrap());
+        assert!(store.needed("bob").await.unwrap());
+        assert_eq!(store.len_hint(), Some(1));
+        assert!(store.remove("ann").await.unwrap());
+        assert!(!store.remove("ann").await.unwrap());
+        assert!(store.needed("ann").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn logins_wait_once_until_they_expire() {
+        let store = ConsentStore::new(Some("1".to_owned()), None);
+        let now = Instant::now();
+        let code = store.hold_at(login("ann"), now).await;
+        assert!(store.waiting(&code).await);
+        assert_eq!(store.take_at(&code, now).await.unwrap().user_id, "ann");
+        assert!(store.take_at(&code, now).await.is_none());
+        assert!(!store.waiting(&code).await);
+
+        let code = store.hold_at(login("ann"), now).await;
+        let later = now + login_state::MAX_AGE;
+        assert!(store.take_at(&code, later).await.is_none());
+        // Holding another login sweeps out expired ones.
+        store.hold_at(login("bob"), now).await;
+        store.hold_at(login("cat"), later).await;
+        assert_eq!(store.pending.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn accepting_logs_in_and_declining_does_not() {
+        let dir = std::env::temp_dir().join(format!("blid-test-{}", token::generate(8)));
+        let mut config = Config::from_args(std::iter::empty()).unwrap();
+        config.art_dir = dir.join("art");
+        config.theme_dir = dir.join("themes");
+        config.consent_version = Some("1".to_owned());
+        let state = Arc::new(AppStateInner::new(&config).await.unwrap());
+        let answer_with = |code: &str, answer| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::COOKIE, format!("{COOKIE}={code}").parse().unwrap());
+            super::answer(
+                State(state.clone()),
+                headers,
+                None,
+                Layout::default(),
+                Format::Json,
+                Form(AnswerForm { answer }),
+            )
+        };
+        let set_cookies = |response: &Response| -> Vec<String> {
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|cookie| cookie.to_str().unwrap().to_owned())
+                .collect()
+        };
+
+        let code = state.consent.hold(login("ann")).await;
+        let declined = answer_with(&code, Answer::Decline)
+            .await
+            .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(set_cookies(&declined), [clear_cookie()]);
+        assert!(state.consent.needed("ann").await.unwrap());
+        let again = answer_with(&code, Answer::Accept)
+            .await
+            .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(again.status(), StatusCode::NOT_FOUND);
+
+        let code = state.consent.hold(login("ann")).await;
+        let accepted = answer_with(&code, Answer::Accept)
+            .await
+            .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(accepted.headers()[header::LOCATION], "/playlists");
+        let cookies = set_cookies(&accepted);
+        assert_eq!(cookies[0], clear_cookie());
+        assert!(cookies
+            .iter()
+            .any(|cookie| cookie.starts_with("session_id=")));
+        assert!(!state.consent.needed("ann").await.unwrap());
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn consents_survive_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-consent-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        let store = ConsentStore::new(Some("1".to_owned()), None).in_database(pool.clone());
+        assert!(store.needed("ann").await.unwrap());
+        store.record("ann").await.unwrap();
+
+        let store = ConsentStore::new(Some("1".to_owned()), None).in_database(pool.clone());
+        assert!(!store.needed("ann").await.unwrap());
+        assert_eq!(store.get("ann").await.unwrap().unwrap().version, "1");
+        let store = ConsentStore::new(Some("2".to_owned()), None).in_database(pool);
+        assert!(store.needed("ann").await.unwrap());
+        assert!(store.remove("ann").await.unwrap());
+        assert!(store.get("ann").await.unwrap().is_none());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,41 +1,83 @@
-#![allow(dead_code)]
-use axum::http::Response;
-use tower::Service;
+//! Cookies the pages set for each other. For now, flash messages: a handler adds a [`Flash`] to
+//! its response, usually a redirect after a form, and the next page shows it in the layout, once.
+//!
+//! The message travels in the `flash` cookie, which [`manage`] sets from the response and clears
+//! once a full page has been served with it. Fragments loaded by HTMX leave it for the next page.
 
-struct CookieManager<Store, S> {
-    inner: S,
-    store: Store,
-}
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponseParts, Response, ResponseParts},
+};
+use base64::prelude::*;
+use std::convert::Infallible;
+
+use crate::cookies;
+
+/// Name of the cookie holding the message.
+pub const FLASH: &str = "flash";
+
+/// Longest message shown, in bytes. Anything longer didn't come from us.
+const MAX_LEN: usize = 256;
+
+/// A message for the next page. Added to a response, it's set for the next page; in the
+/// extensions of a request, put there by [`manage`], it's the one to show.
+#[derive(Clone, Debug)]
+pub struct Flash(pub String);
 
-impl<Store, S> CookieManager<Store, S> {
-    const fn new(store: Store, inner: S) -> Self {
-        Self { inner, store }
+impl IntoResponseParts for Flash {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+        Ok(res)
     }
 }
 
-trait CookieStore {
-    fn get(&self, key: &str) -> Option<&str>;
-    fn set(&mut self, key: &str, val: String) -> bool;
+/// Base64url, as cookie values can't hold spaces, commas or semicolons.
+fn decode(value: &str) -> Option<String> {
+    let bytes = BASE64_URL_SAFE_NO_PAD.decode(value).ok()?;
+    String::from_utf8(bytes)
+        .ok()
+        .filter(|message| !message.is_empty() && message.len() <= MAX_LEN)
 }
 
-impl<Store, S, B> Service<Response<B>> for CookieManager<Store, S>
-where
-    S: Service<Response<B>>,
-    Store: CookieStore,
-{
-    type Error = S::Error;
-    type Future = S::Future;
-    type Response = S::Response;
-
-    fn poll_ready(
-        &mut self,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+/// Middleware passing the flash of the request on to the handler and setting the one of the
+/// response.
+pub async fn manage(mut request: Request, next: Next) -> Response {
+    let shown = cookies::from_headers(request.headers(), FLASH)
+        .and_then(decode)
+        .map(Flash);
+    // Boosted links and forms load whole pages through HTMX, which do show it.
+    let fragment = request.headers().contains_key("hx-request")
+        && !request.headers().contains_key("hx-boosted");
+    if let Some(flash) = &shown {
+        request.extensions_mut().insert(flash.clone());
     }
-
-    fn call(&mut self, req: Response<B>) -> Self::Future {
-        req.headers();
-        todo!()
+    let mut response = next.run(request).await;
+    let cookie = match response.extensions_mut().remove::<Flash>() {
+        Some(Flash(message)) => format!(
+            "{FLASH}={}; Path=/; HttpOnly; SameSite=Lax",
+            BASE64_URL_SAFE_NO_PAD.encode(message)
+        ),
+        None if shown.is_some() && !fragment && is_page(&response) => {
+            format!("{FLASH}=; Max-Age=0; Path=/; HttpOnly; SameSite=Lax")
+        }
+        None => return response,
+    };
+    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
     }
+    response
+}
+
+/// Whether the response is a page that was shown, as opposed to an error or a redirect.
+fn is_page(response: &Response) -> bool {
+    response.status().is_success()
+        && response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"))
 }
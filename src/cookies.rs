@@ -0,0 +1,92 @@
+//! Reading cookies from request headers.
+//!
+//! Every value comes from the client, so anything goes: several `Cookie` headers (HTTP/2 splits
+//! them up), bytes that aren't UTF-8, pairs without `=`, names given twice and values of any
+//! length.
+
+use axum::http::{header, HeaderMap};
+
+/// Name of the cookie holding the session ID.
+pub const SESSION: &str = "session_id";
+
+/// Longest value accepted, what browsers allow for a whole cookie. Nothing set here comes close.
+pub const MAX_VALUE_LEN: usize = 4096;
+
+/// The value of the cookie `name` in a `Cookie` header value.
+///
+/// When it's given more than once the first one wins, which is the one with the most specific
+/// path. Surrounding double quotes, which RFC 6265 allows, are removed, and values longer than
+/// [`MAX_VALUE_LEN`] are ignored.
+pub fn get<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+    cookies
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| key.trim() == name)
+        .map(|(_, value)| {
+            let value = value.trim();
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value)
+        })
+        .find(|value| value.len() <= MAX_VALUE_LEN)
+}
+
+/// The value of the cookie `name` across all `Cookie` headers, skipping those that aren't UTF-8.
+pub fn from_headers<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .find_map(|cookies| get(cookies, name))
+}
+
+/// The session ID the request carries, if any.
+pub fn session(headers: &HeaderMap) -> Option<&str> {
+    from_headers(headers, SESSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use proptest::prelude::*;
+
+    proptest! {
+        /// As the `cookies` fuzz target: whatever is found is a value out of one of the headers,
+        /// within the length limit.
+        #[test]
+        fn values_come_from_the_headers(data in prop::collection::vec(any::<u8>(), 0..512)) {
+            let mut headers = HeaderMap::new();
+            for line in data.split(|&b| b == b'\n') {
+                if let Ok(value) = HeaderValue::from_bytes(line) {
+                    headers.append(header::COOKIE, value);
+                }
+            }
+            for name in [SESSION, "login_nonce", ""] {
+                let Some(value) = from_headers(&headers, name) else {
+                    continue;
+                };
+                prop_assert!(value.len() <= MAX_VALUE_LEN);
+                prop_assert!(!value.contains(';'));
+                prop_assert!(headers
+                    .get_all(header::COOKIE)
+                    .iter()
+                    .filter_map(|line| line.to_str().ok())
+                    .any(|line| line.contains(value)));
+            }
+        }
+
+        #[test]
+        fn finds_the_first_value(
+            value in "[A-Za-z0-9_-]{0,64}",
+            before in "([a-z]{1,8}=[a-z0-9]{0,8}; ){0,3}",
+            after in "(; [a-z]{1,8}=[a-z0-9]{0,8}){0,3}",
+            quoted in any::<bool>(),
+        ) {
+            let sent = if quoted { format!("\"{value}\"") } else { value.clone() };
+            let line = format!("{before}{SESSION}={sent}{after}; {SESSION}=later");
+            prop_assert_eq!(get(&line, SESSION), Some(value.as_str()));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! CORS for `/api`, so browser apps served from other origins, such as a separately hosted
+//! frontend, can call it. Only origins in the allowlist get CORS headers, and they may send
+//! credentials along. The session cookie is `SameSite=Lax` by default, so browsers only send it
+//! from origins on the same site, such as another subdomain; others can use API keys.
+//!
+//! With `SESSION_COOKIE_CROSS_SITE`, the cookie is `SameSite=None` and browsers send it from any
+//! site, forms included, which CORS doesn't stop. [`check_origin`] then refuses requests that
+//! change something unless they come from `PUBLIC_URL` or an allowed origin.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{deadline, AppStateInner};
+
+/// How long browsers may cache a preflight response.
+const MAX_AGE: Duration = Duration::from_hours(1);
+
+/// The allowlist, shared with the layer so a reload can change it.
+pub type Origins = Arc<RwLock<Vec<HeaderValue>>>;
+
+/// With no origins, no cross-origin request is allowed, as if there were no layer at all.
+pub fn layer(origins: Origins) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origins
+                .read()
+                .expect("CORS origins poisoned")
+                .contains(origin)
+        }))
+        .allow_credentials(true)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            HeaderName::from_static(deadline::HEADER),
+        ])
+        .max_age(MAX_AGE)
+}
+
+/// Middleware refusing unsafe requests from other origins when the session cookie is sent
+/// cross-site. The origin is taken from `Origin`, or `Referer` without it. Requests carrying
+/// neither don't come from a browser, which sends at least one of them along with cookies, so
+/// they're let through.
+pub async fn check_origin(
+    State(state): State<Arc<AppStateInner>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let safe = [Method::GET, Method::HEAD, Method::OPTIONS].contains(request.method());
+    if !state.session_config.cross_site || safe {
+        return next.run(request).await;
+    }
+    let headers = request.headers();
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin.to_str().ok().map(str::to_owned),
+        None => match headers.get(header::REFERER) {
+            Some(referer) => referer.to_str().ok().and_then(origin_of),
+            None => return next.run(request).await,
+        },
+    };
+    let allowed = origin.is_some_and(|origin| {
+        origin_of(&state.public_url).as_deref() == Some(origin.as_str())
+            || state
+                .cors_origins
+                .read()
+                .expect("CORS origins poisoned")
+                .iter()
+                .any(|allowed| allowed == origin.as_str())
+    });
+    if allowed {
+        next.run(request).await
+    } else {
+        (StatusCode::FORBIDDEN, "Cross-origin request refused").into_response()
+    }
+}
+
+/// `scheme://host[:port]` of `url`.
+fn origin_of(url: &str) -> Option<String> {
+    let uri: Uri = url.parse().ok()?;
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origin_drops_path_and_query() {
+        assert_eq!(
+            origin_of("https://app.example.com:8443/a/b?c=d").as_deref(),
+            Some("https://app.example.com:8443")
+        );
+        assert_eq!(origin_of("/relative"), None);
+    }
+}
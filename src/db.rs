@@ -0,0 +1,40 @@
+//! The SQL database behind the `sqlite-store` and `postgres` features, picked by the scheme of
+//! `DATABASE_URL`. Queries are written once for both, so they stick to SQL the two agree on.
+//!
+//! The schema is versioned by the migrations in `migrations/`, which are compiled into the binary
+//! and applied on startup, so upgrading is a matter of deploying the new binary. `--migrate-only`
+//! applies them without serving, for deployments that migrate as a separate step.
+
+use sqlx::{any::AnyPoolOptions, migrate::Migrator};
+
+use crate::session_store::SchemaVersion;
+
+pub type Pool = sqlx::AnyPool;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Connects to `url`, such as `sqlite://blid.db?mode=rwc` or `postgres://user@host/blid`.
+pub async fn connect(url: &str) -> anyhow::Result<Pool> {
+    sqlx::any::install_default_drivers();
+    Ok(AnyPoolOptions::new().connect(url).await?)
+}
+
+/// Applies the migrations that weren't yet.
+pub async fn migrate(pool: &Pool) -> anyhow::Result<()> {
+    MIGRATOR.run(pool).await?;
+    let version = schema_version(pool).await?;
+    tracing::info!("Database schema is at version {:?}", version.current);
+    Ok(())
+}
+
+pub async fn schema_version(pool: &Pool) -> anyhow::Result<SchemaVersion> {
+    // The table is created by the first run of the migrations, which happens on startup.
+    let current: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(pool)
+            .await?;
+    Ok(SchemaVersion {
+        current,
+        expected: MIGRATOR.iter().map(|m| m.version).max(),
+    })
+}
@@ -0,0 +1,168 @@
+//! Time budgets for `/api` requests. Each request gets `REQUEST_TIMEOUT_SECS`, or less if the
+//! client asks for it with an `X-Request-Timeout` header in seconds, and every call to Spotify made
+//! while handling it times out when the budget runs out rather than after its own timeout.
+//!
+//! A request out of budget is answered with a `504` and `{"error": "deadline_exceeded"}`.
+//! Endpoints that gather many pages from Spotify answer with what they had gathered by then under
+//! `partial`, with how many items that is under `collected`.
+//!
+//! The budget covers handlers up to their response. Streamed bodies, like server-sent events and
+//! the library listing, go on for as long as they need.
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+use crate::AppStateInner;
+
+pub const HEADER: &str = "x-request-timeout";
+/// Left to handlers after calls to Spotify time out, to answer with what they have.
+const GRACE: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+struct Budget {
+    deadline: Instant,
+    total: Duration,
+}
+
+tokio::task_local! {
+    static BUDGET: Budget;
+}
+
+/// A request ran out of its budget.
+#[derive(Debug)]
+pub struct Exceeded;
+
+impl std::fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the request ran out of time")
+    }
+}
+
+impl std::error::Error for Exceeded {}
+
+/// Whether `e` comes from running out of budget.
+pub fn is_exceeded(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Exceeded>().is_some()
+}
+
+/// What's left of the budget of the request being handled, `None` outside of one.
+pub fn remaining() -> Option<Duration> {
+    BUDGET
+        .try_with(|budget| budget.deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
+
+/// The `504` for a request out of budget, with the result so far if there's one.
+pub fn response<T: Serialize>(partial: Option<(usize, T)>) -> Response {
+    let budget_ms = BUDGET
+        .try_with(|budget| budget.total.as_millis())
+        .unwrap_or_default();
+    let mut body = json!({ "error": "deadline_exceeded", "budget_ms": budget_ms });
+    if let Some((collected, partial)) = partial {
+        body["collected"] = json!(collected);
+        body["partial"] = json!(partial);
+    }
+    (StatusCode::GATEWAY_TIMEOUT, Json(body)).into_response()
+}
+
+/// Runs the request within its budget.
+pub async fn limit(State(s): State<Arc<AppStateInner>>, request: Request, next: Next) -> Response {
+    let asked = request
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+    // Clients can only ask for less time than the server allows.
+    let total = asked.map_or(s.request_timeout, |asked| asked.min(s.request_timeout));
+    let budget = Budget {
+        deadline: Instant::now() + total,
+        total,
+    };
+    let handled = BUDGET.scope(
+        budget,
+        tokio::time::timeout(total + GRACE, next.run(request)),
+    );
+    handled
+        .await
+        .unwrap_or_else(|_| BUDGET.sync_scope(budget, || response::<()>(None)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn call(state: &Arc<AppStateInner>, path: &str, timeout: Option<&str>) -> Response {
+        let app = Router::new()
+            .route(
+                "/remaining",
+                get(|| async { remaining().unwrap().as_millis().to_string() }),
+            )
+            .route("/stuck", get(std::future::pending::<()>))
+            .layer(middleware::from_fn_with_state(state.clone(), limit))
+            .with_state(state.clone());
+        let mut request = Request::get(path);
+        if let Some(timeout) = timeout {
+            request = request.header(HEADER, timeout);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_can_only_ask_for_less_time() {
+        let state = AppStateInner::for_tests().await;
+        let allowed = state.request_timeout.as_millis();
+        for (timeout, at_most) in [
+            (None, allowed),
+            (Some("0.5"), 500),
+            (Some(" 2 "), 2000),
+            (Some("100000"), allowed),
+            (Some("-1"), allowed),
+            (Some("soon"), allowed),
+        ] {
+            let body = json_body(call(&state, "/remaining", timeout).await).await;
+            let remaining = u128::from(body.as_u64().unwrap());
+            assert!(remaining <= at_most, "{timeout:?}");
+            assert!(remaining + 100 > at_most, "{timeout:?}");
+        }
+        assert_eq!(remaining(), None);
+    }
+
+    #[tokio::test]
+    async fn requests_out_of_time_get_a_504() {
+        let state = AppStateInner::for_tests().await;
+        let stuck = call(&state, "/stuck", Some("0.05")).await;
+        assert_eq!(stuck.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            json_body(stuck).await,
+            json!({ "error": "deadline_exceeded", "budget_ms": 50 })
+        );
+
+        let partial = json_body(response(Some((2, ["a", "b"])))).await;
+        assert_eq!(partial["collected"], 2);
+        assert_eq!(partial["partial"], json!(["a", "b"]));
+        assert!(is_exceeded(
+            &anyhow::Error::new(Exceeded).context("listing")
+        ));
+        assert!(!is_exceeded(&anyhow::anyhow!("other")));
+    }
+}
@@ -0,0 +1,484 @@
+//! Logging in clients without a usable browser, such as CLIs and TVs, in the style of the OAuth
+//! device authorization grant (RFC 8628). The client asks for a code pair, shows the user code,
+//! and polls for a token while the user enters that code at `/auth/device` in a browser where
+//! they're logged in. Once approved, the client gets an API key for `/api` with the scopes it asked
+//! for in its `scope` parameter.
+
+use askama_axum::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use itertools::Itertools;
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::{
+    api_keys::Scope,
+    session::PageSession,
+    templates::{self, Format, Layout, Page},
+    token, AppError, AppStateInner,
+};
+
+/// How long the user has to approve a device.
+const MAX_AGE: Duration = Duration::from_mins(10);
+/// How often a client may poll, until told to slow down.
+const INTERVAL: Duration = Duration::from_secs(5);
+/// The grant type clients poll with.
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Consonants only, so user codes can't spell words, and none that are easily confused.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// Shown when a user code doesn't match a waiting device.
+const EXPIRED: &str = "No device is waiting for this code. It may have expired.";
+
+enum Status {
+    Pending,
+    /// Holds the API key until the client picks it up.
+    Approved(String),
+    Denied,
+}
+
+struct DeviceAuthorization {
+    user_code: String,
+    /// What the client called itself, shown when approving and used as the API key's name.
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Instant,
+    interval: Duration,
+    last_poll: Option<Instant>,
+    status: Status,
+}
+
+#[derive(Default)]
+pub struct DeviceStore {
+    /// By device code.
+    authorizations: RwLock<HashMap<String, DeviceAuthorization>>,
+}
+
+impl DeviceStore {
+    /// Starts an authorization, returning its device code and user code.
+    async fn start(&self, name: String, scopes: Vec<Scope>) -> (String, String) {
+        self.start_at(name, scopes, Instant::now()).await
+    }
+
+    async fn start_at(&self, name: String, scopes: Vec<Scope>, now: Instant) -> (String, String) {
+        let device_code = token::generate(token::SESSION_BYTES);
+        let mut authorizations = self.authorizations.write().await;
+        authorizations.retain(|_, auth| auth.expires_at > now);
+        let user_code = loop {
+            let code = user_code();
+            if !authorizations.values().any(|auth| auth.user_code == code) {
+                break code;
+            }
+        };
+        authorizations.insert(
+            device_code.clone(),
+            DeviceAuthorization {
+                user_code: user_code.clone(),
+                name,
+                scopes,
+                expires_at: now + MAX_AGE,
+                interval: INTERVAL,
+                last_poll: None,
+                status: Status::Pending,
+            },
+        );
+        (device_code, user_code)
+    }
+
+    /// The name and requested scopes of the client waiting on `user_code`, if one still is.
+    async fn pending(&self, user_code: &str) -> Option<(String, Vec<Scope>)> {
+        self.authorizations
+            .read()
+            .await
+            .values()
+            .find(|auth| {
+                auth.user_code == user_code
+                    && auth.expires_at > Instant::now()
+                    && matches!(auth.status, Status::Pending)
+            })
+            .map(|auth| (auth.name.clone(), auth.scopes.clone()))
+    }
+
+    /// Settles the authorization waiting on `user_code`, returning whether there was one.
+    async fn settle(&self, user_code: &str, status: Status) -> bool {
+        let mut authorizations = self.authorizations.write().await;
+        let auth = authorizations.values_mut().find(|auth| {
+            auth.user_code == user_code
+                && auth.expires_at > Instant::now()
+                && matches!(auth.status, Status::Pending)
+        });
+        match auth {
+            Some(auth) => {
+                auth.status = status;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Answers a poll of the client, handing out the API key once approved.
+    async fn poll(&self, device_code: &str) -> Result<String, &'static str> {
+        self.poll_at(device_code, Instant::now()).await
+    }
+
+    async fn poll_at(&self, device_code: &str, now: Instant) -> Result<String, &'static str> {
+        let mut authorizations = self.authorizations.write().await;
+        let auth = authorizations.get_mut(device_code).ok_or("invalid_grant")?;
+        if auth.expires_at <= now {
+            authorizations.remove(device_code);
+            return Err("expired_token");
+        }
+        let too_soon = auth
+            .last_poll
+            .is_some_and(|last| now.duration_since(last) < auth.interval);
+        auth.last_poll = Some(now);
+        if too_soon {
+            auth.interval += INTERVAL;
+            return Err("slow_down");
+        }
+        match auth.status {
+            Status::Pending => Err("authorization_pending"),
+            Status::Approved(_) | Status::Denied => {
+                match authorizations.remove(device_code).map(|auth| auth.status) {
+                    Some(Status::Approved(key)) => Ok(key),
+                    _ => Err("access_denied"),
+                }
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.authorizations.try_read().ok().map(|a| a.len())
+    }
+}
+
+/// Eight characters as `XXXX-XXXX`, about 34 bits, which is plenty for a code that's only valid
+/// for a few minutes and can only be entered by logged-in users.
+fn user_code() -> String {
+    let mut code = String::with_capacity(9);
+    for i in 0..8 {
+        if i == 4 {
+            code.push('-');
+        }
+        code.push(USER_CODE_ALPHABET[OsRng.gen_range(0..USER_CODE_ALPHABET.len())].into());
+    }
+    code
+}
+
+/// Tolerates what people do when typing a code: lowercase, spaces and a missing dash.
+fn normalize(user_code: &str) -> String {
+    let code: String = user_code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() == 8 {
+        format!("{}-{}", &code[..4], &code[4..])
+    } else {
+        code
+    }
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/device", get(verification_page).post(verify))
+        .route("/device/code", post(device_code))
+        .route("/device/token", post(device_token))
+}
+
+#[derive(Deserialize)]
+struct CodeRequest {
+    name: Option<String>,
+    /// Space-separated [`Scope`]s.
+    scope: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+async fn device_code(
+    State(s): State<Arc<AppStateInner>>,
+    Form(body): Form<CodeRequest>,
+) -> Response {
+    let Some(scopes) = Scope::parse_list(body.scope.as_deref().unwrap_or_default()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(TokenError {
+                error: "invalid_scope",
+            }),
+        )
+            .into_response();
+    };
+    let name = body
+        .name
+        .map(|name| name.trim().chars().take(64).collect::<String>())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "Device".to_owned());
+    let (device_code, user_code) = s.devices.start(name, scopes).await;
+    let verification_uri = format!("{}/auth/device", s.public_url);
+    Json(CodeResponse {
+        verification_uri_complete: format!("{verification_uri}?user_code={user_code}"),
+        verification_uri,
+        device_code,
+        user_code,
+        expires_in: MAX_AGE.as_secs(),
+        interval: INTERVAL.as_secs(),
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    device_code: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+}
+
+#[derive(Serialize)]
+struct TokenError {
+    error: &'static str,
+}
+
+async fn device_token(
+    State(s): State<Arc<AppStateInner>>,
+    Form(body): Form<TokenRequest>,
+) -> Response {
+    if body.grant_type != GRANT_TYPE {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(TokenError {
+                error: "unsupported_grant_type",
+            }),
+        )
+            .into_response();
+    }
+    match s.devices.poll(&body.device_code).await {
+        Ok(access_token) => Json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+        })
+        .into_response(),
+        Err(error) => (StatusCode::BAD_REQUEST, Json(TokenError { error })).into_response(),
+    }
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "device.html")]
+struct DeviceTemplate {
+    /// Empty until the user entered one.
+    user_code: String,
+    /// Name of the client waiting on `user_code`, empty if none is.
+    name: String,
+    /// What the client asks for, comma-separated.
+    scopes: String,
+    /// Outcome of the last submission, if any.
+    message: String,
+    layout: Layout,
+}
+
+impl Page for DeviceTemplate {
+    const PATH: &'static str = "device.html";
+}
+
+#[derive(Deserialize)]
+struct VerificationQuery {
+    user_code: Option<String>,
+}
+
+/// Asks for the user code, then for approval of the client it belongs to.
+async fn verification_page(
+    PageSession(_): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<VerificationQuery>,
+    layout: Layout,
+    format: Format,
+) -> Response {
+    let user_code = q.user_code.as_deref().map(normalize).unwrap_or_default();
+    let (name, scopes) = if user_code.is_empty() {
+        (String::new(), Vec::new())
+    } else {
+        s.devices.pending(&user_code).await.unwrap_or_default()
+    };
+    let message = if !user_code.is_empty() && name.is_empty() {
+        EXPIRED.to_owned()
+    } else {
+        String::new()
+    };
+    templates::respond(
+        format,
+        DeviceTemplate {
+            user_code,
+            name,
+            scopes: scopes.iter().map(|scope| scope.as_str()).join(", "),
+            message,
+            layout,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct Verification {
+    user_code: String,
+    approve: bool,
+}
+
+async fn verify(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    layout: Layout,
+    format: Format,
+    Form(body): Form<Verification>,
+) -> Result<Response, AppError> {
+    let user_code = normalize(&body.user_code);
+    let Some((name, scopes)) = s.devices.pending(&user_code).await else {
+        return Ok(templates::respond(
+            format,
+            DeviceTemplate {
+                user_code,
+                name: String::new(),
+                scopes: String::new(),
+                message: EXPIRED.to_owned(),
+                layout,
+            },
+        ));
+    };
+    let settled = if body.approve {
+        // The key gets a copy of the session's token, so it needs to know when that expires.
+        let Some(id) = &session.id else {
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        };
+        let Some(data) = s.sessions.get(&id.hash()).await? else {
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        };
+        let (secret, key) = s
+            .api_keys
+            .mint(
+                data.user_id,
+                name,
+                scopes,
+                data.token,
+                data.token_expires_at,
+            )
+            .await?;
+        let settled = s.devices.settle(&user_code, Status::Approved(secret)).await;
+        if !settled {
+            // Expired or settled elsewhere in the meantime, so nobody will ever use the key.
+            s.api_keys.remove(&key.user_id, &key.id).await?;
+        }
+        settled
+    } else {
+        s.devices.settle(&user_code, Status::Denied).await
+    };
+    let message = match (settled, body.approve) {
+        (false, _) => EXPIRED,
+        (true, true) => "Device approved. You can go back to it now.",
+        (true, false) => "Device denied.",
+    };
+    Ok(templates::respond(
+        format,
+        DeviceTemplate {
+            user_code: String::new(),
+            name: String::new(),
+            scopes: String::new(),
+            message: message.to_owned(),
+            layout,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_user_codes() {
+        let code = user_code();
+        assert_eq!(code.len(), 9);
+        assert_eq!(normalize(&code), code);
+        assert_eq!(normalize(" bcdf ghjk"), "BCDF-GHJK");
+        assert_eq!(normalize("bcdfghjk"), "BCDF-GHJK");
+        assert_eq!(normalize("bcd"), "BCD");
+    }
+
+    #[tokio::test]
+    async fn hands_out_the_key_once_approved() {
+        let devices = DeviceStore::default();
+        let now = Instant::now();
+        let (device_code, user_code) = devices
+            .start_at("TV".to_owned(), vec![Scope::ReadPlayback], now)
+            .await;
+        assert_eq!(
+            devices.pending(&user_code).await,
+            Some(("TV".to_owned(), vec![Scope::ReadPlayback]))
+        );
+        assert_eq!(
+            devices.poll_at(&device_code, now).await,
+            Err("authorization_pending")
+        );
+        // Each poll that comes too soon makes the client wait longer from then on.
+        assert_eq!(
+            devices.poll_at(&device_code, now + INTERVAL / 2).await,
+            Err("slow_down")
+        );
+        let later = now + INTERVAL * 2;
+        assert_eq!(devices.poll_at(&device_code, later).await, Err("slow_down"));
+        let later = later + INTERVAL * 3;
+        assert_eq!(
+            devices.poll_at(&device_code, later).await,
+            Err("authorization_pending")
+        );
+
+        assert!(
+            devices
+                .settle(&user_code, Status::Approved("key".to_owned()))
+                .await
+        );
+        assert!(!devices.settle(&user_code, Status::Denied).await);
+        assert_eq!(devices.pending(&user_code).await, None);
+        let later = later + INTERVAL * 3;
+        assert_eq!(
+            devices.poll_at(&device_code, later).await.as_deref(),
+            Ok("key")
+        );
+        assert_eq!(
+            devices.poll_at(&device_code, later + INTERVAL * 4).await,
+            Err("invalid_grant")
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_and_expired_devices_get_no_key() {
+        let devices = DeviceStore::default();
+        let now = Instant::now();
+        let (denied, user_code) = devices.start_at("TV".to_owned(), Vec::new(), now).await;
+        assert!(devices.settle(&user_code, Status::Denied).await);
+        assert_eq!(devices.poll_at(&denied, now).await, Err("access_denied"));
+        assert_eq!(devices.len_hint(), Some(0));
+
+        let (expired, _) = devices.start_at("CLI".to_owned(), Vec::new(), now).await;
+        assert_eq!(
+            devices.poll_at(&expired, now + MAX_AGE).await,
+            Err("expired_token")
+        );
+        assert_eq!(devices.poll_at(&expired, now).await, Err("invalid_grant"));
+    }
+}
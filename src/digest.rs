@@ -0,0 +1,615 @@
+//! Email notifications: weekly listening digests, new-release alerts and milestones. Users opt in through
+//! `/api/me/notifications`, which takes their address from their Spotify profile. Once a week, a
+//! worker mails digest subscribers their top tracks and minutes listened from the collected
+//! history, along with what [`crate::releases`] found from the artists they follow.
+//!
+//! Subscribers to any kind are also told about logins from unusual places, see
+//! [`crate::logins`].
+//!
+//! Every email links to `/unsubscribe/:token`, which works without logging in so it can be
+//! followed from any mail client, and turns off every kind.
+
+use askama_axum::Template;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::Serialize;
+use std::{collections::HashMap, fmt::Write, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    geoip::Location,
+    mail::Email,
+    releases::NewRelease,
+    spotify,
+    stats::Milestone,
+    templates::{self, Format, Layout, Page},
+    token, AppError, AppStateInner,
+};
+
+/// How often the worker looks for digests that are due.
+const CHECK_INTERVAL: Duration = Duration::from_hours(1);
+const PERIOD: chrono::Duration = chrono::Duration::weeks(1);
+const TOP_TRACKS: usize = 5;
+
+/// A user's notification settings.
+#[derive(Serialize, Clone, Debug)]
+pub struct Preferences {
+    pub email: String,
+    pub weekly_digest: bool,
+    /// Whether to email new releases of followed artists as they're found.
+    pub new_releases: bool,
+    /// Whether to email milestones as the collected history reaches them.
+    pub milestones: bool,
+    #[serde(skip)]
+    last_sent: DateTime<Utc>,
+    #[serde(skip)]
+    unsubscribe_token: String,
+}
+
+/// In memory by default, or in the database with a SQL feature and `DATABASE_URL` set.
+pub enum NotificationStore {
+    Memory(RwLock<HashMap<String, Preferences>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for NotificationStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+/// Columns of `notifications` after `user_id`.
+#[cfg(feature = "sql")]
+const COLUMNS: &str =
+    "email, weekly_digest, new_releases, milestones, last_sent, unsubscribe_token";
+
+/// [`COLUMNS`] as selected into a [`Row`]. The booleans are selected as 1 or 0, since the `Any`
+/// driver can't read them from `SQLite`.
+#[cfg(feature = "sql")]
+const SELECTED: &str = "email, CASE WHEN weekly_digest THEN 1 ELSE 0 END, \
+     CASE WHEN new_releases THEN 1 ELSE 0 END, CASE WHEN milestones THEN 1 ELSE 0 END, \
+     last_sent, unsubscribe_token";
+
+#[cfg(feature = "sql")]
+type Row = (String, i64, i64, i64, i64, String);
+
+#[cfg(feature = "sql")]
+fn from_row(
+    (email, weekly_digest, new_releases, milestones, last_sent, unsubscribe_token): Row,
+) -> Preferences {
+    Preferences {
+        email,
+        weekly_digest: weekly_digest != 0,
+        new_releases: new_releases != 0,
+        milestones: milestones != 0,
+        last_sent: DateTime::from_timestamp(last_sent, 0).unwrap_or_default(),
+        unsubscribe_token,
+    }
+}
+
+impl NotificationStore {
+    /// Subscribes a user to what's `true`, at `email`, and unsubscribes them from the rest. The
+    /// first digest is sent a week after subscribing, so it has a week of history to cover.
+    pub async fn subscribe(
+        &self,
+        user_id: &str,
+        email: String,
+        weekly_digest: bool,
+        new_releases: bool,
+        milestones: bool,
+    ) -> anyhow::Result<Preferences> {
+        let existing = self.get(user_id).await?;
+        let mut prefs = existing.unwrap_or_else(|| Preferences {
+            email: email.clone(),
+            weekly_digest: false,
+            new_releases: false,
+            milestones: false,
+            last_sent: Utc::now(),
+            unsubscribe_token: token::generate(token::STATE_BYTES),
+        });
+        if weekly_digest && !prefs.weekly_digest {
+            prefs.last_sent = Utc::now();
+        }
+        prefs.email = email;
+        prefs.weekly_digest = weekly_digest;
+        prefs.new_releases = new_releases;
+        prefs.milestones = milestones;
+        match self {
+            Self::Memory(users) => {
+                users
+                    .write()
+                    .await
+                    .insert(user_id.to_owned(), prefs.clone());
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query(&format!(
+                    "INSERT INTO notifications (user_id, {COLUMNS}) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7) \
+                     ON CONFLICT (user_id) DO UPDATE SET email = $2, weekly_digest = $3, \
+                     new_releases = $4, milestones = $5, last_sent = $6"
+                ))
+                .bind(user_id)
+                .bind(&prefs.email)
+                .bind(prefs.weekly_digest)
+                .bind(prefs.new_releases)
+                .bind(prefs.milestones)
+                .bind(prefs.last_sent.timestamp())
+                .bind(&prefs.unsubscribe_token)
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(prefs)
+    }
+
+    /// Unsubscribes a user from everything, returning their settings if they have any.
+    pub async fn unsubscribe(&self, user_id: &str) -> anyhow::Result<Option<Preferences>> {
+        match self {
+            Self::Memory(users) => {
+                let mut users = users.write().await;
+                let Some(prefs) = users.get_mut(user_id) else {
+                    return Ok(None);
+                };
+                prefs.weekly_digest = false;
+                prefs.new_releases = false;
+                prefs.milestones = false;
+                Ok(Some(prefs.clone()))
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query(
+                    "UPDATE notifications SET weekly_digest = $1, new_releases = $1, \
+                     milestones = $1 WHERE user_id = $2",
+                )
+                .bind(false)
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+                self.get(user_id).await
+            }
+        }
+    }
+
+    /// Unsubscribes whoever `token` was given to, returning whether it was anyone.
+    async fn unsubscribe_by_token(&self, unsubscribe_token: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(users) => {
+                let mut users = users.write().await;
+                let prefs = users.values_mut().find(|prefs| {
+                    token::constant_time_eq(
+                        prefs.unsubscribe_token.as_bytes(),
+                        unsubscribe_token.as_bytes(),
+                    )
+                });
+                let Some(prefs) = prefs else {
+                    return Ok(false);
+                };
+                prefs.weekly_digest = false;
+                prefs.new_releases = false;
+                prefs.milestones = false;
+                Ok(true)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let updated = sqlx::query(
+                    "UPDATE notifications SET weekly_digest = $1, new_releases = $1, \
+                     milestones = $1 WHERE unsubscribe_token = $2",
+                )
+                .bind(false)
+                .bind(unsubscribe_token)
+                .execute(pool)
+                .await?;
+                Ok(updated.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub async fn get(&self, user_id: &str) -> anyhow::Result<Option<Preferences>> {
+        match self {
+            Self::Memory(users) => Ok(users.read().await.get(user_id).cloned()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let row: Option<Row> = sqlx::query_as(&format!(
+                    "SELECT {SELECTED} FROM notifications WHERE user_id = $1"
+                ))
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?;
+                Ok(row.map(from_row))
+            }
+        }
+    }
+
+    /// Forgets a user's settings and address, returning whether there were any.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(users) => Ok(users.write().await.remove(user_id).is_some()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM notifications WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(users) => users.try_read().ok().map(|u| u.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+
+    /// Users whose digest is due, with their settings.
+    async fn due(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<(String, Preferences)>> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .read()
+                .await
+                .iter()
+                .filter(|(_, prefs)| prefs.weekly_digest && now - prefs.last_sent >= PERIOD)
+                .map(|(user_id, prefs)| (user_id.clone(), prefs.clone()))
+                .collect()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, String, i64, i64, i64, i64, String)> =
+                    sqlx::query_as(&format!(
+                        "SELECT user_id, {SELECTED} FROM notifications \
+                         WHERE weekly_digest = $1 AND last_sent <= $2"
+                    ))
+                    .bind(true)
+                    .bind((now - PERIOD).timestamp())
+                    .fetch_all(pool)
+                    .await?;
+                Ok(rows
+                    .into_iter()
+                    .map(
+                        |(user_id, email, weekly, releases, milestones, last_sent, token)| {
+                            let row = (email, weekly, releases, milestones, last_sent, token);
+                            (user_id, from_row(row))
+                        },
+                    )
+                    .collect())
+            }
+        }
+    }
+
+    async fn mark_sent(&self, user_id: &str, at: DateTime<Utc>) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(users) => {
+                if let Some(prefs) = users.write().await.get_mut(user_id) {
+                    prefs.last_sent = at;
+                }
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query("UPDATE notifications SET last_sent = $1 WHERE user_id = $2")
+                    .bind(at.timestamp())
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn spawn_worker(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !state.leader.holds() {
+                continue;
+            }
+            let now = Utc::now();
+            let due = match state.notifications.due(now).await {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::error!("Failed to list due digests: {e:#}");
+                    continue;
+                }
+            };
+            for (user_id, prefs) in due {
+                if let Err(e) = send(&state, &user_id, &prefs, now).await {
+                    tracing::warn!("Failed to send weekly digest: {e:#}");
+                    continue;
+                }
+                if let Err(e) = state.notifications.mark_sent(&user_id, now).await {
+                    tracing::error!("Failed to record sent digest: {e:#}");
+                }
+            }
+        }
+    });
+}
+
+async fn send(
+    state: &AppStateInner,
+    user_id: &str,
+    prefs: &Preferences,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let since = prefs.last_sent;
+    let plays: Vec<_> = state
+        .history
+        .plays(user_id)
+        .await?
+        .into_iter()
+        .filter(|(played_at, _)| *played_at >= since)
+        .collect();
+    let minutes = plays
+        .iter()
+        .map(|(_, track)| track.duration_ms)
+        .sum::<u64>()
+        / 60_000;
+    let mut counts: HashMap<&str, (usize, &spotify::Track)> = HashMap::new();
+    for (_, track) in &plays {
+        counts.entry(&track.id).or_insert((0, track)).0 += 1;
+    }
+    let top = counts
+        .into_values()
+        .sorted_by(|(a, _), (b, _)| b.cmp(a))
+        .take(TOP_TRACKS);
+
+    let unsubscribe = unsubscribe_url(state, prefs);
+    let mut body = format!("You listened to {minutes} minutes of music this week.\n");
+    if plays.is_empty() {
+        body.push_str("\nNothing was collected this week. Log in again to resume collection.\n");
+    } else {
+        body.push_str("\nYour top tracks:\n");
+        for (count, track) in top {
+            writeln!(
+                body,
+                "- {} – {} ({count} plays)",
+                track.name,
+                track.artists.iter().map(|a| &a.name).join(", ")
+            )?;
+        }
+    }
+
+    let releases = state.releases.new_since(user_id, since).await?;
+    if !releases.is_empty() {
+        body.push_str("\nNew from artists you follow:\n");
+        list_releases(&mut body, &releases)?;
+    }
+    write!(body, "\nUnsubscribe: {unsubscribe}\n")?;
+
+    state
+        .mailer
+        .send(Email {
+            to: prefs.email.clone(),
+            subject: format!("Your week in music, {}", now.format("%B %-d")),
+            body,
+            unsubscribe: Some(unsubscribe),
+        })
+        .await
+}
+
+/// Emails new releases to the user if they asked for it.
+pub async fn send_release_alert(
+    state: &AppStateInner,
+    user_id: &str,
+    releases: &[NewRelease],
+) -> anyhow::Result<()> {
+    let Some(prefs) = state.notifications.get(user_id).await? else {
+        return Ok(());
+    };
+    if !prefs.new_releases {
+        return Ok(());
+    }
+    let unsubscribe = unsubscribe_url(state, &prefs);
+    let mut body = String::from("New from artists you follow:\n");
+    list_releases(&mut body, releases)?;
+    write!(body, "\nUnsubscribe: {unsubscribe}\n")?;
+    let subject = match releases {
+        [release] => format!("New from {}: {}", release.artist, release.album.name),
+        _ => format!("{} new releases from artists you follow", releases.len()),
+    };
+    state
+        .mailer
+        .send(Email {
+            to: prefs.email,
+            subject,
+            body,
+            unsubscribe: Some(unsubscribe),
+        })
+        .await
+}
+
+/// Emails the user about a login from an unusual place, if they get any notifications at all.
+pub async fn send_login_alert(
+    state: &AppStateInner,
+    user_id: &str,
+    location: &Location,
+) -> anyhow::Result<()> {
+    let Some(prefs) = state.notifications.get(user_id).await? else {
+        return Ok(());
+    };
+    if !prefs.weekly_digest && !prefs.new_releases && !prefs.milestones {
+        return Ok(());
+    }
+    let unsubscribe = unsubscribe_url(state, &prefs);
+    let body = format!(
+        "Someone just logged in to your account from {}, on the network of {}, unlike where \
+         you usually log in from.\n\nIf it was you, there's nothing to do. Otherwise, log out \
+         everywhere from your Spotify account settings.\n\nUnsubscribe: {unsubscribe}\n",
+        location.country.as_deref().unwrap_or("an unknown country"),
+        location.network
+    );
+    state
+        .mailer
+        .send(Email {
+            to: prefs.email,
+            subject: "Login from a new place".to_owned(),
+            body,
+            unsubscribe: Some(unsubscribe),
+        })
+        .await
+}
+
+/// Emails milestones to the user if they asked for it.
+pub async fn send_milestone_alert(
+    state: &AppStateInner,
+    user_id: &str,
+    milestones: &[Milestone],
+) -> anyhow::Result<()> {
+    let Some(prefs) = state.notifications.get(user_id).await? else {
+        return Ok(());
+    };
+    if !prefs.milestones {
+        return Ok(());
+    }
+    let unsubscribe = unsubscribe_url(state, &prefs);
+    let mut body = String::from("You just reached:\n");
+    for milestone in milestones {
+        writeln!(body, "- {milestone}")?;
+    }
+    write!(body, "\nUnsubscribe: {unsubscribe}\n")?;
+    let subject = match milestones {
+        [milestone] => format!("Milestone: {milestone}"),
+        _ => format!("{} listening milestones", milestones.len()),
+    };
+    state
+        .mailer
+        .send(Email {
+            to: prefs.email,
+            subject,
+            body,
+            unsubscribe: Some(unsubscribe),
+        })
+        .await
+}
+
+fn list_releases(body: &mut String, releases: &[NewRelease]) -> std::fmt::Result {
+    for release in releases {
+        writeln!(
+            body,
+            "- {} – {} https://open.spotify.com/album/{}",
+            release.artist, release.album.name, release.album.id
+        )?;
+    }
+    Ok(())
+}
+
+fn unsubscribe_url(state: &AppStateInner, prefs: &Preferences) -> String {
+    format!(
+        "{}/unsubscribe/{}",
+        state.public_url, prefs.unsubscribe_token
+    )
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new().route("/unsubscribe/:token", get(confirm).post(unsubscribe))
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "unsubscribe.html")]
+struct UnsubscribeTemplate {
+    token: String,
+    done: bool,
+    layout: Layout,
+}
+
+impl Page for UnsubscribeTemplate {
+    const PATH: &'static str = "unsubscribe.html";
+}
+
+/// Asks before unsubscribing, since mail scanners follow links.
+async fn confirm(Path(token): Path<String>, layout: Layout, format: Format) -> Response {
+    templates::respond(
+        format,
+        UnsubscribeTemplate {
+            token,
+            done: false,
+            layout,
+        },
+    )
+}
+
+/// Also the one-click `List-Unsubscribe-Post` target.
+async fn unsubscribe(
+    State(s): State<Arc<AppStateInner>>,
+    Path(token): Path<String>,
+    layout: Layout,
+    format: Format,
+) -> Response {
+    match s.notifications.unsubscribe_by_token(&token).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (StatusCode::NOT_FOUND, "This link is no longer valid").into_response();
+        }
+        Err(e) => return AppError(e).into_response(),
+    }
+    templates::respond(
+        format,
+        UnsubscribeTemplate {
+            token: String::new(),
+            done: true,
+            layout,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn keeps_subscriptions(store: &NotificationStore) {
+        assert!(store.get("ann").await.unwrap().is_none());
+        let prefs = store
+            .subscribe("ann", "ann@example.com".to_owned(), true, false, true)
+            .await
+            .unwrap();
+        let got = store.get("ann").await.unwrap().unwrap();
+        assert!(got.weekly_digest && !got.new_releases && got.milestones);
+        assert_eq!(got.unsubscribe_token, prefs.unsubscribe_token);
+
+        let week_later = prefs.last_sent + PERIOD;
+        let due = store.due(week_later).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, "ann");
+        assert!(store.due(prefs.last_sent).await.unwrap().is_empty());
+
+        assert!(store
+            .unsubscribe_by_token(&prefs.unsubscribe_token)
+            .await
+            .unwrap());
+        let got = store.get("ann").await.unwrap().unwrap();
+        assert!(!got.weekly_digest && !got.new_releases && !got.milestones);
+        assert!(store.due(week_later).await.unwrap().is_empty());
+        assert!(store.remove("ann").await.unwrap());
+        assert!(!store.remove("ann").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn keeps_subscriptions_in_memory() {
+        keeps_subscriptions(&NotificationStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn keeps_subscriptions_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-notifications-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        keeps_subscriptions(&NotificationStore::Sql(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,40 @@
+//! Discovery features that aggregate several Spotify calls into something the API doesn't offer
+//! directly.
+
+use futures::TryStreamExt;
+use itertools::Itertools;
+use std::collections::HashSet;
+
+use crate::spotify::{self, Album, Track};
+
+/// The least popular tracks of an artist's whole catalog that aren't in `known` (track IDs the
+/// user already saved or played). Songs released several times (e.g. as a single and on an
+/// album) are only listed once.
+pub async fn deep_cuts(
+    access_token: &str,
+    artist_id: &str,
+    known: &HashSet<String>,
+    limit: usize,
+) -> anyhow::Result<Vec<Track>> {
+    let albums: Vec<Album> = spotify::artist_albums(access_token, artist_id)
+        .try_collect()
+        .await?;
+    let album_ids: Vec<String> = albums.into_iter().map(|a| a.id).collect();
+
+    // Album listings don't include popularity, which needs the full track objects.
+    let track_ids: Vec<String> = spotify::album_tracks(access_token, &album_ids)
+        .await?
+        .into_iter()
+        .filter(|t| t.artists.iter().any(|a| a.id == artist_id))
+        .filter(|t| !known.contains(&t.id))
+        .map(|t| t.id)
+        .collect();
+    let mut tracks = spotify::tracks(access_token, &track_ids).await?;
+
+    tracks.sort_by_key(|t| t.popularity.unwrap_or(0));
+    Ok(tracks
+        .into_iter()
+        .unique_by(|t| t.name.to_lowercase())
+        .take(limit)
+        .collect())
+}
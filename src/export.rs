@@ -0,0 +1,136 @@
+//! Everything stored about a user, bundled as a ZIP of JSON files for them to download. The
+//! archive is compressed in memory on a blocking thread, then sent as the response body.
+
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Write},
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    activity::Activity, api_keys::ApiKey, consent::Consent, digest::Preferences, friends::Sharing,
+    geoip::Location, releases::NewRelease, retention::Retention, rules::Rule,
+    session_store::SessionData, share::SharedPlaylist, spotify::Track, webhooks::Webhook,
+    AppStateInner,
+};
+
+#[derive(Serialize)]
+struct Account<'a> {
+    user_id: &'a str,
+    exported_at: DateTime<Utc>,
+    /// Slug of the public feed, when enabled.
+    feed: Option<String>,
+    /// Slug of the widget, when enabled.
+    #[cfg(feature = "player")]
+    widget: Option<String>,
+    /// Whether volume normalization is enabled.
+    normalization: bool,
+    notifications: Option<Preferences>,
+}
+
+/// A session without its credentials.
+#[derive(Serialize)]
+struct SessionSummary {
+    created_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+impl From<SessionData> for SessionSummary {
+    fn from(data: SessionData) -> Self {
+        Self {
+            created_at: data.created_at,
+            last_seen: data.last_seen,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Play {
+    played_at: DateTime<Utc>,
+    track: Track,
+}
+
+#[derive(Serialize)]
+struct History {
+    collecting_since: Option<DateTime<Utc>>,
+    plays: Vec<Play>,
+}
+
+/// Collects the user's data and writes the archive.
+pub async fn bundle(state: &AppStateInner, user_id: &str) -> anyhow::Result<Body> {
+    let sessions: Vec<SessionSummary> = state
+        .sessions
+        .all()
+        .await?
+        .into_iter()
+        .filter(|session| session.user_id == user_id)
+        .map(SessionSummary::from)
+        .collect();
+    let history = History {
+        collecting_since: state.history.collecting_since(user_id).await?,
+        plays: state
+            .history
+            .plays(user_id)
+            .await?
+            .into_iter()
+            .map(|(played_at, track)| Play { played_at, track })
+            .collect(),
+    };
+    let rules: Vec<Rule> = state.rules.owned_by(user_id).await?;
+    let webhooks: Vec<Webhook> = state.webhooks.owned_by(user_id).await?;
+    let shares: Vec<SharedPlaylist> = state.shares.owned_by(user_id).await?;
+    let api_keys: Vec<ApiKey> = state.api_keys.owned_by(user_id).await?;
+    let releases: Vec<NewRelease> = state.releases.new_releases(user_id).await?;
+    let activity: HashMap<String, Vec<Activity>> = state.activity.owned_by(user_id).await?;
+    let consent: Option<Consent> = state.consent.get(user_id).await?;
+    let links: Vec<String> = state.links.linked(user_id).await?;
+    let sharing: Sharing = state.friends.get(user_id).await?;
+    let retention: Retention = state.retention.get(user_id).await?;
+    let logins: Vec<Location> = state.logins.recent(user_id).await?;
+    let files = vec![
+        (
+            "account.json",
+            serde_json::to_vec_pretty(&Account {
+                user_id,
+                exported_at: Utc::now(),
+                feed: state.feeds.slug(user_id).await?,
+                #[cfg(feature = "player")]
+                widget: state.widgets.slug(user_id).await?,
+                normalization: state.normalization.enabled(user_id).await?,
+                notifications: state.notifications.get(user_id).await?,
+            })?,
+        ),
+        ("sessions.json", serde_json::to_vec_pretty(&sessions)?),
+        ("history.json", serde_json::to_vec_pretty(&history)?),
+        ("rules.json", serde_json::to_vec_pretty(&rules)?),
+        ("webhooks.json", serde_json::to_vec_pretty(&webhooks)?),
+        ("shares.json", serde_json::to_vec_pretty(&shares)?),
+        ("api_keys.json", serde_json::to_vec_pretty(&api_keys)?),
+        ("new_releases.json", serde_json::to_vec_pretty(&releases)?),
+        (
+            "playlist_activity.json",
+            serde_json::to_vec_pretty(&activity)?,
+        ),
+        ("consent.json", serde_json::to_vec_pretty(&consent)?),
+        ("links.json", serde_json::to_vec_pretty(&links)?),
+        ("sharing.json", serde_json::to_vec_pretty(&sharing)?),
+        ("retention.json", serde_json::to_vec_pretty(&retention)?),
+        ("logins.json", serde_json::to_vec_pretty(&logins)?),
+    ];
+
+    let zip = tokio::task::spawn_blocking(move || write_zip(files)).await??;
+    Ok(Body::from(zip))
+}
+
+fn write_zip(files: Vec<(&str, Vec<u8>)>) -> io::Result<Vec<u8>> {
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&contents)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
@@ -0,0 +1,356 @@
+//! Public feeds of a user's recent listens, as JSON Feed and RSS, for embedding in personal sites.
+//! Feeds are opt-in: enabling one mints an unguessable slug for its URLs, and disabling it makes
+//! them stop working.
+//!
+//! Anyone can fetch a feed, so its items are cached for a few minutes and each feed only answers
+//! a limited number of requests per minute. Slugs are kept like other settings, see
+//! [`crate::slugs`], but rate windows are only counted by each instance.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::Serialize;
+use std::{collections::HashMap, fmt::Write, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{cache::Cache, slugs::SlugStore, spotify::Track, AppStateInner};
+
+/// Listens in a feed, most recent first.
+const ITEMS: usize = 30;
+const CACHE_TTL: Duration = Duration::from_mins(5);
+const RATE_WINDOW: Duration = Duration::from_mins(1);
+/// Requests a feed answers per [`RATE_WINDOW`].
+const RATE_LIMIT: u32 = 60;
+
+type Listens = Arc<Vec<(DateTime<Utc>, Track)>>;
+
+pub struct FeedStore {
+    slugs: SlugStore,
+    /// Listens of each feed, by slug.
+    cache: Arc<dyn Cache>,
+    /// Start of the current rate window of each feed, and requests made in it.
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl FeedStore {
+    pub fn new(cache: Arc<dyn Cache>) -> Self {
+        Self {
+            slugs: SlugStore::default(),
+            cache,
+            windows: Mutex::default(),
+        }
+    }
+
+    /// Keeps slugs in the database instead of in memory.
+    #[cfg(feature = "sql")]
+    pub fn in_database(self, pool: db::Pool) -> Self {
+        Self {
+            slugs: SlugStore::in_database(pool, "feeds"),
+            ..self
+        }
+    }
+
+    /// Enables the feed of a user, returning its slug. Enabling it again keeps the same slug.
+    pub async fn enable(&self, user_id: &str) -> anyhow::Result<String> {
+        self.slugs.enable(user_id).await
+    }
+
+    /// Disables the feed of a user, returning whether it was enabled.
+    pub async fn disable(&self, user_id: &str) -> anyhow::Result<bool> {
+        let Some(slug) = self.slugs.disable(user_id).await? else {
+            return Ok(false);
+        };
+        if let Err(e) = self.cache.invalidate(&cache_key(&slug)).await {
+            tracing::warn!("Failed to drop cached feed: {e:#}");
+        }
+        self.windows.lock().await.remove(&slug);
+        Ok(true)
+    }
+
+    pub async fn slug(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        self.slugs.slug(user_id).await
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.slugs.len_hint()
+    }
+
+    /// Counts a request to the feed, returning whether it's within the rate limit.
+    async fn allow(&self, slug: &str) -> bool {
+        self.allow_at(slug, Instant::now()).await
+    }
+
+    async fn allow_at(&self, slug: &str, now: Instant) -> bool {
+        let mut windows = self.windows.lock().await;
+        let (start, count) = windows.entry(slug.to_owned()).or_insert((now, 0));
+        if now.duration_since(*start) >= RATE_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= RATE_LIMIT
+    }
+
+    /// The listens of the feed of `user_id`, or `None` if they can't be loaded.
+    async fn listens(&self, state: &AppStateInner, slug: &str, user_id: &str) -> Option<Listens> {
+        // The feed is served from history when the cache fails.
+        match self.cache.get_json(&cache_key(slug)).await {
+            Ok(Some(listens)) => return Some(Arc::new(listens)),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to read cached feed: {e:#}"),
+        }
+        let mut plays = match state.history.plays(user_id).await {
+            Ok(plays) => plays,
+            Err(e) => {
+                tracing::error!("Failed to load history for a feed: {e:#}");
+                return None;
+            }
+        };
+        plays.reverse();
+        plays.truncate(ITEMS);
+        if let Err(e) = self
+            .cache
+            .set_json(&cache_key(slug), &plays, Some(CACHE_TTL))
+            .await
+        {
+            tracing::warn!("Failed to cache feed: {e:#}");
+        }
+        Some(Arc::new(plays))
+    }
+}
+
+fn cache_key(slug: &str) -> String {
+    format!("feed:{slug}")
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/u/:slug/feed.json", get(json_feed))
+        .route("/u/:slug/feed.xml", get(rss_feed))
+}
+
+/// The listens of a feed, or the response to send instead.
+async fn load(state: &AppStateInner, slug: &str) -> Result<Listens, Response> {
+    // Checked first, so made-up slugs don't each get a rate window.
+    let user_id = match state.feeds.slugs.owner(slug).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return Err(StatusCode::NOT_FOUND.into_response()),
+        Err(e) => {
+            tracing::error!("Failed to look a feed up: {e:#}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    if !state.feeds.allow(slug).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, RATE_WINDOW.as_secs().to_string())],
+        )
+            .into_response());
+    }
+    state
+        .feeds
+        .listens(state, slug, &user_id)
+        .await
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())
+}
+
+fn title(track: &Track) -> String {
+    format!(
+        "{} – {}",
+        track.name,
+        track.artists.iter().map(|a| &a.name).join(", ")
+    )
+}
+
+fn track_url(track: &Track) -> String {
+    format!("https://open.spotify.com/track/{}", track.id)
+}
+
+const CACHE_CONTROL: &str = "public, max-age=300";
+
+/// <https://www.jsonfeed.org/version/1.1/>
+#[derive(Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: &'static str,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    title: String,
+    content_text: String,
+    date_published: DateTime<Utc>,
+}
+
+async fn json_feed(State(s): State<Arc<AppStateInner>>, Path(slug): Path<String>) -> Response {
+    let listens = match load(&s, &slug).await {
+        Ok(listens) => listens,
+        Err(response) => return response,
+    };
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: "Recent listens",
+        feed_url: format!("{}/u/{slug}/feed.json", s.public_url),
+        items: listens
+            .iter()
+            .map(|(played_at, track)| JsonFeedItem {
+                id: format!("{}/{}", played_at.timestamp(), track.id),
+                url: track_url(track),
+                title: title(track),
+                content_text: format!("Listened to {}", title(track)),
+                date_published: *played_at,
+            })
+            .collect(),
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "application/feed+json"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        Json(feed),
+    )
+        .into_response()
+}
+
+async fn rss_feed(State(s): State<Arc<AppStateInner>>, Path(slug): Path<String>) -> Response {
+    let listens = match load(&s, &slug).await {
+        Ok(listens) => listens,
+        Err(response) => return response,
+    };
+    let link = escape(&format!("{}/u/{slug}/feed.xml", s.public_url));
+    let mut rss = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <rss version=\"2.0\"><channel>\
+         <title>Recent listens</title><link>{link}</link>\
+         <description>Tracks recently listened to on Spotify</description>"
+    );
+    for (played_at, track) in listens.iter() {
+        let _ = write!(
+            rss,
+            "<item><title>{}</title><link>{}</link>\
+             <guid isPermaLink=\"false\">{}/{}</guid><pubDate>{}</pubDate></item>",
+            escape(&title(track)),
+            escape(&track_url(track)),
+            played_at.timestamp(),
+            escape(&track.id),
+            played_at.to_rfc2822(),
+        );
+    }
+    rss.push_str("</channel></rss>");
+    (
+        [
+            (header::CONTENT_TYPE, "application/rss+xml; charset=utf-8"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        rss,
+    )
+        .into_response()
+}
+
+/// Escapes text for XML element content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::MemoryCache, spotify::PlayHistory};
+
+    fn play(id: &str, name: &str, played_at: DateTime<Utc>) -> PlayHistory {
+        PlayHistory {
+            track: serde_json::from_value(serde_json::json!({
+                "id": id,
+                "name": name,
+                "uri": format!("spotify:track:{id}"),
+                "duration_ms": 1000,
+                "artists": [{ "id": "a", "name": "Artist" }],
+            }))
+            .unwrap(),
+            played_at: played_at.to_rfc3339(),
+        }
+    }
+
+    async fn body(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn limits_requests_per_feed() {
+        let feeds = FeedStore::new(Arc::new(MemoryCache::default()));
+        let now = Instant::now();
+        for _ in 0..RATE_LIMIT {
+            assert!(feeds.allow_at("a", now).await);
+        }
+        assert!(!feeds.allow_at("a", now + RATE_WINDOW / 2).await);
+        assert!(feeds.allow_at("b", now).await);
+        assert!(feeds.allow_at("a", now + RATE_WINDOW).await);
+    }
+
+    #[tokio::test]
+    async fn serves_cached_listens_until_disabled() {
+        let state = AppStateInner::for_tests().await;
+        let now = Utc::now();
+        let plays = vec![
+            play("t1", "First", now - chrono::Duration::minutes(2)),
+            play("t2", "<Second> & more", now - chrono::Duration::minutes(1)),
+        ];
+        state.history.record("ann", plays).await.unwrap();
+        let slug = state.feeds.enable("ann").await.unwrap();
+
+        let response = json_feed(State(state.clone()), Path(slug.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let feed: serde_json::Value = serde_json::from_str(&body(response).await).unwrap();
+        let titles: Vec<_> = feed["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["title"].as_str().unwrap())
+            .collect();
+        assert_eq!(titles, ["<Second> & more – Artist", "First – Artist"]);
+
+        // Listens since are only served once the cached ones expire.
+        state
+            .history
+            .record("ann", vec![play("t3", "Third", now)])
+            .await
+            .unwrap();
+        let rss = body(rss_feed(State(state.clone()), Path(slug.clone())).await).await;
+        assert!(!rss.contains("Third"));
+        assert!(rss.contains("<title>&lt;Second&gt; &amp; more – Artist</title>"));
+
+        assert!(state.feeds.disable("ann").await.unwrap());
+        assert!(!state.feeds.disable("ann").await.unwrap());
+        let response = json_feed(State(state.clone()), Path(slug)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let slug = state.feeds.enable("ann").await.unwrap();
+        let rss = body(rss_feed(State(state), Path(slug)).await).await;
+        assert!(rss.contains("Third"));
+    }
+}
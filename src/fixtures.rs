@@ -0,0 +1,237 @@
+//! Recorded Spotify responses, for deterministic runs of the aggregation endpoints against real
+//! data. `--record-spotify DIR` passes requests on to Spotify and writes each response to a
+//! fixture in `DIR`; `--replay-spotify DIR` answers requests from those fixtures without reaching
+//! Spotify, with a `404` for requests that weren't recorded.
+//!
+//! A fixture is named after the endpoint and a hash of the method and URL, so the same request
+//! always finds the same fixture, and recording it again replaces it. Request bodies aren't part
+//! of it, which is fine for Spotify's reads. Fixtures are JSON meant to be committed: headers
+//! aren't kept except those the client looks at, and tokens and emails in responses are scrubbed.
+//! The tests replay those in `fixtures/`.
+
+use anyhow::Context;
+use axum::http::{header, StatusCode};
+use base64::prelude::*;
+use once_cell::sync::OnceCell;
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::token;
+
+/// What to do with fixtures, from the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fixtures {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// Fields of response bodies whose values are replaced in fixtures.
+const SCRUBBED: &[&str] = &["access_token", "refresh_token", "email"];
+const SCRUBBED_VALUE: &str = "scrubbed";
+
+static FIXTURES: OnceCell<Fixtures> = OnceCell::new();
+
+pub fn enable(fixtures: Fixtures) -> anyhow::Result<()> {
+    match &fixtures {
+        Fixtures::Record(dir) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            tracing::warn!("Recording Spotify responses to {}", dir.display());
+        }
+        Fixtures::Replay(dir) => {
+            tracing::warn!(
+                "Replaying Spotify responses from {}, nothing is sent to it",
+                dir.display()
+            );
+        }
+    }
+    let _ = FIXTURES.set(fixtures);
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    method: String,
+    url: String,
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// For rate limited responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after: Option<String>,
+    /// JSON bodies are kept as they are, to be readable in diffs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    json: Option<Value>,
+    /// Other bodies, like images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_base64: Option<String>,
+}
+
+impl Fixture {
+    fn path(dir: &Path, endpoint: &str, method: &str, url: &str) -> PathBuf {
+        let hash = token::hex(&Sha256::digest(format!("{method} {url}")));
+        dir.join(format!("{endpoint}-{}.json", &hash[..16]))
+    }
+
+    async fn from_response(
+        method: String,
+        url: String,
+        response: Response,
+    ) -> anyhow::Result<(Self, Response)> {
+        let status = response.status();
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let content_type = header(header::CONTENT_TYPE);
+        let retry_after = header(header::RETRY_AFTER);
+        let body = response.bytes().await?;
+        let mut fixture = Self {
+            method,
+            url,
+            status: status.as_u16(),
+            content_type,
+            retry_after,
+            json: None,
+            body_base64: None,
+        };
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(mut json) => {
+                scrub(&mut json);
+                fixture.json = Some(json);
+            }
+            Err(_) if body.is_empty() => {}
+            Err(_) => fixture.body_base64 = Some(BASE64_STANDARD.encode(&body)),
+        }
+        // What was received is passed on, not what was scrubbed.
+        let passed_on = fixture.response(body.to_vec())?;
+        Ok((fixture, passed_on))
+    }
+
+    fn body(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(match (&self.json, &self.body_base64) {
+            (Some(json), _) => serde_json::to_vec(json)?,
+            (None, Some(body)) => BASE64_STANDARD.decode(body)?,
+            (None, None) => Vec::new(),
+        })
+    }
+
+    fn response(&self, body: Vec<u8>) -> anyhow::Result<Response> {
+        let mut response = axum::http::Response::builder().status(self.status);
+        if let Some(content_type) = &self.content_type {
+            response = response.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(retry_after) = &self.retry_after {
+            response = response.header(header::RETRY_AFTER, retry_after);
+        }
+        Ok(response.body(body)?.into())
+    }
+}
+
+/// Replaces the values of [`SCRUBBED`] fields anywhere in `json`.
+fn scrub(json: &mut Value) {
+    match json {
+        Value::Object(fields) => {
+            for (name, value) in fields {
+                if SCRUBBED.contains(&name.as_str()) && !value.is_null() {
+                    *value = Value::from(SCRUBBED_VALUE);
+                } else {
+                    scrub(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(scrub),
+        _ => {}
+    }
+}
+
+/// Sends `request` to Spotify, recording the response or replaying it instead with fixtures
+/// enabled.
+pub async fn send(endpoint: &'static str, request: RequestBuilder) -> reqwest::Result<Response> {
+    let Some(fixtures) = FIXTURES.get() else {
+        return request.send().await;
+    };
+    let (client, request) = request.build_split();
+    let request = request?;
+    let method = request.method().to_string();
+    let url = request.url().to_string();
+    match fixtures {
+        Fixtures::Record(dir) => {
+            let response = client.execute(request).await?;
+            let (fixture, response) = match Fixture::from_response(method, url, response).await {
+                Ok(recorded) => recorded,
+                Err(e) => {
+                    tracing::error!("Failed to record {endpoint}: {e:#}");
+                    return Ok(missing(&format!("{e:#}")));
+                }
+            };
+            let path = Fixture::path(dir, endpoint, &fixture.method, &fixture.url);
+            let written = async {
+                let file = serde_json::to_vec_pretty(&fixture)?;
+                tokio::fs::write(&path, file).await?;
+                anyhow::Ok(())
+            };
+            if let Err(e) = written.await {
+                tracing::error!("Failed to write fixture {}: {e:#}", path.display());
+            }
+            Ok(response)
+        }
+        Fixtures::Replay(dir) => {
+            let path = Fixture::path(dir, endpoint, &method, &url);
+            let replayed = async {
+                let file = tokio::fs::read(&path).await.with_context(|| {
+                    format!("no fixture for {method} {url} at {}", path.display())
+                })?;
+                let fixture: Fixture = serde_json::from_slice(&file)
+                    .with_context(|| format!("invalid fixture {}", path.display()))?;
+                fixture.response(fixture.body()?)
+            };
+            Ok(replayed.await.unwrap_or_else(|e| {
+                tracing::error!("Failed to replay {endpoint}: {e:#}");
+                missing(&format!("{e:#}"))
+            }))
+        }
+    }
+}
+
+/// Stands in for a response that couldn't be read or replayed.
+fn missing(message: &str) -> Response {
+    let status = StatusCode::NOT_FOUND;
+    let body = serde_json::json!({ "error": { "status": status.as_u16(), "message": message } });
+    axum::http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .expect("valid response")
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spotify;
+
+    /// Replays `fixtures/` through the Spotify client, as `--replay-spotify fixtures` would.
+    #[tokio::test]
+    async fn replays_recorded_responses() {
+        enable(Fixtures::Replay(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures"),
+        ))
+        .unwrap();
+
+        let playlist = spotify::playlist("token", "3cEYpjA9oz9GiPac4AsH4n")
+            .await
+            .unwrap();
+        assert_eq!(playlist.name, "Spotify Web API Testing playlist");
+        assert_eq!(playlist.tracks.total, 5);
+        assert!(playlist.images.is_empty());
+        // Not recorded.
+        assert!(spotify::playlist("token", "unknown").await.is_err());
+    }
+}
@@ -0,0 +1,462 @@
+//! Friend activity: what other users of this instance are listening to, at `/friends` and
+//! `/api/friends/activity`. Sharing is opt-in through `/api/me/sharing` or the settings on that
+//! page, either with the accounts linked to the user (see [`crate::links`]) or with everyone on
+//! the instance. Only users who share their own activity get to see anyone else's.
+//!
+//! What a friend is playing is asked of Spotify with any live session of theirs, and kept for a
+//! little while so a busy instance doesn't turn every page view into a call per friend.
+//!
+//! Sharing settings are kept in memory by default, or in the database with a SQL feature and
+//! `DATABASE_URL` set.
+
+use askama_axum::Template;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use futures::future::join_all;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    art,
+    cookie_manager::Flash,
+    session::PageSession,
+    session_store::SessionData,
+    spotify,
+    templates::{self, Format, Layout, Page},
+    AppError, AppStateInner,
+};
+
+const CACHE_TTL: Duration = Duration::from_secs(30);
+/// Longest display name, in characters.
+const MAX_NAME_LEN: usize = 40;
+
+/// Who sees what a user is listening to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Nobody,
+    /// The accounts linked to the user.
+    Linked,
+    /// Every user of the instance who shares too.
+    Everyone,
+}
+
+impl Visibility {
+    #[cfg(feature = "sql")]
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Nobody => "nobody",
+            Self::Linked => "linked",
+            Self::Everyone => "everyone",
+        }
+    }
+
+    #[cfg(feature = "sql")]
+    fn parse(visibility: &str) -> anyhow::Result<Self> {
+        match visibility {
+            "nobody" => Ok(Self::Nobody),
+            "linked" => Ok(Self::Linked),
+            "everyone" => Ok(Self::Everyone),
+            _ => anyhow::bail!("bad visibility {visibility:?}"),
+        }
+    }
+}
+
+/// A user's sharing settings.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Sharing {
+    pub visibility: Visibility,
+    /// Shown to friends instead of the Spotify user ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FriendTrack {
+    name: String,
+    artists: String,
+    album: String,
+    /// Our URL of the album art, empty when there's none.
+    artwork: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct Friend {
+    user_id: String,
+    name: String,
+    /// `None` when nothing is playing, or the friend has no live session to ask Spotify with.
+    track: Option<FriendTrack>,
+}
+
+#[derive(Default)]
+pub struct FriendStore {
+    sharing: SharingStore,
+    /// What each user was last seen playing, and when.
+    cache: RwLock<HashMap<String, (Instant, Option<FriendTrack>)>>,
+}
+
+/// Of users who share, by user ID.
+enum SharingStore {
+    Memory(RwLock<HashMap<String, Sharing>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for SharingStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl FriendStore {
+    #[cfg(feature = "sql")]
+    pub fn in_database(pool: db::Pool) -> Self {
+        Self {
+            sharing: SharingStore::Sql(pool),
+            cache: RwLock::default(),
+        }
+    }
+
+    pub async fn get(&self, user_id: &str) -> anyhow::Result<Sharing> {
+        match &self.sharing {
+            SharingStore::Memory(users) => {
+                Ok(users.read().await.get(user_id).cloned().unwrap_or_default())
+            }
+            #[cfg(feature = "sql")]
+            SharingStore::Sql(pool) => {
+                let row: Option<(String, String)> =
+                    sqlx::query_as("SELECT visibility, name FROM sharing WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_optional(pool)
+                        .await?;
+                row.map_or_else(
+                    || Ok(Sharing::default()),
+                    |(visibility, name)| {
+                        Ok(Sharing {
+                            visibility: Visibility::parse(&visibility)?,
+                            name: Some(name).filter(|name| !name.is_empty()),
+                        })
+                    },
+                )
+            }
+        }
+    }
+
+    /// Replaces a user's settings, turning sharing off with [`Visibility::Nobody`]. Names are
+    /// trimmed, and cut down to [`MAX_NAME_LEN`].
+    pub async fn set(&self, user_id: &str, mut sharing: Sharing) -> anyhow::Result<Sharing> {
+        sharing.name = sharing
+            .name
+            .map(|name| name.trim().chars().take(MAX_NAME_LEN).collect::<String>())
+            .filter(|name| !name.is_empty());
+        if sharing.visibility == Visibility::Nobody {
+            self.remove(user_id).await?;
+            return Ok(sharing);
+        }
+        match &self.sharing {
+            SharingStore::Memory(users) => {
+                users
+                    .write()
+                    .await
+                    .insert(user_id.to_owned(), sharing.clone());
+            }
+            #[cfg(feature = "sql")]
+            SharingStore::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO sharing (user_id, visibility, name) VALUES ($1, $2, $3) \
+                     ON CONFLICT (user_id) DO UPDATE SET visibility = $2, name = $3",
+                )
+                .bind(user_id)
+                .bind(sharing.visibility.as_str())
+                .bind(sharing.name.clone().unwrap_or_default())
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(sharing)
+    }
+
+    /// Stops sharing, returning whether the user did.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<bool> {
+        self.cache.write().await.remove(user_id);
+        match &self.sharing {
+            SharingStore::Memory(users) => Ok(users.write().await.remove(user_id).is_some()),
+            #[cfg(feature = "sql")]
+            SharingStore::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM sharing WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// The settings of everyone who shares.
+    async fn all(&self) -> anyhow::Result<HashMap<String, Sharing>> {
+        match &self.sharing {
+            SharingStore::Memory(users) => Ok(users.read().await.clone()),
+            #[cfg(feature = "sql")]
+            SharingStore::Sql(pool) => {
+                let rows: Vec<(String, String, String)> =
+                    sqlx::query_as("SELECT user_id, visibility, name FROM sharing")
+                        .fetch_all(pool)
+                        .await?;
+                rows.into_iter()
+                    .map(|(user_id, visibility, name)| {
+                        let sharing = Sharing {
+                            visibility: Visibility::parse(&visibility)?,
+                            name: Some(name).filter(|name| !name.is_empty()),
+                        };
+                        Ok((user_id, sharing))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.sharing {
+            SharingStore::Memory(users) => users.try_read().ok().map(|s| s.len()),
+            #[cfg(feature = "sql")]
+            SharingStore::Sql(_) => None,
+        }
+    }
+
+    /// What the friends of `viewer` are listening to, those playing something first, then by
+    /// name. Empty unless the viewer shares too.
+    pub async fn activity(
+        &self,
+        state: &AppStateInner,
+        viewer: &str,
+    ) -> anyhow::Result<Vec<Friend>> {
+        let sharing = self.all().await?;
+        if !sharing.contains_key(viewer) {
+            return Ok(Vec::new());
+        }
+        let mut friends = Vec::new();
+        for (user_id, settings) in sharing {
+            let visible = user_id != viewer
+                && match settings.visibility {
+                    Visibility::Nobody => false,
+                    Visibility::Linked => state.links.are_linked(&user_id, viewer).await?,
+                    Visibility::Everyone => true,
+                };
+            if visible {
+                friends.push((user_id, settings));
+            }
+        }
+        if friends.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let sessions = state.sessions.all().await?;
+        let tracks = join_all(friends.iter().map(|(user_id, _)| {
+            let session = sessions.iter().find(|session| &session.user_id == user_id);
+            self.playing(user_id, session)
+        }))
+        .await;
+        Ok(friends
+            .into_iter()
+            .zip(tracks)
+            .map(|((user_id, settings), track)| Friend {
+                name: settings.name.unwrap_or_else(|| user_id.clone()),
+                user_id,
+                track,
+            })
+            .sorted_by(|a, b| {
+                b.track
+                    .is_some()
+                    .cmp(&a.track.is_some())
+                    .then_with(|| a.name.cmp(&b.name))
+            })
+            .collect())
+    }
+
+    /// What `user_id` is playing, asked with `session` when not cached. A failure shows as
+    /// nothing playing, so one friend's expired token doesn't break the list.
+    async fn playing(&self, user_id: &str, session: Option<&SessionData>) -> Option<FriendTrack> {
+        if let Some((at, track)) = self.cache.read().await.get(user_id) {
+            if at.elapsed() < CACHE_TTL {
+                return track.clone();
+            }
+        }
+        let track = match spotify::currently_playing(&session?.token.access_token).await {
+            Ok(track) => track,
+            Err(e) => {
+                tracing::warn!("Failed to get what a friend is playing: {e:#}");
+                None
+            }
+        };
+        let track = track.map(|track| FriendTrack {
+            artists: track.artists.iter().map(|a| &a.name).join(", "),
+            artwork: track
+                .album
+                .images
+                .last()
+                .map(|image| art::local_url("", &image.url))
+                .unwrap_or_default(),
+            album: track.album.name,
+            url: format!("https://open.spotify.com/track/{}", track.id),
+            name: track.name,
+        });
+        self.cache
+            .write()
+            .await
+            .insert(user_id.to_owned(), (Instant::now(), track.clone()));
+        track
+    }
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/friends", get(friends))
+        .route("/friends/sharing", post(update_sharing))
+}
+
+/// A [`Friend`], with the track's fields empty when nothing is playing.
+#[derive(Serialize)]
+struct FriendRow {
+    name: String,
+    playing: bool,
+    title: String,
+    artists: String,
+    artwork: String,
+    url: String,
+}
+
+impl From<Friend> for FriendRow {
+    fn from(friend: Friend) -> Self {
+        let track = friend.track;
+        Self {
+            name: friend.name,
+            playing: track.is_some(),
+            title: track.as_ref().map(|t| t.name.clone()).unwrap_or_default(),
+            artists: track
+                .as_ref()
+                .map(|t| t.artists.clone())
+                .unwrap_or_default(),
+            artwork: track
+                .as_ref()
+                .map(|t| t.artwork.clone())
+                .unwrap_or_default(),
+            url: track.map(|t| t.url).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "friends.html")]
+struct FriendsTemplate {
+    /// `nobody`, `linked` or `everyone`.
+    visibility: String,
+    /// Empty when unset.
+    name: String,
+    friends: Vec<FriendRow>,
+    layout: Layout,
+}
+
+impl Page for FriendsTemplate {
+    const PATH: &'static str = "friends.html";
+}
+
+async fn friends(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    layout: Layout,
+    format: Format,
+) -> Result<Response, AppError> {
+    let sharing = s.friends.get(&session.user_id).await?;
+    let friends = s.friends.activity(&s, &session.user_id).await?;
+    let visibility = match sharing.visibility {
+        Visibility::Nobody => "nobody",
+        Visibility::Linked => "linked",
+        Visibility::Everyone => "everyone",
+    };
+    Ok(templates::respond(
+        format,
+        FriendsTemplate {
+            visibility: visibility.to_owned(),
+            name: sharing.name.unwrap_or_default(),
+            friends: friends.into_iter().map(FriendRow::from).collect(),
+            layout,
+        },
+    ))
+}
+
+/// The settings form of the page. The session cookie is `SameSite=Lax`, so other sites can't
+/// post it on the user's behalf.
+async fn update_sharing(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    Form(sharing): Form<Sharing>,
+) -> Result<impl IntoResponse, AppError> {
+    s.friends.set(&session.user_id, sharing).await?;
+    Ok((
+        Flash("Sharing settings saved".to_owned()),
+        Redirect::to("/friends"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn keeps_sharing_settings(store: &FriendStore) {
+        assert_eq!(
+            store.get("ann").await.unwrap().visibility,
+            Visibility::Nobody
+        );
+        let sharing = Sharing {
+            visibility: Visibility::Linked,
+            name: Some("  Ann  ".to_owned()),
+        };
+        let saved = store.set("ann", sharing).await.unwrap();
+        assert_eq!(saved.name.as_deref(), Some("Ann"));
+        let got = store.get("ann").await.unwrap();
+        assert_eq!(got.visibility, Visibility::Linked);
+        assert_eq!(got.name.as_deref(), Some("Ann"));
+
+        let unnamed = Sharing {
+            visibility: Visibility::Everyone,
+            name: Some(" ".to_owned()),
+        };
+        store.set("bob", unnamed).await.unwrap();
+        let all = store.all().await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all["bob"].name, None);
+
+        store.set("bob", Sharing::default()).await.unwrap();
+        assert!(store.remove("ann").await.unwrap());
+        assert!(!store.remove("ann").await.unwrap());
+        assert!(store.all().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_sharing_settings_in_memory() {
+        keeps_sharing_settings(&FriendStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn keeps_sharing_settings_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-sharing-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        keeps_sharing_settings(&FriendStore::in_database(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,122 @@
+//! Where a client connects from, by country and network (ASN), for noticing logins from unusual
+//! places, see [`crate::logins`]. Lookups need the `geoip` feature and a database in `GEOIP_DB`,
+//! in the tab-separated format of `IPtoASN`'s `ip2asn-combined.tsv`: one address range per line,
+//! with its first and last address, AS number, country code and AS name. Without them, nothing
+//! is ever located.
+
+use serde::Serialize;
+use std::{net::IpAddr, path::Path};
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166 code, or `None` for networks without one.
+    pub country: Option<String>,
+    pub asn: u32,
+    /// The name of the network.
+    pub network: String,
+}
+
+#[cfg(feature = "geoip")]
+struct Range {
+    first: u128,
+    last: u128,
+    location: Location,
+}
+
+#[derive(Default)]
+pub struct GeoIp {
+    /// Sorted and without overlaps.
+    #[cfg(feature = "geoip")]
+    ranges: Vec<Range>,
+}
+
+/// IPv4 addresses as IPv4-mapped IPv6 ones, so both kinds sort together.
+#[cfg(feature = "geoip")]
+fn key(ip: IpAddr) -> u128 {
+    let v6 = match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    u128::from(v6)
+}
+
+impl GeoIp {
+    /// Reads `path`, or locates nothing without one.
+    #[cfg(feature = "geoip")]
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let tsv = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read GEOIP_DB {}", path.display()))?;
+        let mut ranges = Vec::new();
+        for (number, line) in tsv.lines().enumerate() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [first, last, asn, country, network] = fields[..] else {
+                anyhow::bail!("line {} of {} isn't a range", number + 1, path.display());
+            };
+            let asn: u32 = asn
+                .parse()
+                .with_context(|| format!("bad AS number on line {}", number + 1))?;
+            // Unrouted ranges, which nobody connects from.
+            if asn == 0 {
+                continue;
+            }
+            let parse = |ip: &str| {
+                ip.parse::<IpAddr>()
+                    .with_context(|| format!("bad address on line {}", number + 1))
+            };
+            ranges.push(Range {
+                first: key(parse(first)?),
+                last: key(parse(last)?),
+                location: Location {
+                    country: Some(country)
+                        .filter(|country| *country != "None")
+                        .map(str::to_owned),
+                    asn,
+                    network: network.to_owned(),
+                },
+            });
+        }
+        ranges.sort_unstable_by_key(|range| range.first);
+        tracing::info!("Loaded {} GeoIP ranges", ranges.len());
+        Ok(Self { ranges })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        if path.is_some() {
+            tracing::warn!("GEOIP_DB is set, but this build has no `geoip` feature to use it");
+        }
+        Ok(Self::default())
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn locate(&self, ip: IpAddr) -> Option<Location> {
+        let key = key(ip);
+        // The last range starting at or before the address, if the address is in it.
+        let index = self.ranges.partition_point(|range| range.first <= key);
+        let range = &self.ranges[index.checked_sub(1)?];
+        (key <= range.last).then(|| range.location.clone())
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    #[allow(clippy::unused_self)]
+    pub const fn locate(&self, _ip: IpAddr) -> Option<Location> {
+        None
+    }
+
+    /// Always known, unlike the lengths of the stores behind a lock.
+    #[allow(clippy::unnecessary_wraps)]
+    #[cfg_attr(not(feature = "geoip"), allow(clippy::unused_self))]
+    pub const fn len_hint(&self) -> Option<usize> {
+        #[cfg(feature = "geoip")]
+        let len = self.ranges.len();
+        #[cfg(not(feature = "geoip"))]
+        let len = 0;
+        Some(len)
+    }
+}
@@ -0,0 +1,339 @@
+//! Handing a login over to another device, typically a phone to control playback from. The
+//! logged-in browser shows a QR code of a short-lived one-time link; opening and confirming it on
+//! the phone gives the phone a session of its own for the same user.
+//!
+//! Confirming is a `POST` from the page the link opens, and needs a cookie set by that page, so
+//! link previews don't use up the code and other sites can't log a visitor into someone else's
+//! account.
+
+use askama_axum::Template;
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use chrono::Utc;
+use qrcode::{render::svg, QrCode};
+use serde::Serialize;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::{
+    cookie_manager::Flash,
+    cookies, logins,
+    session::{self, Session},
+    session_store::SessionData,
+    templates::{self, Format, Layout, Page},
+    token::{self, SessionHash},
+    AppError, AppStateInner,
+};
+
+/// How long a code can be used for.
+const MAX_AGE: Duration = Duration::from_mins(2);
+
+/// Name of the cookie tying a confirmation to the browser that opened the link.
+const NONCE_COOKIE: &str = "handoff_nonce";
+
+struct Handoff {
+    /// The session being handed over. Looked up again on confirmation, so logging out in the
+    /// meantime cancels the handoff.
+    session: SessionHash,
+    expires_at: Instant,
+    /// Set once the link is opened.
+    nonce: Option<String>,
+}
+
+#[derive(Default)]
+pub struct HandoffStore {
+    codes: RwLock<HashMap<String, Handoff>>,
+}
+
+impl HandoffStore {
+    async fn issue(&self, session: SessionHash) -> String {
+        self.issue_at(session, Instant::now()).await
+    }
+
+    async fn issue_at(&self, session: SessionHash, now: Instant) -> String {
+        let code = token::generate(token::STATE_BYTES);
+        let mut codes = self.codes.write().await;
+        codes.retain(|_, handoff| handoff.expires_at > now);
+        codes.insert(
+            code.clone(),
+            Handoff {
+                session,
+                expires_at: now + MAX_AGE,
+                nonce: None,
+            },
+        );
+        code
+    }
+
+    /// Marks the code as opened by a browser, returning the nonce that browser must confirm with.
+    async fn open(&self, code: &str) -> Option<String> {
+        let mut codes = self.codes.write().await;
+        let handoff = codes
+            .get_mut(code)
+            .filter(|handoff| handoff.expires_at > Instant::now())?;
+        Some(
+            handoff
+                .nonce
+                .get_or_insert_with(|| token::generate(token::STATE_BYTES))
+                .clone(),
+        )
+    }
+
+    /// Uses up the code, returning the session it hands over if `nonce` matches.
+    async fn redeem(&self, code: &str, nonce: &str) -> Option<SessionHash> {
+        self.redeem_at(code, nonce, Instant::now()).await
+    }
+
+    async fn redeem_at(&self, code: &str, nonce: &str, now: Instant) -> Option<SessionHash> {
+        let mut codes = self.codes.write().await;
+        let handoff = codes.get(code)?;
+        let matches = handoff
+            .nonce
+            .as_deref()
+            .is_some_and(|expected| token::constant_time_eq(expected.as_bytes(), nonce.as_bytes()));
+        if !matches {
+            return None;
+        }
+        let handoff = codes.remove(code)?;
+        (handoff.expires_at > now).then_some(handoff.session)
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.codes.try_read().ok().map(|c| c.len())
+    }
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/handoff", get(start))
+        .route("/handoff/:code", get(open).post(confirm))
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "handoff.html")]
+struct HandoffTemplate {
+    url: String,
+    /// The QR code of `url`, as an SVG document.
+    qr: String,
+    /// Seconds the link stays valid for.
+    expires_in: u64,
+    layout: Layout,
+}
+
+impl Page for HandoffTemplate {
+    const PATH: &'static str = "handoff.html";
+}
+
+/// Shows the QR code of a fresh handoff link for the current session.
+async fn start(
+    session: Session,
+    State(s): State<Arc<AppStateInner>>,
+    layout: Layout,
+    format: Format,
+) -> Result<Response, AppError> {
+    // The other device gets a session of its own, which an API key has none of to copy.
+    let Some(id) = &session.id else {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+    let code = s.handoffs.issue(id.hash()).await;
+    let url = format!("{}/auth/handoff/{code}", s.public_url);
+    let qr = QrCode::new(url.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(240, 240)
+        .build();
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        templates::respond(
+            format,
+            HandoffTemplate {
+                url,
+                qr,
+                expires_in: MAX_AGE.as_secs(),
+                layout,
+            },
+        ),
+    )
+        .into_response())
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "handoff_confirm.html")]
+struct HandoffConfirmTemplate {
+    code: String,
+    layout: Layout,
+}
+
+impl Page for HandoffConfirmTemplate {
+    const PATH: &'static str = "handoff_confirm.html";
+}
+
+/// The page the QR code leads to, asking to confirm before anything happens.
+async fn open(
+    State(s): State<Arc<AppStateInner>>,
+    Path(code): Path<String>,
+    layout: Layout,
+    format: Format,
+) -> Response {
+    let Some(nonce) = s.handoffs.open(&code).await else {
+        return (StatusCode::NOT_FOUND, "This link has expired").into_response();
+    };
+    (
+        [
+            (
+                header::SET_COOKIE,
+                format!(
+                    "{NONCE_COOKIE}={nonce}; Max-Age={}; Path=/auth/handoff; HttpOnly; \
+                     SameSite=Strict",
+                    MAX_AGE.as_secs()
+                ),
+            ),
+            (header::CACHE_CONTROL, "no-store".to_owned()),
+        ],
+        templates::respond(format, HandoffConfirmTemplate { code, layout }),
+    )
+        .into_response()
+}
+
+/// Logs this browser in as the user who showed the QR code.
+async fn confirm(
+    State(s): State<Arc<AppStateInner>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<Response, AppError> {
+    let nonce = cookies::from_headers(&headers, NONCE_COOKIE).unwrap_or_default();
+    let Some(handed_over) = s.handoffs.redeem(&code, nonce).await else {
+        return Ok((StatusCode::NOT_FOUND, "This link has expired").into_response());
+    };
+    let Some(data) = s.sessions.get(&handed_over).await? else {
+        return Ok((StatusCode::NOT_FOUND, "The other device has logged out").into_response());
+    };
+    // Keeping `created_at` means a handoff can't outlive the session it was made from.
+    let user_id = data.user_id.clone();
+    let flash = Flash(format!("Logged in as {user_id}"));
+    let (session_id, ttl) = session::start(
+        &s,
+        SessionData {
+            last_seen: Utc::now(),
+            ..data
+        },
+    )
+    .await?;
+    if let Some(ip) = s.lockout.client_ip(&headers, peer.as_ref()) {
+        let s = s.clone();
+        tokio::spawn(async move { logins::check(&s, &user_id, ip).await });
+    }
+    Ok((
+        AppendHeaders([
+            (
+                header::SET_COOKIE,
+                session::cookie(&s.session_config, &session_id, ttl),
+            ),
+            (
+                header::SET_COOKIE,
+                format!(
+                    "{NONCE_COOKIE}=; Max-Age=0; Path=/auth/handoff; HttpOnly; SameSite=Strict"
+                ),
+            ),
+        ]),
+        flash,
+        Redirect::to("/"),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{spotify::SpotifyToken, token::SessionId};
+
+    #[tokio::test]
+    async fn codes_are_redeemed_once_with_their_nonce() {
+        let handoffs = HandoffStore::default();
+        let session = SessionHash::of("secret");
+        let code = handoffs.issue(session).await;
+        // Not opened yet, so there's no nonce to match.
+        assert_eq!(handoffs.redeem(&code, "").await, None);
+        let nonce = handoffs.open(&code).await.unwrap();
+        assert_eq!(handoffs.open(&code).await.as_deref(), Some(nonce.as_str()));
+        assert_eq!(handoffs.redeem(&code, "guess").await, None);
+        assert_eq!(handoffs.redeem(&code, &nonce).await, Some(session));
+        assert_eq!(handoffs.redeem(&code, &nonce).await, None);
+        assert_eq!(handoffs.open(&code).await, None);
+    }
+
+    #[tokio::test]
+    async fn codes_expire() {
+        let handoffs = HandoffStore::default();
+        let now = Instant::now();
+        let code = handoffs.issue_at(SessionHash::of("secret"), now).await;
+        let nonce = handoffs.open(&code).await.unwrap();
+        assert_eq!(handoffs.redeem_at(&code, &nonce, now + MAX_AGE).await, None);
+        assert_eq!(handoffs.len_hint(), Some(0));
+
+        handoffs.issue_at(SessionHash::of("other"), now).await;
+        handoffs
+            .issue_at(SessionHash::of("another"), now + MAX_AGE)
+            .await;
+        assert_eq!(handoffs.len_hint(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn confirming_starts_a_session_for_the_same_user() {
+        let state = AppStateInner::for_tests().await;
+        let created_at = Utc::now() - chrono::Duration::days(1);
+        let (id, _) = session::start(
+            &state,
+            SessionData {
+                user_id: "ann".to_owned(),
+                token: SpotifyToken {
+                    access_token: "token".to_owned(),
+                    refresh_token: String::new(),
+                    expires_in: 3600,
+                    token_type: "Bearer".to_owned(),
+                    scope: String::new(),
+                },
+                token_expires_at: Utc::now() + chrono::Duration::hours(1),
+                created_at,
+                last_seen: created_at,
+                premium: None,
+                do_not_track: false,
+            },
+        )
+        .await
+        .unwrap();
+        let code = state.handoffs.issue(id.hash()).await;
+        let nonce = state.handoffs.open(&code).await.unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("{NONCE_COOKIE}={nonce}").parse().unwrap(),
+        );
+        let response = confirm(State(state.clone()), Path(code), headers, None)
+            .await
+            .unwrap_or_else(|AppError(e)| panic!("{e:#}"));
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let handed_over = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .find_map(|cookie| cookies::get(cookie.to_str().unwrap(), "session_id"))
+            .map(SessionId::from)
+            .unwrap();
+        assert_ne!(handed_over.as_str(), id.as_str());
+        let data = state
+            .sessions
+            .get(&handed_over.hash())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.user_id, "ann");
+        assert_eq!(data.created_at, created_at);
+    }
+}
@@ -0,0 +1,365 @@
+//! Listening history. Spotify only ever returns the last 50 plays, so a background collector
+//! polls that endpoint for every logged-in user and accumulates what it sees, keyed by the
+//! Spotify user ID so that history survives across sessions. With a database, it also survives
+//! restarts.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    jobs::Reporter,
+    spotify::{self, PlayHistory, Track},
+    stats, AppStateInner,
+};
+
+/// 50 plays take a couple of hours to listen through, so this leaves a comfortable margin.
+const COLLECTION_INTERVAL: Duration = Duration::from_mins(10);
+/// Spotify counts a stream as a play from 30 seconds on.
+const MIN_PLAYED_MS: u64 = 30_000;
+
+#[derive(Default)]
+pub struct UserHistory {
+    /// When the collector first saw this user. Nothing is known about plays before that.
+    collecting_since: Option<DateTime<Utc>>,
+    /// Keyed by play time, which also deduplicates the overlapping windows we get from Spotify.
+    plays: BTreeMap<DateTime<Utc>, Track>,
+}
+
+/// In memory by default, or in the database with a SQL feature and `DATABASE_URL` set.
+pub enum HistoryStore {
+    Memory(RwLock<HashMap<String, UserHistory>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for HistoryStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl HistoryStore {
+    /// Adds plays to a user's history, returning how many weren't known yet.
+    pub async fn record(&self, user_id: &str, plays: Vec<PlayHistory>) -> anyhow::Result<usize> {
+        let plays =
+            plays
+                .into_iter()
+                .filter_map(|play| match play.played_at.parse::<DateTime<Utc>>() {
+                    Ok(played_at) => Some((played_at, play.track)),
+                    Err(e) => {
+                        tracing::warn!("Ignoring play with bad timestamp {}: {e}", play.played_at);
+                        None
+                    }
+                });
+        match self {
+            Self::Memory(users) => {
+                let mut users = users.write().await;
+                let history = users.entry(user_id.to_owned()).or_default();
+                history.collecting_since.get_or_insert_with(Utc::now);
+                let before = history.plays.len();
+                for (played_at, track) in plays {
+                    history.plays.entry(played_at).or_insert(track);
+                }
+                Ok(history.plays.len() - before)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query(
+                    "INSERT INTO history_users (user_id, collecting_since) VALUES ($1, $2) \
+                     ON CONFLICT (user_id) DO NOTHING",
+                )
+                .bind(user_id)
+                .bind(Utc::now().timestamp())
+                .execute(&mut *tx)
+                .await?;
+                let mut new = 0;
+                for (played_at, track) in plays {
+                    let inserted = sqlx::query(
+                        "INSERT INTO plays (user_id, played_at, track_id, track) \
+                         VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, played_at) DO NOTHING",
+                    )
+                    .bind(user_id)
+                    .bind(played_at.timestamp_millis())
+                    .bind(&track.id)
+                    .bind(serde_json::to_string(&track)?)
+                    .execute(&mut *tx)
+                    .await?;
+                    new += usize::try_from(inserted.rows_affected())?;
+                }
+                tx.commit().await?;
+                Ok(new)
+            }
+        }
+    }
+
+    pub async fn collecting_since(&self, user_id: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .read()
+                .await
+                .get(user_id)
+                .and_then(|h| h.collecting_since)),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let since: Option<i64> = sqlx::query_scalar(
+                    "SELECT collecting_since FROM history_users WHERE user_id = $1",
+                )
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?;
+                Ok(since.and_then(|since| DateTime::from_timestamp(since, 0)))
+            }
+        }
+    }
+
+    /// The last time each track was played.
+    pub async fn last_played(
+        &self,
+        user_id: &str,
+    ) -> anyhow::Result<HashMap<String, DateTime<Utc>>> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .read()
+                .await
+                .get(user_id)
+                .map(|h| {
+                    h.plays
+                        .iter()
+                        .map(|(played_at, track)| (track.id.clone(), *played_at))
+                        .collect()
+                })
+                .unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, i64)> = sqlx::query_as(
+                    "SELECT track_id, MAX(played_at) FROM plays WHERE user_id = $1 \
+                     GROUP BY track_id",
+                )
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter()
+                    .map(|(track_id, played_at)| Ok((track_id, from_millis(played_at)?)))
+                    .collect()
+            }
+        }
+    }
+
+    /// Every play of a user, oldest first.
+    pub async fn plays(&self, user_id: &str) -> anyhow::Result<Vec<(DateTime<Utc>, Track)>> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .read()
+                .await
+                .get(user_id)
+                .map(|h| h.plays.iter().map(|(at, t)| (*at, t.clone())).collect())
+                .unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(i64, String)> = sqlx::query_as(
+                    "SELECT played_at, track FROM plays WHERE user_id = $1 ORDER BY played_at",
+                )
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter()
+                    .map(|(played_at, track)| {
+                        Ok((from_millis(played_at)?, serde_json::from_str(&track)?))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Forgets a user's plays from before `cutoff`, returning how many there were.
+    pub async fn prune(&self, user_id: &str, cutoff: DateTime<Utc>) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(users) => Ok(users.write().await.get_mut(user_id).map_or(0, |h| {
+                let kept = h.plays.split_off(&cutoff);
+                std::mem::replace(&mut h.plays, kept).len()
+            })),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted =
+                    sqlx::query("DELETE FROM plays WHERE user_id = $1 AND played_at < $2")
+                        .bind(user_id)
+                        .bind(cutoff.timestamp_millis())
+                        .execute(pool)
+                        .await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    /// Forgets a user's history, returning how many plays it held.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .write()
+                .await
+                .remove(user_id)
+                .map_or(0, |h| h.plays.len())),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let deleted = sqlx::query("DELETE FROM plays WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM history_users WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(users) => users.try_read().ok().map(|u| u.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+/// A stream from the extended streaming history Spotify sends users who ask for it in their
+/// account's privacy settings. Other fields are left out, as are podcast episodes, which have no
+/// track URI.
+#[derive(Deserialize)]
+pub struct Stream {
+    /// When the stream ended.
+    ts: String,
+    ms_played: u64,
+    spotify_track_uri: Option<String>,
+}
+
+/// Adds the plays of an extended streaming history to the user's history, for a history going
+/// back further than when we started collecting. Returns how many plays weren't known yet.
+pub async fn import(
+    history: &HistoryStore,
+    access_token: &str,
+    user_id: &str,
+    streams: Vec<Stream>,
+    reporter: &Reporter,
+) -> anyhow::Result<usize> {
+    let streams: Vec<(String, String)> = streams
+        .into_iter()
+        .filter(|stream| stream.ms_played >= MIN_PLAYED_MS)
+        .filter_map(|stream| {
+            let id = stream
+                .spotify_track_uri?
+                .strip_prefix("spotify:track:")?
+                .to_owned();
+            Some((stream.ts, id))
+        })
+        .collect();
+    let ids: Vec<String> = streams
+        .iter()
+        .map(|(_, id)| id.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    reporter
+        .log(format!(
+            "Found {} plays of {} tracks, looking the tracks up",
+            streams.len(),
+            ids.len()
+        ))
+        .await;
+    // The streaming history only names tracks, so their details are looked up.
+    let mut tracks = HashMap::with_capacity(ids.len());
+    for (i, chunk) in ids.chunks(50).enumerate() {
+        reporter.progress(i * 50, ids.len()).await;
+        for track in spotify::tracks(access_token, chunk).await? {
+            tracks.insert(track.id.clone(), track);
+        }
+    }
+    reporter.progress(ids.len(), ids.len()).await;
+
+    let plays = streams
+        .into_iter()
+        .filter_map(|(played_at, id)| {
+            Some(PlayHistory {
+                track: tracks.get(&id)?.clone(),
+                played_at,
+            })
+        })
+        .collect();
+    let new = history.record(user_id, plays).await?;
+    reporter
+        .log(format!(
+            "Added {new} plays that weren't in your history yet"
+        ))
+        .await;
+    Ok(new)
+}
+
+#[cfg(feature = "sql")]
+fn from_millis(ms: i64) -> anyhow::Result<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(ms).ok_or_else(|| anyhow::anyhow!("bad play time {ms}"))
+}
+
+pub fn spawn_collector(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COLLECTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !state.leader.holds() {
+                continue;
+            }
+            let sessions = match state.sessions.all().await {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    tracing::error!("Failed to list sessions for history collection: {e:#}");
+                    continue;
+                }
+            };
+            // A user logged in from several browsers only needs to be polled once.
+            let mut seen = HashSet::new();
+            for session in sessions {
+                if session.do_not_track || !seen.insert(session.user_id.clone()) {
+                    continue;
+                }
+                let plays = match spotify::recently_played(&session.token.access_token).await {
+                    Ok(plays) => plays,
+                    Err(e) => {
+                        tracing::warn!("Failed to collect recent plays: {e:#}");
+                        continue;
+                    }
+                };
+                // Worked out before and after, so only those the new plays reach are announced.
+                let milestones = stats::announced(&state, &session.user_id).await;
+                let new = match state.history.record(&session.user_id, plays).await {
+                    Ok(new) => new,
+                    Err(e) => {
+                        tracing::error!("Failed to store recent plays: {e:#}");
+                        continue;
+                    }
+                };
+                tracing::debug!("Collected {new} new plays for {}", session.user_id);
+                let Some(before) = milestones.filter(|_| new > 0) else {
+                    continue;
+                };
+                if let Some(after) = stats::announced(&state, &session.user_id).await {
+                    let reached = stats::newly_reached(&before, after);
+                    if !reached.is_empty() {
+                        stats::announce(&state, &session.user_id, &reached).await;
+                    }
+                }
+            }
+        }
+    });
+}
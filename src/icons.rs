@@ -0,0 +1,201 @@
+//! Favicons and touch icons, drawn at startup from `BRAND_COLOR` and `BRAND_LETTER`: the letter
+//! in white or black, whichever reads better, on a square of the colour. Browsers and home
+//! screens ask for these whether or not a page links them, so serving them keeps 404s out of the
+//! logs, and lets a deployment tell itself apart without design work.
+//!
+//! The letter comes from a built-in 5×7 pixel font, scaled up in whole pixels so it stays crisp.
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use image::{
+    codecs::{
+        ico::{IcoEncoder, IcoFrame},
+        png::PngEncoder,
+    },
+    ExtendedColorType, ImageEncoder, Rgba, RgbaImage,
+};
+use std::sync::Arc;
+
+use crate::{config::Brand, AppStateInner};
+
+/// Sizes in the `.ico`, for tabs, bookmarks and the taskbar at various densities.
+const FAVICON_SIZES: [u32; 3] = [16, 32, 48];
+/// What iOS asks for.
+const TOUCH_ICON_SIZE: u32 = 180;
+/// The sizes the web app manifest needs to be installable.
+pub const MANIFEST_SIZES: [u32; 2] = [192, 512];
+/// Icons change only with the configuration, but might then.
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// Every icon, encoded once.
+pub struct Icons {
+    /// The brand colour as `#rrggbb`, for the manifest's theme colour.
+    pub color: String,
+    favicon: Bytes,
+    touch: Bytes,
+    /// In the order of [`MANIFEST_SIZES`].
+    manifest: Vec<Bytes>,
+}
+
+impl Icons {
+    pub fn render(brand: Brand) -> anyhow::Result<Self> {
+        let frames: Vec<Vec<u8>> = FAVICON_SIZES
+            .iter()
+            .map(|&size| png(&draw(brand, size)))
+            .collect::<anyhow::Result<_>>()?;
+        let frames = FAVICON_SIZES
+            .iter()
+            .zip(&frames)
+            .map(|(&size, png)| IcoFrame::with_encoded(png, size, size, ExtendedColorType::Rgba8))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut favicon = Vec::new();
+        IcoEncoder::new(&mut favicon).encode_images(&frames)?;
+        Ok(Self {
+            color: brand.css_color(),
+            favicon: favicon.into(),
+            touch: png(&draw(brand, TOUCH_ICON_SIZE))?.into(),
+            manifest: MANIFEST_SIZES
+                .iter()
+                .map(|&size| Ok(png(&draw(brand, size))?.into()))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/favicon.ico", get(favicon))
+        .route("/apple-touch-icon.png", get(touch_icon))
+        // Asked for by older iOS versions.
+        .route("/apple-touch-icon-precomposed.png", get(touch_icon))
+        .route("/icon-192.png", get(small_icon))
+        .route("/icon-512.png", get(large_icon))
+}
+
+async fn favicon(State(s): State<Arc<AppStateInner>>) -> Response {
+    respond("image/x-icon", s.icons.favicon.clone())
+}
+
+async fn touch_icon(State(s): State<Arc<AppStateInner>>) -> Response {
+    respond("image/png", s.icons.touch.clone())
+}
+
+async fn small_icon(State(s): State<Arc<AppStateInner>>) -> Response {
+    respond("image/png", s.icons.manifest[0].clone())
+}
+
+async fn large_icon(State(s): State<Arc<AppStateInner>>) -> Response {
+    respond("image/png", s.icons.manifest[1].clone())
+}
+
+fn respond(content_type: &'static str, data: Bytes) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        data,
+    )
+        .into_response()
+}
+
+fn png(image: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    PngEncoder::new(&mut out).write_image(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ExtendedColorType::Rgba8,
+    )?;
+    Ok(out)
+}
+
+/// The icon `size` pixels square. Edge to edge, since home screens cut their own shape out.
+#[allow(clippy::suboptimal_flops)]
+fn draw(brand: Brand, size: u32) -> RgbaImage {
+    let [red, green, blue] = brand.color;
+    let mut image = RgbaImage::from_pixel(size, size, Rgba([red, green, blue, 255]));
+    // Relative luminance, roughly: light colours get a black letter.
+    let luminance = 0.2126 * f64::from(red) + 0.7152 * f64::from(green) + 0.0722 * f64::from(blue);
+    let ink = if luminance > 150.0 {
+        Rgba([0, 0, 0, 255])
+    } else {
+        Rgba([255, 255, 255, 255])
+    };
+
+    let glyph = glyph(brand.letter);
+    // The letter takes about 60% of the height, and at least a pixel per font pixel.
+    let scale = (size * 3 / 5 / GLYPH_HEIGHT).max(1);
+    let left = size.saturating_sub(GLYPH_WIDTH * scale) / 2;
+    let top = size.saturating_sub(GLYPH_HEIGHT * scale) / 2;
+    for (row, bits) in (0..).zip(glyph) {
+        for column in 0..GLYPH_WIDTH {
+            if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                continue;
+            }
+            for y in 0..scale {
+                for x in 0..scale {
+                    let (x, y) = (left + column * scale + x, top + row * scale + y);
+                    if x < size && y < size {
+                        image.put_pixel(x, y, ink);
+                    }
+                }
+            }
+        }
+    }
+    image
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// Rows of `letter`, top first, the leftmost pixel in the highest of the low 5 bits. Only ASCII
+/// letters and digits are drawn, as checked when the configuration is loaded; letters are drawn
+/// in capitals.
+const fn glyph(letter: char) -> [u8; 7] {
+    match letter.to_ascii_uppercase() {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        _ => [0; 7],
+    }
+}
@@ -0,0 +1,99 @@
+//! IDs taken from request paths. Handlers pass them on to Spotify in the paths of its endpoints,
+//! and axum decodes them first, so an unchecked `..%2Fme%2Fplayer%2Fpause%3F` would reach a
+//! different endpoint than the one meant. Routes take them through [`SpotifyId`] and [`UserId`],
+//! which refuse anything else with a `400` before the handler runs.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+/// A playlist, track, artist or album ID: 22 base62 characters.
+pub struct SpotifyId(pub String);
+
+/// A user ID, which for older accounts is the username they picked.
+pub struct UserId(pub String);
+
+fn is_spotify_id(id: &str) -> bool {
+    id.len() == 22 && id.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// Letters, digits, `.`, `_` and `-`, which are all usernames could ever hold. `.` and `..` are
+/// refused too, as they'd still be read as going up a level.
+fn is_user_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+        && id.bytes().any(|b| b != b'.')
+}
+
+async fn checked<S: Send + Sync>(
+    parts: &mut Parts,
+    state: &S,
+    valid: fn(&str) -> bool,
+    what: &str,
+) -> Result<String, Response> {
+    let Path(id) = Path::<String>::from_request_parts(parts, state)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    if valid(&id) {
+        Ok(id)
+    } else {
+        Err((StatusCode::BAD_REQUEST, format!("Not a {what}")).into_response())
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SpotifyId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        checked(parts, state, is_spotify_id, "Spotify ID")
+            .await
+            .map(Self)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UserId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        checked(parts, state, is_user_id, "user ID").await.map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_ids_spotify_hands_out_are_valid() {
+        assert!(is_spotify_id("37i9dQZF1DXcBWIGoYBM5M"));
+        for id in [
+            "",
+            "p1",
+            "37i9dQZF1DXcBWIGoYBM5MM",
+            "..%2Fme%2Fplayer%2Fpau",
+            "../me/player/pause?abc",
+            "37i9dQZF1DXcBWIGoYBM5é",
+        ] {
+            assert!(!is_spotify_id(id), "{id}");
+        }
+
+        for id in [
+            "ann",
+            "wizzler",
+            "jane.doe-99_x",
+            "31l77y2al5lnn7mxfrmd4bpfhqke",
+        ] {
+            assert!(is_user_id(id), "{id}");
+        }
+        for id in ["", ".", "..", "a/b", "a?b", "a b", "a%2Fb", &"a".repeat(65)] {
+            assert!(!is_user_id(id), "{id}");
+        }
+    }
+}
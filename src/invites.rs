@@ -0,0 +1,290 @@
+//! Invitation-only instances, with `INVITE_ONLY`. Only the users in `ADMINS` or `ALLOWED_USERS`
+//! get a session from the login callback, along with anyone who logs in through an invite link
+//! minted by an admin under `/admin/invites`. Everyone else is shown a page saying the instance is
+//! private, without a session.
+//!
+//! An invite is used up by the first user it lets in, who is then let in for good. Invites and
+//! the users they let in live in memory by default, or in the database with a SQL feature and
+//! `DATABASE_URL` set.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::token;
+
+/// How long an invite can be used for.
+const INVITE_MAX_AGE: chrono::Duration = chrono::Duration::days(7);
+/// Longest invite code accepted at `/auth`, so the OAuth `state` stays small.
+pub const MAX_CODE_LEN: usize = 64;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Invite {
+    pub code: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct InviteStore {
+    enabled: bool,
+    /// From `ADMINS` and `ALLOWED_USERS`.
+    allowed: HashSet<String>,
+    stored: Stored,
+}
+
+enum Stored {
+    Memory {
+        /// Users let in with an invite.
+        admitted: RwLock<HashSet<String>>,
+        /// Unused invites, by code.
+        invites: RwLock<HashMap<String, DateTime<Utc>>>,
+    },
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl InviteStore {
+    pub fn new<'a>(enabled: bool, allowed: impl IntoIterator<Item = &'a String>) -> Self {
+        Self {
+            enabled,
+            allowed: allowed.into_iter().cloned().collect(),
+            stored: Stored::Memory {
+                admitted: RwLock::default(),
+                invites: RwLock::default(),
+            },
+        }
+    }
+
+    /// Keeps invites and the users they let in in the database instead of in memory.
+    #[cfg(feature = "sql")]
+    pub fn in_database(self, pool: db::Pool) -> Self {
+        Self {
+            stored: Stored::Sql(pool),
+            ..self
+        }
+    }
+
+    pub const fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether `user_id` may log in, using up the invite `code` if it takes one.
+    pub async fn admit(&self, user_id: &str, code: Option<&str>) -> anyhow::Result<bool> {
+        if !self.enabled || self.allowed.contains(user_id) || self.admitted(user_id).await? {
+            return Ok(true);
+        }
+        let Some(code) = code else {
+            return Ok(false);
+        };
+        let used = match &self.stored {
+            Stored::Memory { admitted, invites } => {
+                let used = {
+                    let mut invites = invites.write().await;
+                    invites.retain(|_, expires_at| *expires_at > Utc::now());
+                    invites.remove(code).is_some()
+                };
+                if used {
+                    admitted.write().await.insert(user_id.to_owned());
+                }
+                used
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let used = sqlx::query("DELETE FROM invites WHERE code = $1 AND expires_at > $2")
+                    .bind(code)
+                    .bind(Utc::now().timestamp())
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected()
+                    > 0;
+                if used {
+                    sqlx::query(
+                        "INSERT INTO admitted_users (user_id) VALUES ($1) \
+                         ON CONFLICT (user_id) DO NOTHING",
+                    )
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await?;
+                used
+            }
+        };
+        if used {
+            tracing::info!("Letting in {user_id} with an invite");
+        }
+        Ok(used)
+    }
+
+    /// Whether `user_id` was let in with an invite before.
+    async fn admitted(&self, user_id: &str) -> anyhow::Result<bool> {
+        match &self.stored {
+            Stored::Memory { admitted, .. } => Ok(admitted.read().await.contains(user_id)),
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let found: Option<String> =
+                    sqlx::query_scalar("SELECT user_id FROM admitted_users WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_optional(pool)
+                        .await?;
+                Ok(found.is_some())
+            }
+        }
+    }
+
+    pub async fn mint(&self) -> anyhow::Result<Invite> {
+        let invite = Invite {
+            code: token::generate(token::STATE_BYTES),
+            expires_at: Utc::now() + INVITE_MAX_AGE,
+        };
+        match &self.stored {
+            Stored::Memory { invites, .. } => {
+                invites
+                    .write()
+                    .await
+                    .insert(invite.code.clone(), invite.expires_at);
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                // Expired invites are only cleared out here, as they can't be used anyway.
+                sqlx::query("DELETE FROM invites WHERE expires_at <= $1")
+                    .bind(Utc::now().timestamp())
+                    .execute(pool)
+                    .await?;
+                sqlx::query("INSERT INTO invites (code, expires_at) VALUES ($1, $2)")
+                    .bind(&invite.code)
+                    .bind(invite.expires_at.timestamp())
+                    .execute(pool)
+                    .await?;
+            }
+        }
+        Ok(invite)
+    }
+
+    /// The invites that can still be used, those expiring first first.
+    pub async fn list(&self) -> anyhow::Result<Vec<Invite>> {
+        let now = Utc::now();
+        let mut invites: Vec<Invite> = match &self.stored {
+            Stored::Memory { invites, .. } => invites
+                .read()
+                .await
+                .iter()
+                .filter(|(_, expires_at)| **expires_at > now)
+                .map(|(code, expires_at)| Invite {
+                    code: code.clone(),
+                    expires_at: *expires_at,
+                })
+                .collect(),
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let rows: Vec<(String, i64)> =
+                    sqlx::query_as("SELECT code, expires_at FROM invites WHERE expires_at > $1")
+                        .bind(now.timestamp())
+                        .fetch_all(pool)
+                        .await?;
+                rows.into_iter()
+                    .map(|(code, expires_at)| Invite {
+                        code,
+                        expires_at: DateTime::from_timestamp(expires_at, 0).unwrap_or_default(),
+                    })
+                    .collect()
+            }
+        };
+        invites.sort_unstable_by_key(|invite| invite.expires_at);
+        Ok(invites)
+    }
+
+    /// Revokes an unused invite, returning whether there was one.
+    pub async fn revoke(&self, code: &str) -> anyhow::Result<bool> {
+        match &self.stored {
+            Stored::Memory { invites, .. } => Ok(invites.write().await.remove(code).is_some()),
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM invites WHERE code = $1")
+                    .bind(code)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.stored {
+            Stored::Memory { invites, .. } => invites.try_read().ok().map(|i| i.len()),
+            #[cfg(feature = "sql")]
+            Stored::Sql(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lets_in_allowed_users_and_invitees() {
+        let open = InviteStore::new(false, &[]);
+        assert!(open.admit("ann", None).await.unwrap());
+
+        let admin = "admin".to_owned();
+        let store = InviteStore::new(true, [&admin]);
+        assert!(store.admit("admin", None).await.unwrap());
+        assert!(!store.admit("ann", None).await.unwrap());
+        assert!(!store.admit("ann", Some("made-up")).await.unwrap());
+        let invite = store.mint().await.unwrap();
+        let revoked = store.mint().await.unwrap();
+        assert_eq!(store.list().await.unwrap().len(), 2);
+        assert!(store.revoke(&revoked.code).await.unwrap());
+        assert!(!store.revoke(&revoked.code).await.unwrap());
+        assert!(!store.admit("ann", Some(&revoked.code)).await.unwrap());
+
+        assert!(store.admit("ann", Some(&invite.code)).await.unwrap());
+        assert!(!store.admit("bob", Some(&invite.code)).await.unwrap());
+        assert!(store.admit("ann", None).await.unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_invites_let_nobody_in() {
+        let store = InviteStore::new(true, &[]);
+        match &store.stored {
+            Stored::Memory { invites, .. } => invites
+                .write()
+                .await
+                .insert("old".to_owned(), Utc::now() - chrono::Duration::seconds(1)),
+            #[cfg(feature = "sql")]
+            Stored::Sql(_) => unreachable!(),
+        };
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(!store.admit("ann", Some("old")).await.unwrap());
+        assert_eq!(store.len_hint(), Some(0));
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn admissions_survive_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-invites-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        let store = InviteStore::new(true, &[]).in_database(pool.clone());
+        let invite = store.mint().await.unwrap();
+        let unused = store.mint().await.unwrap();
+        assert!(!store.admit("ann", None).await.unwrap());
+        assert!(store.admit("ann", Some(&invite.code)).await.unwrap());
+        assert!(!store.admit("bob", Some(&invite.code)).await.unwrap());
+
+        let store = InviteStore::new(true, &[]).in_database(pool);
+        assert!(store.admit("ann", None).await.unwrap());
+        assert!(!store.admit("bob", None).await.unwrap());
+        assert_eq!(store.list().await.unwrap()[0].code, unused.code);
+        assert!(store.revoke(&unused.code).await.unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,460 @@
+//! Background jobs for operations too long for a request: data exports, playlist imports and
+//! history imports. Starting one returns its ID right away; `GET /api/jobs/:id` reports how it's
+//! going and `GET /api/jobs/:id/result` fetches what it made once it succeeded. For showing a live
+//! progress bar, `GET /api/jobs/:id/stream` streams the same as it changes, along with what the
+//! job logs.
+//!
+//! Each user's jobs run one at a time, in the order they were started, so a user can't take all
+//! of our Spotify rate limit by starting many at once. Jobs and their results live in memory and
+//! are forgotten an hour after finishing.
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, OwnedSemaphorePermit, RwLock, Semaphore},
+    time::Instant,
+};
+
+use crate::token;
+
+/// How long finished jobs are kept for their results to be fetched.
+const KEPT_FOR: Duration = Duration::from_hours(1);
+/// Jobs running at once across all users.
+const CONCURRENCY: usize = 4;
+/// Log lines kept per job, for streams opened after the job started.
+const LOG_KEPT: usize = 100;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Export,
+    PlaylistImport,
+    HistoryImport,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    pub percent: u8,
+}
+
+impl JobState {
+    pub const fn finished(self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed)
+    }
+}
+
+/// What a job reports about itself.
+#[derive(Serialize, Clone, Debug)]
+pub struct JobStatus {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// For jobs that know how much there is to do.
+    pub progress: Option<Progress>,
+    /// Why the job failed.
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// What's streamed about a job.
+#[derive(Clone, Debug)]
+pub enum JobEvent {
+    /// The job changed state. A job's last event is the one of it finishing.
+    Status(JobStatus),
+    Progress(Progress),
+    Log(String),
+}
+
+/// What a successful job made.
+#[derive(Clone)]
+pub enum Output {
+    File {
+        content_type: &'static str,
+        filename: &'static str,
+        data: Bytes,
+    },
+    Json(serde_json::Value),
+}
+
+struct Job {
+    owner: String,
+    status: JobStatus,
+    output: Option<Output>,
+    finished: Option<Instant>,
+    /// The last [`LOG_KEPT`] lines, oldest first.
+    log: VecDeque<String>,
+    events: broadcast::Sender<JobEvent>,
+}
+
+pub struct JobQueue {
+    /// By job ID.
+    jobs: RwLock<HashMap<String, Job>>,
+    /// One permit per user, so each user's jobs run in turn. A std mutex, since it's only held
+    /// to look the permit up.
+    users: Mutex<HashMap<String, Arc<Semaphore>>>,
+    running: Arc<Semaphore>,
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self {
+            jobs: RwLock::default(),
+            users: Mutex::default(),
+            running: Arc::new(Semaphore::new(CONCURRENCY)),
+        }
+    }
+}
+
+impl JobQueue {
+    /// Queues `run` as a job of `owner`, returning its status. `run` gets a [`Reporter`] to
+    /// report progress with.
+    pub async fn submit<F, Fut>(self: &Arc<Self>, owner: &str, kind: JobKind, run: F) -> JobStatus
+    where
+        F: FnOnce(Reporter) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<Output>> + Send + 'static,
+    {
+        let status = JobStatus {
+            id: token::generate(12),
+            kind,
+            state: JobState::Queued,
+            progress: None,
+            error: None,
+            created_at: Utc::now(),
+            finished_at: None,
+        };
+        {
+            let mut jobs = self.jobs.write().await;
+            jobs.retain(|_, job| !job.expired());
+            jobs.insert(
+                status.id.clone(),
+                Job {
+                    owner: owner.to_owned(),
+                    status: status.clone(),
+                    output: None,
+                    finished: None,
+                    log: VecDeque::new(),
+                    events: broadcast::channel(64).0,
+                },
+            );
+        }
+        let turn = Arc::clone(
+            self.users
+                .lock()
+                .expect("job users poisoned")
+                .entry(owner.to_owned())
+                .or_insert_with(|| Arc::new(Semaphore::new(1))),
+        );
+        let queue = Arc::clone(self);
+        let id = status.id.clone();
+        tokio::spawn(async move {
+            let permits = acquire(turn, Arc::clone(&queue.running)).await;
+            queue
+                .update(&id, |job| {
+                    job.status.state = JobState::Running;
+                    JobEvent::Status(job.status.clone())
+                })
+                .await;
+            let reporter = Reporter {
+                queue: Arc::clone(&queue),
+                id: id.clone(),
+            };
+            let result = run(reporter).await;
+            drop(permits);
+            if let Err(e) = &result {
+                tracing::warn!("Job {id} failed: {e:#}");
+            }
+            let mut jobs = queue.jobs.write().await;
+            let Some(job) = jobs.get_mut(&id) else {
+                return;
+            };
+            job.status.finished_at = Some(Utc::now());
+            job.finished = Some(Instant::now());
+            match result {
+                Ok(output) => {
+                    job.status.state = JobState::Succeeded;
+                    job.output = Some(output);
+                }
+                Err(e) => {
+                    job.status.state = JobState::Failed;
+                    job.status.error = Some(format!("{e:#}"));
+                }
+            }
+            let _ = job.events.send(JobEvent::Status(job.status.clone()));
+        });
+        status
+    }
+
+    /// A job of `owner`. Other users' jobs are as good as nonexistent.
+    pub async fn get(&self, owner: &str, id: &str) -> Option<JobStatus> {
+        self.jobs
+            .read()
+            .await
+            .get(id)
+            .filter(|job| job.owner == owner && !job.expired())
+            .map(|job| job.status.clone())
+    }
+
+    /// What a job of `owner` made, once it succeeded.
+    pub async fn output(&self, owner: &str, id: &str) -> Option<Output> {
+        self.jobs
+            .read()
+            .await
+            .get(id)
+            .filter(|job| job.owner == owner && !job.expired())
+            .and_then(|job| job.output.clone())
+    }
+
+    /// Subscribes to the events of a job of `owner`, returning its status and log so far along
+    /// with them.
+    pub async fn subscribe(
+        &self,
+        owner: &str,
+        id: &str,
+    ) -> Option<(JobStatus, Vec<String>, broadcast::Receiver<JobEvent>)> {
+        self.jobs
+            .read()
+            .await
+            .get(id)
+            .filter(|job| job.owner == owner && !job.expired())
+            .map(|job| {
+                let log = job.log.iter().cloned().collect();
+                (job.status.clone(), log, job.events.subscribe())
+            })
+    }
+
+    /// Jobs of `owner`, newest first.
+    pub async fn owned_by(&self, owner: &str) -> Vec<JobStatus> {
+        let mut jobs: Vec<JobStatus> = self
+            .jobs
+            .read()
+            .await
+            .values()
+            .filter(|job| job.owner == owner && !job.expired())
+            .map(|job| job.status.clone())
+            .collect();
+        jobs.sort_by_key(|job| Reverse(job.created_at));
+        jobs
+    }
+
+    /// Forgets every job of `owner`, returning how many there were. Jobs still running finish,
+    /// but their results are dropped.
+    pub async fn remove_owned_by(&self, owner: &str) -> usize {
+        let mut jobs = self.jobs.write().await;
+        let before = jobs.len();
+        jobs.retain(|_, job| job.owner != owner);
+        before - jobs.len()
+    }
+
+    /// Changes a job with `f`, sending the event it returns to subscribers.
+    async fn update(&self, id: &str, f: impl FnOnce(&mut Job) -> JobEvent) {
+        if let Some(job) = self.jobs.write().await.get_mut(id) {
+            let event = f(job);
+            // Nobody may be listening.
+            let _ = job.events.send(event);
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.jobs.try_read().ok().map(|j| j.len())
+    }
+}
+
+impl Job {
+    fn expired(&self) -> bool {
+        self.finished.is_some_and(|at| at.elapsed() > KEPT_FOR)
+    }
+}
+
+/// Waits for the user's turn first, so one user's queue doesn't hold up others' jobs.
+async fn acquire(
+    turn: Arc<Semaphore>,
+    running: Arc<Semaphore>,
+) -> (OwnedSemaphorePermit, OwnedSemaphorePermit) {
+    let turn = turn.acquire_owned().await.expect("job semaphore closed");
+    let running = running.acquire_owned().await.expect("job semaphore closed");
+    (turn, running)
+}
+
+/// Lets a running job report its progress, and log what it's doing for the user to see.
+pub struct Reporter {
+    queue: Arc<JobQueue>,
+    id: String,
+}
+
+impl Reporter {
+    pub async fn progress(&self, done: usize, total: usize) {
+        let percent = (done.min(total) * 100)
+            .checked_div(total)
+            .map_or(100, |percent| u8::try_from(percent).unwrap_or(100));
+        let progress = Progress {
+            done,
+            total,
+            percent,
+        };
+        self.queue
+            .update(&self.id, |job| {
+                job.status.progress = Some(progress);
+                JobEvent::Progress(progress)
+            })
+            .await;
+    }
+
+    pub async fn log(&self, line: impl Into<String>) {
+        let line = line.into();
+        self.queue
+            .update(&self.id, |job| {
+                if job.log.len() == LOG_KEPT {
+                    job.log.pop_front();
+                }
+                job.log.push_back(line.clone());
+                JobEvent::Log(line)
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    /// Waits until the job is in `state`, failing the test if that takes long.
+    async fn reaches(queue: &JobQueue, owner: &str, id: &str, state: JobState) -> JobStatus {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match queue.get(owner, id).await {
+                    Some(status) if status.state == state => return status,
+                    _ => tokio::time::sleep(Duration::from_millis(5)).await,
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("job {id} never got {state:?}"))
+    }
+
+    fn json(value: &str) -> Output {
+        Output::Json(serde_json::json!(value))
+    }
+
+    #[tokio::test]
+    async fn runs_each_users_jobs_in_turn() {
+        let queue = Arc::new(JobQueue::default());
+        let (release, released) = oneshot::channel::<()>();
+        let first = queue
+            .submit("ann", JobKind::Export, |_| async move {
+                released.await?;
+                Ok(json("first"))
+            })
+            .await;
+        let second = queue
+            .submit("ann", JobKind::Export, |_| async { Ok(json("second")) })
+            .await;
+        let other = queue
+            .submit("bob", JobKind::Export, |_| async { Ok(json("other")) })
+            .await;
+
+        reaches(&queue, "ann", &first.id, JobState::Running).await;
+        reaches(&queue, "bob", &other.id, JobState::Succeeded).await;
+        assert_eq!(
+            queue.get("ann", &second.id).await.unwrap().state,
+            JobState::Queued
+        );
+        release.send(()).unwrap();
+        let status = reaches(&queue, "ann", &second.id, JobState::Succeeded).await;
+        assert!(status.finished_at.is_some());
+        assert_eq!(
+            queue.get("ann", &first.id).await.unwrap().state,
+            JobState::Succeeded
+        );
+        let owned: Vec<_> = queue
+            .owned_by("ann")
+            .await
+            .into_iter()
+            .map(|j| j.id)
+            .collect();
+        assert_eq!(owned.len(), 2);
+        assert!(owned.contains(&first.id) && owned.contains(&second.id));
+    }
+
+    #[tokio::test]
+    async fn reports_progress_and_logs_to_the_owner_only() {
+        let queue = Arc::new(JobQueue::default());
+        let (release, released) = oneshot::channel::<()>();
+        let job = queue
+            .submit("ann", JobKind::HistoryImport, |reporter| async move {
+                reporter.log("Reading").await;
+                released.await?;
+                reporter.progress(1, 4).await;
+                reporter.log("Saving").await;
+                Ok(json("done"))
+            })
+            .await;
+        assert!(queue.subscribe("bob", &job.id).await.is_none());
+        reaches(&queue, "ann", &job.id, JobState::Running).await;
+        let (status, log, mut events) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let subscribed = queue.subscribe("ann", &job.id).await.unwrap();
+                if !subscribed.1.is_empty() {
+                    return subscribed;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status.state, JobState::Running);
+        assert_eq!(log, ["Reading"]);
+
+        release.send(()).unwrap();
+        let JobEvent::Progress(progress) = events.recv().await.unwrap() else {
+            panic!("expected progress first");
+        };
+        assert_eq!(progress.percent, 25);
+        assert!(matches!(events.recv().await.unwrap(), JobEvent::Log(line) if line == "Saving"));
+        let JobEvent::Status(status) = events.recv().await.unwrap() else {
+            panic!("expected the job to finish");
+        };
+        assert_eq!(status.state, JobState::Succeeded);
+        assert!(queue.output("bob", &job.id).await.is_none());
+        assert!(matches!(
+            queue.output("ann", &job.id).await,
+            Some(Output::Json(value)) if value == "done"
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_keep_their_error_until_removed() {
+        let queue = Arc::new(JobQueue::default());
+        let job = queue
+            .submit("ann", JobKind::PlaylistImport, |_| async {
+                anyhow::bail!("no playlist")
+            })
+            .await;
+        let status = reaches(&queue, "ann", &job.id, JobState::Failed).await;
+        assert_eq!(status.error.as_deref(), Some("no playlist"));
+        assert!(queue.output("ann", &job.id).await.is_none());
+        assert_eq!(queue.remove_owned_by("bob").await, 0);
+        assert_eq!(queue.remove_owned_by("ann").await, 1);
+        assert!(queue.get("ann", &job.id).await.is_none());
+    }
+}
@@ -0,0 +1,54 @@
+//! JSON arrays sent as their items come in, for responses of thousands of items such as a whole
+//! library. Items are serialized one at a time and written out in batches, so neither the items
+//! nor the JSON of all of them need to be held in memory at once.
+
+use axum::{
+    body::{Body, Bytes},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Serialize;
+
+/// Items serialized into one write of the response body at most.
+const BATCH: usize = 256;
+
+/// Responds with the items of a stream as a JSON array. The status is sent before the first item
+/// is known, so an error midway fails the response body instead, and clients see a truncated
+/// response rather than an incomplete array.
+pub struct JsonArray<S>(pub S);
+
+impl<S, T> IntoResponse for JsonArray<S>
+where
+    S: Stream<Item = anyhow::Result<T>> + Send + 'static,
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let items = self
+            .0
+            .enumerate()
+            .map(|(i, item)| {
+                let mut json = if i == 0 { Vec::new() } else { vec![b','] };
+                serde_json::to_writer(&mut json, &item?)?;
+                anyhow::Ok(json)
+            })
+            .ready_chunks(BATCH)
+            .map(|batch| {
+                let batch: Vec<Vec<u8>> = batch.into_iter().collect::<anyhow::Result<_>>()?;
+                anyhow::Ok(Bytes::from(batch.concat()))
+            })
+            .inspect(|batch| {
+                if let Err(e) = batch {
+                    tracing::warn!("Failed to stream JSON array: {e:#}");
+                }
+            });
+        let body = stream::once(future::ok(Bytes::from_static(b"[")))
+            .chain(items)
+            .chain(stream::once(future::ok(Bytes::from_static(b"]"))));
+        (
+            [(header::CONTENT_TYPE, "application/json")],
+            Body::from_stream(body),
+        )
+            .into_response()
+    }
+}
@@ -0,0 +1,223 @@
+//! Which instance runs the background workers that must only run once however many instances
+//! there are: the history collector, the weekly digest and the release watcher, which would
+//! otherwise poll Spotify once per instance and send the same emails and webhooks several times.
+//!
+//! The instance holding a lease runs them. It renews the lease every [`RENEW_INTERVAL`], and if
+//! it stops, another instance takes the lease over once it lapses after [`LEASE`]. The lease is
+//! kept in the database with `DATABASE_URL` set and a SQL feature, or in Redis with the
+//! `redis-store` feature and `REDIS_URL` set. Without either, there's nothing shared between
+//! instances, so each one leads itself.
+//!
+//! Workers over stores that only live in an instance's memory, like smart playlist rules without
+//! a database, run on every instance, since each one has its own.
+
+#[cfg(any(feature = "redis-store", feature = "sql"))]
+use once_cell::sync::Lazy;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{config::Config, AppStateInner};
+
+/// How long a lease lasts without being renewed, and so how long workers can go without running
+/// after the instance running them stops.
+#[cfg(any(feature = "redis-store", feature = "sql"))]
+pub const LEASE: Duration = Duration::from_secs(30);
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+#[cfg(any(feature = "redis-store", feature = "sql"))]
+const WORKERS_LEASE: &str = "workers";
+
+/// Random, identifying this instance in leases.
+#[cfg(any(feature = "redis-store", feature = "sql"))]
+pub static INSTANCE: Lazy<String> = Lazy::new(|| crate::token::generate(16));
+
+/// Takes the lease in `KEYS[1]` for the holder `ARGV[1]` for `ARGV[2]` milliseconds, unless
+/// another holder has it. Returns whether the holder has it.
+#[cfg(feature = "redis-store")]
+static TAKE_LEASE: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        end
+        if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+/// Takes or renews the lease `key` in Redis for this instance, returning whether it has it.
+#[cfg(feature = "redis-store")]
+pub async fn take_redis_lease(
+    conn: &mut redis::aio::ConnectionManager,
+    key: &str,
+    lease: Duration,
+) -> anyhow::Result<bool> {
+    Ok(TAKE_LEASE
+        .key(key)
+        .arg(INSTANCE.as_str())
+        .arg(u64::try_from(lease.as_millis())?)
+        .invoke_async(conn)
+        .await?)
+}
+
+pub enum Lease {
+    Local,
+    #[cfg(feature = "redis-store")]
+    Redis(redis::aio::ConnectionManager),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Lease {
+    #[allow(clippy::unused_async)]
+    pub async fn connect(config: &Config) -> anyhow::Result<Self> {
+        #[cfg(feature = "redis-store")]
+        if let Some(url) = &config.redis_url {
+            let client = redis::Client::open(url.as_str())?;
+            return Ok(Self::Redis(client.get_connection_manager().await?));
+        }
+        let _ = config;
+        Ok(Self::Local)
+    }
+
+    /// Takes or renews the lease on the workers, returning whether this instance has it.
+    #[cfg_attr(
+        not(any(feature = "redis-store", feature = "sql")),
+        allow(clippy::unused_async)
+    )]
+    async fn take(&self) -> anyhow::Result<bool> {
+        match self {
+            Self::Local => Ok(true),
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => {
+                take_redis_lease(&mut conn.clone(), &format!("leader:{WORKERS_LEASE}"), LEASE).await
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                take_sql_lease(pool, INSTANCE.as_str(), chrono::Utc::now().timestamp()).await
+            }
+        }
+    }
+}
+
+/// Takes or renews the lease on the workers in the database for `holder` at `now`, in seconds,
+/// returning whether `holder` has it.
+#[cfg(feature = "sql")]
+async fn take_sql_lease(pool: &db::Pool, holder: &str, now: i64) -> anyhow::Result<bool> {
+    let taken = sqlx::query(
+        "INSERT INTO leases (name, holder, expires_at) VALUES ($1, $2, $3) \
+         ON CONFLICT (name) DO UPDATE \
+         SET holder = excluded.holder, expires_at = excluded.expires_at \
+         WHERE leases.holder = excluded.holder OR leases.expires_at <= $4",
+    )
+    .bind(WORKERS_LEASE)
+    .bind(holder)
+    .bind(now + i64::try_from(LEASE.as_secs())?)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    Ok(taken.rows_affected() == 1)
+}
+
+pub struct Leadership {
+    lease: Lease,
+    held: AtomicBool,
+}
+
+impl Leadership {
+    pub const fn new(lease: Lease) -> Self {
+        Self {
+            lease,
+            held: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether this instance runs the singleton workers, as of the last renewal.
+    pub fn holds(&self) -> bool {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    async fn renew(&self) {
+        let held = match self.lease.take().await {
+            Ok(held) => held,
+            Err(e) => {
+                // Without knowing, stepping down risks nobody running the workers for a while,
+                // which is better than two instances running them.
+                tracing::warn!("Failed to renew the workers lease: {e:#}");
+                false
+            }
+        };
+        if self.held.swap(held, Ordering::Relaxed) != held {
+            if held {
+                tracing::info!("This instance now runs the singleton workers");
+            } else {
+                tracing::info!("Another instance now runs the singleton workers");
+            }
+        }
+    }
+}
+
+/// Takes the lease if it's free, before the workers check it for the first time, then keeps
+/// renewing it in the background.
+pub async fn start(state: Arc<AppStateInner>) {
+    state.leader.renew().await;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RENEW_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            state.leader.renew().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn leads_itself_alone() {
+        let leader = Leadership::new(Lease::Local);
+        assert!(!leader.holds());
+        leader.renew().await;
+        assert!(leader.holds());
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn one_instance_holds_the_lease_until_it_lapses() {
+        let path =
+            std::env::temp_dir().join(format!("blid-leader-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        let lease = i64::try_from(LEASE.as_secs()).unwrap();
+        let now = chrono::Utc::now().timestamp();
+        assert!(take_sql_lease(&pool, "a", now).await.unwrap());
+        assert!(!take_sql_lease(&pool, "b", now).await.unwrap());
+        // Renewing pushes the lapse back.
+        assert!(take_sql_lease(&pool, "a", now + lease - 1).await.unwrap());
+        assert!(!take_sql_lease(&pool, "b", now + lease).await.unwrap());
+        let lapsed = now + 2 * lease - 1;
+        assert!(take_sql_lease(&pool, "b", lapsed).await.unwrap());
+        assert!(!take_sql_lease(&pool, "a", lapsed).await.unwrap());
+
+        // Not knowing whether it still holds the lease, an instance steps down.
+        let leader = Leadership::new(Lease::Sql(pool.clone()));
+        leader.held.store(true, Ordering::Relaxed);
+        pool.close().await;
+        leader.renew().await;
+        assert!(!leader.holds());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,16 @@
+//! The parts of the request path that take apart what clients send, as a library so the fuzz
+//! targets in `fuzz/` can reach them: cookies, and the OAuth `state` and callback query of the
+//! login flow. The server uses them from here. Their tests run the properties the fuzz targets check
+//! under `cargo test` too, on fewer and simpler inputs.
+
+// Only the server and the fuzz targets use this library, neither of which needs these.
+#![allow(
+    clippy::must_use_candidate,
+    clippy::missing_errors_doc,
+    clippy::missing_panics_doc
+)]
+
+pub mod cookies;
+pub mod login_state;
+pub mod redact;
+pub mod token;
@@ -0,0 +1,281 @@
+//! A user's saved tracks, joined with what Spotify knows about them but doesn't return along
+//! with them: audio features and the genres of their artists.
+
+use futures::TryStreamExt;
+#[cfg(any(feature = "library", feature = "stats"))]
+use futures::{stream, Stream};
+use itertools::Itertools;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+
+use crate::{
+    cache::Cache,
+    spotify::{self, AudioAnalysis, AudioFeatures, SavedTrack, Section, Track},
+};
+
+/// Artist genres rarely change.
+const GENRE_TTL: Duration = Duration::from_hours(7 * 24);
+/// Saved tracks whose genres [`tagged_stream`] looks up at once.
+#[cfg(any(feature = "library", feature = "stats"))]
+const TAGGED_BATCH: usize = 500;
+
+/// Genres of artists we've looked up before. Spotify only tags artists, not tracks, and artist
+/// genres rarely change, so they are kept for a week and shared by every user.
+#[derive(Clone)]
+pub struct GenreCache {
+    cache: Arc<dyn Cache>,
+}
+
+impl GenreCache {
+    pub fn new(cache: Arc<dyn Cache>) -> Self {
+        Self { cache }
+    }
+
+    /// Genres of each of `artist_ids`, only asking Spotify about artists not seen before.
+    pub async fn resolve(
+        &self,
+        access_token: &str,
+        artist_ids: impl IntoIterator<Item = &str>,
+    ) -> anyhow::Result<HashMap<String, Vec<String>>> {
+        let wanted: Vec<String> = artist_ids.into_iter().unique().map(str::to_owned).collect();
+        let keys: Vec<String> = wanted.iter().map(|id| format!("genres:{id}")).collect();
+        let cached: Vec<Option<Vec<String>>> = self.cache.get_many_json(&keys).await?;
+        let mut genres = HashMap::new();
+        let mut missing = Vec::new();
+        for (id, cached) in wanted.into_iter().zip(cached) {
+            match cached {
+                Some(cached) => {
+                    genres.insert(id, cached);
+                }
+                None => missing.push(id),
+            }
+        }
+        if !missing.is_empty() {
+            for artist in spotify::artists(access_token, &missing).await? {
+                self.cache
+                    .set_json(
+                        &format!("genres:{}", artist.id),
+                        &artist.genres,
+                        Some(GENRE_TTL),
+                    )
+                    .await?;
+                genres.insert(artist.id, artist.genres);
+            }
+        }
+        Ok(genres)
+    }
+
+    /// For each of `tracks`, the union of its artists' genres.
+    pub async fn of_tracks<'a>(
+        &self,
+        access_token: &str,
+        tracks: impl IntoIterator<Item = &'a Track> + Clone,
+    ) -> anyhow::Result<Vec<Vec<String>>> {
+        let artist_ids: Vec<&str> = tracks
+            .clone()
+            .into_iter()
+            .flat_map(|t| t.artists.iter().map(|a| a.id.as_str()))
+            .collect();
+        let genres = self.resolve(access_token, artist_ids).await?;
+        Ok(tracks
+            .into_iter()
+            .map(|track| {
+                track
+                    .artists
+                    .iter()
+                    .filter_map(|a| genres.get(&a.id))
+                    .flatten()
+                    .unique()
+                    .cloned()
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// Audio features of tracks we've looked up before. They're computed once by Spotify and never
+/// change, so they're kept without expiry. Tracks without an analysis are remembered too, so
+/// they aren't asked about again.
+pub struct FeatureCache {
+    cache: Arc<dyn Cache>,
+}
+
+impl FeatureCache {
+    pub fn new(cache: Arc<dyn Cache>) -> Self {
+        Self { cache }
+    }
+
+    /// Features of each of `track_ids` that Spotify has an analysis for.
+    pub async fn resolve(
+        &self,
+        access_token: &str,
+        track_ids: &[String],
+    ) -> anyhow::Result<HashMap<String, AudioFeatures>> {
+        let wanted: Vec<&String> = track_ids.iter().unique().collect();
+        let keys: Vec<String> = wanted.iter().map(|id| format!("features:{id}")).collect();
+        // `Some(None)` for tracks known to have no analysis.
+        let cached: Vec<Option<Option<AudioFeatures>>> = self.cache.get_many_json(&keys).await?;
+        let mut features = HashMap::new();
+        let mut missing = Vec::new();
+        for (id, cached) in wanted.into_iter().zip(cached) {
+            match cached {
+                Some(cached) => features.extend(cached.map(|f| (id.clone(), f))),
+                None => missing.push(id.clone()),
+            }
+        }
+        if !missing.is_empty() {
+            let mut fetched: HashMap<String, AudioFeatures> =
+                spotify::audio_features(access_token, &missing)
+                    .await?
+                    .into_iter()
+                    .map(|f| (f.id.clone(), f))
+                    .collect();
+            for id in missing {
+                let found = fetched.remove(&id);
+                self.cache
+                    .set_json(&format!("features:{id}"), &found, None)
+                    .await?;
+                features.extend(found.map(|f| (id, f)));
+            }
+        }
+        Ok(features)
+    }
+}
+
+/// How a track ends, from its audio analysis, for frontends preparing the transition to the
+/// next one. Times are in seconds.
+#[derive(Serialize, Clone)]
+pub struct Ending {
+    pub duration: f32,
+    pub start_of_fade_out: f32,
+    /// Sections still playing when the fade-out starts, with the last one at least.
+    pub sections: Vec<Section>,
+}
+
+impl From<AudioAnalysis> for Ending {
+    fn from(analysis: AudioAnalysis) -> Self {
+        let fade_out = analysis.track.start_of_fade_out;
+        let last = analysis.sections.len().saturating_sub(1);
+        Self {
+            duration: analysis.track.duration,
+            start_of_fade_out: fade_out,
+            sections: analysis
+                .sections
+                .into_iter()
+                .enumerate()
+                .filter(|(i, section)| *i == last || section.start + section.duration > fade_out)
+                .map(|(_, section)| section)
+                .collect(),
+        }
+    }
+}
+
+/// Endings of tracks we've analyzed before. Like audio features, analyses never change, and only
+/// the ending is kept since full analyses are large.
+#[derive(Default)]
+pub struct EndingCache {
+    tracks: RwLock<HashMap<String, Option<Ending>>>,
+}
+
+impl EndingCache {
+    /// The ending of a track, or `None` if Spotify has no analysis of it.
+    #[cfg(feature = "player")]
+    pub async fn resolve(
+        &self,
+        access_token: &str,
+        track_id: &str,
+    ) -> anyhow::Result<Option<Ending>> {
+        if let Some(ending) = self.tracks.read().await.get(track_id) {
+            return Ok(ending.clone());
+        }
+        let ending = spotify::audio_analysis(access_token, track_id)
+            .await?
+            .map(Ending::from);
+        self.tracks
+            .write()
+            .await
+            .insert(track_id.to_owned(), ending.clone());
+        Ok(ending)
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.tracks.try_read().ok().map(|t| t.len())
+    }
+}
+
+/// A saved track along with its genres.
+#[derive(Serialize)]
+pub struct TaggedTrack {
+    #[serde(flatten)]
+    pub saved: SavedTrack,
+    pub genres: Vec<String>,
+}
+
+/// Every saved track with its genres.
+pub async fn tagged(access_token: &str, genres: &GenreCache) -> anyhow::Result<Vec<TaggedTrack>> {
+    let saved: Vec<SavedTrack> = spotify::saved_tracks(access_token).try_collect().await?;
+    let track_genres = genres
+        .of_tracks(access_token, saved.iter().map(|s| &s.track))
+        .await?;
+    Ok(saved
+        .into_iter()
+        .zip(track_genres)
+        .map(|(saved, genres)| TaggedTrack { saved, genres })
+        .collect())
+}
+
+/// Like [`tagged`], yielding tracks a batch at a time as they come in, so that a large library is
+/// never all in memory.
+#[cfg(any(feature = "library", feature = "stats"))]
+pub fn tagged_stream(
+    access_token: String,
+    genres: GenreCache,
+) -> impl Stream<Item = anyhow::Result<TaggedTrack>> + Send {
+    spotify::saved_tracks(&access_token)
+        .try_chunks(TAGGED_BATCH)
+        .map_err(|e| e.1)
+        .and_then(move |saved| {
+            let (access_token, genres) = (access_token.clone(), genres.clone());
+            async move {
+                let tracks: Vec<&Track> = saved.iter().map(|s| &s.track).collect();
+                let track_genres = genres
+                    .of_tracks(&access_token, tracks.iter().copied())
+                    .await?;
+                Ok(stream::iter(
+                    saved
+                        .into_iter()
+                        .zip(track_genres)
+                        .map(|(saved, genres)| Ok(TaggedTrack { saved, genres })),
+                ))
+            }
+        })
+        .try_flatten()
+}
+
+pub struct LibraryTrack {
+    pub saved: SavedTrack,
+    pub features: Option<AudioFeatures>,
+    /// Union of the genres of the track's artists.
+    pub genres: Vec<String>,
+}
+
+pub async fn load(
+    access_token: &str,
+    genres: &GenreCache,
+    features: &FeatureCache,
+) -> anyhow::Result<Vec<LibraryTrack>> {
+    let tagged = tagged(access_token, genres).await?;
+
+    let track_ids: Vec<String> = tagged.iter().map(|t| t.saved.track.id.clone()).collect();
+    let mut features = features.resolve(access_token, &track_ids).await?;
+
+    Ok(tagged
+        .into_iter()
+        .map(|TaggedTrack { saved, genres }| LibraryTrack {
+            features: features.remove(&saved.track.id),
+            genres,
+            saved,
+        })
+        .collect())
+}
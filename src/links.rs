@@ -0,0 +1,314 @@
+//! Accounts linked to compare listening, e.g. two people sharing an instance or one person with a
+//! second Spotify account. Linking takes both sides: one user mints a short-lived invite code,
+//! and the other accepts it while logged in. Either side can unlink at any time.
+
+#[cfg(feature = "sql")]
+use chrono::Utc;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::Instant};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::token;
+
+/// How long an invite can be accepted for.
+const INVITE_MAX_AGE: Duration = Duration::from_hours(24);
+
+struct Invite {
+    owner: String,
+    expires_at: Instant,
+}
+
+/// In memory by default, or in the database with a SQL feature and `DATABASE_URL` set, so an
+/// invite minted through one instance can be accepted through another.
+pub struct LinkStore {
+    stored: Stored,
+}
+
+enum Stored {
+    Memory {
+        invites: RwLock<HashMap<String, Invite>>,
+        /// Each user's linked users. Links go both ways, so each is in both sets.
+        links: RwLock<HashMap<String, HashSet<String>>>,
+    },
+    /// Links go both ways, so each has a row for either side.
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for LinkStore {
+    fn default() -> Self {
+        Self {
+            stored: Stored::Memory {
+                invites: RwLock::default(),
+                links: RwLock::default(),
+            },
+        }
+    }
+}
+
+impl LinkStore {
+    #[cfg(feature = "sql")]
+    pub const fn in_database(pool: db::Pool) -> Self {
+        Self {
+            stored: Stored::Sql(pool),
+        }
+    }
+
+    /// Mints an invite to link with `owner`, returning its code.
+    pub async fn invite(&self, owner: &str) -> anyhow::Result<String> {
+        let code = token::generate(token::STATE_BYTES);
+        match &self.stored {
+            Stored::Memory { invites, .. } => {
+                let mut invites = invites.write().await;
+                invites.retain(|_, invite| invite.expires_at > Instant::now());
+                invites.insert(
+                    code.clone(),
+                    Invite {
+                        owner: owner.to_owned(),
+                        expires_at: Instant::now() + INVITE_MAX_AGE,
+                    },
+                );
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let now = Utc::now().timestamp();
+                sqlx::query("DELETE FROM link_invites WHERE expires_at <= $1")
+                    .bind(now)
+                    .execute(pool)
+                    .await?;
+                sqlx::query(
+                    "INSERT INTO link_invites (code, owner, expires_at) VALUES ($1, $2, $3)",
+                )
+                .bind(&code)
+                .bind(owner)
+                .bind(now + i64::try_from(INVITE_MAX_AGE.as_secs())?)
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(code)
+    }
+
+    /// Links `user_id` with whoever minted the invite `code`, returning them. An invite is used
+    /// up by being accepted, and can't be accepted by its owner.
+    pub async fn accept(&self, code: &str, user_id: &str) -> anyhow::Result<Option<String>> {
+        match &self.stored {
+            Stored::Memory { invites, links } => {
+                let owner = {
+                    let mut invites = invites.write().await;
+                    let Some(invite) = invites.get(code).filter(|invite| {
+                        invite.expires_at > Instant::now() && invite.owner != user_id
+                    }) else {
+                        return Ok(None);
+                    };
+                    let owner = invite.owner.clone();
+                    invites.remove(code);
+                    owner
+                };
+                let mut links = links.write().await;
+                links
+                    .entry(owner.clone())
+                    .or_default()
+                    .insert(user_id.to_owned());
+                links
+                    .entry(user_id.to_owned())
+                    .or_default()
+                    .insert(owner.clone());
+                Ok(Some(owner))
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let owner: Option<String> = sqlx::query_scalar(
+                    "DELETE FROM link_invites \
+                     WHERE code = $1 AND expires_at > $2 AND owner <> $3 RETURNING owner",
+                )
+                .bind(code)
+                .bind(Utc::now().timestamp())
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+                if let Some(owner) = &owner {
+                    sqlx::query(
+                        "INSERT INTO links (user_id, linked_id) VALUES ($1, $2), ($2, $1) \
+                         ON CONFLICT (user_id, linked_id) DO NOTHING",
+                    )
+                    .bind(owner)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await?;
+                Ok(owner)
+            }
+        }
+    }
+
+    /// The users linked with `user_id`, sorted.
+    pub async fn linked(&self, user_id: &str) -> anyhow::Result<Vec<String>> {
+        match &self.stored {
+            Stored::Memory { links, .. } => {
+                let mut linked: Vec<String> = links
+                    .read()
+                    .await
+                    .get(user_id)
+                    .into_iter()
+                    .flatten()
+                    .cloned()
+                    .collect();
+                linked.sort_unstable();
+                Ok(linked)
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => Ok(sqlx::query_scalar(
+                "SELECT linked_id FROM links WHERE user_id = $1 ORDER BY linked_id",
+            )
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?),
+        }
+    }
+
+    pub async fn are_linked(&self, a: &str, b: &str) -> anyhow::Result<bool> {
+        match &self.stored {
+            Stored::Memory { links, .. } => Ok(links
+                .read()
+                .await
+                .get(a)
+                .is_some_and(|linked| linked.contains(b))),
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let found: Option<String> = sqlx::query_scalar(
+                    "SELECT linked_id FROM links WHERE user_id = $1 AND linked_id = $2",
+                )
+                .bind(a)
+                .bind(b)
+                .fetch_optional(pool)
+                .await?;
+                Ok(found.is_some())
+            }
+        }
+    }
+
+    /// Unlinks two users, returning whether they were linked.
+    pub async fn unlink(&self, a: &str, b: &str) -> anyhow::Result<bool> {
+        match &self.stored {
+            Stored::Memory { links, .. } => {
+                let mut links = links.write().await;
+                let mut remove = |from: &str, other: &str| {
+                    links
+                        .get_mut(from)
+                        .is_some_and(|linked| linked.remove(other))
+                };
+                let was_linked = remove(a, b);
+                remove(b, a);
+                Ok(was_linked)
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let deleted = sqlx::query(
+                    "DELETE FROM links \
+                     WHERE (user_id = $1 AND linked_id = $2) OR (user_id = $2 AND linked_id = $1)",
+                )
+                .bind(a)
+                .bind(b)
+                .execute(pool)
+                .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Unlinks a user from everyone and drops their invites, returning how many links there were.
+    pub async fn remove_owned_by(&self, user_id: &str) -> anyhow::Result<usize> {
+        match &self.stored {
+            Stored::Memory { invites, links } => {
+                invites
+                    .write()
+                    .await
+                    .retain(|_, invite| invite.owner != user_id);
+                let mut links = links.write().await;
+                let linked = links.remove(user_id).unwrap_or_default();
+                for other in &linked {
+                    if let Some(theirs) = links.get_mut(other) {
+                        theirs.remove(user_id);
+                    }
+                }
+                Ok(linked.len())
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query("DELETE FROM link_invites WHERE owner = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                let deleted = sqlx::query("DELETE FROM links WHERE user_id = $1 OR linked_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                // Each link has a row for either side.
+                Ok(usize::try_from(deleted.rows_affected() / 2)?)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.stored {
+            Stored::Memory { links, .. } => links.try_read().ok().map(|l| l.len()),
+            #[cfg(feature = "sql")]
+            Stored::Sql(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn links_both_ways(store: &LinkStore) {
+        let code = store.invite("bob").await.unwrap();
+        assert_eq!(store.accept(&code, "bob").await.unwrap(), None);
+        assert_eq!(
+            store.accept(&code, "ann").await.unwrap().as_deref(),
+            Some("bob")
+        );
+        assert_eq!(store.accept(&code, "cat").await.unwrap(), None);
+        assert!(store.are_linked("ann", "bob").await.unwrap());
+        assert!(store.are_linked("bob", "ann").await.unwrap());
+        assert_eq!(store.linked("bob").await.unwrap(), ["ann"]);
+
+        let code = store.invite("cat").await.unwrap();
+        store.accept(&code, "bob").await.unwrap();
+        assert_eq!(store.linked("bob").await.unwrap(), ["ann", "cat"]);
+        assert!(store.unlink("ann", "bob").await.unwrap());
+        assert!(!store.unlink("bob", "ann").await.unwrap());
+        assert!(!store.are_linked("ann", "bob").await.unwrap());
+
+        store.invite("bob").await.unwrap();
+        assert_eq!(store.remove_owned_by("bob").await.unwrap(), 1);
+        assert!(store.linked("cat").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn links_both_ways_in_memory() {
+        links_both_ways(&LinkStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn links_both_ways_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-links-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        links_both_ways(&LinkStore::in_database(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
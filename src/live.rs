@@ -0,0 +1,268 @@
+//! Live now-playing updates, streamed to the player over SSE at `/api/player/events`. However many
+//! tabs and devices a user has open, Spotify is polled once per user: the first subscriber starts
+//! a polling task, later ones share its updates, and the task stops when the last one goes away.
+//!
+//! With the `redis-store` feature and `REDIS_URL` set, that holds across instances too. Pollers
+//! for a user on different instances compete for a lease in Redis, and only the holder polls
+//! Spotify; it publishes each update to the `now-playing:<user ID>` channel, which every instance
+//! subscribes to and passes on to its own subscribers. A client connected to one instance so gets
+//! updates polled by another, and when the polling instance stops, another one takes the lease
+//! over once it lapses.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{sync::watch, task::JoinHandle};
+
+#[cfg(feature = "redis-store")]
+use crate::leader;
+use crate::{api::NowPlaying, config::Config, spotify};
+
+/// About as often as a progress bar needs correcting, and well within Spotify's rate limits for
+/// one request per user.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a lease on polling for a user lasts without being renewed, which the holder does with
+/// each poll. A few polls' worth, so a slow poll doesn't lose it.
+#[cfg(feature = "redis-store")]
+const LEASE: Duration = Duration::from_secs(15);
+
+/// What a user's device is playing, `None` when no device is active.
+pub type Update = Option<NowPlaying>;
+
+/// An update as published to other instances.
+#[cfg(feature = "redis-store")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Published {
+    /// The instance that polled it, which ignores its own updates.
+    from: String,
+    update: Update,
+}
+
+struct Poller {
+    subscribers: usize,
+    updates: Arc<watch::Sender<Update>>,
+    /// The token polls are made with, replaced by each new subscriber's so a long-lived poller
+    /// picks up refreshed tokens.
+    token: watch::Sender<String>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct NowPlayingHub {
+    /// By user ID. A std mutex, since subscriptions are dropped outside async code.
+    pollers: Mutex<HashMap<String, Poller>>,
+    /// Shares updates with other instances, when sessions are in Redis.
+    #[cfg(feature = "redis-store")]
+    fanout: Option<redis::aio::ConnectionManager>,
+}
+
+impl NowPlayingHub {
+    /// The hub to use with `config`, which shares updates between instances through Redis with
+    /// the `redis-store` feature and `REDIS_URL` set.
+    #[allow(clippy::unused_async)]
+    pub async fn connect(config: &Config) -> anyhow::Result<Arc<Self>> {
+        #[cfg(feature = "redis-store")]
+        if let Some(url) = &config.redis_url {
+            let client = redis::Client::open(url.as_str())?;
+            let hub = Arc::new(Self {
+                pollers: Mutex::default(),
+                fanout: Some(client.get_connection_manager().await?),
+            });
+            tokio::spawn(listen(Arc::downgrade(&hub), client));
+            tracing::info!("Sharing now-playing updates between instances through Redis");
+            return Ok(hub);
+        }
+        let _ = config;
+        Ok(Arc::default())
+    }
+
+    /// Subscribes to a user's now-playing updates, starting to poll if nobody else is.
+    pub fn subscribe(self: &Arc<Self>, user_id: &str, access_token: &str) -> Subscription {
+        let mut pollers = self.pollers.lock().expect("now-playing pollers poisoned");
+        let poller = pollers.entry(user_id.to_owned()).or_insert_with(|| {
+            let (token, token_rx) = watch::channel(access_token.to_owned());
+            let updates = Arc::new(watch::channel(None).0);
+            Poller {
+                subscribers: 0,
+                updates: Arc::clone(&updates),
+                token,
+                task: tokio::spawn(poll(
+                    Arc::downgrade(self),
+                    user_id.to_owned(),
+                    token_rx,
+                    updates,
+                )),
+            }
+        });
+        poller.subscribers += 1;
+        poller.token.send_replace(access_token.to_owned());
+        let mut updates = poller.updates.subscribe();
+        // A subscriber joining an existing poller gets its latest update right away, rather than
+        // the placeholder a new poller starts with.
+        if poller.subscribers > 1 {
+            updates.mark_changed();
+        }
+        Subscription {
+            hub: Arc::clone(self),
+            user_id: user_id.to_owned(),
+            updates,
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.pollers.try_lock().ok().map(|p| p.len())
+    }
+
+    /// Whether this instance is the one to poll for `user_id`, which it always is without other
+    /// instances to share with. When Redis fails, every instance polls for itself.
+    #[allow(clippy::unused_async)]
+    async fn leads(&self, user_id: &str) -> bool {
+        #[cfg(feature = "redis-store")]
+        if let Some(conn) = &self.fanout {
+            let key = format!("now-playing-poller:{user_id}");
+            let lease = leader::take_redis_lease(&mut conn.clone(), &key, LEASE).await;
+            return lease.unwrap_or_else(|e| {
+                tracing::warn!("Failed to take the now-playing lease for {user_id}: {e:#}");
+                true
+            });
+        }
+        let _ = user_id;
+        true
+    }
+
+    /// Passes an update this instance polled on to the other instances.
+    #[allow(clippy::unused_async)]
+    async fn publish(&self, user_id: &str, update: &Update) {
+        #[cfg(feature = "redis-store")]
+        if let Some(conn) = &self.fanout {
+            let published = serde_json::to_string(&Published {
+                from: leader::INSTANCE.clone(),
+                update: update.clone(),
+            });
+            let result = match published {
+                Ok(published) => redis::AsyncCommands::publish::<_, _, usize>(
+                    &mut conn.clone(),
+                    format!("now-playing:{user_id}"),
+                    published,
+                )
+                .await
+                .map(drop)
+                .map_err(anyhow::Error::from),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to publish now playing for {user_id}: {e:#}");
+            }
+        }
+        let _ = (user_id, update);
+    }
+}
+
+/// Receives a user's updates for as long as it's kept.
+pub struct Subscription {
+    hub: Arc<NowPlayingHub>,
+    user_id: String,
+    updates: watch::Receiver<Update>,
+}
+
+impl Subscription {
+    /// Waits for the next update, or `None` if polling stopped.
+    pub async fn next(&mut self) -> Option<Update> {
+        self.updates.changed().await.ok()?;
+        Some(self.updates.borrow_and_update().clone())
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut pollers = self
+            .hub
+            .pollers
+            .lock()
+            .expect("now-playing pollers poisoned");
+        let Some(poller) = pollers.get_mut(&self.user_id) else {
+            return;
+        };
+        poller.subscribers -= 1;
+        if poller.subscribers == 0 {
+            poller.task.abort();
+            pollers.remove(&self.user_id);
+        }
+    }
+}
+
+async fn poll(
+    hub: Weak<NowPlayingHub>,
+    user_id: String,
+    token: watch::Receiver<String>,
+    updates: Arc<watch::Sender<Update>>,
+) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(hub) = hub.upgrade() else { return };
+        if !hub.leads(&user_id).await {
+            continue;
+        }
+        let access_token = token.borrow().clone();
+        match spotify::playback_state(&access_token).await {
+            Ok(playback) => {
+                let update = playback.map(NowPlaying::from);
+                hub.publish(&user_id, &update).await;
+                updates.send_replace(update);
+            }
+            Err(e) => tracing::warn!("Failed to poll now playing: {e:#}"),
+        }
+    }
+}
+
+/// Passes updates other instances publish on to this instance's subscribers, resubscribing when
+/// the connection to Redis drops.
+#[cfg(feature = "redis-store")]
+async fn listen(hub: Weak<NowPlayingHub>, client: redis::Client) {
+    while hub.strong_count() > 0 {
+        if let Err(e) = forward(&hub, &client).await {
+            tracing::warn!("Now-playing subscription to Redis failed: {e:#}");
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(feature = "redis-store")]
+async fn forward(hub: &Weak<NowPlayingHub>, client: &redis::Client) -> anyhow::Result<()> {
+    use futures::StreamExt;
+
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.psubscribe("now-playing:*").await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let Some(hub) = hub.upgrade() else {
+            return Ok(());
+        };
+        let Some(user_id) = message.get_channel_name().strip_prefix("now-playing:") else {
+            continue;
+        };
+        let published = message
+            .get_payload::<String>()
+            .map_err(anyhow::Error::from)
+            .and_then(|payload| Ok(serde_json::from_str::<Published>(&payload)?));
+        let published = match published {
+            Ok(published) => published,
+            Err(e) => {
+                tracing::warn!("Ignoring a malformed now-playing update for {user_id}: {e:#}");
+                continue;
+            }
+        };
+        if published.from == *leader::INSTANCE {
+            continue;
+        }
+        let pollers = hub.pollers.lock().expect("now-playing pollers poisoned");
+        if let Some(poller) = pollers.get(user_id) {
+            poller.updates.send_replace(published.update);
+        }
+    }
+    anyhow::bail!("Redis closed the subscription")
+}
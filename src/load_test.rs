@@ -0,0 +1,282 @@
+//! `blid-test load-test`: drives a running server with simulated sessions, each logged in and
+//! requesting a list of endpoints in turn for a while, then reports latency percentiles per
+//! endpoint and how long requests waited on the locks of the in-memory stores, read from the
+//! server's `/metrics` before and after, with the token in `METRICS_TOKEN`. That tells which
+//! stores are worth moving off a single lock.
+//!
+//! The server has to run with `--mock-spotify`, both so sessions can log in without Spotify and
+//! so Spotify's latency and rate limits stay out of the numbers.
+
+use anyhow::{bail, Context};
+use axum::http::header;
+use futures::future::try_join_all;
+use reqwest::{redirect::Policy, Client, Response};
+use std::{
+    collections::BTreeMap,
+    env,
+    time::{Duration, Instant},
+};
+
+use crate::{cookies, login_state};
+
+const USAGE: &str = "usage: blid-test load-test [--server URL] [--sessions N] [--duration SECS] \
+                     [--endpoint PATH]...";
+
+/// Requested when no `--endpoint` is given: the reads a frontend makes most.
+const DEFAULT_ENDPOINTS: &[&str] = &[
+    "/api/session",
+    "/api/player",
+    "/api/player/upnext",
+    "/api/playlists",
+    "/api/playlists/mockplaylist0000000000/tracks",
+    "/api/library/tracks",
+    "/api/stats/genres",
+    "/api/following",
+];
+
+/// Lock waits above this count as contended, in microseconds. One of the bucket bounds of
+/// `store_lock_wait_us`.
+const CONTENDED_US: &str = "1000";
+
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+/// What `/metrics` says about one store's lock.
+#[derive(Default, Clone, Copy)]
+struct LockWaits {
+    count: f64,
+    sum_us: f64,
+    within_contended: f64,
+}
+
+/// Runs the load test, with `args` the arguments after `load-test`. `--server` defaults to
+/// `BLID_SERVER`, then `http://localhost:3000`.
+pub async fn run(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut server = env::var("BLID_SERVER").ok();
+    let mut sessions = 20;
+    let mut duration = Duration::from_secs(30);
+    let mut endpoints = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = Some(args.next().context("--server needs a value")?),
+            "--sessions" => {
+                sessions = args.next().context("--sessions needs a value")?.parse()?;
+            }
+            "--duration" => {
+                let secs = args.next().context("--duration needs a value")?;
+                duration = Duration::from_secs(secs.parse()?);
+            }
+            "--endpoint" => endpoints.push(args.next().context("--endpoint needs a value")?),
+            other => bail!("unexpected argument `{other}`\n{USAGE}"),
+        }
+    }
+    if sessions == 0 {
+        bail!("--sessions needs to be at least 1");
+    }
+    if endpoints.is_empty() {
+        endpoints = DEFAULT_ENDPOINTS
+            .iter()
+            .map(|&path| path.to_owned())
+            .collect();
+    }
+    let server = server
+        .as_deref()
+        .unwrap_or("http://localhost:3000")
+        .trim_end_matches('/')
+        .to_owned();
+    // Redirects are followed by hand while logging in, to pick up cookies along the way.
+    let http = Client::builder().redirect(Policy::none()).build()?;
+
+    let ids = try_join_all((0..sessions).map(|_| login(&http, &server))).await?;
+    println!(
+        "Logged in {sessions} sessions, running for {}s",
+        duration.as_secs()
+    );
+
+    let before = lock_waits(&http, &server).await?;
+    let started = Instant::now();
+    let workers = ids.into_iter().enumerate().map(|(n, id)| {
+        let (http, server, endpoints) = (http.clone(), server.clone(), endpoints.clone());
+        tokio::spawn(async move {
+            let mut samples: Vec<Samples> = endpoints.iter().map(|_| Samples::default()).collect();
+            // Sessions start at different endpoints, so they don't all hit the same one at once.
+            for i in (n..).map(|i| i % endpoints.len()) {
+                if started.elapsed() >= duration {
+                    break;
+                }
+                let request = http
+                    .get(format!("{server}{}", endpoints[i]))
+                    .header(header::COOKIE, format!("{}={id}", cookies::SESSION));
+                let sent = Instant::now();
+                let ok = request
+                    .send()
+                    .await
+                    .is_ok_and(|response| response.status().is_success());
+                samples[i].latencies.push(sent.elapsed());
+                if !ok {
+                    samples[i].errors += 1;
+                }
+            }
+            samples
+        })
+    });
+    let mut totals: Vec<Samples> = endpoints.iter().map(|_| Samples::default()).collect();
+    for samples in try_join_all(workers).await? {
+        for (total, samples) in totals.iter_mut().zip(samples) {
+            total.latencies.extend(samples.latencies);
+            total.errors += samples.errors;
+        }
+    }
+    let elapsed = started.elapsed();
+    let after = lock_waits(&http, &server).await?;
+
+    report(&endpoints, totals, elapsed);
+    report_locks(&before, &after);
+    Ok(())
+}
+
+/// Logs a new session in through the mocked Spotify login, returning its ID.
+async fn login(http: &Client, server: &str) -> anyhow::Result<String> {
+    let started = http.get(format!("{server}/auth")).send().await?;
+    let location = started
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .context("/auth didn't redirect")?;
+    if !location.starts_with("/auth/callback") {
+        bail!("logging in needs the server to run with --mock-spotify");
+    }
+    let nonce = set_cookie(&started, login_state::NONCE_COOKIE)
+        .context("/auth didn't set the login nonce")?;
+    let callback = http
+        .get(format!("{server}{location}"))
+        .header(
+            header::COOKIE,
+            format!("{}={nonce}", login_state::NONCE_COOKIE),
+        )
+        .send()
+        .await?;
+    set_cookie(&callback, cookies::SESSION).with_context(|| {
+        format!(
+            "the login callback didn't start a session, it responded {}",
+            callback.status()
+        )
+    })
+}
+
+/// The value of the cookie `name` that `response` sets.
+fn set_cookie(response: &Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(';').next()?.split_once('='))
+        .find_map(|(key, value)| (key.trim() == name).then(|| value.to_owned()))
+}
+
+/// The lock waits of each store so far, from `/metrics`.
+async fn lock_waits(http: &Client, server: &str) -> anyhow::Result<BTreeMap<String, LockWaits>> {
+    let mut request = http.get(format!("{server}/metrics"));
+    if let Ok(token) = env::var("METRICS_TOKEN") {
+        request = request.bearer_auth(token);
+    }
+    let metrics = request
+        .send()
+        .await
+        .with_context(|| format!("couldn't reach {server}"))?
+        .error_for_status()?
+        .text()
+        .await?;
+    let mut waits = BTreeMap::<String, LockWaits>::new();
+    for line in metrics.lines() {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let Some((name, labels)) = series.split_once('{') else {
+            continue;
+        };
+        let Some(store) = labels
+            .split(',')
+            .find_map(|label| label.strip_prefix("store=\""))
+            .and_then(|store| store.split('"').next())
+        else {
+            continue;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        let entry = waits.entry(store.to_owned()).or_default();
+        match name {
+            "store_lock_wait_us_count" => entry.count = value,
+            "store_lock_wait_us_sum" => entry.sum_us = value,
+            "store_lock_wait_us_bucket" if labels.contains(&format!("le=\"{CONTENDED_US}\"")) => {
+                entry.within_contended = value;
+            }
+            _ => {}
+        }
+    }
+    Ok(waits)
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn report(endpoints: &[String], totals: Vec<Samples>, elapsed: Duration) {
+    let requests: usize = totals.iter().map(|samples| samples.latencies.len()).sum();
+    println!(
+        "\n{requests} requests in {:.1}s, {:.0}/s\n",
+        elapsed.as_secs_f64(),
+        requests as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{:<48} {:>9} {:>7} {:>9} {:>9} {:>9}",
+        "endpoint", "requests", "errors", "p50 ms", "p99 ms", "max ms"
+    );
+    for (endpoint, mut samples) in endpoints.iter().zip(totals) {
+        samples.latencies.sort_unstable();
+        let ms = |p: usize| {
+            percentile(&samples.latencies, p).map_or_else(
+                || "-".to_owned(),
+                |latency| format!("{:.1}", latency.as_secs_f64() * 1000.0),
+            )
+        };
+        println!(
+            "{endpoint:<48} {:>9} {:>7} {:>9} {:>9} {:>9}",
+            samples.latencies.len(),
+            samples.errors,
+            ms(50),
+            ms(99),
+            ms(100)
+        );
+    }
+}
+
+fn report_locks(before: &BTreeMap<String, LockWaits>, after: &BTreeMap<String, LockWaits>) {
+    println!(
+        "\n{:<16} {:>12} {:>12} {:>14}",
+        "store lock",
+        "taken",
+        "mean wait µs",
+        format!("over {CONTENDED_US}µs")
+    );
+    for (store, after) in after {
+        let before = before.get(store).copied().unwrap_or_default();
+        let taken = after.count - before.count;
+        if taken <= 0.0 {
+            continue;
+        }
+        let contended = taken - (after.within_contended - before.within_contended);
+        println!(
+            "{store:<16} {taken:>12} {:>12.1} {contended:>14}",
+            (after.sum_us - before.sum_us) / taken
+        );
+    }
+}
+
+/// The `p`th percentile of `sorted`, nearest rank.
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted.get(rank.saturating_sub(1)).copied()
+}
@@ -0,0 +1,300 @@
+//! Protection against guessing session IDs. Clients presenting many different unknown
+//! `session_id` cookies in a short time are refused with `429` for a while, twice as long each
+//! time they do it again, and each ban is recorded in the [`crate::audit`] log.
+//!
+//! A single stale cookie only counts once, so a browser left with an expired session isn't
+//! banned for retrying it. Clients are told apart by address: the peer's for TCP, or with
+//! `TRUST_PROXY` the last one in `X-Forwarded-For`, as a proxy in front would add it. Without
+//! either, e.g. on a Unix socket without `TRUST_PROXY`, nothing is counted. IPv6 clients are
+//! counted by their /64, since a single host usually has all of it to pick addresses from.
+//!
+//! There's no general rate limiter in front of the app for this to hand bans to, so the lockout
+//! keeps them itself, for at most [`CAPACITY`] clients, and forgets clients it hasn't seen in a
+//! day every [`SWEEP_INTERVAL`].
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::{
+    audit::{self, Event},
+    token::SessionHash,
+    AppStateInner,
+};
+
+/// Unknown session IDs a client may present within [`WINDOW`].
+const THRESHOLD: usize = 20;
+const WINDOW: Duration = Duration::from_mins(10);
+/// The first ban, doubled for each one after it.
+const FIRST_BAN: Duration = Duration::from_mins(1);
+const MAX_BAN: Duration = Duration::from_hours(24);
+/// How long a client's bans count towards the next one's length.
+const MEMORY: Duration = Duration::from_hours(24);
+/// Clients kept track of at once. Each is at most [`THRESHOLD`] hashes, so this is some
+/// megabytes.
+const CAPACITY: usize = 10_000;
+/// How often counting a guess also forgets the clients not seen for [`MEMORY`].
+const SWEEP_INTERVAL: Duration = Duration::from_mins(10);
+
+struct Client {
+    /// Unknown session IDs presented since `since`.
+    guesses: HashSet<SessionHash>,
+    since: Instant,
+    /// Bans so far.
+    bans: u32,
+    banned_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl Client {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+struct Clients {
+    /// By [`client_of`] their address.
+    clients: HashMap<IpAddr, Client>,
+    swept_at: Instant,
+}
+
+pub struct Lockout {
+    /// `TRUST_PROXY`.
+    trust_proxy: bool,
+    clients: RwLock<Clients>,
+}
+
+impl Lockout {
+    pub fn new(trust_proxy: bool) -> Self {
+        Self {
+            trust_proxy,
+            clients: RwLock::new(Clients {
+                clients: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// The address of the client making a request, if known.
+    pub fn client_ip(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<&ConnectInfo<SocketAddr>>,
+    ) -> Option<IpAddr> {
+        if self.trust_proxy {
+            return headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .next_back()
+                .and_then(|ip| ip.trim().parse().ok());
+        }
+        peer.map(|ConnectInfo(addr)| addr.ip())
+    }
+
+    /// How much longer `ip` is banned for, if it is.
+    async fn banned(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        self.clients
+            .read()
+            .await
+            .clients
+            .get(&client_of(ip))?
+            .banned_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Counts an unknown session ID presented by `ip`, banning it past [`THRESHOLD`].
+    pub async fn fail(&self, ip: IpAddr, id: SessionHash) {
+        self.fail_at(ip, id, Instant::now()).await;
+    }
+
+    async fn fail_at(&self, ip: IpAddr, id: SessionHash, now: Instant) {
+        let mut guard = self.clients.write().await;
+        let Clients { clients, swept_at } = &mut *guard;
+        if now.duration_since(*swept_at) >= SWEEP_INTERVAL {
+            clients.retain(|_, client| {
+                client.is_banned(now) || now.duration_since(client.last_seen) < MEMORY
+            });
+            *swept_at = now;
+        }
+        let key = client_of(ip);
+        if clients.len() >= CAPACITY && !clients.contains_key(&key) {
+            // Banned clients go last, so flooding the lockout with addresses lifts no bans.
+            let forgotten = clients
+                .iter()
+                .min_by_key(|(_, client)| (client.is_banned(now), client.last_seen))
+                .map(|(key, _)| *key);
+            if let Some(forgotten) = forgotten {
+                clients.remove(&forgotten);
+            }
+        }
+        let client = clients.entry(key).or_insert_with(|| Client {
+            guesses: HashSet::new(),
+            since: now,
+            bans: 0,
+            banned_until: None,
+            last_seen: now,
+        });
+        client.last_seen = now;
+        if now - client.since > WINDOW {
+            client.guesses.clear();
+            client.since = now;
+        }
+        client.guesses.insert(id);
+        if client.guesses.len() < THRESHOLD {
+            return;
+        }
+        let banned_for = FIRST_BAN
+            .saturating_mul(2u32.saturating_pow(client.bans))
+            .min(MAX_BAN);
+        let attempts = client.guesses.len();
+        client.bans += 1;
+        client.banned_until = Some(now + banned_for);
+        client.guesses.clear();
+        client.since = now;
+        drop(guard);
+        audit::record(&Event::SessionGuessing {
+            ip,
+            attempts,
+            banned_for,
+        });
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.clients.try_read().ok().map(|c| c.clients.len())
+    }
+}
+
+/// What `ip` is counted as: itself, or its /64 for IPv6.
+fn client_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or_else(
+            || IpAddr::V6(Ipv6Addr::from(u128::from(v6) & (u128::MAX << 64))),
+            IpAddr::V4,
+        ),
+    }
+}
+
+/// Middleware refusing banned clients before anything else looks at their request.
+pub async fn guard(
+    State(state): State<Arc<AppStateInner>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = state
+        .lockout
+        .client_ip(request.headers(), request.extensions().get());
+    if let Some(ip) = ip {
+        if let Some(left) = state.lockout.banned(ip, Instant::now()).await {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, left.as_secs().max(1).to_string())],
+                "Too many attempts, try again later",
+            )
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::SessionId;
+
+    async fn guess(lockout: &Lockout, ip: IpAddr, times: usize, now: Instant) {
+        for _ in 0..times {
+            lockout.fail_at(ip, SessionId::generate().hash(), now).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn bans_twice_as_long_each_time() {
+        let lockout = Lockout::new(false);
+        let ip = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        guess(&lockout, ip, THRESHOLD - 1, now).await;
+        assert_eq!(lockout.banned(ip, now).await, None);
+        guess(&lockout, ip, 1, now).await;
+        assert_eq!(lockout.banned(ip, now).await, Some(FIRST_BAN));
+
+        let later = now + FIRST_BAN;
+        assert_eq!(lockout.banned(ip, later).await, None);
+        guess(&lockout, ip, THRESHOLD, later).await;
+        assert_eq!(lockout.banned(ip, later).await, Some(2 * FIRST_BAN));
+        assert_eq!(
+            lockout.banned("192.0.2.2".parse().unwrap(), later).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_guesses_within_the_window() {
+        let lockout = Lockout::new(false);
+        let ip = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        // A stale cookie retried counts once.
+        let stale = SessionId::generate().hash();
+        for _ in 0..THRESHOLD {
+            lockout.fail_at(ip, stale, now).await;
+        }
+        guess(&lockout, ip, THRESHOLD - 2, now).await;
+        assert_eq!(lockout.banned(ip, now).await, None);
+
+        let later = now + WINDOW + Duration::from_secs(1);
+        guess(&lockout, ip, THRESHOLD - 1, later).await;
+        assert_eq!(lockout.banned(ip, later).await, None);
+    }
+
+    #[tokio::test]
+    async fn counts_ipv6_clients_by_their_64() {
+        let lockout = Lockout::new(false);
+        let now = Instant::now();
+        for i in 0..THRESHOLD {
+            let ip = format!("2001:db8::{i:x}").parse().unwrap();
+            guess(&lockout, ip, 1, now).await;
+        }
+        let neighbour = "2001:db8::ffff:1".parse().unwrap();
+        assert_eq!(lockout.banned(neighbour, now).await, Some(FIRST_BAN));
+        let other = "2001:db8:0:1::1".parse().unwrap();
+        assert_eq!(lockout.banned(other, now).await, None);
+        assert_eq!(lockout.len_hint(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn forgets_clients_not_seen_for_a_day() {
+        let lockout = Lockout::new(false);
+        let now = Instant::now();
+        guess(&lockout, "192.0.2.1".parse().unwrap(), 1, now).await;
+        let later = now + MEMORY;
+        guess(&lockout, "192.0.2.2".parse().unwrap(), 1, later).await;
+        assert_eq!(lockout.len_hint(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn keeps_banned_clients_past_capacity() {
+        let lockout = Lockout::new(false);
+        let now = Instant::now();
+        let banned = "192.0.2.1".parse().unwrap();
+        guess(&lockout, banned, THRESHOLD, now).await;
+        for i in 0..CAPACITY {
+            let ip = IpAddr::V4(u32::try_from(i).unwrap().into());
+            guess(&lockout, ip, 1, now).await;
+        }
+        assert_eq!(lockout.len_hint(), Some(CAPACITY));
+        assert_eq!(lockout.banned(banned, now).await, Some(FIRST_BAN));
+    }
+}
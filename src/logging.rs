@@ -0,0 +1,46 @@
+//! Log output. What's logged is picked by a filter in `RUST_LOG` syntax, which can be changed
+//! while running through `PUT /admin/log-level` or by changing `RUST_LOG` in `CONFIG_FILE` and
+//! reloading, so debugging a production issue doesn't take a restart that might make it go away.
+
+use once_cell::sync::OnceCell;
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+/// axum logs rejections from built-in extractors with the `axum::rejection` target, at `TRACE`
+/// level. `axum::rejection=trace` enables showing those events.
+pub const DEFAULT_FILTER: &str = "blid_test=debug,tower_http=debug,axum::rejection=trace";
+
+static FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// Starts logging with `filter`, from `RUST_LOG`, or [`DEFAULT_FILTER`] without one or when it's
+/// invalid.
+pub fn init(filter: Option<&str>) {
+    let filter = filter
+        .and_then(|filter| EnvFilter::try_new(filter).ok())
+        .unwrap_or_else(|| DEFAULT_FILTER.into());
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().pretty())
+        .init();
+    let _ = FILTER.set(handle);
+}
+
+/// The filter in effect.
+pub fn filter() -> anyhow::Result<String> {
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("logging isn't initialized"))?;
+    Ok(handle.with_current(ToString::to_string)?)
+}
+
+/// Replaces the filter with `directives`, such as `blid_test=trace,tower_http=info`. An invalid
+/// filter leaves the current one in place.
+pub fn set_filter(directives: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(directives)?;
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("logging isn't initialized"))?;
+    handle.reload(filter)?;
+    tracing::warn!("Log filter is now {directives}");
+    Ok(())
+}
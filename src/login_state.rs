@@ -0,0 +1,182 @@
+//! The OAuth `state` parameter. Instead of a random string remembered server-side, it is a signed
+//! payload describing the login flow, so any instance holding the key can validate a callback.
+//!
+//! Format: `base64url(json(LoginState)) "." base64url(hmac_sha256(key, payload))`.
+//!
+//! The payload is signed, not encrypted: it only carries things that are fine for the user and
+//! Spotify to see. Its `nonce` is also set in a cookie on the browser that started the login, and
+//! the callback requires both to match, which is what ties a callback to that browser.
+
+use anyhow::{bail, Context};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{redact::Redacted, token};
+
+/// How long a user has to complete the login on Spotify's side.
+pub const MAX_AGE: Duration = Duration::from_mins(10);
+
+/// Name of the cookie holding the nonce of the login in progress.
+pub const NONCE_COOKIE: &str = "login_nonce";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginState {
+    pub nonce: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Local path to send the user to once logged in.
+    pub next: Option<String>,
+    /// Invite code to be let in with, on an invitation-only instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<String>,
+}
+
+impl LoginState {
+    pub fn new(next: Option<String>) -> Self {
+        Self {
+            nonce: token::generate(token::STATE_BYTES),
+            created_at: now(),
+            next,
+            invite: None,
+        }
+    }
+}
+
+pub struct StateKey(Vec<u8>);
+
+impl StateKey {
+    pub fn new(secret: &[u8]) -> Self {
+        Self(secret.to_vec())
+    }
+
+    /// A key that only this process knows. Logins started on another instance, or before a
+    /// restart, won't validate.
+    pub fn random() -> Self {
+        Self(token::generate(32).into_bytes())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.0).expect("HMAC accepts keys of any length")
+    }
+
+    pub fn sign(&self, state: &LoginState) -> anyhow::Result<String> {
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(state)?);
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        Ok(format!("{payload}.{signature}"))
+    }
+
+    /// Checks the signature and age of `state`, and that it belongs to the login identified by
+    /// the `nonce` cookie.
+    pub fn verify(&self, state: &str, nonce: &str) -> anyhow::Result<LoginState> {
+        let (payload, signature) = state.split_once('.').context("state is malformed")?;
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .context("state signature is malformed")?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .ok()
+            .context("state signature is invalid")?;
+
+        let state: LoginState = serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(payload)?)?;
+        if now().saturating_sub(state.created_at) > MAX_AGE.as_secs() {
+            bail!("state has expired");
+        }
+        if !token::constant_time_eq(state.nonce.as_bytes(), nonce.as_bytes()) {
+            bail!("state was issued to another browser");
+        }
+        Ok(state)
+    }
+}
+
+/// The query string Spotify redirects back with. On success it carries a `code`, but if the user
+/// declines (or anything else goes wrong on Spotify's side) it carries an `error` instead.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SpotifyAuthResponse {
+    Success { code: String, state: String },
+    Error { error: String, state: String },
+}
+
+impl std::fmt::Debug for SpotifyAuthResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Success { code, state } => f
+                .debug_struct("Success")
+                .field("code", &Redacted(code))
+                .field("state", &Redacted(state))
+                .finish(),
+            Self::Error { error, state } => f
+                .debug_struct("Error")
+                .field("error", error)
+                .field("state", &Redacted(state))
+                .finish(),
+        }
+    }
+}
+
+impl SpotifyAuthResponse {
+    pub fn state(&self) -> &str {
+        match self {
+            Self::Success { state, .. } | Self::Error { state, .. } => state,
+        }
+    }
+}
+
+impl std::fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StateKey([redacted])")
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        /// As the `login_state` fuzz target: made-up states never verify, and signed ones only
+        /// with their own key and nonce.
+        #[test]
+        fn only_signed_states_verify(state in ".*", nonce in ".*", next in any::<Option<String>>()) {
+            let key = StateKey::new(b"test key");
+            prop_assert!(key.verify(&state, &nonce).is_err());
+
+            let login = LoginState::new(next.clone());
+            let signed = key.sign(&login).unwrap();
+            prop_assert_eq!(key.verify(&signed, &login.nonce).unwrap().next, next);
+            if nonce != login.nonce {
+                prop_assert!(key.verify(&signed, &nonce).is_err());
+            }
+            prop_assert!(StateKey::new(b"another key").verify(&signed, &login.nonce).is_err());
+        }
+
+        /// As the `callback_query` fuzz target: a parsed callback never logs its code or state.
+        #[test]
+        fn callbacks_never_log_secrets(query in "((code|state|error|[a-z]{1,5})=[^&]{0,16}&?){0,4}") {
+            let Ok(callback) = serde_urlencoded::from_str::<SpotifyAuthResponse>(&query) else {
+                return Ok(());
+            };
+            let logged = format!("{callback:?}");
+            match &callback {
+                SpotifyAuthResponse::Success { .. } => {
+                    prop_assert_eq!(logged, "Success { code: [redacted], state: [redacted] }");
+                }
+                SpotifyAuthResponse::Error { .. } => {
+                    prop_assert!(logged.ends_with("state: [redacted] }"), "{}", logged);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Noticing logins from unusual places. Each new session is located with [`crate::geoip`], and
+//! one from a country and a network neither seen in the user's recent logins is recorded in the
+//! [`crate::audit`] log. The user is told through webhooks subscribed to `unusual_login`, and by
+//! email if they get any notifications.
+//!
+//! A user's first located login is never unusual, there being nothing to compare it with.
+//!
+//! Recent logins are kept in memory by default, or in the database with a SQL feature and
+//! `DATABASE_URL` set, so logins through any instance are compared with those through every other.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    audit::{self, Event},
+    digest,
+    geoip::Location,
+    webhooks, AppStateInner,
+};
+
+/// Located logins remembered per user.
+const RECENT: usize = 20;
+
+pub enum LoginStore {
+    /// Newest first, by user ID.
+    Memory(RwLock<HashMap<String, VecDeque<Location>>>),
+    /// Countries are empty for networks without one.
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for LoginStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl LoginStore {
+    /// Remembers a login of `user_id` from `location`, returning whether it's unusual for them.
+    pub async fn record(&self, user_id: &str, location: Location) -> anyhow::Result<bool> {
+        let unusual = |seen: &[Location]| {
+            !seen.is_empty()
+                && !seen
+                    .iter()
+                    .any(|past| past.country == location.country || past.asn == location.asn)
+        };
+        match self {
+            Self::Memory(recent) => {
+                let mut recent = recent.write().await;
+                let seen = recent.entry(user_id.to_owned()).or_default();
+                let unusual = unusual(seen.make_contiguous());
+                seen.push_front(location);
+                seen.truncate(RECENT);
+                Ok(unusual)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let unusual = unusual(&self.recent(user_id).await?);
+                let mut tx = pool.begin().await?;
+                sqlx::query(
+                    "INSERT INTO logins (user_id, country, asn, network, logged_in_at) \
+                     VALUES ($1, $2, $3, $4, $5)",
+                )
+                .bind(user_id)
+                .bind(location.country.clone().unwrap_or_default())
+                .bind(i64::from(location.asn))
+                .bind(&location.network)
+                .bind(chrono::Utc::now().timestamp_millis())
+                .execute(&mut *tx)
+                .await?;
+                let oldest_kept: Option<i64> = sqlx::query_scalar(
+                    "SELECT logged_in_at FROM logins WHERE user_id = $1 \
+                     ORDER BY logged_in_at DESC LIMIT 1 OFFSET $2",
+                )
+                .bind(user_id)
+                .bind(i64::try_from(RECENT - 1)?)
+                .fetch_optional(&mut *tx)
+                .await?;
+                if let Some(oldest_kept) = oldest_kept {
+                    sqlx::query("DELETE FROM logins WHERE user_id = $1 AND logged_in_at < $2")
+                        .bind(user_id)
+                        .bind(oldest_kept)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await?;
+                Ok(unusual)
+            }
+        }
+    }
+
+    /// Where a user's recent logins were from, newest first.
+    pub async fn recent(&self, user_id: &str) -> anyhow::Result<Vec<Location>> {
+        match self {
+            Self::Memory(recent) => Ok(recent
+                .read()
+                .await
+                .get(user_id)
+                .map(|seen| seen.iter().cloned().collect())
+                .unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, i64, String)> = sqlx::query_as(
+                    "SELECT country, asn, network FROM logins WHERE user_id = $1 \
+                     ORDER BY logged_in_at DESC",
+                )
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter()
+                    .map(|(country, asn, network)| {
+                        Ok(Location {
+                            country: Some(country).filter(|country| !country.is_empty()),
+                            asn: u32::try_from(asn)?,
+                            network,
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Forgets a user's logins, returning how many were remembered.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(recent) => Ok(recent
+                .write()
+                .await
+                .remove(user_id)
+                .map_or(0, |seen| seen.len())),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM logins WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(usize::try_from(deleted.rows_affected())?)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(recent) => recent.try_read().ok().map(|r| r.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+/// Checks a new session of `user_id`, started from `ip`, telling the user if it's unusual.
+pub async fn check(state: &Arc<AppStateInner>, user_id: &str, ip: IpAddr) {
+    let Some(location) = state.geoip.locate(ip) else {
+        return;
+    };
+    match state.logins.record(user_id, location.clone()).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            tracing::error!("Failed to record a login: {e:#}");
+            return;
+        }
+    }
+    audit::record(&Event::UnusualLogin {
+        user_id: user_id.to_owned(),
+        ip,
+        location: location.clone(),
+    });
+    let event = webhooks::Event::UnusualLogin {
+        location: location.clone(),
+    };
+    webhooks::dispatch(state, user_id, &event).await;
+    if let Err(e) = digest::send_login_alert(state, user_id, &location).await {
+        tracing::warn!("Failed to email an unusual login: {e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(country: &str, asn: u32) -> Location {
+        Location {
+            country: Some(country.to_owned()).filter(|country| !country.is_empty()),
+            asn,
+            network: format!("AS{asn}"),
+        }
+    }
+
+    async fn flags_logins_from_new_places(store: &LoginStore) {
+        assert!(!store.record("ann", location("CH", 1)).await.unwrap());
+        // Same country, or same network.
+        assert!(!store.record("ann", location("CH", 2)).await.unwrap());
+        assert!(!store.record("ann", location("", 1)).await.unwrap());
+        assert!(store.record("ann", location("BR", 3)).await.unwrap());
+        assert!(!store.record("bob", location("BR", 3)).await.unwrap());
+
+        let recent = store.recent("ann").await.unwrap();
+        assert_eq!(recent.len(), 4);
+        assert_eq!(recent[0], location("BR", 3));
+        assert_eq!(recent[1].country, None);
+
+        for asn in 0..u32::try_from(RECENT).unwrap() {
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+            store.record("ann", location("FR", 10 + asn)).await.unwrap();
+        }
+        let recent = store.recent("ann").await.unwrap();
+        assert_eq!(recent.len(), RECENT);
+        // Only French logins are remembered now.
+        assert!(store.record("ann", location("CH", 1)).await.unwrap());
+        assert_eq!(store.remove("ann").await.unwrap(), RECENT);
+        assert!(store.recent("ann").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn flags_logins_from_new_places_in_memory() {
+        flags_logins_from_new_places(&LoginStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn flags_logins_from_new_places_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-logins-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        flags_logins_from_new_places(&LoginStore::Sql(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,116 @@
+//! Sending email. With `MAIL_URL` set, mail goes out over SMTP; otherwise it's only logged, which
+//! is enough for development and for deployments that don't send any.
+
+use anyhow::Context;
+use lettre::{
+    message::header::{ContentType, Header, HeaderName, HeaderValue},
+    transport::smtp::AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::config::MailConfig;
+
+/// A message to one recipient, in plain text.
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    /// Where the recipient can unsubscribe with a `POST`, advertised to mail clients so they can
+    /// offer a one-click button (RFC 8058).
+    pub unsubscribe: Option<String>,
+}
+
+pub enum Mailer {
+    Smtp {
+        transport: Box<AsyncSmtpTransport<Tokio1Executor>>,
+        from: String,
+    },
+    Log,
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig) -> anyhow::Result<Self> {
+        let Some(url) = &config.url else {
+            return Ok(Self::Log);
+        };
+        Ok(Self::Smtp {
+            transport: Box::new(AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build()),
+            from: config.from.clone(),
+        })
+    }
+
+    /// Connects to the SMTP server and authenticates, without sending anything, and checks the
+    /// sender address.
+    pub async fn test_connection(&self) -> anyhow::Result<()> {
+        let Self::Smtp { transport, from } = self else {
+            return Ok(());
+        };
+        from.parse::<lettre::message::Mailbox>()
+            .with_context(|| format!("invalid MAIL_FROM `{from}`"))?;
+        if !transport.test_connection().await? {
+            anyhow::bail!("the SMTP server didn't respond");
+        }
+        Ok(())
+    }
+
+    pub async fn send(&self, email: Email) -> anyhow::Result<()> {
+        let (transport, from) = match self {
+            Self::Smtp { transport, from } => (transport, from),
+            Self::Log => {
+                tracing::info!(
+                    "Not sending email \"{}\", no MAIL_URL is set:\n{}",
+                    email.subject,
+                    email.body
+                );
+                return Ok(());
+            }
+        };
+        let mut message = Message::builder()
+            .from(from.parse()?)
+            .to(email.to.parse()?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN);
+        if let Some(url) = email.unsubscribe {
+            message = message
+                .header(ListUnsubscribe(format!("<{url}>")))
+                .header(ListUnsubscribePost);
+        }
+        transport.send(message.body(email.body)?).await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.to_owned()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+/// Tells mail clients the `List-Unsubscribe` URL accepts a one-click `POST`.
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(_: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self)
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".to_owned())
+    }
+}
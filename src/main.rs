@@ -1,44 +1,463 @@
+use activity::ActivityStore;
+use api_keys::ApiKeyStore;
+use art::ArtStore;
 use askama_axum::Template;
+#[cfg(feature = "library")]
+use availability::AvailabilityCache;
 use axum::{
-    extract::{Query, State},
-    http::{header, HeaderMap, StatusCode, Uri},
-    response::{IntoResponse, Redirect, Result},
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware,
+    response::{AppendHeaders, IntoResponse, Redirect, Result},
     routing::get,
     Router,
 };
-use base64::prelude::*;
-use dotenv_codegen::dotenv;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use blid_test::{cookies, login_state, redact, token};
+use cache::Cache;
+use chrono::Utc;
+#[cfg(feature = "stats")]
+use collage::CollageCache;
+use config::{Frontend, SessionConfig};
+use consent::ConsentStore;
+use cookie_manager::Flash;
+use device::DeviceStore;
+use digest::NotificationStore;
+use feed::FeedStore;
+use friends::FriendStore;
+use geoip::GeoIp;
+use handoff::HandoffStore;
+use history::HistoryStore;
+use icons::Icons;
+use invites::InviteStore;
+use itertools::Itertools;
+use jobs::JobQueue;
+use leader::{Leadership, Lease};
+use library::{EndingCache, FeatureCache, GenreCache};
+use links::LinkStore;
+#[cfg(feature = "player")]
+use live::NowPlayingHub;
+use lockout::Lockout;
+use login_state::{LoginState, SpotifyAuthResponse, StateKey};
+use logins::LoginStore;
+use mail::Mailer;
+use normalize::NormalizationStore;
+#[cfg(feature = "library")]
+use partials::SearchCoalescer;
+use playlist_cache::{PlaylistCache, SnapshotConflict};
+use privacy::Privacy;
+use redact::Redacted;
+use releases::ReleaseStore;
+use retention::RetentionStore;
+use roles::RoleStore;
+use rules::RuleStore;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
-};
-use tracing_subscriber::prelude::*;
+use session::MissingScopes;
+use session_store::{SessionData, SessionStore};
+use share::ShareStore;
+use spotify::{PremiumRequired, SpotifyToken};
+use std::{net::SocketAddr, sync::Arc};
+use templates::{Format, Layout, Page};
+use themes::ThemeStore;
+use token::SessionId;
+use tower_http::services::{ServeDir, ServeFile};
+use undo::UndoStore;
+use webhooks::WebhookStore;
+#[cfg(feature = "player")]
+use widget::WidgetStore;
+
+#[cfg(all(feature = "redis-store", feature = "sql"))]
+compile_error!("the `redis-store` feature can't be combined with `sqlite-store` or `postgres`");
 
+mod activity;
+mod admin;
+mod api;
+mod api_keys;
+mod art;
+mod assets;
+mod audit;
+#[cfg(feature = "library")]
+mod availability;
+#[cfg(feature = "sql")]
+mod backup;
+mod cache;
+mod check;
+mod client;
+#[cfg(feature = "stats")]
+mod collage;
+mod config;
+mod consent;
 mod cookie_manager;
+mod cors;
+#[cfg(feature = "sql")]
+mod db;
+mod deadline;
+mod device;
+mod digest;
+#[cfg(feature = "library")]
+mod discover;
+mod export;
+mod feed;
+mod fixtures;
+mod friends;
+mod geoip;
+mod handoff;
+mod history;
+mod icons;
+mod ids;
+mod invites;
+mod jobs;
+mod json_array;
+mod leader;
+mod library;
+mod links;
+#[cfg(feature = "player")]
+mod live;
+mod load_test;
+mod lockout;
+mod logging;
+mod logins;
+mod mail;
+mod metrics;
+mod migrate_sessions;
+mod mock_spotify;
+mod normalize;
+mod pages;
+#[cfg(any(feature = "player", feature = "library"))]
+mod partials;
+mod playlist_cache;
+mod privacy;
+mod pwa;
+mod releases;
+mod reload;
+mod retention;
+mod roles;
+mod rules;
+mod running;
+mod server;
+mod session;
+mod session_store;
+mod share;
+mod slugs;
+mod spotify;
+mod stats;
+mod templates;
+mod themes;
+mod undo;
+mod webhooks;
+#[cfg(feature = "player")]
+mod widget;
 
-type AppState = State<Arc<Mutex<AppStateInner>>>;
+type AppState = State<Arc<AppStateInner>>;
 
-#[derive(Debug, Default)]
 struct AppStateInner {
-    code_states: HashSet<String>,
-    sessions: HashMap<String, SpotifyToken>,
+    state_key: StateKey,
+    sessions: SessionStore,
+    playlist_cache: PlaylistCache,
+    undo: UndoStore,
+    rules: RuleStore,
+    history: HistoryStore,
+    webhooks: WebhookStore,
+    feeds: FeedStore,
+    #[cfg(feature = "player")]
+    widgets: WidgetStore,
+    shares: ShareStore,
+    handoffs: HandoffStore,
+    devices: DeviceStore,
+    api_keys: ApiKeyStore,
+    normalization: NormalizationStore,
+    notifications: NotificationStore,
+    releases: ReleaseStore,
+    activity: ActivityStore,
+    retention: RetentionStore,
+    links: LinkStore,
+    friends: FriendStore,
+    roles: RoleStore,
+    invites: InviteStore,
+    consent: ConsentStore,
+    lockout: Lockout,
+    geoip: GeoIp,
+    logins: LoginStore,
+    genres: GenreCache,
+    features: FeatureCache,
+    endings: EndingCache,
+    #[cfg(feature = "library")]
+    availability: AvailabilityCache,
+    art: Arc<ArtStore>,
+    #[cfg(feature = "stats")]
+    collages: CollageCache,
+    icons: Icons,
+    themes: ThemeStore,
+    #[cfg(feature = "library")]
+    searches: SearchCoalescer,
+    /// Backs the feed, genre, audio feature and collage caches.
+    cache: Arc<dyn Cache>,
+    #[cfg(feature = "player")]
+    now_playing: Arc<NowPlayingHub>,
+    leader: Leadership,
+    request_timeout: std::time::Duration,
+    jobs: Arc<JobQueue>,
+    session_config: SessionConfig,
+    public_url: String,
+    /// [`Config::redirect_uri`], sent both when logging in and when exchanging the code.
+    redirect_uri: String,
+    /// `ANALYTICS_SCRIPT`, see [`privacy`].
+    analytics_script: Option<String>,
+    admin_token: Option<String>,
+    metrics_token: Option<String>,
+    started_at: chrono::DateTime<Utc>,
+    cors_origins: cors::Origins,
+    mailer: Mailer,
+}
+
+impl AppStateInner {
+    async fn new(config: &config::Config) -> anyhow::Result<Self> {
+        let state_key = config.state_secret.as_deref().map_or_else(
+            || {
+                tracing::warn!(
+                    "STATE_SECRET is not set, logins will only complete on this instance"
+                );
+                StateKey::random()
+            },
+            |secret| StateKey::new(secret.as_bytes()),
+        );
+        let cache = cache::connect(config).await?;
+        #[cfg_attr(not(feature = "sql"), allow(unused_mut))]
+        let mut state = Self {
+            state_key,
+            sessions: SessionStore::connect(config).await?,
+            playlist_cache: PlaylistCache::default(),
+            undo: UndoStore::default(),
+            rules: RuleStore::default(),
+            history: HistoryStore::default(),
+            webhooks: WebhookStore::default(),
+            feeds: FeedStore::new(cache.clone()),
+            #[cfg(feature = "player")]
+            widgets: WidgetStore::default(),
+            shares: ShareStore::default(),
+            handoffs: HandoffStore::default(),
+            devices: DeviceStore::default(),
+            api_keys: ApiKeyStore::default(),
+            normalization: NormalizationStore::default(),
+            notifications: NotificationStore::default(),
+            releases: ReleaseStore::default(),
+            activity: ActivityStore::default(),
+            retention: RetentionStore::default(),
+            links: LinkStore::default(),
+            friends: FriendStore::default(),
+            roles: RoleStore::new(&config.admins, config.default_role),
+            invites: InviteStore::new(
+                config.invite_only,
+                config.admins.iter().chain(&config.allowed_users),
+            ),
+            consent: ConsentStore::new(config.consent_version.clone(), config.consent_text.clone()),
+            lockout: Lockout::new(config.trust_proxy),
+            geoip: GeoIp::load(config.geoip_db.as_deref())?,
+            logins: LoginStore::default(),
+            genres: GenreCache::new(cache.clone()),
+            features: FeatureCache::new(cache.clone()),
+            endings: EndingCache::default(),
+            #[cfg(feature = "library")]
+            availability: AvailabilityCache::default(),
+            art: Arc::new(ArtStore::new(
+                config.art_dir.clone(),
+                config.art_dir_max_bytes,
+                cache.clone(),
+            )?),
+            #[cfg(feature = "stats")]
+            collages: CollageCache::new(cache.clone()),
+            icons: Icons::render(config.brand)?,
+            themes: ThemeStore::load(config.theme_dir.clone())?,
+            #[cfg(feature = "library")]
+            searches: SearchCoalescer::default(),
+            cache,
+            #[cfg(feature = "player")]
+            now_playing: NowPlayingHub::connect(config).await?,
+            leader: Leadership::new(Lease::connect(config).await?),
+            request_timeout: config.request_timeout,
+            jobs: Arc::default(),
+            session_config: config.session,
+            public_url: config.public_url.clone(),
+            redirect_uri: config.redirect_uri(),
+            analytics_script: config.analytics_script.clone(),
+            admin_token: config.admin_token.clone(),
+            metrics_token: config.metrics_token.clone(),
+            started_at: Utc::now(),
+            cors_origins: Arc::new(std::sync::RwLock::new(config.cors_origins.clone())),
+            mailer: Mailer::new(&config.mail)?,
+        };
+        #[cfg(feature = "sql")]
+        if let Some(url) = &config.database_url {
+            let pool = db::connect(url).await?;
+            db::migrate(&pool).await?;
+            state = state.in_database(pool);
+        }
+        state.sessions.load_snapshot(config).await?;
+        Ok(state)
+    }
+
+    /// Moves what's kept for good to the database. What's only kept for a while stays in memory:
+    /// caches, login lockouts, handoff and device codes, undo history, jobs, and what workers
+    /// remember between polls.
+    #[cfg(feature = "sql")]
+    fn in_database(mut self, pool: db::Pool) -> Self {
+        tracing::info!(
+            "Storing sessions, history, notification, retention, normalization and sharing \
+             settings, feeds, widgets, shares, links, new releases, playlist activity, webhooks, \
+             recent logins, rules, API keys, roles, invites and consents in the database"
+        );
+        self.sessions = SessionStore::Sql(pool.clone());
+        self.history = HistoryStore::Sql(pool.clone());
+        self.notifications = NotificationStore::Sql(pool.clone());
+        self.shares = ShareStore::Sql(pool.clone());
+        self.rules = RuleStore::Sql(pool.clone());
+        self.retention = RetentionStore::Sql(pool.clone());
+        self.normalization = NormalizationStore::in_database(pool.clone());
+        self.friends = FriendStore::in_database(pool.clone());
+        self.links = LinkStore::in_database(pool.clone());
+        self.releases = ReleaseStore::Sql(pool.clone());
+        self.activity = ActivityStore::Sql(pool.clone());
+        self.webhooks = WebhookStore::in_database(pool.clone());
+        self.logins = LoginStore::Sql(pool.clone());
+        self.feeds = self.feeds.in_database(pool.clone());
+        #[cfg(feature = "player")]
+        {
+            self.widgets = self.widgets.in_database(pool.clone());
+        }
+        self.api_keys = ApiKeyStore::Sql(pool.clone());
+        self.roles = self.roles.in_database(pool.clone());
+        self.invites = self.invites.in_database(pool.clone());
+        self.consent = self.consent.in_database(pool.clone());
+        self.leader = Leadership::new(Lease::Sql(pool));
+        self
+    }
+}
+
+#[cfg(test)]
+impl AppStateInner {
+    /// The state of an instance configured from the environment, keeping its files in a fresh
+    /// temporary directory.
+    pub async fn for_tests() -> Arc<Self> {
+        let dir = std::env::temp_dir().join(format!("blid-test-{}", token::generate(8)));
+        let mut config = config::Config::from_args(std::iter::empty()).unwrap();
+        config.art_dir = dir.join("art");
+        config.theme_dir = dir.join("themes");
+        Arc::new(Self::new(&config).await.unwrap())
+    }
 }
 
-fn random_alphanum(len: usize) -> String {
-    thread_rng()
-        .sample_iter(Alphanumeric)
-        .map(char::from)
-        .take(len)
-        .collect()
+impl std::fmt::Debug for AppStateInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("AppStateInner");
+        debug
+            .field("state_key", &self.state_key)
+            .field("sessions", &self.sessions)
+            .field("playlist_cache", &self.playlist_cache.len_hint())
+            .field("undo", &self.undo.len_hint())
+            .field("rules", &self.rules.len_hint())
+            .field("history", &self.history.len_hint())
+            .field("webhooks", &self.webhooks.len_hint())
+            .field("feeds", &self.feeds.len_hint())
+            .field("shares", &self.shares.len_hint())
+            .field("handoffs", &self.handoffs.len_hint())
+            .field("devices", &self.devices.len_hint())
+            .field("api_keys", &self.api_keys.len_hint())
+            .field("normalization", &self.normalization.len_hint())
+            .field("notifications", &self.notifications.len_hint())
+            .field("releases", &self.releases.len_hint())
+            .field("activity", &self.activity.len_hint())
+            .field("retention", &self.retention.len_hint())
+            .field("links", &self.links.len_hint())
+            .field("friends", &self.friends.len_hint())
+            .field("roles", &self.roles.len_hint())
+            .field("invites", &self.invites.len_hint())
+            .field("consent", &self.consent.len_hint())
+            .field("lockout", &self.lockout.len_hint())
+            .field("geoip", &self.geoip.len_hint())
+            .field("logins", &self.logins.len_hint())
+            .field("endings", &self.endings.len_hint())
+            .field("art", &self.art.len_hint())
+            .field("theme", &self.themes.selected())
+            .field("cache", &self.cache.len_hint())
+            .field("jobs", &self.jobs.len_hint())
+            .field("leader", &self.leader.holds());
+        #[cfg(feature = "player")]
+        debug
+            .field("widgets", &self.widgets.len_hint())
+            .field("now_playing", &self.now_playing.len_hint());
+        #[cfg(feature = "library")]
+        debug
+            .field("availability", &self.availability.len_hint())
+            .field("searches", &self.searches.len_hint());
+        // Caches without a size of their own, and configuration.
+        debug.finish_non_exhaustive()
+    }
+}
+
+/// Only local paths and URLs on `allowed_origins` (a frontend hosted elsewhere) are accepted as
+/// `next`, so the login flow can't be turned into an open redirect. `/auth` itself is rejected to
+/// avoid sending the user straight back into a login.
+fn safe_next(next: &str, allowed_origins: &[HeaderValue]) -> Option<String> {
+    let uri: Uri = next.parse().ok()?;
+    if let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) {
+        let origin = format!("{scheme}://{authority}");
+        let allowed = !authority.as_str().contains('@')
+            && allowed_origins
+                .iter()
+                .any(|allowed| allowed == origin.as_str());
+        return allowed.then(|| next.to_owned());
+    }
+    let path = uri.path();
+    let is_local = uri.scheme().is_none()
+        && uri.authority().is_none()
+        && path.starts_with('/')
+        && !path.starts_with("//")
+        && !next.contains('\\');
+    let is_login = path == "/auth" || path.starts_with("/auth/");
+    (is_local && !is_login).then(|| next.to_owned())
+}
+
+/// Where a protected page should send unauthenticated users: the login route, remembering the
+/// page they asked for.
+fn login_redirect(uri: &Uri) -> Redirect {
+    let next = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    serde_qs::to_string(&json!({ "next": next })).map_or_else(
+        |_| Redirect::to("/auth"),
+        |qs| Redirect::to(&format!("/auth?{qs}")),
+    )
 }
 
 struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
+        if let Some(conflict) = self.0.downcast_ref::<SnapshotConflict>() {
+            return (StatusCode::CONFLICT, axum::Json(conflict)).into_response();
+        }
+        if deadline::is_exceeded(&self.0) {
+            return deadline::response::<()>(None);
+        }
+        if self.0.downcast_ref::<PremiumRequired>().is_some() {
+            return (
+                StatusCode::PAYMENT_REQUIRED,
+                axum::Json(json!({
+                    "error": "premium_required",
+                    "message": self.0.to_string(),
+                })),
+            )
+                .into_response();
+        }
+        if let Some(missing) = self.0.downcast_ref::<MissingScopes>() {
+            return (
+                StatusCode::FORBIDDEN,
+                axum::Json(json!({
+                    "error": "missing_scopes",
+                    "missing": missing.missing,
+                    "reauth_url": missing.reauth_url(),
+                })),
+            )
+                .into_response();
+        }
         (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
     }
 }
@@ -52,167 +471,481 @@ where
     }
 }
 
-#[derive(Template)]
+#[derive(Template, Serialize)]
 #[template(path = "index.html")]
-struct MainTemplate {}
+struct MainTemplate {
+    layout: Layout,
+}
+
+impl Page for MainTemplate {
+    const PATH: &'static str = "index.html";
+}
 
-async fn contacts() -> impl IntoResponse {
-    MainTemplate {}
+async fn contacts(layout: Layout, format: Format) -> impl IntoResponse {
+    templates::respond(format, MainTemplate { layout })
 }
 
-async fn send_spotify_code_request(State(s): AppState) -> Result<impl IntoResponse, AppError> {
-    let state = random_alphanum(16);
+/// Everything the app asks Spotify for on login.
+const SCOPES: &str = "streaming user-read-email user-read-private user-library-read \
+                      user-library-modify playlist-read-private playlist-modify-private \
+                      playlist-modify-public user-follow-read user-follow-modify \
+                      user-read-recently-played user-read-currently-playing \
+                      user-read-playback-state user-modify-playback-state \
+                      ugc-image-upload";
+
+#[derive(Deserialize, Debug)]
+struct LoginQuery {
+    next: Option<String>,
+    /// Space-separated scopes to ask for on top of [`SCOPES`], when logging in again to grant
+    /// missing ones.
+    scope: Option<String>,
+    /// Invite code, on an invitation-only instance.
+    invite: Option<String>,
+}
+
+async fn send_spotify_code_request(
+    Query(q): Query<LoginQuery>,
+    State(s): AppState,
+) -> Result<impl IntoResponse, AppError> {
+    let mut login =
+        LoginState::new(q.next.as_deref().and_then(|next| {
+            safe_next(next, &s.cors_origins.read().expect("CORS origins poisoned"))
+        }));
+    login.invite = q.invite.filter(|code| code.len() <= invites::MAX_CODE_LEN);
+    let state = s.state_key.sign(&login)?;
+    let extra = q.scope.as_deref().unwrap_or_default().split_whitespace();
+    let scope = SCOPES
+        .split_whitespace()
+        .chain(extra.filter(|scope| scope.chars().all(|c| c.is_ascii_lowercase() || c == '-')))
+        .unique()
+        .join(" ");
     let qs = serde_qs::to_string(&json!({
         "response_type": "code",
-        "client_id": dotenv!("CLIENT_ID"),
-        "scope": "streaming user-read-email user-read-private",
-        "redirect_uri": "http://localhost:3000/auth/callback",
+        "client_id": spotify::client_id(),
+        "scope": scope,
+        "redirect_uri": s.redirect_uri,
         "state": state,
     }))?;
     tracing::debug!("qs: {qs:#?}");
-    let uri = Uri::builder()
-        .scheme("https")
-        .authority("accounts.spotify.com")
-        .path_and_query(format!("/authorize/?{qs}"))
-        .build()?;
+    let uri = if mock_spotify::enabled() {
+        // Straight back as if the user had consented, with a code the fake accepts.
+        let qs = serde_qs::to_string(&json!({ "code": "mock", "state": state }))?;
+        Uri::builder()
+            .path_and_query(format!("/auth/callback?{qs}"))
+            .build()?
+    } else {
+        Uri::builder()
+            .scheme("https")
+            .authority("accounts.spotify.com")
+            .path_and_query(format!("/authorize/?{qs}"))
+            .build()?
+    };
     tracing::debug!("uri: {uri}");
-    s.lock().unwrap().code_states.insert(state);
-    Ok(Redirect::to(&uri.to_string()))
+    Ok((
+        [(
+            header::SET_COOKIE,
+            format!(
+                "{}={}; Max-Age={}; Path=/auth; HttpOnly; SameSite=Lax",
+                login_state::NONCE_COOKIE,
+                login.nonce,
+                login_state::MAX_AGE.as_secs()
+            ),
+        )],
+        Redirect::to(&uri.to_string()),
+    ))
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "login_error.html")]
+struct LoginErrorTemplate {
+    cancelled: bool,
+    message: String,
+    layout: Layout,
+}
+
+impl Page for LoginErrorTemplate {
+    const PATH: &'static str = "login_error.html";
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SpotifyAuthResponse {
-    code: String,
-    state: String,
+#[derive(Template, Serialize)]
+#[template(path = "private.html")]
+struct PrivateTemplate {
+    user_id: String,
+    layout: Layout,
 }
 
+impl Page for PrivateTemplate {
+    const PATH: &'static str = "private.html";
+}
+
+#[allow(clippy::too_many_lines)]
 async fn send_spotify_token_request(
     Query(q): Query<SpotifyAuthResponse>,
     State(s): AppState,
+    layout: Layout,
+    format: Format,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
 ) -> Result<impl IntoResponse, AppError> {
-    if s.lock().unwrap().code_states.take(&q.state).is_none() {
-        tracing::warn!(
-            "Attempting to find state string {} in state collection {:#?}, but it was not found",
-            q.state,
-            s.lock().unwrap(),
-        );
-        return Ok((StatusCode::UNAUTHORIZED, "Unauthorized").into_response());
-    }
+    let nonce = cookies::from_headers(&headers, login_state::NONCE_COOKIE).unwrap_or_default();
+    let login = match s.state_key.verify(q.state(), nonce) {
+        Ok(login) => login,
+        Err(e) => {
+            tracing::warn!(
+                "Rejecting login callback with state {:?}: {e}",
+                Redacted(q.state())
+            );
+            return Ok((StatusCode::UNAUTHORIZED, "Unauthorized").into_response());
+        }
+    };
+    let clear_nonce = format!(
+        "{}=; Max-Age=0; Path=/auth; HttpOnly; SameSite=Lax",
+        login_state::NONCE_COOKIE
+    );
 
-    let client = reqwest::Client::new();
+    let code = match q {
+        SpotifyAuthResponse::Success { code, .. } => code,
+        SpotifyAuthResponse::Error { error, .. } => {
+            tracing::info!("Spotify login was not completed: {error}");
+            return Ok((
+                [(header::SET_COOKIE, clear_nonce)],
+                templates::respond(
+                    format,
+                    LoginErrorTemplate {
+                        cancelled: error == "access_denied",
+                        message: error,
+                        layout,
+                    },
+                ),
+            )
+                .into_response());
+        }
+    };
 
-    let request = client
-        .post("https://accounts.spotify.com/api/token")
-        .form(&json!({
-            "code": q.code,
-            "redirect_uri": "http://localhost:3000/auth/callback",
-            "grant_type": "authorization_code"
-        }))
-        .header(
-            "Authorization",
-            format!(
-                "Basic {}",
-                BASE64_STANDARD.encode(format!(
-                    "{}:{}",
-                    dotenv!("CLIENT_ID"),
-                    dotenv!("CLIENT_SECRET")
-                )),
+    // The nonce cookie is only cleared once we actually hold a token, so a failed exchange leaves
+    // the user able to start over instead of being stuck without a login or a session.
+    let exchanged = async {
+        let token = spotify::exchange_code(&code, &s.redirect_uri).await?;
+        let user = spotify::current_user(&token.access_token).await?;
+        anyhow::Ok((token, user))
+    };
+    let (token, user) = match exchanged.await {
+        Ok(exchanged) => exchanged,
+        Err(e) => {
+            tracing::error!("Failed to exchange authorization code for a token: {e}");
+            return Ok((
+                StatusCode::BAD_GATEWAY,
+                templates::respond(
+                    format,
+                    LoginErrorTemplate {
+                        cancelled: false,
+                        message: "we couldn't reach Spotify to finish logging you in".to_owned(),
+                        layout,
+                    },
+                ),
+            )
+                .into_response());
+        }
+    };
+    if !s.invites.admit(&user.id, login.invite.as_deref()).await? {
+        tracing::info!("Not letting {} into this invitation-only instance", user.id);
+        return Ok((
+            StatusCode::FORBIDDEN,
+            [(header::SET_COOKIE, clear_nonce)],
+            templates::respond(
+                format,
+                PrivateTemplate {
+                    user_id: user.id,
+                    layout,
+                },
             ),
-        );
+        )
+            .into_response());
+    }
+    let next = login.next.unwrap_or_else(|| "/".to_owned());
+    let premium = user.premium();
 
-    let response = request.send().await?;
-    let token: SpotifyToken = response.json().await?;
-    let max_age = token.expires_in;
-    let mut session_id = random_alphanum(32);
-    loop {
-        let is_duplicate = s.lock().unwrap().sessions.contains_key(&session_id);
-        if is_duplicate {
-            session_id = random_alphanum(32);
-        } else {
-            break;
-        }
+    if s.consent.needed(&user.id).await? {
+        let code = s
+            .consent
+            .hold(consent::Pending {
+                token,
+                user_id: user.id,
+                premium,
+                next,
+            })
+            .await;
+        return Ok((
+            AppendHeaders([
+                (header::SET_COOKIE, clear_nonce),
+                (
+                    header::SET_COOKIE,
+                    format!(
+                        "{}={code}; Max-Age={}; Path=/auth/consent; HttpOnly; SameSite=Lax",
+                        consent::COOKIE,
+                        login_state::MAX_AGE.as_secs()
+                    ),
+                ),
+            ]),
+            Redirect::to("/auth/consent"),
+        )
+            .into_response());
     }
-    s.lock().unwrap().sessions.insert(session_id.clone(), token);
 
+    let flash = Flash(format!("Logged in as {}", user.id));
+    let session_cookie = log_in(&s, &headers, peer.as_ref(), token, user.id, premium).await?;
     Ok((
-        [(
-            header::SET_COOKIE,
-            format!("session_id={session_id}; Max-Age={max_age}"),
-        )],
-        Redirect::to("/"),
+        AppendHeaders(
+            std::iter::once(clear_nonce)
+                .chain(session_cookie)
+                .map(|cookie| (header::SET_COOKIE, cookie)),
+        ),
+        flash,
+        Redirect::to(&next),
     )
         .into_response())
 }
 
-fn get_session(cookies: &str) -> Option<&str> {
-    cookies
-        .split(';')
-        .map(str::trim)
-        .filter_map(|s| s.split_once('='))
-        .find_map(|(key, val)| (key == "session_id").then_some(val))
+/// Gives the browser a session of `user_id` holding `token`, returning the `Set-Cookie` value of
+/// the session if it's a new one. New sessions are checked by [`logins::check`].
+async fn log_in(
+    s: &Arc<AppStateInner>,
+    headers: &HeaderMap,
+    peer: Option<&ConnectInfo<SocketAddr>>,
+    token: SpotifyToken,
+    user_id: String,
+    premium: Option<bool>,
+) -> anyhow::Result<Option<String>> {
+    let now = Utc::now();
+    let token_expires_at = now + chrono::Duration::seconds(token.expires_in.try_into()?);
+    let do_not_track = Privacy::of(headers).opted_out;
+
+    // Logging in again from a session of the same user, e.g. to grant missing scopes, gives that
+    // session the new token instead of starting another one.
+    let current = cookies::session(headers).map(SessionId::from);
+    if let Some(id) = current {
+        let data = s.sessions.get(&id.hash()).await?;
+        if let Some(data) = data.filter(|data| data.user_id == user_id) {
+            let ttl = session::ttl(&s.session_config, data.created_at, now);
+            let data = SessionData {
+                token,
+                token_expires_at,
+                last_seen: now,
+                premium,
+                do_not_track,
+                ..data
+            };
+            s.sessions.update(id.hash(), data, ttl).await?;
+            return Ok(None);
+        }
+    }
+
+    let data = SessionData {
+        premium,
+        user_id: user_id.clone(),
+        token_expires_at,
+        token,
+        created_at: now,
+        last_seen: now,
+        do_not_track,
+    };
+    let (session_id, ttl) = session::start(s, data).await?;
+    if let Some(ip) = s.lockout.client_ip(headers, peer) {
+        let s = s.clone();
+        tokio::spawn(async move { logins::check(&s, &user_id, ip).await });
+    }
+    Ok(Some(session::cookie(&s.session_config, &session_id, ttl)))
 }
 
 async fn test_session(State(s): AppState, headers: HeaderMap) -> impl IntoResponse {
-    let Some(cookies) = headers.get("Cookie") else {
-        return "false";
-    };
-    let Some(session_id) = get_session(cookies.to_str().unwrap()) else {
+    let Some(session_id) = cookies::session(&headers) else {
         return "false";
     };
 
-    if s.lock().unwrap().sessions.contains_key(session_id) {
-        "true"
-    } else {
-        "false"
+    match s
+        .sessions
+        .contains(&SessionId::from(session_id).hash())
+        .await
+    {
+        Ok(true) => "true",
+        Ok(false) => "false",
+        Err(e) => {
+            tracing::error!("Failed to look up session: {e:#}");
+            "false"
+        }
     }
 }
 
-fn get_token(headers: &HeaderMap) -> impl IntoResponse {
-    headers
-        .get("Cookie")
-        .and_then(|cookies| cookies.to_str().ok())
-        .and_then(get_session)
-        .map_or_else(
-            || StatusCode::NOT_FOUND.into_response(),
-            |session_id| session_id.to_owned().into_response(),
-        )
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct SpotifyToken {
-    access_token: String,
-    refresh_token: String,
-    expires_in: u64,
-    token_type: String,
-}
-
 #[tokio::main]
+#[allow(clippy::too_many_lines)]
 async fn main() -> anyhow::Result<()> {
-    let app_state = Arc::new(Mutex::new(AppStateInner::default()));
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                // axum logs rejections from built-in extractors with the `axum::rejection`
-                // target, at `TRACE` level. `axum::rejection=trace` enables showing those events
-                "blid_test=debug,tower_http=debug,axum::rejection=trace".into()
-            }),
-        )
-        .with(tracing_subscriber::fmt::layer().pretty())
-        .init();
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("client") => return client::run(args.skip(1)).await,
+        Some("migrate-sessions") => return migrate_sessions::run(args.skip(1)).await,
+        Some("load-test") => return load_test::run(args.skip(1)).await,
+        #[cfg(feature = "sql")]
+        Some("backup") => return backup::backup(args.skip(1)).await,
+        #[cfg(feature = "sql")]
+        Some("restore") => return backup::restore(args.skip(1)).await,
+        _ => {}
+    }
+
+    let config = config::Config::load()?;
+    logging::init(config.log_filter.as_deref());
+    if let Some((id, secret)) = config.spotify_credentials.clone() {
+        spotify::set_credentials(id, secret);
+    }
+    if config.check_only {
+        return check::run(&config).await;
+    }
+    if config.migrate_only {
+        return migrate_only(&config).await;
+    }
+    spotify::set_concurrency(config.spotify_concurrency);
+    templates::init(config.template_dir.clone());
+    if config.mock_spotify {
+        mock_spotify::enable();
+    } else if let Some(fixtures) = config.spotify_fixtures.clone() {
+        fixtures::enable(fixtures)?;
+    }
+    let app_state = Arc::new(AppStateInner::new(&config).await?);
+    leader::start(app_state.clone()).await;
+    rules::spawn_worker(app_state.clone());
+    history::spawn_collector(app_state.clone());
+    webhooks::spawn_worker(app_state.clone());
+    normalize::spawn_worker(app_state.clone());
+    digest::spawn_worker(app_state.clone());
+    releases::spawn_watcher(app_state.clone());
+    activity::spawn_worker(app_state.clone());
+    retention::spawn_worker(app_state.clone());
+    session_store::spawn_sweeper(app_state.clone());
+    reload::spawn(app_state.clone(), config.clone());
 
     let spotify_auth_routes = Router::new()
         .route("/", get(send_spotify_code_request))
         .route("/callback", get(send_spotify_token_request))
         .route("/test-session", get(test_session))
-        .with_state(app_state);
+        .merge(handoff::router())
+        .merge(consent::router())
+        .merge(device::router())
+        .with_state(app_state.clone());
 
     let app = Router::new()
-        .route("/", get(contacts))
+        .route(
+            "/metrics",
+            get(metrics::serve).with_state(app_state.clone()),
+        )
+        .route("/readyz", get(readyz).with_state(app_state.clone()))
         .nest("/auth", spotify_auth_routes)
+        .nest(
+            "/api",
+            api::router()
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    roles::enforce,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    api_keys::authenticate,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    deadline::limit,
+                ))
+                // Outside authentication, so preflights are answered without credentials.
+                .layer(cors::layer(app_state.cors_origins.clone()))
+                .with_state(app_state.clone()),
+        )
+        .merge(feed::router().with_state(app_state.clone()))
+        .merge(share::router().with_state(app_state.clone()))
+        .merge(art::router().with_state(app_state.clone()))
+        .merge(friends::router().with_state(app_state.clone()))
+        .merge(digest::router().with_state(app_state.clone()))
+        .merge(
+            admin::router()
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    admin::authenticate,
+                ))
+                .with_state(app_state.clone()),
+        )
+        .nest(
+            "/assets",
+            assets::router(config.asset_dir.clone())
+                .merge(themes::router().with_state(app_state.clone())),
+        );
+    #[cfg(feature = "player")]
+    let app = app.merge(widget::router().with_state(app_state.clone()));
+    let app = match &config.frontend {
+        Frontend::Pages => {
+            let app = app
+                .route("/", get(contacts).with_state(app_state.clone()))
+                .merge(pages::router().with_state(app_state.clone()))
+                .merge(pwa::router().with_state(app_state.clone()))
+                .merge(privacy::router().with_state(app_state.clone()))
+                .merge(icons::router().with_state(app_state.clone()));
+            #[cfg(feature = "library")]
+            let app = app.merge(partials::router().with_state(app_state.clone()));
+            app
+        }
+        Frontend::Spa(dir) => app
+            .fallback_service(ServeDir::new(dir).fallback(ServeFile::new(dir.join("index.html")))),
+        Frontend::None => app.merge(icons::router().with_state(app_state.clone())),
+    };
+    let app = app
+        .layer(middleware::from_fn(cookie_manager::manage))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            session::slide,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            cors::check_origin,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            lockout::guard,
+        ))
+        .layer(middleware::from_fn(privacy::detect))
         .layer(tower_http::trace::TraceLayer::new_for_http());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    axum::serve(listener, app).await?;
+    server::serve(app, &config).await?;
+    app_state.sessions.save_snapshot(&config).await?;
 
     Ok(())
 }
+
+/// Applies database migrations without serving, for deployments that migrate as a separate step.
+#[allow(clippy::unused_async)]
+async fn migrate_only(config: &config::Config) -> anyhow::Result<()> {
+    #[cfg(feature = "sql")]
+    if let Some(url) = &config.database_url {
+        return db::migrate(&db::connect(url).await?).await;
+    }
+    let _ = config;
+    anyhow::bail!("--migrate-only needs DATABASE_URL, with the sqlite-store or postgres feature")
+}
+
+/// Not ready while the database schema isn't the one this build expects, so a rollout doesn't
+/// route traffic to an instance ahead of or behind the database.
+async fn readyz(State(s): AppState) -> axum::response::Response {
+    match s.sessions.schema_version().await {
+        Ok(Some(version)) if !version.is_current() => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            axum::Json(json!({ "ready": false, "schema_version": version })),
+        )
+            .into_response(),
+        Ok(version) => {
+            axum::Json(json!({ "ready": true, "schema_version": version })).into_response()
+        }
+        Err(e) => {
+            tracing::warn!("Readiness check failed: {e:#}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                axum::Json(json!({ "ready": false, "error": e.to_string() })),
+            )
+                .into_response()
+        }
+    }
+}
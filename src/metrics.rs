@@ -0,0 +1,159 @@
+//! In-process metrics, rendered in the Prometheus text format at `/metrics` for scrapers holding
+//! `METRICS_TOKEN`.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Instant,
+};
+
+use crate::{token, AppStateInner};
+
+/// Upper bounds, in milliseconds, of the latency histogram buckets.
+const BUCKETS_MS: [u32; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+/// Upper bounds, in microseconds, of the lock wait histogram buckets. Uncontended locks are
+/// taken in well under the first.
+const BUCKETS_US: [u32; 10] = [10, 50, 100, 250, 500, 1000, 5000, 10000, 50000, 100_000];
+
+struct Histogram<const N: usize> {
+    buckets: [u64; N],
+    count: u64,
+    sum: f64,
+}
+
+impl<const N: usize> Default for Histogram<N> {
+    fn default() -> Self {
+        Self {
+            buckets: [0; N],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    fn observe(&mut self, value: f64, bounds: [u32; N]) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(bounds) {
+            if value <= f64::from(bound) {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, bounds: [u32; N]) {
+        for (bucket, bound) in self.buckets.iter().zip(bounds) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {bucket}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// Latency of calls to the Spotify API, keyed by `(endpoint, status)`. The lock is only held for
+/// a handful of additions, never across an `.await`.
+static UPSTREAM: Lazy<Mutex<BTreeMap<(&'static str, u16), Histogram<{ BUCKETS_MS.len() }>>>> =
+    Lazy::new(Mutex::default);
+
+/// How long taking the locks of the in-memory stores waited, keyed by store, to tell which ones
+/// requests queue up on.
+static LOCK_WAIT: Lazy<Mutex<BTreeMap<&'static str, Histogram<{ BUCKETS_US.len() }>>>> =
+    Lazy::new(Mutex::default);
+
+/// Records one upstream call. `status` is `0` when no response was received at all.
+pub fn observe_upstream(endpoint: &'static str, status: u16, latency: Duration) {
+    UPSTREAM
+        .lock()
+        .unwrap()
+        .entry((endpoint, status))
+        .or_default()
+        .observe(latency.as_secs_f64() * 1000.0, BUCKETS_MS);
+}
+
+fn observe_lock_wait(store: &'static str, since: Instant) {
+    LOCK_WAIT
+        .lock()
+        .unwrap()
+        .entry(store)
+        .or_default()
+        .observe(since.elapsed().as_secs_f64() * 1_000_000.0, BUCKETS_US);
+}
+
+/// Takes `lock` of `store` for reading, recording how long that waited.
+pub async fn read<'a, T: Send + Sync>(
+    store: &'static str,
+    lock: &'a RwLock<T>,
+) -> RwLockReadGuard<'a, T> {
+    let since = Instant::now();
+    let guard = lock.read().await;
+    observe_lock_wait(store, since);
+    guard
+}
+
+/// Takes `lock` of `store` for writing, recording how long that waited.
+pub async fn write<'a, T: Send + Sync>(
+    store: &'static str,
+    lock: &'a RwLock<T>,
+) -> RwLockWriteGuard<'a, T> {
+    let since = Instant::now();
+    let guard = lock.write().await;
+    observe_lock_wait(store, since);
+    guard
+}
+
+/// `/metrics`, a `404` without `METRICS_TOKEN` as with `/admin` without `ADMIN_TOKEN`.
+pub async fn serve(State(s): State<Arc<AppStateInner>>, headers: HeaderMap) -> Response {
+    let Some(expected) = &s.metrics_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if token::constant_time_eq(presented.as_bytes(), expected.as_bytes()) => {
+            render().into_response()
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+    }
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP spotify_request_duration_ms Latency of Spotify API calls.\n");
+    out.push_str("# TYPE spotify_request_duration_ms histogram\n");
+    for ((endpoint, status), h) in UPSTREAM.lock().unwrap().iter() {
+        let labels = format!("endpoint=\"{endpoint}\",status=\"{status}\"");
+        h.render(&mut out, "spotify_request_duration_ms", &labels, BUCKETS_MS);
+    }
+    out.push_str(
+        "# HELP store_lock_wait_us Time spent waiting for the locks of in-memory stores.\n",
+    );
+    out.push_str("# TYPE store_lock_wait_us histogram\n");
+    for (store, h) in LOCK_WAIT.lock().unwrap().iter() {
+        h.render(
+            &mut out,
+            "store_lock_wait_us",
+            &format!("store=\"{store}\""),
+            BUCKETS_US,
+        );
+    }
+    out
+}
@@ -0,0 +1,115 @@
+//! `blid-test migrate-sessions --from SOURCE --to DESTINATION`: copies sessions between session
+//! stores, so the store can be changed without logging everyone out. Each of the two is a Redis
+//! URL (`redis://` or `rediss://`, with the `redis-store` feature), a database URL (`sqlite:`,
+//! `postgres://` or `postgresql://`, with a SQL feature) or the path of a session snapshot.
+//!
+//! The in-memory store is migrated through its snapshot: stop the server with `SESSION_SNAPSHOT`
+//! set, migrate from the snapshot, then start it on the new store. Going the other way, migrate
+//! to a snapshot and start the server with `SESSION_SNAPSHOT` pointing at it.
+//!
+//! Sessions keep when they expire. Ones the destination already has are left alone, so a
+//! migration can be run again after one that failed halfway.
+
+use anyhow::{bail, Context};
+use chrono::Utc;
+use std::path::PathBuf;
+
+use crate::session_store::{SessionStore, Snapshot, StoredSession};
+
+const USAGE: &str = "usage: blid-test migrate-sessions --from SOURCE --to DESTINATION";
+
+enum Location {
+    /// Only opened with a store feature.
+    #[cfg_attr(not(any(feature = "redis-store", feature = "sql")), allow(dead_code))]
+    Store(SessionStore),
+    Snapshot(PathBuf),
+}
+
+impl Location {
+    #[cfg_attr(
+        not(any(feature = "redis-store", feature = "sql")),
+        allow(clippy::unused_async)
+    )]
+    async fn open(location: &str) -> anyhow::Result<Self> {
+        if location.starts_with("redis://") || location.starts_with("rediss://") {
+            #[cfg(feature = "redis-store")]
+            {
+                let client = redis::Client::open(location)?;
+                return Ok(Self::Store(SessionStore::Redis(
+                    client.get_connection_manager().await?,
+                )));
+            }
+            #[cfg(not(feature = "redis-store"))]
+            bail!("{location} needs blid-test built with the redis-store feature");
+        }
+        if ["sqlite:", "postgres://", "postgresql://"]
+            .iter()
+            .any(|scheme| location.starts_with(scheme))
+        {
+            #[cfg(feature = "sql")]
+            {
+                let pool = crate::db::connect(location).await?;
+                crate::db::migrate(&pool).await?;
+                return Ok(Self::Store(SessionStore::Sql(pool)));
+            }
+            #[cfg(not(feature = "sql"))]
+            bail!("{location} needs blid-test built with the sqlite-store or postgres feature");
+        }
+        Ok(Self::Snapshot(location.into()))
+    }
+
+    async fn read(&self) -> anyhow::Result<Vec<StoredSession>> {
+        match self {
+            Self::Store(store) => store.export().await,
+            Self::Snapshot(path) => Ok(Snapshot::read(path)?.sessions),
+        }
+    }
+
+    /// Returns how many sessions were written.
+    async fn write(&self, sessions: Vec<StoredSession>) -> anyhow::Result<usize> {
+        match self {
+            Self::Store(store) => store.import(sessions).await,
+            Self::Snapshot(path) => {
+                if path.exists() {
+                    bail!("{} already exists", path.display());
+                }
+                let written = sessions.len();
+                Snapshot {
+                    created_at: Utc::now(),
+                    sessions,
+                }
+                .write(path)?;
+                Ok(written)
+            }
+        }
+    }
+}
+
+/// Runs `blid-test migrate-sessions`, with `args` the arguments after `migrate-sessions`.
+pub async fn run(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut from = None;
+    let mut to = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = Some(args.next().context("--from needs a value")?),
+            "--to" => to = Some(args.next().context("--to needs a value")?),
+            other => bail!("unexpected argument `{other}`\n{USAGE}"),
+        }
+    }
+    let (Some(from), Some(to)) = (from, to) else {
+        bail!(USAGE);
+    };
+    if from == to {
+        bail!("--from and --to are the same");
+    }
+
+    let sessions = Location::open(&from).await?.read().await?;
+    let read = sessions.len();
+    println!("Read {read} sessions");
+    let written = Location::open(&to).await?.write(sessions).await?;
+    println!(
+        "Migrated {written} sessions, skipped {} that expired or were already there",
+        read - written
+    );
+    Ok(())
+}
@@ -0,0 +1,741 @@
+//! `--mock-spotify`: an in-process stand-in for the Spotify Web API and accounts service, for
+//! frontend work and demos without Spotify credentials. Requests that would go to Spotify are
+//! answered here instead (see [`crate::spotify`]), and logging in skips Spotify's consent screen.
+//!
+//! There's one made-up user with a catalog of a few artists, albums and tracks, a library, some
+//! playlists that can be edited, and a player that plays through the catalog as time passes and
+//! follows the player commands. Everything is kept in memory and starts over with the process.
+
+use axum::http::{header, Method, StatusCode};
+use chrono::{DateTime, Utc};
+use image::{codecs::png::PngEncoder, ExtendedColorType, ImageEncoder, Rgb, RgbImage};
+use once_cell::sync::OnceCell;
+use reqwest::{RequestBuilder, Response, Url};
+use serde_json::{json, Value};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::spotify::{
+    Album, Artist, AudioFeatures, Image, PlayHistory, SavedTrack, SimpleArtist, Track,
+};
+
+const USER_ID: &str = "demo";
+const ACCESS_TOKEN: &str = "mock-access-token";
+const REFRESH_TOKEN: &str = "mock-refresh-token";
+const TRACKS_PER_ALBUM: usize = 5;
+/// Plays kept in the recently played list, as many as Spotify returns.
+const RECENTLY_PLAYED: usize = 50;
+
+const ARTISTS: &[(&str, &[&str])] = &[
+    ("Northern Lights", &["indie pop", "dream pop"]),
+    ("The Paper Boats", &["indie rock"]),
+    ("Mira Sol", &["latin pop", "pop"]),
+    ("Low Tide Orchestra", &["ambient", "post-rock"]),
+    ("DJ Parallax", &["house", "electronic"]),
+    ("Cassette Club", &["synthwave", "electronic"]),
+];
+const ALBUMS_PER_ARTIST: usize = 2;
+/// Album art IDs are this, then the size code, then 24 hex digits, like Spotify's.
+const ALBUM_ART_PREFIX: &str = "ab67616d0000";
+const IMAGE_SIZES: [(u32, &str); 3] = [(640, "b273"), (300, "1e02"), (64, "4851")];
+const WORDS: &[&str] = &[
+    "Golden", "Paper", "Midnight", "Electric", "Quiet", "Neon", "Velvet", "Silver", "Summer",
+    "Hollow", "Crystal", "Wild",
+];
+const THINGS: &[&str] = &[
+    "Hearts", "Rivers", "Signals", "Streets", "Dreams", "Waves", "Lights", "Echoes", "Gardens",
+    "Stars", "Mirrors",
+];
+
+static MOCK: OnceCell<Mutex<Mock>> = OnceCell::new();
+
+/// Answers requests meant for Spotify from here on.
+pub fn enable() {
+    let _ = MOCK.set(Mutex::new(Mock::new()));
+    tracing::warn!("Spotify is mocked, nothing is sent to it");
+}
+
+pub fn enabled() -> bool {
+    MOCK.get().is_some()
+}
+
+/// Answers `request` as Spotify would. Only call when [`enabled`].
+pub fn respond(request: RequestBuilder) -> reqwest::Result<Response> {
+    let request = request.build()?;
+    let mut mock = MOCK
+        .get()
+        .expect("mock Spotify not enabled")
+        .lock()
+        .expect("mock Spotify poisoned");
+    let body = request
+        .body()
+        .and_then(reqwest::Body::as_bytes)
+        .unwrap_or_default();
+    Ok(mock.respond(request.method(), request.url(), body))
+}
+
+struct MockPlaylist {
+    name: String,
+    /// Bumped by every change, for the snapshot ID.
+    version: u32,
+    /// Indexes into the catalog's tracks.
+    tracks: Vec<usize>,
+}
+
+struct Player {
+    /// Index into the catalog's tracks.
+    track: usize,
+    playing: bool,
+    /// Progress into the track as of `mark`.
+    progress: Duration,
+    mark: Instant,
+    volume_percent: u32,
+}
+
+struct Mock {
+    artists: Vec<Artist>,
+    albums: Vec<Album>,
+    tracks: Vec<Track>,
+    playlists: Vec<MockPlaylist>,
+    /// Indexes into `tracks` with when they were saved, most recent first.
+    saved: Vec<(usize, DateTime<Utc>)>,
+    /// Most recent first.
+    played: Vec<(usize, DateTime<Utc>)>,
+    player: Player,
+}
+
+impl Mock {
+    fn new() -> Self {
+        let artists: Vec<Artist> = ARTISTS
+            .iter()
+            .enumerate()
+            .map(|(i, (name, genres))| Artist {
+                id: id("artist", i),
+                name: (*name).to_owned(),
+                genres: genres.iter().map(|&genre| genre.to_owned()).collect(),
+            })
+            .collect();
+        let albums: Vec<Album> = (0..artists.len() * ALBUMS_PER_ARTIST)
+            .map(|i| Album {
+                id: id("album", i),
+                name: title(i * 7 + 3),
+                images: IMAGE_SIZES
+                    .iter()
+                    .map(|&(size, code)| Image {
+                        url: format!("https://i.scdn.co/image/{ALBUM_ART_PREFIX}{code}{i:024x}"),
+                        width: Some(size),
+                        height: Some(size),
+                        blurhash: None,
+                    })
+                    .collect(),
+                release_date: Some(format!("{}-0{}-1{}", 2015 + i % 10, 1 + i % 9, i % 10)),
+            })
+            .collect();
+        let tracks: Vec<Track> = (0..albums.len() * TRACKS_PER_ALBUM)
+            .map(|i| {
+                let album = i / TRACKS_PER_ALBUM;
+                let artist = &artists[album / ALBUMS_PER_ARTIST];
+                Track {
+                    id: id("track", i),
+                    name: title(i),
+                    uri: format!("spotify:track:{}", id("track", i)),
+                    duration_ms: 150_000 + (i as u64 * 37_000) % 120_000,
+                    artists: vec![SimpleArtist {
+                        id: artist.id.clone(),
+                        name: artist.name.clone(),
+                    }],
+                    album: albums[album].clone(),
+                    popularity: Some(u32::try_from(20 + i * 13 % 80).unwrap_or(50)),
+                    available_markets: None,
+                    is_playable: None,
+                    linked_from: None,
+                }
+            })
+            .collect();
+        let now = Utc::now();
+        let playlists = vec![
+            MockPlaylist {
+                name: "Morning Coffee".to_owned(),
+                version: 1,
+                tracks: (0..15).collect(),
+            },
+            MockPlaylist {
+                name: "Late Night Drive".to_owned(),
+                version: 1,
+                // With a few duplicates, to have something to dedupe.
+                tracks: (40..60).chain([42, 47, 51]).collect(),
+            },
+            MockPlaylist {
+                name: "Workout".to_owned(),
+                version: 1,
+                tracks: (20..45).rev().collect(),
+            },
+        ];
+        let saved = (0..tracks.len())
+            .step_by(2)
+            .map(|i| {
+                (
+                    i,
+                    now - chrono::Duration::days(i.try_into().unwrap_or_default()),
+                )
+            })
+            .collect();
+        let played = (0..10)
+            .map(|i| {
+                (
+                    usize::try_from(i * 3).unwrap_or_default(),
+                    now - chrono::Duration::minutes(4 * (i + 1)),
+                )
+            })
+            .collect();
+        Self {
+            artists,
+            albums,
+            tracks,
+            playlists,
+            saved,
+            played,
+            player: Player {
+                track: 0,
+                playing: true,
+                progress: Duration::ZERO,
+                mark: Instant::now(),
+                volume_percent: 60,
+            },
+        }
+    }
+
+    /// A route table, which reads best as one `match` with each route's response in place.
+    #[allow(clippy::too_many_lines, clippy::option_if_let_else)]
+    fn respond(&mut self, method: &Method, url: &Url, body: &[u8]) -> Response {
+        if url.host_str() == Some("accounts.spotify.com") {
+            return token();
+        }
+        if url.host_str() == Some("i.scdn.co") {
+            return image(url.path());
+        }
+        self.advance();
+        let path = url.path().strip_prefix("/v1").unwrap_or_else(|| url.path());
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let body: Value = serde_json::from_slice(body).unwrap_or(Value::Null);
+        match (method.as_str(), segments.as_slice()) {
+            ("GET", ["me"]) => ok(json!({
+                "id": USER_ID,
+                "display_name": "Demo User",
+                "email": "demo@example.com",
+                "product": "premium",
+            })),
+            ("GET", ["me", "playlists"]) => {
+                let playlists = (0..self.playlists.len())
+                    .map(|i| self.playlist(i))
+                    .collect();
+                ok(page(url, playlists))
+            }
+            ("GET", ["me", "tracks"]) => {
+                let saved = self
+                    .saved
+                    .iter()
+                    .map(|&(i, added_at)| {
+                        json!(SavedTrack {
+                            added_at: added_at.to_rfc3339(),
+                            track: self.tracks[i].clone(),
+                        })
+                    })
+                    .collect();
+                ok(page(url, saved))
+            }
+            ("GET", ["search"]) => {
+                let wanted = url
+                    .query_pairs()
+                    .find(|(name, _)| name == "q")
+                    .map(|(_, q)| q.to_lowercase())
+                    .unwrap_or_default();
+                let tracks = self
+                    .tracks
+                    .iter()
+                    .filter(|track| track.name.to_lowercase().contains(&wanted))
+                    .map(|track| json!(track))
+                    .collect();
+                ok(json!({ "tracks": page(url, tracks) }))
+            }
+            ("PUT", ["me", "tracks"]) => {
+                for i in ids(url).filter_map(|id| index(id, "track")) {
+                    if i < self.tracks.len() && !self.saved.iter().any(|&(saved, _)| saved == i) {
+                        self.saved.insert(0, (i, Utc::now()));
+                    }
+                }
+                no_content()
+            }
+            ("DELETE", ["me", "tracks"]) => {
+                let removed: Vec<usize> = ids(url).filter_map(|id| index(id, "track")).collect();
+                self.saved.retain(|(i, _)| !removed.contains(i));
+                no_content()
+            }
+            ("PUT" | "DELETE", ["me", "following"]) | ("POST", ["me", "player", "queue"]) => {
+                no_content()
+            }
+            ("GET", ["me", "following"]) => {
+                let artists = self.artists.iter().map(|artist| json!(artist)).collect();
+                ok(json!({ "artists": page(url, artists) }))
+            }
+            ("GET", ["me", "player", "recently-played"]) => {
+                let played = self
+                    .played
+                    .iter()
+                    .map(|&(i, played_at)| {
+                        json!(PlayHistory {
+                            track: self.tracks[i].clone(),
+                            played_at: played_at.to_rfc3339(),
+                        })
+                    })
+                    .collect();
+                ok(page(url, played))
+            }
+            ("GET", ["me", "player"] | ["me", "player", "currently-playing"]) => {
+                ok(self.playback())
+            }
+            ("GET", ["me", "player", "queue"]) => {
+                let track = |i: usize| {
+                    let mut track = json!(self.tracks[i % self.tracks.len()]);
+                    track["type"] = json!("track");
+                    track
+                };
+                let queue: Vec<Value> = (1..=20).map(|n| track(self.player.track + n)).collect();
+                ok(json!({ "currently_playing": track(self.player.track), "queue": queue }))
+            }
+            ("PUT", ["me", "player", "pause"]) => {
+                self.player.playing = false;
+                no_content()
+            }
+            ("PUT", ["me", "player", "play"]) => {
+                self.player.playing = true;
+                no_content()
+            }
+            ("PUT", ["me", "player", "seek"]) => {
+                let position = query(url, "position_ms").unwrap_or(0);
+                self.player.progress = Duration::from_millis(position.try_into().unwrap_or(0));
+                no_content()
+            }
+            ("PUT", ["me", "player", "volume"]) => {
+                let volume = query(url, "volume_percent").unwrap_or(50).min(100);
+                self.player.volume_percent = u32::try_from(volume).unwrap_or(50);
+                no_content()
+            }
+            ("POST", ["me", "player", "next"]) => {
+                self.skip(1);
+                no_content()
+            }
+            ("POST", ["me", "player", "previous"]) => {
+                self.skip(self.tracks.len() - 1);
+                no_content()
+            }
+            ("GET", ["playlists", playlist]) => match self.playlist_index(playlist) {
+                Some(i) => ok(self.playlist(i)),
+                None => not_found(),
+            },
+            ("GET", ["playlists", playlist, "tracks"]) => match self.playlist_index(playlist) {
+                Some(i) => {
+                    let items = self.playlists[i]
+                        .tracks
+                        .iter()
+                        .map(|&track| {
+                            json!({
+                                "added_at": "2024-01-01T00:00:00Z",
+                                "added_by": { "id": USER_ID },
+                                "track": self.tracks[track],
+                            })
+                        })
+                        .collect();
+                    ok(page(url, items))
+                }
+                None => not_found(),
+            },
+            ("PUT", ["playlists", playlist, "images"]) => match self.playlist_index(playlist) {
+                Some(_) => respond_bytes(StatusCode::ACCEPTED, "application/json", Vec::new()),
+                None => not_found(),
+            },
+            (method, ["playlists", playlist, "tracks"]) => match self.playlist_index(playlist) {
+                Some(i) => self.edit_playlist(i, method, &body),
+                None => not_found(),
+            },
+            ("POST", ["users", _, "playlists"]) => {
+                self.playlists.push(MockPlaylist {
+                    name: body["name"].as_str().unwrap_or("New playlist").to_owned(),
+                    version: 1,
+                    tracks: Vec::new(),
+                });
+                ok(self.playlist(self.playlists.len() - 1))
+            }
+            ("GET", ["tracks"]) => {
+                let tracks = by_ids(url, "track", &self.tracks);
+                ok(json!({ "tracks": tracks }))
+            }
+            ("GET", ["tracks", track]) => {
+                match index(track, "track").and_then(|i| self.tracks.get(i)) {
+                    Some(track) => ok(json!(track)),
+                    None => not_found(),
+                }
+            }
+            ("GET", ["artists"]) => {
+                let artists = by_ids(url, "artist", &self.artists);
+                ok(json!({ "artists": artists }))
+            }
+            ("GET", ["artists", artist, "albums"]) => match index(artist, "artist") {
+                Some(artist) if artist < self.artists.len() => {
+                    let albums = self.albums
+                        [artist * ALBUMS_PER_ARTIST..(artist + 1) * ALBUMS_PER_ARTIST]
+                        .iter()
+                        .map(|album| json!(album))
+                        .collect();
+                    ok(page(url, albums))
+                }
+                _ => not_found(),
+            },
+            ("GET", ["albums"]) => {
+                let albums: Vec<Value> = ids(url)
+                    .map(|id| {
+                        index(id, "album")
+                            .filter(|&i| i < self.albums.len())
+                            .map_or(Value::Null, |i| {
+                                let mut album = json!(self.albums[i]);
+                                let tracks =
+                                    &self.tracks[i * TRACKS_PER_ALBUM..(i + 1) * TRACKS_PER_ALBUM];
+                                album["tracks"] = json!({ "items": tracks, "next": null });
+                                album
+                            })
+                    })
+                    .collect();
+                ok(json!({ "albums": albums }))
+            }
+            ("GET", ["audio-features"]) => {
+                let features: Vec<Value> = ids(url)
+                    .map(|id| {
+                        index(id, "track")
+                            .filter(|&i| i < self.tracks.len())
+                            .map_or(Value::Null, |i| json!(features(i)))
+                    })
+                    .collect();
+                ok(json!({ "audio_features": features }))
+            }
+            ("GET", ["audio-analysis", track]) => {
+                match index(track, "track").and_then(|i| self.tracks.get(i)) {
+                    Some(track) => ok(analysis(track)),
+                    None => not_found(),
+                }
+            }
+            _ => respond_json(
+                StatusCode::NOT_FOUND,
+                &json!({ "error": { "status": 404, "message": "Not mocked" } }),
+            ),
+        }
+    }
+
+    /// Moves the player along to where it would be by now, recording tracks played to the end.
+    fn advance(&mut self) {
+        let now = Instant::now();
+        if self.player.playing {
+            self.player.progress += now - self.player.mark;
+        }
+        self.player.mark = now;
+        loop {
+            let duration = Duration::from_millis(self.tracks[self.player.track].duration_ms);
+            if self.player.progress < duration {
+                break;
+            }
+            let left = self.player.progress.saturating_sub(duration);
+            self.skip(1);
+            self.player.progress = left;
+        }
+    }
+
+    /// Skips `by` tracks ahead, wrapping around the catalog.
+    fn skip(&mut self, by: usize) {
+        self.played.insert(0, (self.player.track, Utc::now()));
+        self.played.truncate(RECENTLY_PLAYED);
+        self.player.track = (self.player.track + by) % self.tracks.len();
+        self.player.progress = Duration::ZERO;
+    }
+
+    fn playback(&self) -> Value {
+        json!({
+            "device": {
+                "id": "mock-device",
+                "name": "Demo Speaker",
+                "is_active": true,
+                "volume_percent": self.player.volume_percent,
+            },
+            "is_playing": self.player.playing,
+            "progress_ms": u64::try_from(self.player.progress.as_millis()).unwrap_or(0),
+            "item": self.tracks[self.player.track],
+        })
+    }
+
+    fn playlist_index(&self, id: &str) -> Option<usize> {
+        index(id, "playlist").filter(|&i| i < self.playlists.len())
+    }
+
+    fn playlist(&self, i: usize) -> Value {
+        let playlist = &self.playlists[i];
+        let images = playlist
+            .tracks
+            .first()
+            .map(|&track| self.tracks[track].album.images.clone())
+            .unwrap_or_default();
+        json!({
+            "id": id("playlist", i),
+            "name": playlist.name,
+            "snapshot_id": format!("{}-{}", id("playlist", i), playlist.version),
+            "tracks": { "total": playlist.tracks.len() },
+            "images": images,
+        })
+    }
+
+    /// Removes, reorders, replaces or adds tracks as the Spotify endpoint would.
+    fn edit_playlist(&mut self, i: usize, method: &str, body: &Value) -> Response {
+        let uris = |value: &Value| -> Vec<usize> {
+            value
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|uri| uri.as_str()?.strip_prefix("spotify:track:"))
+                .filter_map(|id| index(id, "track"))
+                .filter(|&track| track < self.tracks.len())
+                .collect()
+        };
+        let added = uris(&body["uris"]);
+        let playlist = &mut self.playlists[i];
+        match method {
+            "DELETE" => {
+                let mut positions: Vec<usize> = body["tracks"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|track| track["positions"].as_array().cloned().unwrap_or_default())
+                    .filter_map(|position| usize::try_from(position.as_u64()?).ok())
+                    .filter(|&position| position < playlist.tracks.len())
+                    .collect();
+                positions.sort_unstable();
+                positions.dedup();
+                for position in positions.into_iter().rev() {
+                    playlist.tracks.remove(position);
+                }
+            }
+            "PUT" if body.get("range_start").is_some() => {
+                let field = |name: &str| {
+                    body[name]
+                        .as_u64()
+                        .and_then(|n| usize::try_from(n).ok())
+                        .unwrap_or(0)
+                };
+                let (start, before) = (field("range_start"), field("insert_before"));
+                let length = field("range_length").max(1);
+                if start + length > playlist.tracks.len() || before > playlist.tracks.len() {
+                    return bad_request("range out of bounds");
+                }
+                let moved: Vec<usize> = playlist.tracks.drain(start..start + length).collect();
+                let at = if before > start {
+                    before - length
+                } else {
+                    before
+                };
+                playlist.tracks.splice(at..at, moved);
+            }
+            "PUT" => playlist.tracks = added,
+            "POST" => playlist.tracks.extend(added),
+            _ => return not_found(),
+        }
+        playlist.version += 1;
+        ok(json!({ "snapshot_id": format!("{}-{}", id("playlist", i), playlist.version) }))
+    }
+}
+
+/// The items of `kind` whose IDs are in the `ids` query of `url`, `null` for unknown ones.
+fn by_ids<T: serde::Serialize>(url: &Url, kind: &str, items: &[T]) -> Vec<Value> {
+    ids(url)
+        .map(|id| {
+            index(id, kind)
+                .and_then(|i| items.get(i))
+                .map_or(Value::Null, |item| json!(item))
+        })
+        .collect()
+}
+
+/// IDs are as long as Spotify's, with the index of what they identify at the end.
+fn id(kind: &str, i: usize) -> String {
+    format!("mock{kind}{i:0>width$}", width = 18 - kind.len())
+}
+
+fn index(id: &str, kind: &str) -> Option<usize> {
+    id.strip_prefix("mock")?.strip_prefix(kind)?.parse().ok()
+}
+
+fn title(i: usize) -> String {
+    format!(
+        "{} {}",
+        WORDS[i % WORDS.len()],
+        THINGS[i * 5 % THINGS.len()]
+    )
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn features(i: usize) -> AudioFeatures {
+    let fraction = |n: usize| (n % 10) as f32 / 10.0 + 0.05;
+    AudioFeatures {
+        id: id("track", i),
+        tempo: 80.0 + (i * 13 % 90) as f32,
+        energy: fraction(i * 7),
+        danceability: fraction(i * 3 + 1),
+        valence: fraction(i * 11 + 2),
+        loudness: -4.0 - (i % 9) as f32,
+    }
+}
+
+/// Four sections of equal length, the last getting quieter.
+#[allow(clippy::cast_precision_loss)]
+fn analysis(track: &Track) -> Value {
+    let duration = track.duration_ms as f32 / 1000.0;
+    let sections: Vec<Value> = (0..4)
+        .map(|n| {
+            json!({
+                "start": duration * n as f32 / 4.0,
+                "duration": duration / 4.0,
+                "loudness": if n == 3 { -14.0 } else { -7.0 },
+                "tempo": 120.0,
+            })
+        })
+        .collect();
+    json!({
+        "track": {
+            "duration": duration,
+            "end_of_fade_in": 0.5,
+            "start_of_fade_out": (duration - 8.0).max(0.0),
+        },
+        "sections": sections,
+    })
+}
+
+fn token() -> Response {
+    ok(json!({
+        "access_token": ACCESS_TOKEN,
+        "refresh_token": REFRESH_TOKEN,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "scope": crate::SCOPES,
+    }))
+}
+
+/// A gradient in the colors of the album numbered by the image ID, at the size its code is for.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss,
+    clippy::suboptimal_flops
+)]
+fn image(path: &str) -> Response {
+    let id = path.rsplit('/').next().unwrap_or_default();
+    let Some((size, album)) = id.strip_prefix(ALBUM_ART_PREFIX).and_then(|rest| {
+        let (code, album) = rest.split_at_checked(4)?;
+        let (size, _) = IMAGE_SIZES.iter().find(|(_, known)| *known == code)?;
+        Some((*size, usize::from_str_radix(album, 16).ok()?))
+    }) else {
+        return not_found();
+    };
+    let hue = |offset: usize| u8::try_from((album * 67 + offset) % 200 + 40).unwrap_or(128);
+    let (from, to) = ([hue(0), hue(80), hue(160)], [hue(120), hue(20), hue(60)]);
+    let image = RgbImage::from_fn(size, size, |x, y| {
+        let t = (x + y) as f32 / (2 * size) as f32;
+        Rgb(std::array::from_fn(|c| {
+            (f32::from(from[c]) * (1.0 - t) + f32::from(to[c]) * t) as u8
+        }))
+    });
+    let mut png = Vec::new();
+    let encoded = PngEncoder::new(&mut png).write_image(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ExtendedColorType::Rgb8,
+    );
+    match encoded {
+        Ok(()) => respond_bytes(StatusCode::OK, "image/png", png),
+        Err(e) => respond_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &json!({ "error": { "status": 500, "message": e.to_string() } }),
+        ),
+    }
+}
+
+/// A page of `items` at the `offset` and `limit` of `url`, linking to the next one.
+fn page(url: &Url, items: Vec<Value>) -> Value {
+    let offset = query(url, "offset").unwrap_or(0);
+    let limit = query(url, "limit").unwrap_or(20).clamp(1, 100);
+    let total = items.len();
+    let next = (offset + limit < total).then(|| {
+        let mut next = url.clone();
+        let kept: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| name != "offset" && name != "limit")
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        next.query_pairs_mut()
+            .clear()
+            .extend_pairs(kept)
+            .append_pair("offset", &(offset + limit).to_string())
+            .append_pair("limit", &limit.to_string());
+        next.to_string()
+    });
+    let items: Vec<Value> = items.into_iter().skip(offset).take(limit).collect();
+    json!({ "items": items, "next": next, "total": total, "offset": offset, "limit": limit })
+}
+
+fn query(url: &Url, name: &str) -> Option<usize> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// The comma-separated `ids` of `url`.
+fn ids(url: &Url) -> impl Iterator<Item = &str> {
+    url.query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("ids="))
+        .flat_map(|ids| ids.split("%2C").flat_map(|ids| ids.split(',')))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn ok(body: Value) -> Response {
+    respond_json(StatusCode::OK, &body)
+}
+
+fn no_content() -> Response {
+    respond_bytes(StatusCode::NO_CONTENT, "application/json", Vec::new())
+}
+
+fn not_found() -> Response {
+    respond_json(
+        StatusCode::NOT_FOUND,
+        &json!({ "error": { "status": 404, "message": "Not found" } }),
+    )
+}
+
+fn bad_request(message: &str) -> Response {
+    respond_json(
+        StatusCode::BAD_REQUEST,
+        &json!({ "error": { "status": 400, "message": message } }),
+    )
+}
+
+fn respond_json(status: StatusCode, body: &Value) -> Response {
+    respond_bytes(status, "application/json", body.to_string().into_bytes())
+}
+
+fn respond_bytes(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response {
+    axum::http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .expect("valid mock response")
+        .into()
+}
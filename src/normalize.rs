@@ -0,0 +1,300 @@
+//! Volume normalization for speakers that lack it. For users who opt in, a background worker
+//! watches playback and, whenever the track changes, nudges the device volume by how much louder
+//! or quieter than average the track is, per its audio features.
+//!
+//! Adjustments are small and relative to the volume the user chose: the worker remembers how far
+//! it moved the volume, so turning it up or down by hand between tracks is kept.
+//!
+//! Who opted in is kept in memory by default, or in the database with a SQL feature and
+//! `DATABASE_URL` set, in which case only the instance holding the workers lease adjusts volumes.
+//! How far it moved each one stays in the memory of that instance.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    spotify::{self, PremiumRequired},
+    AppStateInner,
+};
+
+/// Often enough to adjust early in a track, without polling Spotify constantly.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Loudness in dB that gets no adjustment, about that of typical mastered pop.
+const REFERENCE_LOUDNESS: f32 = -8.0;
+/// Volume points per dB of difference from the reference.
+const POINTS_PER_DB: f32 = 1.5;
+/// The most the volume is ever moved from what the user chose, either way.
+const MAX_ADJUSTMENT: i32 = 10;
+
+#[derive(Default)]
+struct Normalization {
+    /// The track the current adjustment was made for.
+    track_id: Option<String>,
+    /// Volume points added to what the user chose, negative when turned down.
+    applied: i32,
+}
+
+#[derive(Default)]
+pub struct NormalizationStore {
+    users: Users,
+    /// Of the users whose volume this instance adjusted.
+    adjustments: RwLock<HashMap<String, Normalization>>,
+}
+
+/// Users who enabled it.
+enum Users {
+    Memory(RwLock<HashSet<String>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl NormalizationStore {
+    #[cfg(feature = "sql")]
+    pub fn in_database(pool: db::Pool) -> Self {
+        Self {
+            users: Users::Sql(pool),
+            adjustments: RwLock::default(),
+        }
+    }
+
+    pub async fn enable(&self, user_id: &str) -> anyhow::Result<()> {
+        match &self.users {
+            Users::Memory(users) => {
+                users.write().await.insert(user_id.to_owned());
+            }
+            #[cfg(feature = "sql")]
+            Users::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO normalization (user_id) VALUES ($1) \
+                     ON CONFLICT (user_id) DO NOTHING",
+                )
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Disables normalization for a user, returning whether it was enabled. The volume is left
+    /// where it is.
+    pub async fn disable(&self, user_id: &str) -> anyhow::Result<bool> {
+        self.adjustments.write().await.remove(user_id);
+        match &self.users {
+            Users::Memory(users) => Ok(users.write().await.remove(user_id)),
+            #[cfg(feature = "sql")]
+            Users::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM normalization WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub async fn enabled(&self, user_id: &str) -> anyhow::Result<bool> {
+        match &self.users {
+            Users::Memory(users) => Ok(users.read().await.contains(user_id)),
+            #[cfg(feature = "sql")]
+            Users::Sql(pool) => {
+                let found: Option<String> =
+                    sqlx::query_scalar("SELECT user_id FROM normalization WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_optional(pool)
+                        .await?;
+                Ok(found.is_some())
+            }
+        }
+    }
+
+    async fn users(&self) -> anyhow::Result<Vec<String>> {
+        match &self.users {
+            Users::Memory(users) => Ok(users.read().await.iter().cloned().collect()),
+            #[cfg(feature = "sql")]
+            Users::Sql(pool) => Ok(sqlx::query_scalar("SELECT user_id FROM normalization")
+                .fetch_all(pool)
+                .await?),
+        }
+    }
+
+    /// Whether every instance sees the same users, so only one of them should adjust volumes.
+    const fn is_shared(&self) -> bool {
+        match self.users {
+            Users::Memory(_) => false,
+            #[cfg(feature = "sql")]
+            Users::Sql(_) => true,
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.users {
+            Users::Memory(users) => users.try_read().ok().map(|u| u.len()),
+            #[cfg(feature = "sql")]
+            Users::Sql(_) => None,
+        }
+    }
+}
+
+/// How many volume points to add for a track of this loudness.
+#[allow(clippy::cast_possible_truncation)]
+fn adjustment(loudness: f32) -> i32 {
+    // Truncating is fine: a point of volume is barely audible.
+    (((REFERENCE_LOUDNESS - loudness) * POINTS_PER_DB) as i32)
+        .clamp(-MAX_ADJUSTMENT, MAX_ADJUSTMENT)
+}
+
+pub fn spawn_worker(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Users kept in memory are this instance's own, so it adjusts for them whoever leads.
+            if state.normalization.is_shared() && !state.leader.holds() {
+                continue;
+            }
+            let users = match state.normalization.users().await {
+                Ok(users) => users,
+                Err(e) => {
+                    tracing::error!("Failed to list users for volume normalization: {e:#}");
+                    continue;
+                }
+            };
+            // Forget what was done for users who disabled it from another instance.
+            state
+                .normalization
+                .adjustments
+                .write()
+                .await
+                .retain(|user_id, _| users.contains(user_id));
+            if users.is_empty() {
+                continue;
+            }
+            let sessions = match state.sessions.all().await {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    tracing::error!("Failed to list sessions for volume normalization: {e:#}");
+                    continue;
+                }
+            };
+            for user_id in users {
+                // Any live session of the user will do; without one there's no token to use.
+                let Some(session) = sessions.iter().find(|session| session.user_id == user_id)
+                else {
+                    continue;
+                };
+                match normalize(&state, &user_id, &session.token.access_token).await {
+                    Ok(()) => {}
+                    // It will never work for this user, so stop trying.
+                    Err(e) if e.is::<PremiumRequired>() => {
+                        if let Err(e) = state.normalization.disable(&user_id).await {
+                            tracing::error!("Failed to disable volume normalization: {e:#}");
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to normalize volume: {e:#}"),
+                }
+            }
+        }
+    });
+}
+
+/// Adjusts the volume if a new track started since the last time.
+async fn normalize(state: &AppStateInner, user_id: &str, access_token: &str) -> anyhow::Result<()> {
+    let Some(playback) = spotify::playback_state(access_token).await? else {
+        return Ok(());
+    };
+    let (Some(track), Some(volume)) = (playback.item, playback.device.volume_percent) else {
+        return Ok(());
+    };
+    if !playback.is_playing {
+        return Ok(());
+    }
+    let applied = match state.normalization.adjustments.read().await.get(user_id) {
+        // Already adjusted for this track.
+        Some(normalization) if normalization.track_id.as_ref() == Some(&track.id) => {
+            return Ok(());
+        }
+        Some(normalization) => normalization.applied,
+        None => 0,
+    };
+
+    let features = state
+        .features
+        .resolve(access_token, std::slice::from_ref(&track.id))
+        .await?;
+    // Tracks without an analysis are played as the user set them.
+    let adjustment = features
+        .get(&track.id)
+        .map_or(0, |f| adjustment(f.loudness));
+    let chosen = (i32::try_from(volume)? - applied).clamp(0, 100);
+    let target = (chosen + adjustment).clamp(0, 100);
+    if target != i32::try_from(volume)? {
+        spotify::set_volume(access_token, target.try_into()?).await?;
+    }
+
+    state.normalization.adjustments.write().await.insert(
+        user_id.to_owned(),
+        Normalization {
+            track_id: Some(track.id),
+            applied: target - chosen,
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn keeps_who_enabled_it(store: &NormalizationStore) {
+        assert!(!store.enabled("ann").await.unwrap());
+        store.enable("ann").await.unwrap();
+        store.enable("ann").await.unwrap();
+        assert!(store.enabled("ann").await.unwrap());
+        assert_eq!(store.users().await.unwrap(), ["ann"]);
+        assert!(store.disable("ann").await.unwrap());
+        assert!(!store.disable("ann").await.unwrap());
+        assert!(store.users().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_who_enabled_it_in_memory() {
+        keeps_who_enabled_it(&NormalizationStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn keeps_who_enabled_it_in_the_database() {
+        let path = std::env::temp_dir().join(format!(
+            "blid-normalization-{}.db",
+            crate::token::generate(8)
+        ));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        keeps_who_enabled_it(&NormalizationStore::in_database(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn adjusts_within_bounds() {
+        assert_eq!(adjustment(REFERENCE_LOUDNESS), 0);
+        assert!(adjustment(-2.0) < 0);
+        assert!(adjustment(-14.0) > 0);
+        assert_eq!(adjustment(-60.0), MAX_ADJUSTMENT);
+        assert_eq!(adjustment(10.0), -MAX_ADJUSTMENT);
+    }
+}
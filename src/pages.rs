@@ -0,0 +1,475 @@
+//! Server-rendered pages for logged-in users.
+
+use askama_axum::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use base64::prelude::*;
+#[cfg(feature = "stats")]
+use chrono::{Datelike, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[cfg(feature = "stats")]
+use crate::stats;
+#[cfg(feature = "player")]
+use crate::{
+    api::{NowPlaying, READ_PLAYBACK},
+    art,
+};
+use crate::{
+    ids::SpotifyId,
+    session::PageSession,
+    spotify,
+    templates::{self, Format, Layout, Page},
+    AppError, AppStateInner,
+};
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    let router = Router::new()
+        .route("/playlists/:id", get(playlist))
+        .route("/settings/keys", get(api_keys));
+    #[cfg(feature = "player")]
+    let router = router.route("/player", get(player));
+    #[cfg(feature = "library")]
+    let router = router.route("/library", get(library));
+    #[cfg(feature = "stats")]
+    let router = router.route("/wrapped", get(wrapped));
+    router
+}
+
+/// Tracks per page of a playlist.
+const PLAYLIST_PAGE: usize = 100;
+
+#[derive(Serialize)]
+struct PlaylistRow {
+    /// Where the track is in the playlist, counting from 1.
+    number: usize,
+    name: String,
+    artists: String,
+    /// As `m:ss`.
+    duration: String,
+    /// The day it was added, empty when Spotify doesn't know.
+    added: String,
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "playlist.html")]
+struct PlaylistTemplate {
+    id: String,
+    snapshot_id: String,
+    rows: Vec<PlaylistRow>,
+    /// Where the first row is among the matching tracks, counting from 1.
+    start: usize,
+    /// Position of the first row in the playlist, for reordering.
+    offset: usize,
+    /// Whether the rows are in playlist order and unfiltered, so they can be reordered.
+    reorderable: bool,
+    /// What the page was asked for, to fill the form with.
+    sort: String,
+    filter: String,
+    /// Tracks matching `filter`, on every page.
+    total: usize,
+    /// Links to the first, previous and next pages, empty when there's no such page.
+    first: String,
+    previous: String,
+    next: String,
+    layout: Layout,
+}
+
+impl Page for PlaylistTemplate {
+    const PATH: &'static str = "playlist.html";
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Sort {
+    /// The order of the playlist.
+    #[default]
+    Position,
+    AddedAt,
+    Name,
+    Duration,
+}
+
+impl Sort {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Position => "position",
+            Self::AddedAt => "added_at",
+            Self::Name => "name",
+            Self::Duration => "duration",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct PlaylistQuery {
+    /// A [`Cursor`], for pages after the first.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    page: String,
+    #[serde(default)]
+    sort: Sort,
+    /// Only tracks with this in their title or artists, ignoring case.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    filter: String,
+}
+
+/// Where a page starts or ends, by the sort key and position of the row next to it. Pages are
+/// found by where rows sort rather than by counting, so a cursor keeps leading to the same tracks
+/// when others are added or removed in the meantime.
+#[derive(Deserialize, Serialize)]
+struct Cursor {
+    key: String,
+    position: usize,
+    /// Whether the page ends before the row, rather than starting after it.
+    #[serde(default)]
+    before: bool,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+/// What rows are ordered by under `sort`, before their position. Strings, so one kind of cursor
+/// works for every order.
+fn sort_key(sort: Sort, item: &spotify::PlaylistItem) -> String {
+    match sort {
+        Sort::Position => String::new(),
+        Sort::AddedAt => item.added_at.clone().unwrap_or_default(),
+        Sort::Name => item
+            .track
+            .as_ref()
+            .map(|track| track.name.to_lowercase())
+            .unwrap_or_default(),
+        Sort::Duration => format!(
+            "{:012}",
+            item.track.as_ref().map_or(0, |track| track.duration_ms)
+        ),
+    }
+}
+
+impl PlaylistRow {
+    fn new(position: usize, item: spotify::PlaylistItem) -> Self {
+        let added = item
+            .added_at
+            .as_deref()
+            .and_then(|added_at| added_at.get(..10))
+            .unwrap_or_default()
+            .to_owned();
+        match item.track {
+            Some(track) => Self {
+                number: position + 1,
+                name: track.name,
+                artists: track.artists.iter().map(|a| &a.name).join(", "),
+                duration: minutes(track.duration_ms),
+                added,
+            },
+            None => Self {
+                number: position + 1,
+                name: "Unavailable track".to_owned(),
+                artists: String::new(),
+                duration: String::new(),
+                added,
+            },
+        }
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        filter.is_empty()
+            || self.name.to_lowercase().contains(filter)
+            || self.artists.to_lowercase().contains(filter)
+    }
+}
+
+/// A page of `?sort=` ordered tracks, `?filter=`ed, from `?page=`. `400` for a cursor that isn't
+/// one.
+async fn playlist(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    SpotifyId(id): SpotifyId,
+    Query(q): Query<PlaylistQuery>,
+    layout: Layout,
+    format: Format,
+) -> Result<Response, AppError> {
+    let cursor = if q.page.is_empty() {
+        None
+    } else {
+        let Some(cursor) = Cursor::decode(&q.page) else {
+            return Ok((StatusCode::BAD_REQUEST, "Bad page").into_response());
+        };
+        Some(cursor)
+    };
+    let (snapshot_id, items) = s
+        .playlist_cache
+        .items(&session.token.access_token, &id)
+        .await?;
+    let filter = q.filter.trim().to_lowercase();
+    let mut rows: Vec<(String, PlaylistRow)> = items
+        .into_iter()
+        .enumerate()
+        .map(|(position, item)| (sort_key(q.sort, &item), PlaylistRow::new(position, item)))
+        .filter(|(_, row)| row.matches(&filter))
+        .collect();
+    rows.sort_by(|(a_key, a), (b_key, b)| (a_key, a.number).cmp(&(b_key, b.number)));
+
+    let at = |key: &str, row: &PlaylistRow| (key.to_owned(), row.number - 1);
+    let start = cursor.as_ref().map_or(0, |cursor| {
+        let place = (cursor.key.clone(), cursor.position);
+        if cursor.before {
+            rows.partition_point(|(key, row)| at(key, row) < place)
+                .saturating_sub(PLAYLIST_PAGE)
+        } else {
+            rows.partition_point(|(key, row)| at(key, row) <= place)
+        }
+    });
+    let end = (start + PLAYLIST_PAGE).min(rows.len());
+    let link = |page: Option<Cursor>| {
+        let query = PlaylistQuery {
+            page: page.as_ref().map(Cursor::encode).unwrap_or_default(),
+            sort: q.sort,
+            filter: q.filter.clone(),
+        };
+        format!(
+            "/playlists/{id}?{}",
+            serde_qs::to_string(&query).unwrap_or_default()
+        )
+    };
+    let (first, previous) = match rows.get(start) {
+        Some((key, row)) if start > 0 => (
+            link(None),
+            link(Some(Cursor {
+                key: key.clone(),
+                position: row.number - 1,
+                before: true,
+            })),
+        ),
+        _ => (String::new(), String::new()),
+    };
+    let next = match end.checked_sub(1).and_then(|last| rows.get(last)) {
+        Some((key, row)) if end < rows.len() => link(Some(Cursor {
+            key: key.clone(),
+            position: row.number - 1,
+            before: false,
+        })),
+        _ => String::new(),
+    };
+    let total = rows.len();
+    let rows: Vec<PlaylistRow> = rows
+        .into_iter()
+        .skip(start)
+        .take(end - start)
+        .map(|(_, row)| row)
+        .collect();
+    Ok(templates::respond(
+        format,
+        PlaylistTemplate {
+            start: start + 1,
+            offset: rows.first().map_or(0, |row| row.number - 1),
+            reorderable: q.sort == Sort::Position && filter.is_empty(),
+            sort: q.sort.as_str().to_owned(),
+            filter: q.filter,
+            total,
+            first,
+            previous,
+            next,
+            id,
+            snapshot_id,
+            rows,
+            layout,
+        },
+    ))
+}
+
+/// The player as it was when the page was rendered. The page keeps it up to date from then on.
+#[cfg(feature = "player")]
+#[derive(Template, Serialize, Default)]
+#[template(path = "player.html")]
+struct PlayerTemplate {
+    /// Whether a device is active. The rest is empty when not.
+    active: bool,
+    is_playing: bool,
+    device: String,
+    /// Empty for episodes and ads.
+    title: String,
+    artists: String,
+    album: String,
+    /// Our URL of the album art, empty when there's none.
+    artwork: String,
+    duration_ms: u64,
+    progress_ms: u64,
+    /// Both as `m:ss`.
+    duration: String,
+    progress: String,
+    /// False for devices whose volume can't be controlled.
+    has_volume: bool,
+    volume_percent: u32,
+    layout: Layout,
+}
+
+#[cfg(feature = "player")]
+impl Page for PlayerTemplate {
+    const PATH: &'static str = "player.html";
+}
+
+#[cfg(feature = "player")]
+impl From<NowPlaying> for PlayerTemplate {
+    fn from(playing: NowPlaying) -> Self {
+        let track = playing.track;
+        let duration_ms = track.as_ref().map_or(0, |track| track.duration_ms);
+        let progress_ms = playing.progress_ms.unwrap_or_default();
+        Self {
+            active: true,
+            is_playing: playing.is_playing,
+            device: playing.device,
+            artists: track
+                .iter()
+                .flat_map(|track| &track.artists)
+                .map(|a| &a.name)
+                .join(", "),
+            artwork: track
+                .as_ref()
+                .and_then(|track| track.album.images.first())
+                .map(|image| art::local_url("", &image.url))
+                .unwrap_or_default(),
+            duration_ms,
+            progress_ms,
+            duration: minutes(duration_ms),
+            progress: minutes(progress_ms),
+            has_volume: playing.volume_percent.is_some(),
+            volume_percent: playing.volume_percent.unwrap_or_default(),
+            album: track
+                .as_ref()
+                .map(|track| track.album.name.clone())
+                .unwrap_or_default(),
+            title: track.map(|track| track.name).unwrap_or_default(),
+            layout: Layout::default(),
+        }
+    }
+}
+
+/// `ms` as `m:ss`.
+pub fn minutes(ms: u64) -> String {
+    let seconds = ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Controls of the active device, usable from the keyboard alone. See `assets/controls.js` for
+/// the shortcuts.
+#[cfg(feature = "player")]
+async fn player(
+    PageSession(session): PageSession,
+    layout: Layout,
+    format: Format,
+) -> Result<impl IntoResponse, AppError> {
+    session.require(READ_PLAYBACK)?;
+    let mut page: PlayerTemplate = spotify::playback_state(&session.token.access_token)
+        .await?
+        .map_or_else(PlayerTemplate::default, |playback| {
+            NowPlaying::from(playback).into()
+        });
+    page.layout = layout;
+    Ok(templates::respond(format, page))
+}
+
+#[cfg(feature = "library")]
+#[derive(Template, Serialize)]
+#[template(path = "library.html")]
+struct LibraryTemplate {
+    layout: Layout,
+}
+
+#[cfg(feature = "library")]
+impl Page for LibraryTemplate {
+    const PATH: &'static str = "library.html";
+}
+
+/// Saved tracks and playlists, loaded a chunk at a time as they're scrolled through, see
+/// [`crate::partials`].
+#[cfg(feature = "library")]
+async fn library(PageSession(_): PageSession, layout: Layout, format: Format) -> impl IntoResponse {
+    templates::respond(format, LibraryTemplate { layout })
+}
+
+#[derive(Serialize)]
+struct ApiKeyRow {
+    id: String,
+    name: String,
+    scopes: String,
+    created: String,
+    last_used: String,
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "api_keys.html")]
+struct ApiKeysTemplate {
+    rows: Vec<ApiKeyRow>,
+    layout: Layout,
+}
+
+impl Page for ApiKeysTemplate {
+    const PATH: &'static str = "api_keys.html";
+}
+
+/// The user's API keys, most recently used first, with buttons revoking them.
+async fn api_keys(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    layout: Layout,
+    format: Format,
+) -> Result<Response, AppError> {
+    let mut keys = s.api_keys.owned_by(&session.user_id).await?;
+    keys.sort_by(|a, b| {
+        b.last_used
+            .cmp(&a.last_used)
+            .then(b.created_at.cmp(&a.created_at))
+    });
+    let rows = keys
+        .into_iter()
+        .map(|key| ApiKeyRow {
+            scopes: key.scopes.iter().map(|scope| scope.as_str()).join(", "),
+            created: key.created_at.format("%Y-%m-%d").to_string(),
+            last_used: key.last_used.map_or_else(
+                || "Never".to_owned(),
+                |at| at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            ),
+            id: key.id,
+            name: key.name,
+        })
+        .collect();
+    Ok(templates::respond(format, ApiKeysTemplate { rows, layout }))
+}
+
+#[cfg(feature = "stats")]
+#[derive(Deserialize)]
+struct WrappedQuery {
+    year: Option<i32>,
+}
+
+/// The year in review of `GET /api/stats/wrapped`, as a page to look through or share.
+#[cfg(feature = "stats")]
+async fn wrapped(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<WrappedQuery>,
+    layout: Layout,
+    format: Format,
+) -> Result<impl IntoResponse, AppError> {
+    let year = q.year.unwrap_or_else(|| Utc::now().year());
+    let plays = s.history.plays(&session.user_id).await?;
+    let mut page = stats::wrapped(&s.genres, &session.token.access_token, year, plays).await?;
+    page.layout = layout;
+    Ok(templates::respond(format, page))
+}
@@ -0,0 +1,342 @@
+//! HTML fragments for HTMX to swap into the pages, rather than pages of their own.
+//!
+//! - `GET /partials/library?kind=tracks|playlists&cursor=` returns the next rows of the user's
+//!   saved tracks or playlists, ending with a row that loads the ones after once it's scrolled
+//!   into view (`hx-trigger="revealed"`), for the infinite scrolling of `/library`.
+//! - `GET /partials/search?q=` returns rows of the tracks on Spotify matching `q`, for
+//!   searching as the user types.
+//!
+//! Mutations under `/api` answer HTMX with the fragment showing their outcome, see
+//! [`WantsFragment`], so the page updates without fetching it again.
+//!
+//! Searches wait a moment before asking Spotify, and each user's search calls off their earlier
+//! ones, whether still waiting or already waiting on Spotify. A search the browser gave up on,
+//! with `hx-sync="this:replace"`, is dropped along with its request. So a burst of keystrokes
+//! costs one call to Spotify, not one per key.
+
+use askama_axum::Template;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::convert::Infallible;
+
+use crate::templates::{self, Format, Page};
+
+#[cfg(feature = "library")]
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Router,
+};
+#[cfg(feature = "library")]
+use itertools::Itertools;
+#[cfg(feature = "library")]
+use serde::Deserialize;
+#[cfg(feature = "library")]
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError},
+    time::Duration,
+};
+#[cfg(feature = "library")]
+use tokio::sync::watch;
+
+#[cfg(feature = "library")]
+use crate::{pages, session::PageSession, spotify, AppError, AppStateInner};
+
+/// Rows per chunk, the most Spotify returns at once.
+#[cfg(feature = "library")]
+const LIBRARY_CHUNK: usize = 50;
+/// How long a search waits for the next keystroke before asking Spotify.
+#[cfg(feature = "library")]
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(250);
+#[cfg(feature = "library")]
+const SEARCH_RESULTS: usize = 20;
+/// Longest search passed on to Spotify, in bytes.
+#[cfg(feature = "library")]
+const MAX_QUERY_LEN: usize = 200;
+
+#[cfg(feature = "library")]
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/partials/library", get(library))
+        .route("/partials/search", get(search))
+}
+
+#[cfg(feature = "library")]
+#[derive(Serialize)]
+struct LibraryRow {
+    name: String,
+    /// Artists and duration of tracks, size of playlists.
+    detail: String,
+    /// Our page for it, empty for tracks.
+    href: String,
+    /// Empty for playlists.
+    track_id: String,
+    /// Whether the track is in the user's library.
+    saved: bool,
+}
+
+#[cfg(feature = "library")]
+impl LibraryRow {
+    fn track(track: spotify::Track, saved: bool) -> Self {
+        Self {
+            detail: format!(
+                "{} · {}",
+                track.artists.iter().map(|a| &a.name).join(", "),
+                pages::minutes(track.duration_ms)
+            ),
+            name: track.name,
+            href: String::new(),
+            track_id: track.id,
+            saved,
+        }
+    }
+}
+
+#[cfg(feature = "library")]
+#[derive(Template, Serialize)]
+#[template(path = "library_rows.html")]
+struct LibraryRowsTemplate {
+    rows: Vec<LibraryRow>,
+    /// Where the rows after these are loaded from, empty after the last.
+    next: String,
+}
+
+#[cfg(feature = "library")]
+impl Page for LibraryRowsTemplate {
+    const PATH: &'static str = "library_rows.html";
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Tracks,
+    Playlists,
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize, Serialize)]
+struct LibraryQuery {
+    kind: Kind,
+    /// How many rows came before, from the row that loaded these.
+    #[serde(default)]
+    cursor: usize,
+}
+
+#[cfg(feature = "library")]
+async fn library(
+    PageSession(session): PageSession,
+    Query(q): Query<LibraryQuery>,
+) -> Result<Response, AppError> {
+    let token = &session.token.access_token;
+    let (rows, more): (Vec<LibraryRow>, bool) = match q.kind {
+        Kind::Tracks => {
+            let page = spotify::saved_tracks_page(token, q.cursor, LIBRARY_CHUNK).await?;
+            let rows = page
+                .items
+                .into_iter()
+                .map(|saved| LibraryRow::track(saved.track, true))
+                .collect();
+            (rows, page.next.is_some())
+        }
+        Kind::Playlists => {
+            let page = spotify::playlists_page(token, q.cursor, LIBRARY_CHUNK).await?;
+            let rows = page
+                .items
+                .into_iter()
+                .map(|playlist| LibraryRow {
+                    detail: match playlist.tracks.total {
+                        1 => "1 track".to_owned(),
+                        total => format!("{total} tracks"),
+                    },
+                    href: format!("/playlists/{}", playlist.id),
+                    name: playlist.name,
+                    track_id: String::new(),
+                    saved: false,
+                })
+                .collect();
+            (rows, page.next.is_some())
+        }
+    };
+    let next = if more && !rows.is_empty() {
+        let query = LibraryQuery {
+            kind: q.kind,
+            cursor: q.cursor + rows.len(),
+        };
+        format!(
+            "/partials/library?{}",
+            serde_qs::to_string(&query).unwrap_or_default()
+        )
+    } else {
+        String::new()
+    };
+    Ok(templates::render(LibraryRowsTemplate { rows, next }))
+}
+
+/// The latest search of each user, so a new one can call off those before it.
+#[cfg(feature = "library")]
+#[derive(Default)]
+pub struct SearchCoalescer {
+    /// Numbers of the latest searches, counting up.
+    latest: std::sync::Mutex<HashMap<String, watch::Sender<u64>>>,
+}
+
+#[cfg(feature = "library")]
+impl SearchCoalescer {
+    /// Makes a new search the latest of `user_id`, returning its number along with a receiver
+    /// that sees the number of any later one.
+    fn begin(&self, user_id: &str) -> (u64, watch::Receiver<u64>) {
+        let mut latest = self.latest.lock().unwrap_or_else(PoisonError::into_inner);
+        // Users without a search running.
+        latest.retain(|_, sender| sender.receiver_count() > 0);
+        let sender = latest
+            .entry(user_id.to_owned())
+            .or_insert_with(|| watch::channel(0).0);
+        let mut search = 0;
+        sender.send_modify(|latest| {
+            *latest += 1;
+            search = *latest;
+        });
+        (search, sender.subscribe())
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.latest.try_lock().ok().map(|l| l.len())
+    }
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+/// No rows for an empty search, `400` for one longer than [`MAX_QUERY_LEN`], and `204` for one
+/// called off by a later search, which HTMX doesn't swap in.
+#[cfg(feature = "library")]
+async fn search(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    Query(q): Query<SearchQuery>,
+) -> Result<Response, AppError> {
+    let query = q.q.trim();
+    if query.len() > MAX_QUERY_LEN {
+        return Ok((StatusCode::BAD_REQUEST, "This search is too long").into_response());
+    }
+    // Even an empty one, so clearing the search calls off the last one.
+    let (search, mut later) = s.searches.begin(&session.user_id);
+    if query.is_empty() {
+        return Ok(templates::render(LibraryRowsTemplate {
+            rows: Vec::new(),
+            next: String::new(),
+        }));
+    }
+    let results = async {
+        tokio::time::sleep(SEARCH_DEBOUNCE).await;
+        spotify::search_tracks(&session.token.access_token, query, SEARCH_RESULTS).await
+    };
+    let tracks = tokio::select! {
+        tracks = results => tracks?,
+        _ = later.wait_for(|latest| *latest != search) => {
+            return Ok(StatusCode::NO_CONTENT.into_response());
+        }
+    };
+    Ok(templates::render(LibraryRowsTemplate {
+        rows: tracks
+            .into_iter()
+            .map(|track| LibraryRow::track(track, false))
+            .collect(),
+        next: String::new(),
+    }))
+}
+
+/// Whether the client of a mutation wants the fragment showing its outcome rather than an empty
+/// response: HTMX, which says so with `HX-Request`, and clients asking for HTML by name. Others,
+/// including those sending no `Accept`, get the `204` API clients expect.
+pub struct WantsFragment(pub bool);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WantsFragment {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let headers = &parts.headers;
+        let htmx = headers
+            .get("hx-request")
+            .is_some_and(|value| value == "true");
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let asks_for_html =
+            accept.contains("text/html") && Format::from_accept(accept) == Format::Html;
+        Ok(Self(htmx || asks_for_html))
+    }
+}
+
+/// A button swapping itself for the one its request returns.
+#[derive(Template, Serialize)]
+#[template(path = "button.html")]
+pub struct ButtonTemplate {
+    /// `put`, `post` or `delete`.
+    method: String,
+    /// Empty for a disabled button.
+    url: String,
+    label: String,
+    pressed: bool,
+}
+
+impl Page for ButtonTemplate {
+    const PATH: &'static str = "button.html";
+}
+
+impl ButtonTemplate {
+    /// Saves a track, or removes it from the library if it's `saved`.
+    #[cfg(feature = "library")]
+    pub fn save(track_id: &str, saved: bool) -> Self {
+        Self {
+            method: if saved { "delete" } else { "put" }.to_owned(),
+            url: format!("/api/library/tracks/{track_id}"),
+            label: if saved { "Saved" } else { "Save" }.to_owned(),
+            pressed: saved,
+        }
+    }
+
+    /// What adding a track to the queue turns into, as it can't be taken back out.
+    #[cfg(feature = "player")]
+    pub fn queued() -> Self {
+        Self {
+            method: String::new(),
+            url: String::new(),
+            label: "Queued".to_owned(),
+            pressed: true,
+        }
+    }
+
+    /// Follows an artist, or stops if `following`.
+    #[cfg(feature = "library")]
+    pub fn follow(artist_id: &str, following: bool) -> Self {
+        Self {
+            method: if following { "delete" } else { "put" }.to_owned(),
+            url: format!("/api/following/{artist_id}"),
+            label: if following { "Following" } else { "Follow" }.to_owned(),
+            pressed: following,
+        }
+    }
+
+    /// The button for `wants`, `204` without one.
+    pub fn respond(self, WantsFragment(wants): WantsFragment) -> Response {
+        if wants {
+            templates::render(self)
+        } else {
+            StatusCode::NO_CONTENT.into_response()
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Playlist contents, cached by `snapshot_id`. Spotify changes a playlist's snapshot ID on every
+//! modification, so as long as it's unchanged the (possibly many) pages of tracks we fetched
+//! before are still accurate and only the playlist's metadata needs to be requested.
+//!
+//! The cache holds at most [`CAPACITY`] playlists, dropping the least recently used to make room,
+//! and none for longer than [`TTL`].
+
+use futures::TryStreamExt;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    metrics,
+    spotify::{self, PlaylistItem},
+};
+
+/// Playlists kept at once. Large ones run to thousands of items, a few megabytes in all.
+const CAPACITY: usize = 256;
+/// How long a playlist is kept, so that those nobody looks at any more don't stay until evicted.
+const TTL: Duration = Duration::from_hours(1);
+
+struct CachedPlaylist {
+    snapshot_id: String,
+    items: Vec<PlaylistItem>,
+    cached_at: Instant,
+    last_used: Instant,
+}
+
+impl CachedPlaylist {
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.cached_at) >= TTL
+    }
+}
+
+#[derive(Default)]
+pub struct PlaylistCache {
+    playlists: RwLock<HashMap<String, CachedPlaylist>>,
+}
+
+impl PlaylistCache {
+    /// Returns the items of `playlist_id` along with the snapshot they belong to, refetching them
+    /// only when the snapshot changed since they were cached.
+    pub async fn items(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+    ) -> anyhow::Result<(String, Vec<PlaylistItem>)> {
+        let snapshot_id = spotify::playlist_snapshot_id(access_token, playlist_id).await?;
+        if let Some(items) = self.cached(playlist_id, &snapshot_id, Instant::now()).await {
+            tracing::debug!("Playlist {playlist_id} unchanged, serving cached items");
+            return Ok((snapshot_id, items));
+        }
+
+        let items: Vec<PlaylistItem> = spotify::playlist_items(access_token, playlist_id)
+            .try_collect()
+            .await?;
+        self.store(
+            playlist_id,
+            snapshot_id.clone(),
+            items.clone(),
+            Instant::now(),
+        )
+        .await;
+        Ok((snapshot_id, items))
+    }
+
+    /// The cached items of `playlist_id`, if they're of `snapshot_id` and haven't expired.
+    async fn cached(
+        &self,
+        playlist_id: &str,
+        snapshot_id: &str,
+        now: Instant,
+    ) -> Option<Vec<PlaylistItem>> {
+        let mut playlists = metrics::write("playlists", &self.playlists).await;
+        let cached = playlists
+            .get_mut(playlist_id)
+            .filter(|cached| cached.snapshot_id == snapshot_id && !cached.expired(now))?;
+        cached.last_used = now;
+        Some(cached.items.clone())
+    }
+
+    async fn store(
+        &self,
+        playlist_id: &str,
+        snapshot_id: String,
+        items: Vec<PlaylistItem>,
+        now: Instant,
+    ) {
+        let mut playlists = metrics::write("playlists", &self.playlists).await;
+        playlists.retain(|_, cached| !cached.expired(now));
+        if playlists.len() >= CAPACITY && !playlists.contains_key(playlist_id) {
+            let least_recent = playlists
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(id, _)| id.clone());
+            if let Some(id) = least_recent {
+                playlists.remove(&id);
+            }
+        }
+        playlists.insert(
+            playlist_id.to_owned(),
+            CachedPlaylist {
+                snapshot_id,
+                items,
+                cached_at: now,
+                last_used: now,
+            },
+        );
+    }
+
+    /// Returns the current items of `playlist_id`, failing with [`SnapshotConflict`] unless the
+    /// playlist is still at `expected_snapshot`. Mutations go through this so that edits made in
+    /// the meantime (e.g. from the Spotify app) are never clobbered.
+    pub async fn items_at(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        expected_snapshot: &str,
+    ) -> anyhow::Result<Vec<PlaylistItem>> {
+        let (snapshot_id, items) = self.items(access_token, playlist_id).await?;
+        if snapshot_id != expected_snapshot {
+            return Err(SnapshotConflict { snapshot_id }.into());
+        }
+        Ok(items)
+    }
+
+    /// Drops the cached items of a playlist we just modified.
+    pub async fn invalidate(&self, playlist_id: &str) {
+        metrics::write("playlists", &self.playlists)
+            .await
+            .remove(playlist_id);
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.playlists.try_read().ok().map(|p| p.len())
+    }
+}
+
+/// A playlist mutation was based on a snapshot that is no longer current. Returned to the client
+/// as a `409` carrying the current snapshot, so it can refetch and retry.
+#[derive(Debug, Serialize)]
+pub struct SnapshotConflict {
+    pub snapshot_id: String,
+}
+
+impl std::fmt::Display for SnapshotConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "playlist has changed, current snapshot is {}",
+            self.snapshot_id
+        )
+    }
+}
+
+impl std::error::Error for SnapshotConflict {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used() {
+        let cache = PlaylistCache::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        for i in 0..CAPACITY {
+            cache
+                .store(&i.to_string(), "s".to_owned(), Vec::new(), at(i as u64))
+                .await;
+        }
+        // Used since, so the second oldest goes instead.
+        let now = at(CAPACITY as u64);
+        assert!(cache.cached("0", "s", now).await.is_some());
+        cache.store("new", "s".to_owned(), Vec::new(), now).await;
+        assert_eq!(cache.len_hint(), Some(CAPACITY));
+        assert!(cache.cached("0", "s", now).await.is_some());
+        assert!(cache.cached("1", "s", now).await.is_none());
+        assert!(cache.cached("new", "s", now).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn expires() {
+        let cache = PlaylistCache::default();
+        let now = Instant::now();
+        cache.store("a", "s".to_owned(), Vec::new(), now).await;
+        assert!(cache.cached("a", "other", now).await.is_none());
+        assert!(cache.cached("a", "s", now).await.is_some());
+        assert!(cache.cached("a", "s", now + TTL).await.is_none());
+        cache
+            .store("b", "s".to_owned(), Vec::new(), now + TTL)
+            .await;
+        assert_eq!(cache.len_hint(), Some(1));
+    }
+}
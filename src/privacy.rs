@@ -0,0 +1,90 @@
+//! Do Not Track and Global Privacy Control. Requests sending `DNT: 1` or `Sec-GPC: 1` have what
+//! the instance does beyond serving them turned off by default:
+//!
+//! - Sessions started by them don't have their listening history collected in the background.
+//! - `/analytics.js`, which the pages load, is empty for them rather than loading the analytics
+//!   script in `ANALYTICS_SCRIPT`.
+//!
+//! Responses vary on both headers, so caches don't hand one kind of client the other's.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::{convert::Infallible, sync::Arc};
+
+use crate::AppStateInner;
+
+const GPC: &str = "sec-gpc";
+const DNT: &str = "dnt";
+
+/// What a request said about being tracked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Privacy {
+    /// `DNT: 1` or `Sec-GPC: 1`.
+    pub opted_out: bool,
+}
+
+impl Privacy {
+    pub fn of(headers: &HeaderMap) -> Self {
+        Self {
+            opted_out: [DNT, GPC]
+                .iter()
+                .any(|name| headers.get(*name).is_some_and(|value| value == "1")),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Privacy {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Self>()
+            .copied()
+            .unwrap_or_else(|| Self::of(&parts.headers)))
+    }
+}
+
+/// Middleware reading the headers once for everything after it.
+pub async fn detect(mut request: Request, next: Next) -> Response {
+    let privacy = Privacy::of(request.headers());
+    request.extensions_mut().insert(privacy);
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("DNT, Sec-GPC"));
+    response
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new().route("/analytics.js", get(analytics))
+}
+
+/// Loads `ANALYTICS_SCRIPT`, unless the client opted out or there's none.
+async fn analytics(State(s): State<Arc<AppStateInner>>, privacy: Privacy) -> impl IntoResponse {
+    let script = match &s.analytics_script {
+        Some(url) if !privacy.opted_out => format!(
+            "const script = document.createElement(\"script\");\n\
+             script.src = {};\n\
+             script.defer = true;\n\
+             document.head.append(script);\n",
+            serde_json::Value::from(url.as_str())
+        ),
+        _ => String::new(),
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "text/javascript"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        script,
+    )
+}
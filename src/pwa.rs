@@ -0,0 +1,70 @@
+//! What makes the pages an installable web app: the manifest, and a service worker that keeps
+//! the page shell, the player page and album art around for when the network isn't.
+//!
+//! The worker is served from the root rather than under `/assets`, since a worker only controls
+//! pages under the path it's served from.
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Json},
+    routing::get,
+    Router,
+};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{icons, AppStateInner};
+
+/// Browsers check for a new worker at most once a day whatever this says, but a cached one is
+/// never used without asking, so fixes reach installed apps on their next visit.
+const WORKER_CACHE_CONTROL: &str = "no-cache";
+const MANIFEST_CACHE_CONTROL: &str = "public, max-age=86400";
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/manifest.webmanifest", get(manifest))
+        .route("/sw.js", get(worker))
+}
+
+async fn manifest(State(s): State<Arc<AppStateInner>>) -> impl IntoResponse {
+    let icons: Vec<_> = icons::MANIFEST_SIZES
+        .iter()
+        .map(|size| {
+            json!({
+                "src": format!("/icon-{size}.png"),
+                "sizes": format!("{size}x{size}"),
+                "type": "image/png",
+                // Drawn edge to edge with the letter well inside the safe zone.
+                "purpose": "any maskable",
+            })
+        })
+        .collect();
+    (
+        [
+            (header::CONTENT_TYPE, "application/manifest+json"),
+            (header::CACHE_CONTROL, MANIFEST_CACHE_CONTROL),
+        ],
+        Json(json!({
+            "name": "blid",
+            "short_name": "blid",
+            "description": "Your Spotify playlists, library and player",
+            "start_url": "/player",
+            "scope": "/",
+            "display": "standalone",
+            "background_color": "#ffffff",
+            "theme_color": s.icons.color,
+            "icons": icons,
+        })),
+    )
+}
+
+async fn worker() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/javascript; charset=utf-8"),
+            (header::CACHE_CONTROL, WORKER_CACHE_CONTROL),
+        ],
+        include_str!("../assets/sw.js"),
+    )
+}
@@ -0,0 +1,16 @@
+//! Logging policy: nothing that grants access to an account reaches a log line.
+//!
+//! That covers access and refresh tokens, authorization codes, session IDs and pending OAuth
+//! states. Types holding such values implement `Debug` by hand and print those fields through
+//! [`Redacted`], so `{:?}` is always safe to use on them.
+
+use std::fmt;
+
+/// Wraps a secret so that its `Debug` output only reveals that something was there.
+pub struct Redacted<T>(pub T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
@@ -0,0 +1,346 @@
+//! Watching for new releases of the artists users follow. A worker periodically lists the albums
+//! and singles of each logged-in user's followed artists, and whatever wasn't there the last time
+//! is a new release: listed at `/api/releases/new`, sent to webhooks that want `new_release`
+//! events, and emailed to users who asked for it.
+//!
+//! The first check of a user only records what already exists, so following an artist doesn't
+//! flood the feed with their back catalogue.
+//!
+//! What was seen and found is kept in memory by default, or in the database with a SQL feature
+//! and `DATABASE_URL` set, so every instance lists the releases found by the one checking.
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    digest,
+    spotify::{self, Album},
+    webhooks::{self, Event},
+    AppStateInner,
+};
+
+/// Releases come out on Fridays, at most a few a week per artist, so a few checks a day is plenty.
+const CHECK_INTERVAL: Duration = Duration::from_hours(6);
+/// Followed artists checked per user, each being at least one call to Spotify.
+const MAX_ARTISTS: usize = 50;
+/// New releases kept per user.
+const KEPT: usize = 100;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NewRelease {
+    pub artist: String,
+    pub album: Album,
+    pub detected_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct Watched {
+    /// IDs of every release seen so far. Empty until the first check.
+    seen: HashSet<String>,
+    /// Newest first.
+    new: VecDeque<NewRelease>,
+}
+
+pub enum ReleaseStore {
+    Memory(RwLock<HashMap<String, Watched>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for ReleaseStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl ReleaseStore {
+    /// Records the releases found for a user, returning those that weren't seen before.
+    pub async fn record(
+        &self,
+        user_id: &str,
+        releases: Vec<(String, Album)>,
+    ) -> anyhow::Result<Vec<NewRelease>> {
+        let now = Utc::now();
+        match self {
+            Self::Memory(users) => {
+                let mut users = users.write().await;
+                let watched = users.entry(user_id.to_owned()).or_default();
+                let first = watched.seen.is_empty();
+                let mut new = Vec::new();
+                for (artist, album) in releases {
+                    if watched.seen.insert(album.id.clone()) && !first {
+                        new.push(NewRelease {
+                            artist,
+                            album,
+                            detected_at: now,
+                        });
+                    }
+                }
+                for release in &new {
+                    watched.new.push_front(release.clone());
+                }
+                watched.new.truncate(KEPT);
+                Ok(new)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let seen: Option<String> = sqlx::query_scalar(
+                    "SELECT album_id FROM seen_releases WHERE user_id = $1 LIMIT 1",
+                )
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+                let first = seen.is_none();
+                let mut new = Vec::new();
+                for (artist, album) in releases {
+                    let inserted = sqlx::query(
+                        "INSERT INTO seen_releases (user_id, album_id) VALUES ($1, $2) \
+                         ON CONFLICT (user_id, album_id) DO NOTHING",
+                    )
+                    .bind(user_id)
+                    .bind(&album.id)
+                    .execute(&mut *tx)
+                    .await?;
+                    if inserted.rows_affected() > 0 && !first {
+                        new.push(NewRelease {
+                            artist,
+                            album,
+                            detected_at: now,
+                        });
+                    }
+                }
+                for release in &new {
+                    sqlx::query(
+                        "INSERT INTO new_releases (user_id, album_id, artist, album, detected_at) \
+                         VALUES ($1, $2, $3, $4, $5)",
+                    )
+                    .bind(user_id)
+                    .bind(&release.album.id)
+                    .bind(&release.artist)
+                    .bind(serde_json::to_string(&release.album)?)
+                    .bind(now.timestamp())
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                if !new.is_empty() {
+                    sqlx::query(
+                        "DELETE FROM new_releases WHERE user_id = $1 AND album_id NOT IN ( \
+                             SELECT album_id FROM new_releases WHERE user_id = $1 \
+                             ORDER BY detected_at DESC, album_id LIMIT $2 \
+                         )",
+                    )
+                    .bind(user_id)
+                    .bind(i64::try_from(KEPT)?)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await?;
+                Ok(new)
+            }
+        }
+    }
+
+    /// New releases for a user, newest first.
+    pub async fn new_releases(&self, user_id: &str) -> anyhow::Result<Vec<NewRelease>> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .read()
+                .await
+                .get(user_id)
+                .map(|watched| watched.new.iter().cloned().collect())
+                .unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, String, i64)> = sqlx::query_as(
+                    "SELECT artist, album, detected_at FROM new_releases WHERE user_id = $1 \
+                     ORDER BY detected_at DESC, album_id",
+                )
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter()
+                    .map(|(artist, album, detected_at)| {
+                        Ok(NewRelease {
+                            artist,
+                            album: serde_json::from_str(&album)?,
+                            detected_at: DateTime::from_timestamp(detected_at, 0)
+                                .ok_or_else(|| anyhow::anyhow!("bad time {detected_at}"))?,
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub async fn new_since(
+        &self,
+        user_id: &str,
+        since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<NewRelease>> {
+        let mut releases = self.new_releases(user_id).await?;
+        releases.retain(|release| release.detected_at >= since);
+        Ok(releases)
+    }
+
+    /// Forgets what was seen for a user, returning whether anything was.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(users) => Ok(users.write().await.remove(user_id).is_some()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query("DELETE FROM new_releases WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                let deleted = sqlx::query("DELETE FROM seen_releases WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(users) => users.try_read().ok().map(|u| u.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+pub fn spawn_watcher(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !state.leader.holds() {
+                continue;
+            }
+            let sessions = match state.sessions.all().await {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    tracing::error!("Failed to list sessions for release checks: {e:#}");
+                    continue;
+                }
+            };
+            // A user logged in from several browsers only needs to be checked once.
+            let mut seen = HashSet::new();
+            for session in sessions {
+                if !seen.insert(session.user_id.clone()) {
+                    continue;
+                }
+                let releases = match releases(&session.token.access_token).await {
+                    Ok(releases) => releases,
+                    Err(e) => {
+                        tracing::warn!("Failed to check for new releases: {e:#}");
+                        continue;
+                    }
+                };
+                let new = match state.releases.record(&session.user_id, releases).await {
+                    Ok(new) => new,
+                    Err(e) => {
+                        tracing::error!("Failed to record releases: {e:#}");
+                        continue;
+                    }
+                };
+                if new.is_empty() {
+                    continue;
+                }
+                tracing::debug!("Found {} new releases for {}", new.len(), session.user_id);
+                for release in &new {
+                    let event = Event::NewRelease {
+                        artist: release.artist.clone(),
+                        album: release.album.clone(),
+                    };
+                    webhooks::dispatch(&state, &session.user_id, &event).await;
+                }
+                if let Err(e) = digest::send_release_alert(&state, &session.user_id, &new).await {
+                    tracing::warn!("Failed to email new releases: {e:#}");
+                }
+            }
+        }
+    });
+}
+
+/// Albums and singles of the artists the user follows, with the artist's name.
+async fn releases(access_token: &str) -> anyhow::Result<Vec<(String, Album)>> {
+    let artists: Vec<_> = spotify::followed_artists(access_token)
+        .take(MAX_ARTISTS)
+        .try_collect()
+        .await?;
+    let mut releases = Vec::new();
+    for artist in artists {
+        let albums: Vec<Album> = spotify::artist_albums(access_token, &artist.id)
+            .try_collect()
+            .await?;
+        releases.extend(albums.into_iter().map(|album| (artist.name.clone(), album)));
+    }
+    Ok(releases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn album(id: &str) -> (String, Album) {
+        let album = Album {
+            id: id.to_owned(),
+            name: id.to_uppercase(),
+            ..Album::default()
+        };
+        ("Artist".to_owned(), album)
+    }
+
+    async fn only_later_releases_are_new(store: &ReleaseStore) {
+        let first = store.record("ann", vec![album("a"), album("b")]).await;
+        assert!(first.unwrap().is_empty());
+        let new = store
+            .record("ann", vec![album("a"), album("b"), album("c")])
+            .await
+            .unwrap();
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].album.id, "c");
+        let listed = store.new_releases("ann").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].album.name, "C");
+        assert!(store.new_releases("bob").await.unwrap().is_empty());
+
+        let later = Utc::now() + chrono::Duration::minutes(1);
+        assert!(store.new_since("ann", later).await.unwrap().is_empty());
+        assert!(store.remove("ann").await.unwrap());
+        assert!(!store.remove("ann").await.unwrap());
+        assert!(store.new_releases("ann").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_later_releases_are_new_in_memory() {
+        only_later_releases_are_new(&ReleaseStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn only_later_releases_are_new_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-releases-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        only_later_releases_are_new(&ReleaseStore::Sql(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
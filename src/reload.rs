@@ -0,0 +1,82 @@
+//! Configuration reloads on `SIGHUP`. The configuration is loaded again, and what changed is
+//! applied if it can be while running ([`config::RELOADABLE`]: the log filter, the CORS allowlist
+//! and Spotify request concurrency) or reported as needing a restart otherwise.
+//!
+//! The environment of a running process doesn't change, so reloads pick up edits to
+//! `CONFIG_FILE`. Rate limits and polling intervals are fixed at build time, so there's nothing to
+//! reload for them. Changes to the files `_FILE` variables point to aren't noticed, only changes
+//! to the paths.
+
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    config::{self, Config},
+    logging, spotify, AppStateInner,
+};
+
+/// Reloads on every `SIGHUP`, starting from `config`, the configuration in effect.
+pub fn spawn(state: Arc<AppStateInner>, mut config: Config) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP, so the config can't be reloaded: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            reload(&state, &mut config);
+        }
+    });
+}
+
+fn reload(state: &AppStateInner, current: &mut Config) {
+    // A configuration that doesn't load leaves everything as it was.
+    let new = match Config::load() {
+        Ok(new) => new,
+        Err(e) => {
+            tracing::error!("Config reload failed, keeping the current config: {e:#}");
+            return;
+        }
+    };
+    let (mut applied, restart): (Vec<_>, Vec<_>) = current
+        .changed(&new)
+        .into_iter()
+        .partition(|name| config::RELOADABLE.contains(name));
+    applied.retain(|name| apply(state, &new, name));
+    for name in &applied {
+        current.take(&new, name);
+    }
+
+    if applied.is_empty() && restart.is_empty() {
+        tracing::info!("Config reloaded, nothing changed");
+    } else {
+        tracing::warn!(
+            "Config reloaded; applied: [{}]; changed but needing a restart: [{}]",
+            applied.join(", "),
+            restart.join(", "),
+        );
+    }
+}
+
+/// Puts the value of `name` in `new` into effect. `false` when it can't be, which keeps the
+/// current one.
+fn apply(state: &AppStateInner, new: &Config, name: &str) -> bool {
+    match name {
+        "RUST_LOG" => {
+            let filter = new.log_filter.as_deref().unwrap_or(logging::DEFAULT_FILTER);
+            if let Err(e) = logging::set_filter(filter) {
+                tracing::error!("Invalid RUST_LOG on reload, keeping the current filter: {e:#}");
+                return false;
+            }
+        }
+        "CORS_ORIGINS" => {
+            new.cors_origins
+                .clone_into(&mut state.cors_origins.write().expect("CORS origins poisoned"));
+        }
+        "SPOTIFY_CONCURRENCY" => spotify::set_concurrency(new.spotify_concurrency),
+        _ => return false,
+    }
+    true
+}
@@ -0,0 +1,264 @@
+//! How long the instance keeps each user's listening history and the activity of the playlists
+//! they watch, set through `/api/me/retention`. Everything is kept by default. A worker forgets
+//! what's older than each user's setting a few times a day, so a shorter setting takes effect
+//! within hours rather than at once.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::AppStateInner;
+
+const PRUNE_INTERVAL: Duration = Duration::from_hours(6);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Retention {
+    #[serde(rename = "30d")]
+    Days30,
+    #[serde(rename = "90d")]
+    Days90,
+    #[serde(rename = "365d")]
+    Days365,
+    #[default]
+    #[serde(rename = "forever")]
+    Forever,
+}
+
+impl Retention {
+    /// Days kept, `None` for ever.
+    pub const fn days(self) -> Option<i64> {
+        match self {
+            Self::Days30 => Some(30),
+            Self::Days90 => Some(90),
+            Self::Days365 => Some(365),
+            Self::Forever => None,
+        }
+    }
+
+    #[cfg(feature = "sql")]
+    fn from_days(days: i64) -> anyhow::Result<Self> {
+        match days {
+            30 => Ok(Self::Days30),
+            90 => Ok(Self::Days90),
+            365 => Ok(Self::Days365),
+            _ => anyhow::bail!("bad retention of {days} days"),
+        }
+    }
+
+    /// What was before this is forgotten.
+    pub fn cutoff(self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.days().map(|days| now - chrono::Duration::days(days))
+    }
+}
+
+/// In memory by default, or in the database with a SQL feature and `DATABASE_URL` set. Only
+/// users who don't keep everything are stored.
+pub enum RetentionStore {
+    Memory(RwLock<HashMap<String, Retention>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for RetentionStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl RetentionStore {
+    pub async fn get(&self, user_id: &str) -> anyhow::Result<Retention> {
+        match self {
+            Self::Memory(users) => Ok(users.read().await.get(user_id).copied().unwrap_or_default()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let days: Option<i64> =
+                    sqlx::query_scalar("SELECT keep_days FROM retention WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_optional(pool)
+                        .await?;
+                days.map_or(Ok(Retention::Forever), Retention::from_days)
+            }
+        }
+    }
+
+    pub async fn set(&self, user_id: &str, retention: Retention) -> anyhow::Result<()> {
+        if retention == Retention::Forever {
+            self.remove(user_id).await?;
+            return Ok(());
+        }
+        match self {
+            Self::Memory(users) => {
+                users.write().await.insert(user_id.to_owned(), retention);
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO retention (user_id, keep_days) VALUES ($1, $2) \
+                     ON CONFLICT (user_id) DO UPDATE SET keep_days = $2",
+                )
+                .bind(user_id)
+                .bind(retention.days())
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Goes back to keeping everything, returning whether the user had a limit.
+    pub async fn remove(&self, user_id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(users) => Ok(users.write().await.remove(user_id).is_some()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM retention WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Every user with a limit.
+    async fn limited(&self) -> anyhow::Result<Vec<(String, Retention)>> {
+        match self {
+            Self::Memory(users) => Ok(users
+                .read()
+                .await
+                .iter()
+                .map(|(user_id, retention)| (user_id.clone(), *retention))
+                .collect()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, i64)> =
+                    sqlx::query_as("SELECT user_id, keep_days FROM retention")
+                        .fetch_all(pool)
+                        .await?;
+                rows.into_iter()
+                    .map(|(user_id, days)| Ok((user_id, Retention::from_days(days)?)))
+                    .collect()
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(users) => users.try_read().ok().map(|u| u.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+/// Forgets what each user with a limit no longer wants kept, returning how many plays and
+/// activity entries were forgotten.
+pub async fn prune(state: &AppStateInner, now: DateTime<Utc>) -> anyhow::Result<(usize, usize)> {
+    let mut plays = 0;
+    let mut activity = 0;
+    for (user_id, retention) in state.retention.limited().await? {
+        let Some(cutoff) = retention.cutoff(now) else {
+            continue;
+        };
+        plays += state.history.prune(&user_id, cutoff).await?;
+        activity += state.activity.prune(&user_id, cutoff).await?;
+    }
+    Ok((plays, activity))
+}
+
+pub fn spawn_worker(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !state.leader.holds() {
+                continue;
+            }
+            match prune(&state, Utc::now()).await {
+                Ok((0, 0)) => {}
+                Ok((plays, activity)) => tracing::info!(
+                    "Forgot {plays} plays and {activity} playlist changes past their retention"
+                ),
+                Err(e) => tracing::error!("Failed to apply retention settings: {e:#}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spotify::PlayHistory;
+
+    async fn keeps_limits(store: &RetentionStore) {
+        assert_eq!(store.get("ann").await.unwrap(), Retention::Forever);
+        store.set("ann", Retention::Days30).await.unwrap();
+        store.set("bob", Retention::Days365).await.unwrap();
+        store.set("bob", Retention::Days90).await.unwrap();
+        assert_eq!(store.get("ann").await.unwrap(), Retention::Days30);
+        let mut limited = store.limited().await.unwrap();
+        limited.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            limited,
+            [
+                ("ann".to_owned(), Retention::Days30),
+                ("bob".to_owned(), Retention::Days90)
+            ]
+        );
+        // Keeping everything is the default, so it isn't stored.
+        store.set("ann", Retention::Forever).await.unwrap();
+        assert_eq!(store.get("ann").await.unwrap(), Retention::Forever);
+        assert!(store.remove("bob").await.unwrap());
+        assert!(!store.remove("bob").await.unwrap());
+        assert!(store.limited().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_limits_in_memory() {
+        keeps_limits(&RetentionStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn keeps_limits_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-retention-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        keeps_limits(&RetentionStore::Sql(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn prunes_only_what_is_past_each_limit() {
+        let state = AppStateInner::for_tests().await;
+        let now = Utc::now();
+        let play = |id: &str, days_ago| PlayHistory {
+            track: serde_json::from_value(serde_json::json!({
+                "id": id,
+                "name": id,
+                "uri": format!("spotify:track:{id}"),
+                "duration_ms": 1000,
+            }))
+            .unwrap(),
+            played_at: (now - chrono::Duration::days(days_ago)).to_rfc3339(),
+        };
+        for user_id in ["ann", "bob"] {
+            let plays = vec![play("old", 40), play("recent", 10)];
+            state.history.record(user_id, plays).await.unwrap();
+        }
+        state.retention.set("ann", Retention::Days30).await.unwrap();
+
+        assert_eq!(prune(&state, now).await.unwrap(), (1, 0));
+        let kept = state.history.plays("ann").await.unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].1.id, "recent");
+        assert_eq!(state.history.plays("bob").await.unwrap().len(), 2);
+        assert_eq!(prune(&state, now).await.unwrap(), (0, 0));
+    }
+}
@@ -0,0 +1,279 @@
+//! What each user of the instance may do, by Spotify user ID:
+//!
+//! - Admins can also use the `/admin` endpoints with their session, and manage roles there.
+//! - Members can use everything under `/api`.
+//! - Guests can look around, control their own playback and delete their data, but not store
+//!   anything on the instance: no rules, webhooks, API keys, shares, imports or playlist edits.
+//!
+//! The users in `ADMINS` are always admins. Everyone else has the role an admin gave them, or
+//! `DEFAULT_ROLE`. Given roles live in memory by default, or in the database with a SQL feature
+//! and `DATABASE_URL` set.
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{session::Session, AppError, AppStateInner};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    #[default]
+    Member,
+    Guest,
+}
+
+impl Role {
+    #[cfg(feature = "sql")]
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Member => "member",
+            Self::Guest => "guest",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "admin" => Ok(Self::Admin),
+            "member" => Ok(Self::Member),
+            "guest" => Ok(Self::Guest),
+            _ => anyhow::bail!("unknown role `{s}`, expected admin, member or guest"),
+        }
+    }
+}
+
+/// The role of a user in `ADMINS` can't be changed, only by changing `ADMINS`.
+#[derive(Debug)]
+pub struct Seeded;
+
+impl std::fmt::Display for Seeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the user is an admin through ADMINS")
+    }
+}
+
+impl std::error::Error for Seeded {}
+
+pub struct RoleStore {
+    /// From `ADMINS`.
+    admins: HashSet<String>,
+    default: Role,
+    given: Given,
+}
+
+/// Roles given by admins.
+enum Given {
+    Memory(RwLock<HashMap<String, Role>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl RoleStore {
+    pub fn new(admins: &[String], default: Role) -> Self {
+        Self {
+            admins: admins.iter().cloned().collect(),
+            default,
+            given: Given::Memory(RwLock::default()),
+        }
+    }
+
+    /// Keeps given roles in the database instead of in memory.
+    #[cfg(feature = "sql")]
+    pub fn in_database(self, pool: db::Pool) -> Self {
+        Self {
+            given: Given::Sql(pool),
+            ..self
+        }
+    }
+
+    pub async fn role(&self, user_id: &str) -> anyhow::Result<Role> {
+        if self.admins.contains(user_id) {
+            return Ok(Role::Admin);
+        }
+        let given = match &self.given {
+            Given::Memory(roles) => roles.read().await.get(user_id).copied(),
+            #[cfg(feature = "sql")]
+            Given::Sql(pool) => {
+                let role: Option<String> =
+                    sqlx::query_scalar("SELECT role FROM roles WHERE user_id = $1")
+                        .bind(user_id)
+                        .fetch_optional(pool)
+                        .await?;
+                role.as_deref().map(str::parse).transpose()?
+            }
+        };
+        Ok(given.unwrap_or(self.default))
+    }
+
+    /// Fails with [`Seeded`] for users in `ADMINS`.
+    pub async fn set(&self, user_id: &str, role: Role) -> anyhow::Result<()> {
+        if self.admins.contains(user_id) {
+            return Err(Seeded.into());
+        }
+        match &self.given {
+            Given::Memory(roles) => {
+                roles.write().await.insert(user_id.to_owned(), role);
+            }
+            #[cfg(feature = "sql")]
+            Given::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO roles (user_id, role) VALUES ($1, $2) \
+                     ON CONFLICT (user_id) DO UPDATE SET role = $2",
+                )
+                .bind(user_id)
+                .bind(role.as_str())
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives a user the default role again, returning whether they had another one. Fails with
+    /// [`Seeded`] for users in `ADMINS`.
+    pub async fn reset(&self, user_id: &str) -> anyhow::Result<bool> {
+        if self.admins.contains(user_id) {
+            return Err(Seeded.into());
+        }
+        match &self.given {
+            Given::Memory(roles) => Ok(roles.write().await.remove(user_id).is_some()),
+            #[cfg(feature = "sql")]
+            Given::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM roles WHERE user_id = $1")
+                    .bind(user_id)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Every user whose role isn't the default one, by user ID.
+    pub async fn all(&self) -> anyhow::Result<BTreeMap<String, Role>> {
+        let given: Vec<(String, Role)> = match &self.given {
+            Given::Memory(roles) => roles
+                .read()
+                .await
+                .iter()
+                .map(|(user_id, role)| (user_id.clone(), *role))
+                .collect(),
+            #[cfg(feature = "sql")]
+            Given::Sql(pool) => {
+                let rows: Vec<(String, String)> = sqlx::query_as("SELECT user_id, role FROM roles")
+                    .fetch_all(pool)
+                    .await?;
+                rows.into_iter()
+                    .map(|(user_id, role)| Ok((user_id, role.parse()?)))
+                    .collect::<anyhow::Result<_>>()?
+            }
+        };
+        let mut roles: BTreeMap<String, Role> = given
+            .into_iter()
+            .filter(|(_, role)| *role != self.default)
+            .collect();
+        roles.extend(
+            self.admins
+                .iter()
+                .map(|user_id| (user_id.clone(), Role::Admin)),
+        );
+        Ok(roles)
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.given {
+            Given::Memory(roles) => roles.try_read().ok().map(|r| r.len()),
+            #[cfg(feature = "sql")]
+            Given::Sql(_) => None,
+        }
+    }
+}
+
+/// Whether a guest may make a request to `path` under `/api`.
+fn guest_allowed(method: &Method, path: &str) -> bool {
+    let under = |prefix: &str| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+        || under("/player")
+        || (method == Method::DELETE && path == "/me/data")
+}
+
+/// Middleware for `/api`, inside [`crate::api_keys::authenticate`] so keys act with the role of
+/// their user. Requests of guests that aren't theirs to make are refused with `403`. Requests
+/// without a session are left to the routes.
+pub async fn enforce(
+    State(state): State<Arc<AppStateInner>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if guest_allowed(request.method(), request.uri().path()) {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    if let Ok(session) = Session::from_request_parts(&mut parts, &state).await {
+        match state.roles.role(&session.user_id).await {
+            Ok(Role::Guest) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    "guests can't do this on this instance",
+                )
+                    .into_response();
+            }
+            Ok(_) => {}
+            Err(e) => return AppError(e).into_response(),
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(all(test, feature = "sqlite-store"))]
+mod tests {
+    use super::*;
+    use crate::token;
+
+    #[tokio::test]
+    async fn roles_survive_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-roles-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        let admins = ["root".to_owned()];
+        let store = RoleStore::new(&admins, Role::Member).in_database(pool.clone());
+        store.set("ann", Role::Guest).await.unwrap();
+        store.set("bob", Role::Admin).await.unwrap();
+        assert!(store
+            .set("root", Role::Guest)
+            .await
+            .unwrap_err()
+            .is::<Seeded>());
+
+        let store = RoleStore::new(&admins, Role::Member).in_database(pool);
+        assert_eq!(store.role("ann").await.unwrap(), Role::Guest);
+        assert_eq!(store.role("cat").await.unwrap(), Role::Member);
+        assert_eq!(store.all().await.unwrap().len(), 3);
+        assert!(store.reset("bob").await.unwrap());
+        assert!(!store.reset("bob").await.unwrap());
+        assert_eq!(store.role("bob").await.unwrap(), Role::Member);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,394 @@
+//! Smart playlists: a rule selects tracks from the owner's library by criteria, and a background
+//! worker keeps the rule's target playlist filled with exactly the matching tracks.
+//!
+//! Rules belong to a Spotify user and are kept like shares: in memory, or in the database with a
+//! SQL feature and `DATABASE_URL` set. The worker can only refresh a rule while its owner has a
+//! session to act with.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    library::{self, LibraryTrack},
+    spotify::{self, SpotifyToken},
+    token, AppStateInner,
+};
+
+/// How often the worker brings every target playlist up to date.
+const EVALUATION_INTERVAL: Duration = Duration::from_mins(15);
+
+/// An inclusive range of an audio feature.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Range {
+    fn contains(self, value: f32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+/// What a track must satisfy to be selected. Every criterion that is set must hold.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Criteria {
+    /// The track must have at least one of these genres.
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub tempo: Option<Range>,
+    pub energy: Option<Range>,
+    /// An ISO 8601 date or timestamp; the track must have been saved at or after it.
+    pub added_after: Option<String>,
+    /// Artist IDs; the track must be by at least one of them.
+    #[serde(default)]
+    pub include_artists: Vec<String>,
+    /// Artist IDs; the track must be by none of them.
+    #[serde(default)]
+    pub exclude_artists: Vec<String>,
+}
+
+impl Criteria {
+    pub fn matches(&self, track: &LibraryTrack) -> bool {
+        let artists = &track.saved.track.artists;
+        let has_artist = |ids: &[String]| artists.iter().any(|a| ids.contains(&a.id));
+        let feature = |range: Option<Range>, get: fn(&spotify::AudioFeatures) -> f32| {
+            range.is_none_or(|range| {
+                track
+                    .features
+                    .as_ref()
+                    .is_some_and(|f| range.contains(get(f)))
+            })
+        };
+
+        (self.genres.is_empty() || track.genres.iter().any(|g| self.genres.contains(g)))
+            && feature(self.tempo, |f| f.tempo)
+            && feature(self.energy, |f| f.energy)
+            // ISO 8601 timestamps in UTC sort lexicographically.
+            && self
+                .added_after
+                .as_ref()
+                .is_none_or(|after| track.saved.added_at.as_str() >= after.as_str())
+            && (self.include_artists.is_empty() || has_artist(&self.include_artists))
+            && !has_artist(&self.exclude_artists)
+    }
+
+    /// URIs of the matching tracks, in library order (most recently saved first).
+    pub fn select(&self, library: &[LibraryTrack]) -> Vec<String> {
+        library
+            .iter()
+            .filter(|track| self.matches(track))
+            .map(|track| track.saved.track.uri.clone())
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    pub criteria: Criteria,
+    pub target_playlist_id: String,
+    #[serde(skip)]
+    pub owner: String,
+}
+
+/// In memory by default, or in the database with a SQL feature and `DATABASE_URL` set.
+pub enum RuleStore {
+    Memory(RwLock<HashMap<String, Rule>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for RuleStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl RuleStore {
+    pub async fn insert(
+        &self,
+        owner: String,
+        name: String,
+        criteria: Criteria,
+        target_playlist_id: String,
+    ) -> anyhow::Result<Rule> {
+        let rule = Rule {
+            id: token::generate(8),
+            name,
+            criteria,
+            target_playlist_id,
+            owner,
+        };
+        match self {
+            Self::Memory(rules) => {
+                rules.write().await.insert(rule.id.clone(), rule.clone());
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query("INSERT INTO rules (id, owner, data) VALUES ($1, $2, $3)")
+                    .bind(&rule.id)
+                    .bind(&rule.owner)
+                    .bind(serde_json::to_string(&rule)?)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+        Ok(rule)
+    }
+
+    pub async fn owned_by(&self, owner: &str) -> anyhow::Result<Vec<Rule>> {
+        match self {
+            Self::Memory(rules) => Ok(rules
+                .read()
+                .await
+                .values()
+                .filter(|rule| rule.owner == owner)
+                .cloned()
+                .collect()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<String> =
+                    sqlx::query_scalar("SELECT data FROM rules WHERE owner = $1")
+                        .bind(owner)
+                        .fetch_all(pool)
+                        .await?;
+                rows.iter()
+                    .map(|data| from_row(owner.to_owned(), data))
+                    .collect()
+            }
+        }
+    }
+
+    pub async fn get(&self, owner: &str, id: &str) -> anyhow::Result<Option<Rule>> {
+        match self {
+            Self::Memory(rules) => Ok(rules
+                .read()
+                .await
+                .get(id)
+                .filter(|rule| rule.owner == owner)
+                .cloned()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let data: Option<String> =
+                    sqlx::query_scalar("SELECT data FROM rules WHERE id = $1 AND owner = $2")
+                        .bind(id)
+                        .bind(owner)
+                        .fetch_optional(pool)
+                        .await?;
+                data.map(|data| from_row(owner.to_owned(), &data))
+                    .transpose()
+            }
+        }
+    }
+
+    /// Returns whether a rule was removed.
+    pub async fn remove(&self, owner: &str, id: &str) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(rules) => {
+                let mut rules = rules.write().await;
+                if rules.get(id).is_some_and(|rule| rule.owner == owner) {
+                    rules.remove(id);
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM rules WHERE id = $1 AND owner = $2")
+                    .bind(id)
+                    .bind(owner)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected() > 0)
+            }
+        }
+    }
+
+    /// Removes every rule of `owner`, returning how many there were.
+    pub async fn remove_owned_by(&self, owner: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(rules) => {
+                let mut rules = rules.write().await;
+                let before = rules.len();
+                rules.retain(|_, rule| rule.owner != owner);
+                Ok(before - rules.len())
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM rules WHERE owner = $1")
+                    .bind(owner)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Rule>> {
+        match self {
+            Self::Memory(rules) => Ok(rules.read().await.values().cloned().collect()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, String)> = sqlx::query_as("SELECT owner, data FROM rules")
+                    .fetch_all(pool)
+                    .await?;
+                rows.into_iter()
+                    .map(|(owner, data)| from_row(owner, &data))
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether every instance sees these rules, so that only the one leading should apply them.
+    const fn is_shared(&self) -> bool {
+        match self {
+            Self::Memory(_) => false,
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => true,
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(rules) => rules.try_read().ok().map(|r| r.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+/// `owner` isn't serialized with the rest of the rule, so it has a column of its own.
+#[cfg(feature = "sql")]
+fn from_row(owner: String, data: &str) -> anyhow::Result<Rule> {
+    Ok(Rule {
+        owner,
+        ..serde_json::from_str::<Rule>(data)?
+    })
+}
+
+/// Fills the rule's target playlist with its matching tracks. Returns the new snapshot ID and the
+/// number of tracks.
+pub async fn apply(
+    access_token: &str,
+    rule: &Rule,
+    library: &[LibraryTrack],
+) -> anyhow::Result<(String, usize)> {
+    let uris = rule.criteria.select(library);
+    let snapshot_id =
+        spotify::replace_playlist_items(access_token, &rule.target_playlist_id, &uris).await?;
+    Ok((snapshot_id, uris.len()))
+}
+
+/// Periodically re-applies every rule whose owner still has a session.
+pub fn spawn_worker(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVALUATION_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Rules in memory are this instance's own, so it applies them whoever leads.
+            if state.rules.is_shared() && !state.leader.holds() {
+                continue;
+            }
+            let tokens: HashMap<String, SpotifyToken> = match state.sessions.all().await {
+                Ok(sessions) => sessions
+                    .into_iter()
+                    .map(|session| (session.user_id, session.token))
+                    .collect(),
+                Err(e) => {
+                    tracing::error!("Failed to list sessions for smart playlists: {e:#}");
+                    continue;
+                }
+            };
+            let mut rules = match state.rules.all().await {
+                Ok(rules) => rules,
+                Err(e) => {
+                    tracing::error!("Failed to list smart playlist rules: {e:#}");
+                    continue;
+                }
+            };
+            rules.sort_by(|a, b| a.owner.cmp(&b.owner));
+            // Libraries are loaded once per owner, however many rules they have.
+            for owner_rules in rules.chunk_by(|a, b| a.owner == b.owner) {
+                let Some(token) = tokens.get(&owner_rules[0].owner) else {
+                    continue;
+                };
+                let library = match library::load(
+                    &token.access_token,
+                    &state.genres,
+                    &state.features,
+                )
+                .await
+                {
+                    Ok(library) => library,
+                    Err(e) => {
+                        tracing::warn!("Failed to load library for smart playlists: {e:#}");
+                        continue;
+                    }
+                };
+                for rule in owner_rules {
+                    match apply(&token.access_token, rule, &library).await {
+                        Ok((_, count)) => {
+                            tracing::debug!("Rule {} selected {count} tracks", rule.id);
+                        }
+                        Err(e) => tracing::warn!("Failed to apply rule {}: {e:#}", rule.id),
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(all(test, feature = "sqlite-store"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rules_survive_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-rules-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        let store = RuleStore::Sql(pool);
+        let criteria = Criteria {
+            genres: vec!["shoegaze".to_owned()],
+            ..Criteria::default()
+        };
+        let rule = store
+            .insert(
+                "ann".to_owned(),
+                "Haze".to_owned(),
+                criteria,
+                "p1".to_owned(),
+            )
+            .await
+            .unwrap();
+        store
+            .insert(
+                "bob".to_owned(),
+                "Other".to_owned(),
+                Criteria::default(),
+                "p2".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let got = store.get("ann", &rule.id).await.unwrap().unwrap();
+        assert_eq!(got.owner, "ann");
+        assert_eq!(got.criteria.genres, ["shoegaze"]);
+        assert!(store.get("bob", &rule.id).await.unwrap().is_none());
+        assert_eq!(store.owned_by("ann").await.unwrap().len(), 1);
+        assert_eq!(store.all().await.unwrap().len(), 2);
+        assert!(!store.remove("bob", &rule.id).await.unwrap());
+        assert!(store.remove("ann", &rule.id).await.unwrap());
+        assert_eq!(store.remove_owned_by("bob").await.unwrap(), 1);
+        assert!(store.all().await.unwrap().is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+}
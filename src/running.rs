@@ -0,0 +1,83 @@
+//! Running playlists: tracks picked by tempo around a target cadence, arranged so the tempo
+//! climbs during a warm-up, holds during the steady part and comes back down to cool down.
+
+use rand::seq::SliceRandom;
+
+use crate::library::LibraryTrack;
+
+/// Share of the total duration spent warming up, and again cooling down.
+const EASE_SHARE: f32 = 0.15;
+/// How far from the target a steady-phase track's tempo may be, as a fraction of the target.
+const STEADY_TOLERANCE: f32 = 0.04;
+/// Slowest tempo used while warming up or cooling down, as a fraction of the target.
+const EASE_FLOOR: f32 = 0.8;
+
+/// The tempo a runner would step to: half-time tracks count double, so a 85 BPM track works for
+/// a 170 BPM cadence.
+#[allow(clippy::suboptimal_flops)]
+fn effective_tempo(tempo: f32, target: f32) -> f32 {
+    if (tempo * 2.0 - target).abs() < (tempo - target).abs() {
+        tempo * 2.0
+    } else {
+        tempo
+    }
+}
+
+/// Picks tracks whose durations add up to about `duration_ms` and returns their URIs in play
+/// order.
+pub fn build(library: &[LibraryTrack], target_bpm: f32, duration_ms: u64) -> Vec<String> {
+    let mut candidates: Vec<(f32, &LibraryTrack)> = library
+        .iter()
+        .filter_map(|track| {
+            let tempo = track.features.as_ref()?.tempo;
+            Some((effective_tempo(tempo, target_bpm), track))
+        })
+        .filter(|(tempo, _)| *tempo >= target_bpm * EASE_FLOOR)
+        .collect();
+    candidates.shuffle(&mut rand::thread_rng());
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let ease_ms = (duration_ms as f32 * EASE_SHARE) as u64;
+    let steady_ms = duration_ms.saturating_sub(2 * ease_ms);
+
+    let is_steady = |tempo: f32| (tempo - target_bpm).abs() <= target_bpm * STEADY_TOLERANCE;
+    let (mut steady, easing): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|(tempo, _)| is_steady(*tempo));
+    let mut easing: Vec<_> = easing
+        .into_iter()
+        .filter(|(tempo, _)| *tempo < target_bpm)
+        .collect();
+
+    let mut warm_up = take_for(&mut easing, ease_ms);
+    let mut cool_down = take_for(&mut easing, ease_ms);
+    let steady = take_for(&mut steady, steady_ms);
+    warm_up.sort_by(|a, b| a.0.total_cmp(&b.0));
+    cool_down.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    warm_up
+        .into_iter()
+        .chain(steady)
+        .chain(cool_down)
+        .map(|(_, track)| track.saved.track.uri.clone())
+        .collect()
+}
+
+/// Takes tracks off `pool` until they last at least `duration_ms`, or the pool runs out.
+fn take_for<'a>(
+    pool: &mut Vec<(f32, &'a LibraryTrack)>,
+    duration_ms: u64,
+) -> Vec<(f32, &'a LibraryTrack)> {
+    let mut taken = Vec::new();
+    let mut total = 0;
+    while total < duration_ms {
+        let Some(track) = pool.pop() else { break };
+        total += track.1.saved.track.duration_ms;
+        taken.push(track);
+    }
+    taken
+}
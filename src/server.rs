@@ -0,0 +1,230 @@
+//! The accept loop. axum's `serve` only handles TCP, so connections are driven through hyper
+//! directly, which works the same for TCP and Unix domain sockets. Requests over TCP carry the
+//! peer's address as [`ConnectInfo`], as with axum's own server.
+//!
+//! On shutdown, the listener is closed first and the open connections are then given
+//! [`DRAIN_TIMEOUT`] to finish the requests they're serving.
+
+use crate::config::{Config, Listen, ServerConfig};
+use axum::{extract::ConnectInfo, http::Request, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+    service::TowerToHyperService,
+};
+use std::{
+    fs::DirBuilder,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    signal::unix::{signal, SignalKind},
+};
+use tower::ServiceExt;
+
+/// How long connections still open on shutdown get to finish. Streams such as the now-playing
+/// events never do, so they're cut off after it.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn serve(app: Router, config: &Config) -> anyhow::Result<()> {
+    let builder = connection_builder(&config.server);
+    let connections = GracefulShutdown::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    match &config.listen {
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            tracing::info!("Listening on {addr}");
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, peer)) => serve_connection(
+                            stream,
+                            Some(peer),
+                            app.clone(),
+                            builder.clone(),
+                            connections.watcher(),
+                        ),
+                        Err(e) => tracing::warn!("Failed to accept connection: {e}"),
+                    },
+                    () = &mut shutdown => break,
+                }
+            }
+            drop(listener);
+            drain(connections).await;
+        }
+        Listen::Unix(path) => {
+            let listener = bind_unix(path, config.unix_socket_mode)?;
+            tracing::info!("Listening on unix:{}", path.display());
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => serve_connection(
+                            stream,
+                            None,
+                            app.clone(),
+                            builder.clone(),
+                            connections.watcher(),
+                        ),
+                        Err(e) => tracing::warn!("Failed to accept connection: {e}"),
+                    },
+                    () = &mut shutdown => break,
+                }
+            }
+            drop(listener);
+            drain(connections).await;
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove socket {}: {e}", path.display());
+            }
+        }
+    }
+    tracing::info!("Shutting down");
+    Ok(())
+}
+
+/// Asks every open connection to close once its requests are answered, and waits for them up to
+/// [`DRAIN_TIMEOUT`].
+async fn drain(connections: GracefulShutdown) {
+    let open = connections.count();
+    if open > 0 {
+        tracing::info!("Waiting for {open} open connections to finish");
+    }
+    if tokio::time::timeout(DRAIN_TIMEOUT, connections.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Closing the connections still open after {}s",
+            DRAIN_TIMEOUT.as_secs()
+        );
+    }
+}
+
+/// Binds in a private directory next to `path` and moves the socket into place once its mode is
+/// set, so it is never reachable with looser permissions than configured.
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    // A socket left behind by an unclean exit is replaced, but nothing else is.
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| !metadata.file_type().is_socket()) {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a socket path", path.display()))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = (|| {
+        let staged = private.join(name);
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        anyhow::Ok(listener)
+    })();
+    std::fs::remove_dir_all(&private)?;
+    bound
+}
+
+fn connection_builder(config: &ServerConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.http1_keep_alive)
+        .header_read_timeout(config.header_read_timeout);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(config.http2_keep_alive_interval)
+        .keep_alive_timeout(config.http2_keep_alive_timeout);
+    if config.http2 {
+        builder
+    } else {
+        builder.http1_only()
+    }
+}
+
+fn serve_connection<I>(
+    io: I,
+    peer: Option<SocketAddr>,
+    app: Router,
+    builder: auto::Builder<TokioExecutor>,
+    watcher: Watcher,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let app = app.map_request(move |mut request: Request<_>| {
+        if let Some(peer) = peer {
+            request.extensions_mut().insert(ConnectInfo(peer));
+        }
+        request
+    });
+    tokio::spawn(async move {
+        let connection =
+            builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(app));
+        if let Err(e) = watcher.watch(connection).await {
+            tracing::debug!("Connection closed with error: {e}");
+        }
+    });
+}
+
+/// Ctrl-C, or `SIGTERM` as sent by service managers, so stopping a service saves what's kept
+/// on shutdown, like the session snapshot.
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for ctrl-c: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        () = terminate => {}
+        () = interrupt => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unix_socket_is_bound_with_its_mode() {
+        let dir = std::env::temp_dir().join(format!("blid-test-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.sock");
+        std::fs::write(&path, "").unwrap();
+        assert!(bind_unix(&path, 0o600).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        drop(listener);
+        // A stale socket is replaced.
+        bind_unix(&path, 0o660).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+//! Extracting the logged-in user's session from a request, and keeping it alive while it's used.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    api_keys::KeyAuth,
+    config::SessionConfig,
+    cookies,
+    session_store::SessionData,
+    spotify::{self, PremiumRequired, SpotifyToken},
+    token::SessionId,
+    AppStateInner,
+};
+
+/// A session is only written back to the store (and its cookie re-issued) when it was last seen
+/// more than this long ago, so browsing doesn't cost a store write per request.
+pub const TOUCH_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Access tokens expiring sooner than this are refreshed ahead of time, so a handler never
+/// starts an upstream call with a token that dies halfway.
+pub const REFRESH_MARGIN: Duration = Duration::from_mins(5);
+
+/// The `Set-Cookie` value for a session lasting `ttl`.
+pub fn cookie(config: &SessionConfig, id: &SessionId, ttl: Duration) -> String {
+    format!(
+        "session_id={}; Max-Age={}; Path=/; HttpOnly; {}",
+        id.as_str(),
+        ttl.as_secs(),
+        same_site(config)
+    )
+}
+
+/// The `Set-Cookie` value ending the session in the browser.
+pub fn clear_cookie(config: &SessionConfig) -> String {
+    format!(
+        "session_id=; Max-Age=0; Path=/; HttpOnly; {}",
+        same_site(config)
+    )
+}
+
+/// Browsers only accept `SameSite=None` on secure cookies.
+const fn same_site(config: &SessionConfig) -> &'static str {
+    if config.cross_site {
+        "SameSite=None; Secure"
+    } else {
+        "SameSite=Lax"
+    }
+}
+
+/// How much longer a session may live if used at `now`: a full idle timeout, but never past its
+/// absolute maximum age.
+pub fn ttl(config: &SessionConfig, created_at: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    let age = (now - created_at).to_std().unwrap_or_default();
+    config.max_age.saturating_sub(age).min(config.idle_timeout)
+}
+
+/// Stores `data` as a new session, returning its ID and time to live.
+pub async fn start(
+    state: &AppStateInner,
+    data: SessionData,
+) -> anyhow::Result<(SessionId, Duration)> {
+    let ttl = ttl(&state.session_config, data.created_at, Utc::now());
+    // `insert_new` checks and inserts atomically, so two logins can't race to the same ID.
+    let mut id = SessionId::generate();
+    while !state
+        .sessions
+        .insert_new(id.hash(), data.clone(), ttl)
+        .await?
+    {
+        id = SessionId::generate();
+    }
+    Ok((id, ttl))
+}
+
+/// Middleware sliding the expiry of the request's session forward, and refreshing its Spotify
+/// token when it's about to expire. Unknown session IDs are counted by [`crate::lockout`].
+pub async fn slide(
+    State(state): State<Arc<AppStateInner>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let id = cookies::session(request.headers()).map(SessionId::from);
+    let Some(id) = id else {
+        return next.run(request).await;
+    };
+
+    // Done before running the handler, so it sees the refreshed token.
+    let slid = match touch(&state, &id).await {
+        Ok(Touched::Unknown) => {
+            let ip = state
+                .lockout
+                .client_ip(request.headers(), request.extensions().get());
+            if let Some(ip) = ip {
+                state.lockout.fail(ip, id.hash()).await;
+            }
+            None
+        }
+        Ok(Touched::Kept) => None,
+        Ok(Touched::Slid(ttl)) => Some(ttl),
+        Err(e) => {
+            tracing::warn!("Failed to keep session alive: {e:#}");
+            None
+        }
+    };
+    let mut response = next.run(request).await;
+    // A handler that set the cookie itself, e.g. to end the session, knows better.
+    let overridden = response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .any(|value| value.as_bytes().starts_with(b"session_id="));
+    if let Some(ttl) = slid.filter(|_| !overridden) {
+        if let Ok(value) = HeaderValue::from_str(&cookie(&state.session_config, &id, ttl)) {
+            response.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    response
+}
+
+enum Touched {
+    /// There's no such session.
+    Unknown,
+    Kept,
+    /// Extended, with its new time to live.
+    Slid(Duration),
+}
+
+async fn touch(state: &AppStateInner, id: &SessionId) -> anyhow::Result<Touched> {
+    let Some(mut data) = state.sessions.get(&id.hash()).await? else {
+        return Ok(Touched::Unknown);
+    };
+    let now = Utc::now();
+    let token_stale = data.token_expires_at - now < chrono::Duration::from_std(REFRESH_MARGIN)?;
+    let recently_seen = now - data.last_seen < chrono::Duration::from_std(TOUCH_INTERVAL)?;
+    if !token_stale && recently_seen {
+        return Ok(Touched::Kept);
+    }
+
+    if token_stale {
+        data.token = spotify::refresh(&data.token).await?;
+        data.token_expires_at = now + chrono::Duration::seconds(data.token.expires_in.try_into()?);
+    }
+    data.last_seen = now;
+    let ttl = ttl(&state.session_config, data.created_at, now);
+    if ttl.is_zero() {
+        return Ok(Touched::Kept);
+    }
+    state.sessions.update(id.hash(), data, ttl).await?;
+    Ok(Touched::Slid(ttl))
+}
+
+/// The session of the request's `session_id` cookie, or the API key it was authenticated with by
+/// [`crate::api_keys::authenticate`]. Handlers taking this are only reached by logged-in users;
+/// everyone else gets a `401`.
+pub struct Session {
+    /// `None` when authenticated with an API key.
+    pub id: Option<SessionId>,
+    pub user_id: String,
+    pub token: SpotifyToken,
+    pub token_expires_at: DateTime<Utc>,
+    /// `None` when unknown, as for API keys.
+    pub premium: Option<bool>,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppStateInner>> for Session {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppStateInner>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(auth) = parts.extensions.get::<KeyAuth>().cloned() {
+            return Ok(Self {
+                id: None,
+                user_id: auth.user_id,
+                token: auth.token,
+                token_expires_at: auth.token_expires_at,
+                premium: None,
+            });
+        }
+        let id = cookies::session(&parts.headers)
+            .map(SessionId::from)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let data = state
+            .sessions
+            .get(&id.hash())
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to look up session: {e:#}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let SessionData {
+            user_id,
+            token,
+            token_expires_at,
+            premium,
+            ..
+        } = data;
+        Ok(Self {
+            id: Some(id),
+            user_id,
+            token,
+            token_expires_at,
+            premium,
+        })
+    }
+}
+
+impl Session {
+    /// Fails with [`MissingScopes`] unless the Spotify token was granted all of `scopes`.
+    pub fn require(&self, scopes: &[&'static str]) -> Result<(), MissingScopes> {
+        let missing = self.token.missing_scopes(scopes);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(MissingScopes { missing })
+        }
+    }
+
+    /// Fails with [`PremiumRequired`] for users known not to have Premium, without bothering
+    /// Spotify. When unknown, Spotify's own refusal is mapped to the same error.
+    pub const fn require_premium(&self) -> Result<(), PremiumRequired> {
+        match self.premium {
+            Some(false) => Err(PremiumRequired),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The Spotify token of a session lacks scopes a route needs, typically because it was granted
+/// before the feature existed. Returned to the client as a `403` with a URL logging in again with
+/// them, which gives the session a new token.
+#[derive(Debug)]
+pub struct MissingScopes {
+    pub missing: Vec<&'static str>,
+}
+
+impl MissingScopes {
+    /// The login route asking for the missing scopes on top of the usual ones. Clients can add a
+    /// `next` parameter to come back where they were.
+    pub fn reauth_url(&self) -> String {
+        serde_qs::to_string(&serde_json::json!({ "scope": self.missing.join(" ") }))
+            .map_or_else(|_| "/auth".to_owned(), |qs| format!("/auth?{qs}"))
+    }
+}
+
+impl std::fmt::Display for MissingScopes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missing Spotify scopes: {}", self.missing.join(" "))
+    }
+}
+
+impl std::error::Error for MissingScopes {}
+
+/// A [`Session`] for HTML pages: logged-out users are sent to the login flow, and brought back to
+/// the page afterwards, instead of getting a bare `401`.
+pub struct PageSession(pub Session);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppStateInner>> for PageSession {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppStateInner>,
+    ) -> Result<Self, Self::Rejection> {
+        match Session::from_request_parts(parts, state).await {
+            Ok(session) => Ok(Self(session)),
+            Err(StatusCode::UNAUTHORIZED) => Err(crate::login_redirect(&parts.uri).into_response()),
+            Err(status) => Err(status.into_response()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn data(created_at: DateTime<Utc>, last_seen: DateTime<Utc>) -> SessionData {
+        SessionData {
+            user_id: "ann".to_owned(),
+            token: SpotifyToken {
+                access_token: "token".to_owned(),
+                refresh_token: String::new(),
+                expires_in: 3600,
+                token_type: "Bearer".to_owned(),
+                scope: String::new(),
+            },
+            token_expires_at: Utc::now() + chrono::Duration::hours(1),
+            created_at,
+            last_seen,
+            premium: None,
+            do_not_track: false,
+        }
+    }
+
+    #[test]
+    fn sessions_live_an_idle_timeout_up_to_their_max_age() {
+        let config = SessionConfig {
+            idle_timeout: Duration::from_hours(7 * 24),
+            max_age: Duration::from_hours(30 * 24),
+            cross_site: false,
+        };
+        let created_at = Utc::now();
+        let days = |days| created_at + chrono::Duration::days(days);
+        assert_eq!(ttl(&config, created_at, created_at), config.idle_timeout);
+        assert_eq!(ttl(&config, created_at, days(20)), config.idle_timeout);
+        assert_eq!(
+            ttl(&config, created_at, days(25)),
+            Duration::from_hours(5 * 24)
+        );
+        assert_eq!(ttl(&config, created_at, days(31)), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn sessions_slide_once_per_touch_interval() {
+        let state = AppStateInner::for_tests().await;
+        let app = Router::new()
+            .route("/", get(|| async { "" }))
+            .route(
+                "/logout",
+                get(|State(s): State<Arc<AppStateInner>>| async move {
+                    [(header::SET_COOKIE, clear_cookie(&s.session_config))]
+                }),
+            )
+            .layer(middleware::from_fn_with_state(state.clone(), slide))
+            .with_state(state.clone());
+        let set_cookie = |path: &str, id: &str| {
+            let request = Request::get(path)
+                .header(header::COOKIE, format!("session_id={id}"))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                response
+                    .headers()
+                    .get_all(header::SET_COOKIE)
+                    .iter()
+                    .map(|value| value.to_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let now = Utc::now();
+        let (fresh, _) = start(&state, data(now, now)).await.unwrap();
+        assert!(set_cookie("/", fresh.as_str()).await.is_empty());
+
+        let seen_at = now - chrono::Duration::from_std(TOUCH_INTERVAL * 2).unwrap();
+        let (idle, _) = start(&state, data(seen_at, seen_at)).await.unwrap();
+        let ttl = ttl(&state.session_config, seen_at, Utc::now());
+        assert_eq!(
+            set_cookie("/", idle.as_str()).await,
+            [cookie(&state.session_config, &idle, ttl)]
+        );
+        let touched = state.sessions.get(&idle.hash()).await.unwrap().unwrap();
+        assert!(touched.last_seen > seen_at);
+        // Seen just now, so left alone.
+        assert!(set_cookie("/", idle.as_str()).await.is_empty());
+
+        let (ending, _) = start(&state, data(seen_at, seen_at)).await.unwrap();
+        assert_eq!(
+            set_cookie("/logout", ending.as_str()).await,
+            [clear_cookie(&state.session_config)]
+        );
+        assert!(set_cookie("/", "unknown").await.is_empty());
+    }
+}
@@ -0,0 +1,783 @@
+//! Where sessions live. The in-memory store only works for a single instance; with the
+//! `redis-store` feature and `REDIS_URL` set, sessions are kept in Redis so any number of
+//! instances can run behind a load balancer without sticky sessions. With `DATABASE_URL` set
+//! and a SQL feature, they're kept in the database along with the other persistent stores (see
+//! [`crate::db`]).
+//!
+//! Expired sessions are swept out of memory and the database every [`SWEEP_INTERVAL`] by
+//! [`spawn_sweeper`]; Redis expires them by itself. To find sessions without scanning every key,
+//! Redis also keeps the IDs of all sessions in a set, and those of each user in another.
+//!
+//! The in-memory store can be kept across restarts by naming a file in `SESSION_SNAPSHOT`, which
+//! it's written to on shutdown and read back from on startup. The same snapshots are what
+//! `blid-test migrate-sessions` (see [`crate::migrate_sessions`]) moves sessions between stores
+//! with.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "redis-store")]
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write, path::Path, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{config::Config, metrics, spotify::SpotifyToken, token::SessionHash, AppStateInner};
+
+const SWEEP_INTERVAL: Duration = Duration::from_mins(5);
+
+/// What a session maps to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionData {
+    /// The Spotify user ID, which outlives any single session.
+    pub user_id: String,
+    pub token: SpotifyToken,
+    /// When `token.access_token` stops working and needs refreshing.
+    pub token_expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    /// Last request made with this session, to the precision of
+    /// [`crate::session::TOUCH_INTERVAL`].
+    pub last_seen: DateTime<Utc>,
+    /// Whether the user has Spotify Premium, which player commands need. `None` for sessions
+    /// from before it was recorded.
+    #[serde(default)]
+    pub premium: Option<bool>,
+    /// Whether the login asked not to be tracked, which keeps the history collector away. See
+    /// [`crate::privacy`].
+    #[serde(default)]
+    pub do_not_track: bool,
+}
+
+/// A session as kept in a [`Snapshot`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredSession {
+    /// The [`SessionHash`] in hex. Session IDs themselves are never stored.
+    pub id: String,
+    pub expires_at: DateTime<Utc>,
+    pub data: SessionData,
+}
+
+impl StoredSession {
+    fn new(id: SessionHash, expires_at: DateTime<Utc>, data: SessionData) -> Self {
+        Self {
+            id: id.to_hex(),
+            expires_at,
+            data,
+        }
+    }
+}
+
+/// Every live session of a store, as JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub created_at: DateTime<Utc>,
+    pub sessions: Vec<StoredSession>,
+}
+
+impl Snapshot {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("couldn't read session snapshot {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("{} isn't a session snapshot", path.display()))
+    }
+
+    /// Writes the snapshot readable by the owner only, since it holds Spotify tokens, and in
+    /// place of any previous one only once complete.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let partial = path.with_extension("partial");
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&partial)
+            .with_context(|| format!("couldn't create {}", partial.display()))?;
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+/// Which migrations a database has had applied.
+#[derive(Serialize, Debug)]
+pub struct SchemaVersion {
+    /// The last migration applied, `None` before the first.
+    pub current: Option<i64>,
+    /// The last migration this build knows.
+    pub expected: Option<i64>,
+}
+
+impl SchemaVersion {
+    pub fn is_current(&self) -> bool {
+        self.current == self.expected
+    }
+}
+
+pub struct MemoryEntry {
+    data: SessionData,
+    expires_at: Instant,
+}
+
+impl MemoryEntry {
+    fn live(&self) -> Option<&SessionData> {
+        (self.expires_at > Instant::now()).then_some(&self.data)
+    }
+}
+
+pub enum SessionStore {
+    Memory(RwLock<HashMap<SessionHash, MemoryEntry>>),
+    #[cfg(feature = "redis-store")]
+    Redis(redis::aio::ConnectionManager),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl SessionStore {
+    #[allow(clippy::unused_async)]
+    pub async fn connect(config: &Config) -> anyhow::Result<Self> {
+        #[cfg(feature = "redis-store")]
+        if let Some(url) = &config.redis_url {
+            let client = redis::Client::open(url.as_str())?;
+            tracing::info!("Storing sessions in Redis");
+            let mut conn = client.get_connection_manager().await?;
+            index_existing(&mut conn).await?;
+            return Ok(Self::Redis(conn));
+        }
+        let _ = config;
+        Ok(Self::Memory(RwLock::default()))
+    }
+
+    pub async fn get(&self, id: &SessionHash) -> anyhow::Result<Option<SessionData>> {
+        match self {
+            Self::Memory(sessions) => Ok(metrics::read("sessions", sessions)
+                .await
+                .get(id)
+                .and_then(MemoryEntry::live)
+                .cloned()),
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => {
+                let value: Option<String> =
+                    redis::AsyncCommands::get(&mut conn.clone(), key(&id.to_hex())).await?;
+                Ok(value.map(|v| serde_json::from_str(&v)).transpose()?)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let value: Option<String> = sqlx::query_scalar(
+                    "SELECT data FROM sessions WHERE id = $1 AND expires_at > $2",
+                )
+                .bind(id.to_hex())
+                .bind(Utc::now().timestamp())
+                .fetch_optional(pool)
+                .await?;
+                Ok(value.map(|v| serde_json::from_str(&v)).transpose()?)
+            }
+        }
+    }
+
+    pub async fn contains(&self, id: &SessionHash) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(sessions) => Ok(metrics::read("sessions", sessions)
+                .await
+                .get(id)
+                .and_then(MemoryEntry::live)
+                .is_some()),
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => {
+                Ok(redis::AsyncCommands::exists(&mut conn.clone(), key(&id.to_hex())).await?)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => Ok(sqlx::query(
+                "SELECT 1 FROM sessions WHERE id = $1 AND expires_at > $2",
+            )
+            .bind(id.to_hex())
+            .bind(Utc::now().timestamp())
+            .fetch_optional(pool)
+            .await?
+            .is_some()),
+        }
+    }
+
+    /// Stores a new session, unless a live one with the same ID already exists, in which case
+    /// `false` is returned and nothing is changed.
+    pub async fn insert_new(
+        &self,
+        id: SessionHash,
+        data: SessionData,
+        ttl: Duration,
+    ) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory(sessions) => {
+                let mut sessions = metrics::write("sessions", sessions).await;
+                if sessions.get(&id).and_then(MemoryEntry::live).is_some() {
+                    return Ok(false);
+                }
+                sessions.insert(
+                    id,
+                    MemoryEntry {
+                        data,
+                        expires_at: Instant::now() + ttl,
+                    },
+                );
+                Ok(true)
+            }
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => write(&mut conn.clone(), id, &data, ttl, "NX").await,
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let now = Utc::now().timestamp();
+                let inserted = sqlx::query(
+                    "INSERT INTO sessions (id, user_id, data, expires_at) VALUES ($1, $2, $3, $4) \
+                     ON CONFLICT (id) DO UPDATE SET user_id = $2, data = $3, expires_at = $4 \
+                     WHERE sessions.expires_at <= $5",
+                )
+                .bind(id.to_hex())
+                .bind(&data.user_id)
+                .bind(serde_json::to_string(&data)?)
+                .bind(expires_at(now, ttl)?)
+                .bind(now)
+                .execute(pool)
+                .await?;
+                Ok(inserted.rows_affected() == 1)
+            }
+        }
+    }
+
+    /// Replaces the data of an existing session and resets its time to live. Sessions that have
+    /// expired in the meantime stay expired.
+    pub async fn update(
+        &self,
+        id: SessionHash,
+        data: SessionData,
+        ttl: Duration,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(sessions) => {
+                let mut sessions = metrics::write("sessions", sessions).await;
+                if let Some(entry) = sessions.get_mut(&id).filter(|e| e.live().is_some()) {
+                    *entry = MemoryEntry {
+                        data,
+                        expires_at: Instant::now() + ttl,
+                    };
+                }
+                Ok(())
+            }
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => {
+                write(&mut conn.clone(), id, &data, ttl, "XX").await?;
+                Ok(())
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let now = Utc::now().timestamp();
+                sqlx::query(
+                    "UPDATE sessions SET user_id = $1, data = $2, expires_at = $3 \
+                     WHERE id = $4 AND expires_at > $5",
+                )
+                .bind(&data.user_id)
+                .bind(serde_json::to_string(&data)?)
+                .bind(expires_at(now, ttl)?)
+                .bind(id.to_hex())
+                .bind(now)
+                .execute(pool)
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Every live session, for background workers acting on behalf of logged-in users.
+    pub async fn all(&self) -> anyhow::Result<Vec<SessionData>> {
+        match self {
+            Self::Memory(sessions) => Ok(metrics::read("sessions", sessions)
+                .await
+                .values()
+                .filter_map(MemoryEntry::live)
+                .cloned()
+                .collect()),
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => live(&mut conn.clone())
+                .await?
+                .iter()
+                .map(|(_, value)| Ok(serde_json::from_str(value)?))
+                .collect(),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let values: Vec<String> =
+                    sqlx::query_scalar("SELECT data FROM sessions WHERE expires_at > $1")
+                        .bind(Utc::now().timestamp())
+                        .fetch_all(pool)
+                        .await?;
+                values
+                    .iter()
+                    .map(|value| Ok(serde_json::from_str(value)?))
+                    .collect()
+            }
+        }
+    }
+
+    /// Ends every session of a user, returning how many there were.
+    pub async fn remove_user(&self, user_id: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(sessions) => {
+                let mut sessions = metrics::write("sessions", sessions).await;
+                let before = sessions.len();
+                sessions.retain(|_, entry| entry.data.user_id != user_id);
+                Ok(before - sessions.len())
+            }
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => {
+                let mut conn = conn.clone();
+                let ids: Vec<String> =
+                    redis::AsyncCommands::smembers(&mut conn, user_key(user_id)).await?;
+                if ids.is_empty() {
+                    return Ok(0);
+                }
+                let (removed,): (usize,) = redis::pipe()
+                    .atomic()
+                    .del(ids.iter().map(|id| key(id)).collect::<Vec<_>>())
+                    .srem(ALL_SESSIONS, &ids)
+                    .ignore()
+                    .del(user_key(user_id))
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await?;
+                Ok(removed)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted =
+                    sqlx::query("DELETE FROM sessions WHERE user_id = $1 AND expires_at > $2")
+                        .bind(user_id)
+                        .bind(Utc::now().timestamp())
+                        .execute(pool)
+                        .await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    /// Every live session with when it expires, for moving them to another store.
+    pub async fn export(&self) -> anyhow::Result<Vec<StoredSession>> {
+        match self {
+            Self::Memory(sessions) => {
+                let now = Instant::now();
+                let sessions = metrics::read("sessions", sessions).await;
+                let mut exported = Vec::with_capacity(sessions.len());
+                for (id, entry) in sessions.iter() {
+                    let Some(data) = entry.live() else { continue };
+                    let left = chrono::Duration::from_std(entry.expires_at - now)?;
+                    exported.push(StoredSession::new(*id, Utc::now() + left, data.clone()));
+                }
+                Ok(exported)
+            }
+            #[cfg(feature = "redis-store")]
+            Self::Redis(conn) => {
+                let mut conn = conn.clone();
+                let sessions = live(&mut conn).await?;
+                let mut exported = Vec::with_capacity(sessions.len());
+                for batch in sessions.chunks(BATCH) {
+                    let mut ttls = redis::pipe();
+                    for (id, _) in batch {
+                        ttls.ttl(key(id));
+                    }
+                    let ttls: Vec<i64> = ttls.query_async(&mut conn).await?;
+                    for ((id, value), ttl) in batch.iter().zip(ttls) {
+                        // Sessions can expire between the read and asking how long they have.
+                        let Ok(ttl) = u64::try_from(ttl) else {
+                            continue;
+                        };
+                        let hash = SessionHash::from_hex(id)
+                            .with_context(|| format!("unexpected session ID `{id}`"))?;
+                        let expires_at = Utc::now() + chrono::Duration::seconds(ttl.try_into()?);
+                        exported.push(StoredSession::new(
+                            hash,
+                            expires_at,
+                            serde_json::from_str(value)?,
+                        ));
+                    }
+                }
+                Ok(exported)
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<(String, String, i64)> = sqlx::query_as(
+                    "SELECT id, data, expires_at FROM sessions WHERE expires_at > $1",
+                )
+                .bind(Utc::now().timestamp())
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter()
+                    .map(|(id, data, expires_at)| {
+                        let hash = SessionHash::from_hex(&id)
+                            .with_context(|| format!("unexpected session ID `{id}`"))?;
+                        let expires_at = DateTime::from_timestamp(expires_at, 0)
+                            .context("session expiry out of range")?;
+                        Ok(StoredSession::new(
+                            hash,
+                            expires_at,
+                            serde_json::from_str(&data)?,
+                        ))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Adds sessions from another store, keeping when they expire. Sessions that have expired
+    /// since, or that this store already has, are skipped. Returns how many were added.
+    pub async fn import(&self, sessions: Vec<StoredSession>) -> anyhow::Result<usize> {
+        let mut added = 0;
+        for session in sessions {
+            let id = SessionHash::from_hex(&session.id)
+                .with_context(|| format!("unexpected session ID `{}`", session.id))?;
+            let Ok(ttl) = (session.expires_at - Utc::now()).to_std() else {
+                continue;
+            };
+            if self.insert_new(id, session.data, ttl).await? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Restores the in-memory store from `SESSION_SNAPSHOT`, if there's one. The snapshot is
+    /// removed once read, so sessions ended before a crash don't come back on the restart after.
+    pub async fn load_snapshot(&self, config: &Config) -> anyhow::Result<()> {
+        let (Self::Memory(_), Some(path)) = (self, &config.session_snapshot) else {
+            return Ok(());
+        };
+        if !path.exists() {
+            return Ok(());
+        }
+        let snapshot = Snapshot::read(path)?;
+        let restored = self.import(snapshot.sessions).await?;
+        std::fs::remove_file(path)?;
+        tracing::info!("Restored {restored} sessions from {}", path.display());
+        Ok(())
+    }
+
+    /// Writes the in-memory store to `SESSION_SNAPSHOT`, if set, for [`Self::load_snapshot`] on
+    /// the next start. Other stores outlive the process by themselves.
+    pub async fn save_snapshot(&self, config: &Config) -> anyhow::Result<()> {
+        let (Self::Memory(_), Some(path)) = (self, &config.session_snapshot) else {
+            return Ok(());
+        };
+        let sessions = self.export().await?;
+        let saved = sessions.len();
+        Snapshot {
+            created_at: Utc::now(),
+            sessions,
+        }
+        .write(path)?;
+        tracing::info!("Saved {saved} sessions to {}", path.display());
+        Ok(())
+    }
+
+    /// Forgets expired sessions, returning how many there were. Redis expires them by itself.
+    pub async fn sweep(&self) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(sessions) => {
+                let mut sessions = metrics::write("sessions", sessions).await;
+                let before = sessions.len();
+                sessions.retain(|_, entry| entry.live().is_some());
+                Ok(before - sessions.len())
+            }
+            #[cfg(feature = "redis-store")]
+            Self::Redis(_) => Ok(0),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM sessions WHERE expires_at <= $1")
+                    .bind(Utc::now().timestamp())
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    /// Number of sessions, when cheaply known.
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(sessions) => sessions.try_read().ok().map(|s| s.len()),
+            #[cfg(feature = "redis-store")]
+            Self::Redis(_) => None,
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+
+    /// The version of the database schema, for stores that have one.
+    #[allow(clippy::unused_async)]
+    pub async fn schema_version(&self) -> anyhow::Result<Option<SchemaVersion>> {
+        #[cfg(feature = "sql")]
+        if let Self::Sql(pool) = self {
+            return Ok(Some(db::schema_version(pool).await?));
+        }
+        Ok(None)
+    }
+}
+
+impl std::fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory(_) => f
+                .debug_struct("Memory")
+                .field("sessions", &self.len_hint())
+                .finish(),
+            #[cfg(feature = "redis-store")]
+            Self::Redis(_) => f.write_str("Redis"),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => f.write_str("Sql"),
+        }
+    }
+}
+
+/// When a session written at `now` for `ttl` expires, in Unix seconds.
+#[cfg(feature = "sql")]
+fn expires_at(now: i64, ttl: Duration) -> anyhow::Result<i64> {
+    Ok(now + i64::try_from(ttl.as_secs())?)
+}
+
+/// Sweeps expired sessions every [`SWEEP_INTERVAL`].
+pub fn spawn_sweeper(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.sessions.sweep().await {
+                Ok(0) => {}
+                Ok(swept) => tracing::debug!("Swept {swept} expired sessions"),
+                Err(e) => tracing::error!("Failed to sweep expired sessions: {e:#}"),
+            }
+        }
+    });
+}
+
+/// The set of every session ID in Redis. Sessions expire on their own, so it can hold IDs of
+/// sessions that are gone, which are dropped from it whenever they're come across.
+#[cfg(feature = "redis-store")]
+const ALL_SESSIONS: &str = "sessions";
+/// Set once sessions from before [`ALL_SESSIONS`] have been added to it.
+#[cfg(feature = "redis-store")]
+const INDEXED: &str = "sessions:indexed";
+/// How many sessions are read from Redis per round trip.
+#[cfg(feature = "redis-store")]
+const BATCH: usize = 500;
+
+/// Writes the session `ARGV[1]` to `KEYS[1]` for `ARGV[2]` seconds, if `ARGV[3]` (`NX` or `XX`)
+/// lets it, and adds its ID `ARGV[4]` to [`ALL_SESSIONS`] in `KEYS[2]` and to the sessions of its
+/// user in `KEYS[3]`. The latter lives as long as the user's last session. Returns whether the
+/// session was written.
+#[cfg(feature = "redis-store")]
+static WRITE_SESSION: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if not redis.call('SET', KEYS[1], ARGV[1], ARGV[3], 'EX', ARGV[2]) then
+            return 0
+        end
+        redis.call('SADD', KEYS[2], ARGV[4])
+        redis.call('SADD', KEYS[3], ARGV[4])
+        if redis.call('TTL', KEYS[3]) < tonumber(ARGV[2]) then
+            redis.call('EXPIRE', KEYS[3], ARGV[2])
+        end
+        return 1
+        ",
+    )
+});
+
+#[cfg(feature = "redis-store")]
+async fn write(
+    conn: &mut redis::aio::ConnectionManager,
+    id: SessionHash,
+    data: &SessionData,
+    ttl: Duration,
+    condition: &str,
+) -> anyhow::Result<bool> {
+    let id = id.to_hex();
+    Ok(WRITE_SESSION
+        .key(key(&id))
+        .key(ALL_SESSIONS)
+        .key(user_key(&data.user_id))
+        .arg(serde_json::to_string(data)?)
+        .arg(ttl.as_secs().max(1))
+        .arg(condition)
+        .arg(&id)
+        .invoke_async(conn)
+        .await?)
+}
+
+/// The ID and data of every live session in Redis.
+#[cfg(feature = "redis-store")]
+async fn live(conn: &mut redis::aio::ConnectionManager) -> anyhow::Result<Vec<(String, String)>> {
+    let ids: Vec<String> = redis::AsyncCommands::smembers(&mut *conn, ALL_SESSIONS).await?;
+    let mut sessions = Vec::with_capacity(ids.len());
+    let mut expired = Vec::new();
+    for batch in ids.chunks(BATCH) {
+        // One round trip per batch instead of one per session.
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(batch.iter().map(|id| key(id)).collect::<Vec<_>>())
+            .query_async(&mut *conn)
+            .await?;
+        for (id, value) in batch.iter().zip(values) {
+            match value {
+                Some(value) => sessions.push((id.clone(), value)),
+                None => expired.push(id.clone()),
+            }
+        }
+    }
+    if !expired.is_empty() {
+        redis::AsyncCommands::srem::<_, _, ()>(&mut *conn, ALL_SESSIONS, expired).await?;
+    }
+    Ok(sessions)
+}
+
+/// Adds sessions written before they were indexed to [`ALL_SESSIONS`] and their users' sets, by
+/// scanning for them once.
+#[cfg(feature = "redis-store")]
+async fn index_existing(conn: &mut redis::aio::ConnectionManager) -> anyhow::Result<()> {
+    if redis::AsyncCommands::exists::<_, bool>(&mut *conn, INDEXED).await? {
+        return Ok(());
+    }
+    let mut keys = Vec::new();
+    {
+        let mut iter =
+            redis::AsyncCommands::scan_match::<_, String>(&mut *conn, "session:*").await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+    let mut indexed = 0;
+    for batch in keys.chunks(BATCH) {
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(batch.to_vec())
+            .query_async(&mut *conn)
+            .await?;
+        let mut index = redis::pipe();
+        let mut found = 0;
+        for (key, value) in batch.iter().zip(values) {
+            // Sessions can expire between the scan and the read.
+            let (Some(id), Some(value)) = (key.strip_prefix("session:"), value) else {
+                continue;
+            };
+            let data: SessionData = serde_json::from_str(&value)?;
+            index
+                .sadd(ALL_SESSIONS, id)
+                .ignore()
+                .sadd(user_key(&data.user_id), id)
+                .ignore();
+            found += 1;
+        }
+        if found > 0 {
+            index.query_async::<_, ()>(&mut *conn).await?;
+            indexed += found;
+        }
+    }
+    redis::AsyncCommands::set::<_, _, ()>(&mut *conn, INDEXED, 1).await?;
+    if indexed > 0 {
+        tracing::info!("Indexed {indexed} sessions from before they were indexed");
+    }
+    Ok(())
+}
+
+/// The key of the session with the hex [`SessionHash`] `id`.
+#[cfg(feature = "redis-store")]
+fn key(id: &str) -> String {
+    format!("session:{id}")
+}
+
+/// The set of IDs of the sessions of `user_id`.
+#[cfg(feature = "redis-store")]
+fn user_key(user_id: &str) -> String {
+    format!("user_sessions:{user_id}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::SessionId;
+
+    fn data() -> SessionData {
+        SessionData {
+            user_id: "ann".to_owned(),
+            token: SpotifyToken {
+                access_token: String::new(),
+                refresh_token: String::new(),
+                expires_in: 3600,
+                token_type: "Bearer".to_owned(),
+                scope: String::new(),
+            },
+            token_expires_at: Utc::now(),
+            created_at: Utc::now(),
+            last_seen: Utc::now(),
+            premium: None,
+            do_not_track: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_sweeps_expired_sessions() {
+        let store = SessionStore::Memory(RwLock::default());
+        let expired = SessionId::generate().hash();
+        assert!(store
+            .insert_new(expired, data(), Duration::ZERO)
+            .await
+            .unwrap());
+        assert!(!store.contains(&expired).await.unwrap());
+
+        let live = SessionId::generate().hash();
+        assert!(store
+            .insert_new(live, data(), Duration::from_mins(1))
+            .await
+            .unwrap());
+        // Logging in doesn't sweep; the sweeper does.
+        assert_eq!(store.len_hint(), Some(2));
+        assert_eq!(store.sweep().await.unwrap(), 1);
+        assert_eq!(store.len_hint(), Some(1));
+        assert!(store.contains(&live).await.unwrap());
+        // Taken IDs aren't handed out twice, but expired ones can be.
+        assert!(!store
+            .insert_new(live, data(), Duration::from_mins(1))
+            .await
+            .unwrap());
+        assert!(store
+            .insert_new(expired, data(), Duration::from_mins(1))
+            .await
+            .unwrap());
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn sweeps_expired_sessions_in_the_database() {
+        let path =
+            std::env::temp_dir().join(format!("blid-sessions-{}.db", crate::token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        let store = SessionStore::Sql(pool);
+        let expired = SessionId::generate().hash();
+        assert!(store
+            .insert_new(expired, data(), Duration::ZERO)
+            .await
+            .unwrap());
+        let live = SessionId::generate().hash();
+        assert!(store
+            .insert_new(live, data(), Duration::from_mins(1))
+            .await
+            .unwrap());
+        assert!(!store
+            .insert_new(live, data(), Duration::from_mins(1))
+            .await
+            .unwrap());
+        assert_eq!(store.sweep().await.unwrap(), 1);
+        assert_eq!(store.all().await.unwrap().len(), 1);
+        assert_eq!(store.remove_user("ann").await.unwrap(), 1);
+        assert!(!store.contains(&live).await.unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,264 @@
+//! Read-only snapshots of playlists, shared by link with people who may not have Spotify at all.
+//! A share freezes the playlist as it was when shared, so later edits don't show up in it.
+
+use askama_axum::Template;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    art,
+    playlist_cache::PlaylistCache,
+    spotify::{self, Image},
+    templates::{self, Format, Layout, Page},
+    token, AppError, AppStateInner,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedTrack {
+    pub name: String,
+    pub artists: String,
+    pub album: String,
+    /// Empty when there is none.
+    pub artwork: String,
+    /// As `m:ss`.
+    pub duration: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedPlaylist {
+    pub id: String,
+    pub playlist_id: String,
+    pub snapshot_id: String,
+    pub name: String,
+    /// Empty when there is none.
+    pub artwork: String,
+    pub shared_at: DateTime<Utc>,
+    pub tracks: Vec<SharedTrack>,
+    #[serde(skip)]
+    pub owner: String,
+}
+
+/// In memory by default, or in the database with a SQL feature and `DATABASE_URL` set.
+pub enum ShareStore {
+    Memory(RwLock<HashMap<String, SharedPlaylist>>),
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for ShareStore {
+    fn default() -> Self {
+        Self::Memory(RwLock::default())
+    }
+}
+
+impl ShareStore {
+    /// Freezes the current contents of a playlist into a new share.
+    pub async fn create(
+        &self,
+        access_token: &str,
+        cache: &PlaylistCache,
+        owner: String,
+        playlist_id: &str,
+    ) -> anyhow::Result<SharedPlaylist> {
+        let playlist = spotify::playlist(access_token, playlist_id).await?;
+        let (snapshot_id, items) = cache.items(access_token, playlist_id).await?;
+        let tracks = items
+            .into_iter()
+            .filter_map(|item| item.track)
+            .map(|track| SharedTrack {
+                artists: track.artists.iter().map(|a| &a.name).join(", "),
+                artwork: smallest(&track.album.images),
+                album: track.album.name,
+                duration: duration(track.duration_ms),
+                name: track.name,
+            })
+            .collect();
+        let share = SharedPlaylist {
+            id: token::generate(12),
+            playlist_id: playlist.id,
+            snapshot_id,
+            name: playlist.name,
+            artwork: playlist
+                .images
+                .first()
+                .map(|image| image.url.clone())
+                .unwrap_or_default(),
+            shared_at: Utc::now(),
+            tracks,
+            owner,
+        };
+        self.insert(&share).await?;
+        Ok(share)
+    }
+
+    /// Stores a share made earlier.
+    pub async fn insert(&self, share: &SharedPlaylist) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(shares) => {
+                shares.write().await.insert(share.id.clone(), share.clone());
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                sqlx::query("INSERT INTO shares (id, owner, data) VALUES ($1, $2, $3)")
+                    .bind(&share.id)
+                    .bind(&share.owner)
+                    .bind(serde_json::to_string(share)?)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<SharedPlaylist>> {
+        match self {
+            Self::Memory(shares) => Ok(shares.read().await.get(id).cloned()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let row: Option<(String, String)> =
+                    sqlx::query_as("SELECT owner, data FROM shares WHERE id = $1")
+                        .bind(id)
+                        .fetch_optional(pool)
+                        .await?;
+                row.map(|(owner, data)| from_row(owner, &data)).transpose()
+            }
+        }
+    }
+
+    pub async fn owned_by(&self, owner: &str) -> anyhow::Result<Vec<SharedPlaylist>> {
+        match self {
+            Self::Memory(shares) => Ok(shares
+                .read()
+                .await
+                .values()
+                .filter(|share| share.owner == owner)
+                .cloned()
+                .collect()),
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let rows: Vec<String> =
+                    sqlx::query_scalar("SELECT data FROM shares WHERE owner = $1")
+                        .bind(owner)
+                        .fetch_all(pool)
+                        .await?;
+                rows.iter()
+                    .map(|data| from_row(owner.to_owned(), data))
+                    .collect()
+            }
+        }
+    }
+
+    /// Removes every share of `owner`, returning how many there were.
+    pub async fn remove_owned_by(&self, owner: &str) -> anyhow::Result<usize> {
+        match self {
+            Self::Memory(shares) => {
+                let mut shares = shares.write().await;
+                let before = shares.len();
+                shares.retain(|_, share| share.owner != owner);
+                Ok(before - shares.len())
+            }
+            #[cfg(feature = "sql")]
+            Self::Sql(pool) => {
+                let deleted = sqlx::query("DELETE FROM shares WHERE owner = $1")
+                    .bind(owner)
+                    .execute(pool)
+                    .await?;
+                Ok(deleted.rows_affected().try_into()?)
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match self {
+            Self::Memory(shares) => shares.try_read().ok().map(|s| s.len()),
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => None,
+        }
+    }
+}
+
+/// `owner` isn't serialized with the rest of the share, so it has a column of its own.
+#[cfg(feature = "sql")]
+fn from_row(owner: String, data: &str) -> anyhow::Result<SharedPlaylist> {
+    Ok(SharedPlaylist {
+        owner,
+        ..serde_json::from_str::<SharedPlaylist>(data)?
+    })
+}
+
+/// Thumbnails are shown small, so the narrowest image will do.
+fn smallest(images: &[Image]) -> String {
+    images
+        .last()
+        .map(|image| image.url.clone())
+        .unwrap_or_default()
+}
+
+fn duration(ms: u64) -> String {
+    let seconds = ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new().route("/p/:share_id", get(shared_playlist))
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "shared_playlist.html")]
+struct SharedPlaylistTemplate {
+    playlist: SharedPlaylist,
+    /// `shared_at` as a date, formatted here so both template engines agree.
+    shared_on: String,
+    layout: Layout,
+}
+
+impl Page for SharedPlaylistTemplate {
+    const PATH: &'static str = "shared_playlist.html";
+}
+
+/// Public: anyone with the link can see the share.
+async fn shared_playlist(
+    State(s): State<Arc<AppStateInner>>,
+    Path(share_id): Path<String>,
+    layout: Layout,
+    format: Format,
+) -> Response {
+    let playlist = match s.shares.get(&share_id).await {
+        Ok(Some(playlist)) => playlist,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return AppError(e).into_response(),
+    };
+    let shared_on = playlist.shared_at.format("%Y-%m-%d").to_string();
+    let playlist = SharedPlaylist {
+        artwork: art::local_url("", &playlist.artwork),
+        tracks: playlist
+            .tracks
+            .into_iter()
+            .map(|track| SharedTrack {
+                artwork: art::local_url("", &track.artwork),
+                ..track
+            })
+            .collect(),
+        ..playlist
+    };
+    templates::respond(
+        format,
+        SharedPlaylistTemplate {
+            playlist,
+            shared_on,
+            layout,
+        },
+    )
+}
@@ -0,0 +1,168 @@
+//! Unguessable slugs addressing what users make public, like their feed or widget. Each user has
+//! at most one slug per kind, which stays the same until they disable it. Slugs live in memory by
+//! default, or in the database with a SQL feature and `DATABASE_URL` set, so every instance
+//! serves the same ones.
+
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::token;
+
+pub struct SlugStore {
+    stored: Stored,
+}
+
+enum Stored {
+    /// Slug to user ID.
+    Memory(RwLock<HashMap<String, String>>),
+    /// In `table`, whose columns are `slug` and `user_id`.
+    #[cfg(feature = "sql")]
+    Sql { pool: db::Pool, table: &'static str },
+}
+
+impl Default for SlugStore {
+    fn default() -> Self {
+        Self {
+            stored: Stored::Memory(RwLock::default()),
+        }
+    }
+}
+
+impl SlugStore {
+    /// Keeps slugs in `table` of the database instead of in memory.
+    #[cfg(feature = "sql")]
+    pub const fn in_database(pool: db::Pool, table: &'static str) -> Self {
+        Self {
+            stored: Stored::Sql { pool, table },
+        }
+    }
+
+    /// The slug of `user_id`, minting one if they have none.
+    pub async fn enable(&self, user_id: &str) -> anyhow::Result<String> {
+        match &self.stored {
+            Stored::Memory(slugs) => {
+                let mut slugs = slugs.write().await;
+                if let Some(slug) = slug_of(&slugs, user_id) {
+                    return Ok(slug);
+                }
+                let slug = token::generate(12);
+                slugs.insert(slug.clone(), user_id.to_owned());
+                Ok(slug)
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql { pool, table } => {
+                // Another instance may enable it at the same time, and the slug it picked wins.
+                sqlx::query(&format!(
+                    "INSERT INTO {table} (slug, user_id) VALUES ($1, $2) \
+                     ON CONFLICT (user_id) DO NOTHING"
+                ))
+                .bind(token::generate(12))
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+                self.slug(user_id)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("slug of {user_id} was disabled meanwhile"))
+            }
+        }
+    }
+
+    /// Forgets the slug of `user_id`, returning it if they had one.
+    pub async fn disable(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        match &self.stored {
+            Stored::Memory(slugs) => {
+                let mut slugs = slugs.write().await;
+                let slug = slug_of(&slugs, user_id);
+                if let Some(slug) = &slug {
+                    slugs.remove(slug);
+                }
+                Ok(slug)
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql { pool, table } => Ok(sqlx::query_scalar(&format!(
+                "DELETE FROM {table} WHERE user_id = $1 RETURNING slug"
+            ))
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?),
+        }
+    }
+
+    pub async fn slug(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        match &self.stored {
+            Stored::Memory(slugs) => Ok(slug_of(&*slugs.read().await, user_id)),
+            #[cfg(feature = "sql")]
+            Stored::Sql { pool, table } => Ok(sqlx::query_scalar(&format!(
+                "SELECT slug FROM {table} WHERE user_id = $1"
+            ))
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?),
+        }
+    }
+
+    /// The user with `slug`, if anyone has it.
+    pub async fn owner(&self, slug: &str) -> anyhow::Result<Option<String>> {
+        match &self.stored {
+            Stored::Memory(slugs) => Ok(slugs.read().await.get(slug).cloned()),
+            #[cfg(feature = "sql")]
+            Stored::Sql { pool, table } => Ok(sqlx::query_scalar(&format!(
+                "SELECT user_id FROM {table} WHERE slug = $1"
+            ))
+            .bind(slug)
+            .fetch_optional(pool)
+            .await?),
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.stored {
+            Stored::Memory(slugs) => slugs.try_read().ok().map(|s| s.len()),
+            #[cfg(feature = "sql")]
+            Stored::Sql { .. } => None,
+        }
+    }
+}
+
+fn slug_of(slugs: &HashMap<String, String>, user_id: &str) -> Option<String> {
+    slugs
+        .iter()
+        .find_map(|(slug, owner)| (owner == user_id).then(|| slug.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn keeps_one_slug_per_user(slugs: &SlugStore) {
+        let slug = slugs.enable("ann").await.unwrap();
+        assert_eq!(slugs.enable("ann").await.unwrap(), slug);
+        assert_ne!(slugs.enable("bob").await.unwrap(), slug);
+        assert_eq!(slugs.owner(&slug).await.unwrap().as_deref(), Some("ann"));
+        assert_eq!(slugs.disable("ann").await.unwrap(), Some(slug.clone()));
+        assert_eq!(slugs.disable("ann").await.unwrap(), None);
+        assert_eq!(slugs.owner(&slug).await.unwrap(), None);
+        assert_ne!(slugs.enable("ann").await.unwrap(), slug);
+    }
+
+    #[tokio::test]
+    async fn keeps_one_slug_per_user_in_memory() {
+        keeps_one_slug_per_user(&SlugStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn keeps_one_slug_per_user_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-slugs-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        for table in ["feeds", "widgets"] {
+            keeps_one_slug_per_user(&SlugStore::in_database(pool.clone(), table)).await;
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,1349 @@
+//! Calls to the Spotify Web API and accounts service. Every request goes through [`send`], which
+//! records the endpoint, status and latency both in a tracing span (nested in the request span of
+//! the handler making the call) and in [`crate::metrics`].
+//!
+//! Whole libraries are fetched a page or chunk of IDs at a time, so the pages of offset-paged
+//! endpoints and the chunks of batch endpoints are requested a few at a time, each retried on
+//! rate limiting and server errors. How many at a time is set by `SPOTIFY_CONCURRENCY`.
+//!
+//! Requests made while handling an `/api` request time out when its budget runs out (see
+//! [`crate::deadline`]), and aren't retried past it.
+
+use anyhow::{bail, Context};
+use base64::prelude::*;
+use dotenv_codegen::dotenv;
+use futures::{
+    future::{BoxFuture, WeakShared},
+    stream, Future, FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use percent_encoding::{utf8_percent_encode, AsciiSet, PercentEncode, NON_ALPHANUMERIC};
+use reqwest::{RequestBuilder, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{field, Instrument};
+
+use crate::{deadline, fixtures, metrics, mock_spotify, redact::Redacted, token};
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+const API: &str = "https://api.spotify.com/v1";
+
+/// What's left as is of the IDs put in the paths of endpoints: the unreserved characters.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// `id` as one segment of an endpoint's path, so that whatever it holds, it can't turn the
+/// request into one to another endpoint.
+fn segment(id: &str) -> PercentEncode<'_> {
+    utf8_percent_encode(id, SEGMENT)
+}
+
+/// Requests made at once by each fetch of many pages or chunks, set at startup and on reloads.
+static CONCURRENCY: AtomicUsize = AtomicUsize::new(4);
+/// Tries of each of those requests before giving up.
+const ATTEMPTS: u32 = 3;
+
+/// Sets how many requests fetches of many pages or chunks make at once.
+pub fn set_concurrency(concurrency: usize) {
+    CONCURRENCY.store(concurrency.max(1), Ordering::Relaxed);
+}
+
+fn concurrency() -> usize {
+    CONCURRENCY.load(Ordering::Relaxed)
+}
+
+/// The app's client ID and secret, when given at runtime rather than compiled in.
+static CREDENTIALS: OnceCell<(String, String)> = OnceCell::new();
+
+/// Uses `client_id` and `client_secret` instead of the ones compiled in.
+pub fn set_credentials(client_id: String, client_secret: String) {
+    let _ = CREDENTIALS.set((client_id, client_secret));
+}
+
+pub fn client_id() -> &'static str {
+    CREDENTIALS
+        .get()
+        .map_or(dotenv!("CLIENT_ID"), |(id, _)| id.as_str())
+}
+
+fn client_secret() -> &'static str {
+    CREDENTIALS
+        .get()
+        .map_or(dotenv!("CLIENT_SECRET"), |(_, secret)| secret.as_str())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpotifyToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: u64,
+    pub token_type: String,
+    /// Space-separated scopes the user granted. Empty for tokens stored before it was kept.
+    #[serde(default)]
+    pub scope: String,
+}
+
+impl SpotifyToken {
+    /// Scopes of `wanted` the token wasn't granted. Tokens whose scopes are unknown are assumed to
+    /// have them all.
+    pub fn missing_scopes(&self, wanted: &[&'static str]) -> Vec<&'static str> {
+        if self.scope.is_empty() {
+            return Vec::new();
+        }
+        let granted: Vec<&str> = self.scope.split_whitespace().collect();
+        wanted
+            .iter()
+            .filter(|scope| !granted.contains(scope))
+            .copied()
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for SpotifyToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpotifyToken")
+            .field("access_token", &Redacted(&self.access_token))
+            .field("refresh_token", &Redacted(&self.refresh_token))
+            .field("expires_in", &self.expires_in)
+            .field("token_type", &self.token_type)
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+/// What Spotify returns when refreshing a token. A new refresh token is only sometimes included.
+#[derive(Deserialize)]
+struct RefreshedToken {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: u64,
+    token_type: String,
+    scope: Option<String>,
+}
+
+/// A response read in full, so it can be handed to every request it answers.
+#[derive(Clone)]
+struct Buffered {
+    status: reqwest::StatusCode,
+    headers: reqwest::header::HeaderMap,
+    body: axum::body::Bytes,
+}
+
+impl From<Buffered> for Response {
+    fn from(buffered: Buffered) -> Self {
+        let mut response = axum::http::Response::new(buffered.body);
+        *response.status_mut() = buffered.status;
+        *response.headers_mut() = buffered.headers;
+        response.into()
+    }
+}
+
+/// A failure to reach Spotify shared by the requests a coalesced call answered. Its source is
+/// the original error, so [`retryable`] sees through it.
+#[derive(Debug, Clone)]
+struct Coalesced(Arc<reqwest::Error>);
+
+impl std::fmt::Display for Coalesced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shared request failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for Coalesced {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+type InFlight = WeakShared<BoxFuture<'static, Result<Buffered, Coalesced>>>;
+
+/// `GET`s waiting on Spotify, by method, URL and token. Identical ones made while one is in flight,
+/// like the same page requested by several tabs or the now-playing pollers of one user, wait for
+/// its response instead of making their own call. Calls are held weakly, with an ID telling them
+/// apart from later ones under the same key, so one nobody waits on any more is dropped.
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, (u64, InFlight)>>> = Lazy::new(Mutex::default);
+static NEXT_CALL: AtomicU64 = AtomicU64::new(0);
+/// Timeout of a call shared by several requests, each of which waits on it for as long as its own
+/// budget allows.
+const SHARED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Removes a call from [`IN_FLIGHT`] when it finishes or is dropped.
+struct Landed {
+    key: String,
+    id: u64,
+}
+
+impl Drop for Landed {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap_or_else(PoisonError::into_inner);
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|(id, _)| *id == self.id)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Hands `request` to the mock or fixtures if enabled, or sends it to Spotify.
+async fn transport(endpoint: &'static str, request: RequestBuilder) -> reqwest::Result<Response> {
+    let start = Instant::now();
+    let result = if mock_spotify::enabled() {
+        mock_spotify::respond(request)
+    } else {
+        fixtures::send(endpoint, request).await
+    };
+    let status = result.as_ref().map_or(0, |r| r.status().as_u16());
+    metrics::observe_upstream(endpoint, status, start.elapsed());
+    result
+}
+
+/// Sends `request`, a `GET`, unless an identical one is in flight, and waits for the response
+/// either way.
+async fn coalesced(endpoint: &'static str, request: RequestBuilder) -> anyhow::Result<Response> {
+    let (client, built) = request.build_split();
+    let mut built = built?;
+    *built.timeout_mut() = Some(SHARED_TIMEOUT);
+    let authorization = built
+        .headers()
+        .get(reqwest::header::AUTHORIZATION)
+        .map(reqwest::header::HeaderValue::as_bytes)
+        .unwrap_or_default();
+    // The token is hashed into the key so it's not kept around in the clear.
+    let key = format!(
+        "{} {} {}",
+        built.method(),
+        built.url(),
+        token::hex(&Sha256::digest(authorization))
+    );
+    let call = {
+        let mut in_flight = IN_FLIGHT.lock().expect("in-flight requests poisoned");
+        if let Some(call) = in_flight.get(&key).and_then(|(_, call)| call.upgrade()) {
+            tracing::debug!("Joining a request to {endpoint} in flight");
+            call
+        } else {
+            let request = RequestBuilder::from_parts(client, built);
+            let id = NEXT_CALL.fetch_add(1, Ordering::Relaxed);
+            let landed = Landed {
+                key: key.clone(),
+                id,
+            };
+            let call = async move {
+                let _landed = landed;
+                let response = transport(endpoint, request).await?;
+                Ok(Buffered {
+                    status: response.status(),
+                    headers: response.headers().clone(),
+                    body: response.bytes().await?,
+                })
+            }
+            .map(|result: reqwest::Result<_>| result.map_err(|e| Coalesced(Arc::new(e))))
+            .boxed()
+            .shared();
+            let weak = call
+                .downgrade()
+                .expect("a call that hasn't run has a future");
+            in_flight.insert(key, (id, weak));
+            call
+        }
+    };
+    // The call may be someone else's, with their budget, so this one's is kept to separately.
+    let buffered = match deadline::remaining() {
+        Some(left) => tokio::time::timeout(left, call)
+            .await
+            .map_err(|_| deadline::Exceeded)?,
+        None => call.await,
+    }?;
+    Ok(buffered.into())
+}
+
+/// Sends `request` and fails on non-success statuses. `endpoint` is a low-cardinality name for
+/// the route (no IDs in it), used as the metrics label. Identical `GET`s in flight at once share
+/// one call to Spotify.
+async fn send(endpoint: &'static str, request: RequestBuilder) -> anyhow::Result<Response> {
+    let span = tracing::info_span!(
+        "spotify",
+        endpoint,
+        status = field::Empty,
+        latency_ms = field::Empty
+    );
+    async {
+        let request = match deadline::remaining() {
+            Some(left) if left.is_zero() => return Err(deadline::Exceeded.into()),
+            Some(left) => request.timeout(left),
+            None => request,
+        };
+        let is_get = request
+            .try_clone()
+            .and_then(|request| request.build().ok())
+            .is_some_and(|request| request.method() == reqwest::Method::GET);
+        let start = Instant::now();
+        let result = if is_get {
+            coalesced(endpoint, request).await
+        } else {
+            transport(endpoint, request).await.map_err(Into::into)
+        };
+        let latency = start.elapsed();
+        let status = result.as_ref().map_or(0, |r| r.status().as_u16());
+
+        let span = tracing::Span::current();
+        span.record("status", status);
+        span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+        tracing::debug!("Spotify responded");
+
+        let timed_out = result.as_ref().is_err_and(|e| {
+            e.chain()
+                .filter_map(|e| e.downcast_ref::<reqwest::Error>())
+                .any(reqwest::Error::is_timeout)
+        });
+        if timed_out && deadline::remaining().is_some_and(|left| left.is_zero()) {
+            return Err(deadline::Exceeded.into());
+        }
+        let response = result.with_context(|| format!("request to {endpoint} failed"))?;
+        if response.status() == reqwest::StatusCode::FORBIDDEN {
+            let body = response.text().await.unwrap_or_default();
+            if body.contains("PREMIUM_REQUIRED") {
+                return Err(PremiumRequired.into());
+            }
+            bail!("{endpoint} responded 403 Forbidden: {body}");
+        }
+        Ok(response.error_for_status()?)
+    }
+    .instrument(span)
+    .await
+}
+
+fn token_request(form: &serde_json::Value) -> RequestBuilder {
+    CLIENT
+        .post("https://accounts.spotify.com/api/token")
+        .form(form)
+        .header(
+            "Authorization",
+            format!(
+                "Basic {}",
+                BASE64_STANDARD.encode(format!("{}:{}", client_id(), client_secret())),
+            ),
+        )
+}
+
+/// Trades the code Spotify sent back to `redirect_uri` for a token. The URI has to be the one the
+/// login started with.
+pub async fn exchange_code(code: &str, redirect_uri: &str) -> anyhow::Result<SpotifyToken> {
+    let request = token_request(&json!({
+        "code": code,
+        "redirect_uri": redirect_uri,
+        "grant_type": "authorization_code"
+    }));
+    Ok(send("token", request).await?.json().await?)
+}
+
+/// Trades the refresh token of `token` for a new access token.
+pub async fn refresh(token: &SpotifyToken) -> anyhow::Result<SpotifyToken> {
+    let request = token_request(&json!({
+        "refresh_token": token.refresh_token,
+        "grant_type": "refresh_token",
+    }));
+    let refreshed: RefreshedToken = send("token", request).await?.json().await?;
+    Ok(SpotifyToken {
+        access_token: refreshed.access_token,
+        refresh_token: refreshed
+            .refresh_token
+            .unwrap_or_else(|| token.refresh_token.clone()),
+        expires_in: refreshed.expires_in,
+        token_type: refreshed.token_type,
+        scope: refreshed.scope.unwrap_or_else(|| token.scope.clone()),
+    })
+}
+
+/// Asks Spotify for an app-only token, which only works with valid client credentials.
+pub async fn check_credentials() -> anyhow::Result<()> {
+    let request = token_request(&json!({ "grant_type": "client_credentials" }));
+    send("token", request).await?;
+    Ok(())
+}
+
+/// Spotify refused a player command because the user doesn't have Premium. Returned to the
+/// client as a `402`.
+#[derive(Debug)]
+pub struct PremiumRequired;
+
+impl std::fmt::Display for PremiumRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("controlling playback needs Spotify Premium")
+    }
+}
+
+impl std::error::Error for PremiumRequired {}
+
+/// One page of a Spotify paging object. Offset- and cursor-based pages both link to the next
+/// page through `next`, which is all we need to walk them.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Paging<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+    /// Items across all pages, for offset-based pages.
+    #[serde(default)]
+    pub total: Option<usize>,
+}
+
+/// Whether a failed request is worth trying again.
+fn retryable(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<reqwest::Error>())
+        .any(|e| {
+            e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|status| {
+                    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+                })
+        })
+}
+
+/// Runs `request` up to [`ATTEMPTS`] times, backing off between tries, for as long as it fails
+/// with a [`retryable`] error and the budget of the request being handled leaves time to wait.
+async fn with_retries<T, Fut>(mut request: impl FnMut() -> Fut) -> anyhow::Result<T>
+where
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut delay = Duration::from_millis(500);
+    for _ in 1..ATTEMPTS {
+        match request().await {
+            Err(e) if retryable(&e) => {
+                if deadline::remaining().is_some_and(|left| left <= delay) {
+                    return Err(e.context(deadline::Exceeded));
+                }
+                tracing::debug!("Retrying Spotify request in {delay:?}: {e:#}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    request().await
+}
+
+/// Fetches `ids` in chunks of at most `size`, [`concurrency`] chunks at a time, and concatenates
+/// the results in order. `fetch` gets the IDs of a chunk joined with commas, as batch endpoints
+/// take them.
+async fn chunked<T, Fut>(
+    ids: &[String],
+    size: usize,
+    fetch: impl Fn(String) -> Fut + Sync,
+) -> anyhow::Result<Vec<T>>
+where
+    T: Send,
+    Fut: Future<Output = anyhow::Result<Vec<T>>> + Send,
+{
+    let fetch = &fetch;
+    let batches: Vec<String> = ids.chunks(size).map(|chunk| chunk.join(",")).collect();
+    let chunks: Vec<Vec<T>> = stream::iter(batches)
+        .map(|ids| with_retries(move || fetch(ids.clone())))
+        .buffered(concurrency())
+        .try_collect()
+        .await?;
+    Ok(chunks.into_iter().flatten().collect())
+}
+
+/// Lazily walks every page starting at `url`, yielding items one by one. A page is only fetched
+/// once the items of the previous one have been consumed, so callers can stop early for free.
+pub fn paginate<T>(
+    endpoint: &'static str,
+    access_token: &str,
+    url: String,
+) -> impl Stream<Item = anyhow::Result<T>>
+where
+    T: DeserializeOwned,
+{
+    paginate_with::<T, Paging<T>>(endpoint, access_token, url)
+}
+
+/// Like [`paginate`], for endpoints that wrap their paging object (e.g. `{"artists": {...}}`).
+pub fn paginate_with<T, W>(
+    endpoint: &'static str,
+    access_token: &str,
+    url: String,
+) -> impl Stream<Item = anyhow::Result<T>>
+where
+    W: DeserializeOwned + Into<Paging<T>>,
+{
+    let access_token = access_token.to_owned();
+    stream::try_unfold(
+        (Some(url), VecDeque::new()),
+        move |(mut next, mut buffer)| {
+            let access_token = access_token.clone();
+            async move {
+                loop {
+                    if let Some(item) = buffer.pop_front() {
+                        return Ok(Some((item, (next, buffer))));
+                    }
+                    let Some(url) = next.take() else {
+                        return Ok(None);
+                    };
+                    let page: Paging<T> =
+                        send(endpoint, CLIENT.get(url).bearer_auth(&access_token))
+                            .await?
+                            .json::<W>()
+                            .await?
+                            .into();
+                    buffer.extend(page.items);
+                    next = page.next;
+                }
+            }
+        },
+    )
+}
+
+/// Like [`paginate`], for offset-paged endpoints: once the first page tells how many items there
+/// are, the other pages of `limit` items are fetched [`concurrency`] at a time. Items are still
+/// yielded in order.
+fn paginate_concurrently<T>(
+    endpoint: &'static str,
+    access_token: &str,
+    url: String,
+    limit: usize,
+) -> impl Stream<Item = anyhow::Result<T>>
+where
+    T: DeserializeOwned,
+{
+    let access_token = access_token.to_owned();
+    stream::once(async move {
+        let first: Paging<T> =
+            with_retries(|| page(endpoint, &access_token, &url, limit, 0)).await?;
+        let total = first.next.as_ref().and(first.total).unwrap_or(0);
+        let offsets = (limit..total).step_by(limit.max(1));
+        let rest =
+            stream::iter(offsets)
+                .map(move |offset| {
+                    let (access_token, url) = (access_token.clone(), url.clone());
+                    async move {
+                        with_retries(|| page(endpoint, &access_token, &url, limit, offset)).await
+                    }
+                })
+                .buffered(concurrency())
+                .map_ok(|page| stream::iter(page.items).map(anyhow::Ok))
+                .try_flatten();
+        anyhow::Ok(stream::iter(first.items).map(anyhow::Ok).chain(rest))
+    })
+    .try_flatten()
+}
+
+async fn page<T: DeserializeOwned>(
+    endpoint: &'static str,
+    access_token: &str,
+    url: &str,
+    limit: usize,
+    offset: usize,
+) -> anyhow::Result<Paging<T>> {
+    let request = CLIENT
+        .get(url)
+        .query(&[("limit", limit), ("offset", offset)])
+        .bearer_auth(access_token);
+    Ok(send(endpoint, request).await?.json().await?)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimpleArtist {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub genres: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Image {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Placeholder to show while the image loads, once we have one. See [`crate::art`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    /// Widest first.
+    #[serde(default)]
+    pub images: Vec<Image>,
+    /// `YYYY-MM-DD`, or just `YYYY-MM` or `YYYY` for some older releases.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_date: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Track {
+    pub id: String,
+    pub name: String,
+    pub uri: String,
+    pub duration_ms: u64,
+    // Podcast episodes in playlists come without these.
+    #[serde(default)]
+    pub artists: Vec<SimpleArtist>,
+    // Tracks listed as part of an album come without these.
+    #[serde(default)]
+    pub album: Album,
+    #[serde(default)]
+    pub popularity: Option<u32>,
+    /// Only present when requested without a market.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub available_markets: Option<Vec<String>>,
+    /// Only present when requested for a market.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_playable: Option<bool>,
+    /// Set when the track was relinked: what's returned is a different release of the requested
+    /// track (the one playable in the market), and this is the one that was asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_from: Option<LinkedTrack>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkedTrack {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedTrack {
+    pub added_at: String,
+    pub track: Track,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistTracksRef {
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub snapshot_id: String,
+    pub tracks: PlaylistTracksRef,
+    /// Widest first. `null` rather than empty for some playlists.
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub images: Vec<Image>,
+}
+
+fn null_as_empty<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// An entry of a playlist. `track` is `null` for tracks that are no longer available.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistItem {
+    pub added_at: Option<String>,
+    /// `null` for items added before Spotify started tracking it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_by: Option<UserRef>,
+    pub track: Option<Track>,
+}
+
+/// A user as referred to from other objects, without their profile.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserRef {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioFeatures {
+    pub id: String,
+    /// Beats per minute.
+    pub tempo: f32,
+    /// From 0.0 to 1.0.
+    pub energy: f32,
+    /// From 0.0 to 1.0.
+    pub danceability: f32,
+    /// From 0.0 to 1.0, how positive the track sounds.
+    pub valence: f32,
+    /// Average loudness in dB, typically between -60 and 0.
+    pub loudness: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: String,
+    pub display_name: Option<String>,
+    /// Only present with the `user-read-email` scope, and unverified.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// `premium`, `free` or `open`. Only present with the `user-read-private` scope.
+    #[serde(default)]
+    pub product: Option<String>,
+}
+
+impl User {
+    /// `None` when the tier isn't known.
+    pub fn premium(&self) -> Option<bool> {
+        self.product.as_deref().map(|product| product == "premium")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayHistory {
+    pub track: Track,
+    /// ISO 8601 timestamp, in UTC.
+    pub played_at: String,
+}
+
+/// An album along with (the first page of) its tracks.
+#[cfg(feature = "library")]
+#[derive(Deserialize, Debug, Clone)]
+pub struct FullAlbum {
+    pub tracks: Paging<Track>,
+}
+
+/// The parts of a track's audio analysis describing its structure. Times are in seconds.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AudioAnalysis {
+    pub track: AnalyzedTrack,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnalyzedTrack {
+    pub duration: f32,
+    pub start_of_fade_out: f32,
+}
+
+/// A part of a track with a roughly constant feel, such as a chorus or an outro.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Section {
+    pub start: f32,
+    pub duration: f32,
+    /// Average loudness in dB.
+    pub loudness: f32,
+    /// Beats per minute.
+    pub tempo: f32,
+}
+
+/// What plays now and next on the user's active device.
+#[cfg(feature = "player")]
+#[derive(Debug, Clone)]
+pub struct Queue {
+    pub currently_playing: Option<Track>,
+    /// Episodes are left out.
+    pub queue: Vec<Track>,
+}
+
+#[derive(Deserialize)]
+struct CurrentlyPlaying {
+    is_playing: bool,
+    /// `null` when an episode or an ad is playing.
+    item: Option<Track>,
+}
+
+/// A device Spotify can play on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Device {
+    /// `null` for some restricted devices.
+    pub id: Option<String>,
+    pub name: String,
+    pub is_active: bool,
+    /// `null` for devices whose volume can't be controlled.
+    pub volume_percent: Option<u32>,
+}
+
+/// What the user's active device is doing.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlaybackState {
+    pub device: Device,
+    pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    /// `null` when an episode or an ad is playing.
+    pub item: Option<Track>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotResponse {
+    snapshot_id: String,
+}
+
+#[derive(Deserialize)]
+struct FollowedArtists {
+    artists: Paging<Artist>,
+}
+
+impl From<FollowedArtists> for Paging<Artist> {
+    fn from(value: FollowedArtists) -> Self {
+        value.artists
+    }
+}
+
+pub fn playlists(access_token: &str) -> impl Stream<Item = anyhow::Result<Playlist>> {
+    paginate(
+        "me/playlists",
+        access_token,
+        format!("{API}/me/playlists?limit=50"),
+    )
+}
+
+pub fn saved_tracks(access_token: &str) -> impl Stream<Item = anyhow::Result<SavedTrack>> {
+    paginate_concurrently("me/tracks", access_token, format!("{API}/me/tracks"), 50)
+}
+
+/// One page of the user's playlists, starting at `offset`, for listing them a bit at a time.
+#[cfg(feature = "library")]
+pub async fn playlists_page(
+    access_token: &str,
+    offset: usize,
+    limit: usize,
+) -> anyhow::Result<Paging<Playlist>> {
+    let url = format!("{API}/me/playlists");
+    with_retries(|| page("me/playlists", access_token, &url, limit, offset)).await
+}
+
+/// Like [`playlists_page`], for saved tracks.
+#[cfg(feature = "library")]
+pub async fn saved_tracks_page(
+    access_token: &str,
+    offset: usize,
+    limit: usize,
+) -> anyhow::Result<Paging<SavedTrack>> {
+    let url = format!("{API}/me/tracks");
+    with_retries(|| page("me/tracks", access_token, &url, limit, offset)).await
+}
+
+pub fn followed_artists(access_token: &str) -> impl Stream<Item = anyhow::Result<Artist>> {
+    paginate_with::<Artist, FollowedArtists>(
+        "me/following",
+        access_token,
+        format!("{API}/me/following?type=artist&limit=50"),
+    )
+}
+
+pub async fn playlist_snapshot_id(access_token: &str, playlist_id: &str) -> anyhow::Result<String> {
+    let request = CLIENT
+        .get(format!("{API}/playlists/{}", segment(playlist_id)))
+        .query(&[("fields", "snapshot_id")])
+        .bearer_auth(access_token);
+    Ok(send("playlists/{id}", request)
+        .await?
+        .json::<SnapshotResponse>()
+        .await?
+        .snapshot_id)
+}
+
+pub async fn playlist(access_token: &str, playlist_id: &str) -> anyhow::Result<Playlist> {
+    let request = CLIENT
+        .get(format!("{API}/playlists/{}", segment(playlist_id)))
+        .query(&[("fields", "id,name,snapshot_id,tracks(total),images")])
+        .bearer_auth(access_token);
+    Ok(send("playlists/{id}", request).await?.json().await?)
+}
+
+pub fn playlist_items(
+    access_token: &str,
+    playlist_id: &str,
+) -> impl Stream<Item = anyhow::Result<PlaylistItem>> {
+    paginate_concurrently(
+        "playlists/{id}/tracks",
+        access_token,
+        format!("{API}/playlists/{}/tracks", segment(playlist_id)),
+        100,
+    )
+}
+
+/// Removes the occurrences of each URI at the given positions, positions being relative to
+/// `snapshot_id`. Returns the playlist's new snapshot ID.
+pub async fn remove_playlist_items(
+    access_token: &str,
+    playlist_id: &str,
+    occurrences: &[(String, Vec<usize>)],
+    snapshot_id: &str,
+) -> anyhow::Result<String> {
+    let mut new_snapshot = snapshot_id.to_owned();
+    // Spotify accepts at most 100 tracks per request. Every chunk is resolved against the
+    // original snapshot, so positions don't need adjusting between chunks.
+    for chunk in occurrences.chunks(100) {
+        let tracks: Vec<_> = chunk
+            .iter()
+            .map(|(uri, positions)| json!({ "uri": uri, "positions": positions }))
+            .collect();
+        let request = CLIENT
+            .delete(format!("{API}/playlists/{}/tracks", segment(playlist_id)))
+            .bearer_auth(access_token)
+            .json(&json!({ "tracks": tracks, "snapshot_id": snapshot_id }));
+        new_snapshot = send("playlists/{id}/tracks", request)
+            .await?
+            .json::<SnapshotResponse>()
+            .await?
+            .snapshot_id;
+    }
+    Ok(new_snapshot)
+}
+
+/// Moves `range_length` items starting at `range_start` to before `insert_before`, positions
+/// being relative to `snapshot_id`. Returns the playlist's new snapshot ID.
+pub async fn reorder_playlist_items(
+    access_token: &str,
+    playlist_id: &str,
+    range_start: usize,
+    insert_before: usize,
+    range_length: usize,
+    snapshot_id: &str,
+) -> anyhow::Result<String> {
+    let request = CLIENT
+        .put(format!("{API}/playlists/{}/tracks", segment(playlist_id)))
+        .bearer_auth(access_token)
+        .json(&json!({
+            "range_start": range_start,
+            "insert_before": insert_before,
+            "range_length": range_length,
+            "snapshot_id": snapshot_id,
+        }));
+    Ok(send("playlists/{id}/tracks", request)
+        .await?
+        .json::<SnapshotResponse>()
+        .await?
+        .snapshot_id)
+}
+
+/// Audio features of `track_ids`, fetched 100 at a time. Tracks Spotify has no analysis for are
+/// left out.
+pub async fn audio_features(
+    access_token: &str,
+    track_ids: &[String],
+) -> anyhow::Result<Vec<AudioFeatures>> {
+    #[derive(Deserialize)]
+    struct Response {
+        audio_features: Vec<Option<AudioFeatures>>,
+    }
+
+    chunked(track_ids, 100, |ids| async move {
+        let request = CLIENT
+            .get(format!("{API}/audio-features"))
+            .query(&[("ids", ids)])
+            .bearer_auth(access_token);
+        let response: Response = send("audio-features", request).await?.json().await?;
+        Ok(response.audio_features.into_iter().flatten().collect())
+    })
+    .await
+}
+
+/// Full artist objects of `artist_ids`, fetched 50 at a time.
+pub async fn artists(access_token: &str, artist_ids: &[String]) -> anyhow::Result<Vec<Artist>> {
+    #[derive(Deserialize)]
+    struct Response {
+        artists: Vec<Option<Artist>>,
+    }
+
+    chunked(artist_ids, 50, |ids| async move {
+        let request = CLIENT
+            .get(format!("{API}/artists"))
+            .query(&[("ids", ids)])
+            .bearer_auth(access_token);
+        let response: Response = send("artists", request).await?.json().await?;
+        Ok(response.artists.into_iter().flatten().collect())
+    })
+    .await
+}
+
+/// Replaces the whole content of a playlist with `uris`. Returns the new snapshot ID.
+pub async fn replace_playlist_items(
+    access_token: &str,
+    playlist_id: &str,
+    uris: &[String],
+) -> anyhow::Result<String> {
+    let url = format!("{API}/playlists/{}/tracks", segment(playlist_id));
+    // Replacing is limited to 100 items, anything beyond is appended afterwards.
+    let (first, rest) = uris.split_at(uris.len().min(100));
+    let request = CLIENT
+        .put(&url)
+        .bearer_auth(access_token)
+        .json(&json!({ "uris": first }));
+    let mut snapshot_id = send("playlists/{id}/tracks", request)
+        .await?
+        .json::<SnapshotResponse>()
+        .await?
+        .snapshot_id;
+    for chunk in rest.chunks(100) {
+        let request = CLIENT
+            .post(&url)
+            .bearer_auth(access_token)
+            .json(&json!({ "uris": chunk }));
+        snapshot_id = send("playlists/{id}/tracks", request)
+            .await?
+            .json::<SnapshotResponse>()
+            .await?
+            .snapshot_id;
+    }
+    Ok(snapshot_id)
+}
+
+/// Makes `jpeg` the cover of a playlist. Spotify takes it base64-encoded, in at most 256 KB, and
+/// applies it in the background.
+pub async fn upload_playlist_image(
+    access_token: &str,
+    playlist_id: &str,
+    jpeg: &[u8],
+) -> anyhow::Result<()> {
+    let request = CLIENT
+        .put(format!("{API}/playlists/{}/images", segment(playlist_id)))
+        .header(reqwest::header::CONTENT_TYPE, "image/jpeg")
+        .bearer_auth(access_token)
+        .body(BASE64_STANDARD.encode(jpeg));
+    send("playlists/{id}/images", request).await?;
+    Ok(())
+}
+
+/// The image at `https://i.scdn.co/image/<id>`. Images are public, so no token is needed.
+pub async fn image(id: &str) -> anyhow::Result<axum::body::Bytes> {
+    let request = CLIENT.get(format!("https://i.scdn.co/image/{id}"));
+    Ok(send("image", request).await?.bytes().await?)
+}
+
+#[cfg(feature = "library")]
+#[derive(Deserialize)]
+struct SearchResults {
+    tracks: Paging<Track>,
+}
+
+/// The first `limit` tracks matching `query`, in Spotify's search syntax.
+#[cfg(feature = "library")]
+pub async fn search_tracks(
+    access_token: &str,
+    query: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<Track>> {
+    let request = CLIENT
+        .get(format!("{API}/search"))
+        .query(&[("q", query), ("type", "track")])
+        .query(&[("limit", limit)])
+        .bearer_auth(access_token);
+    Ok(send("search", request)
+        .await?
+        .json::<SearchResults>()
+        .await?
+        .tracks
+        .items)
+}
+
+pub async fn current_user(access_token: &str) -> anyhow::Result<User> {
+    let request = CLIENT.get(format!("{API}/me")).bearer_auth(access_token);
+    Ok(send("me", request).await?.json().await?)
+}
+
+/// The (at most 50) most recently played tracks. Spotify keeps no history beyond that, which is
+/// why [`crate::history`] collects it.
+pub async fn recently_played(access_token: &str) -> anyhow::Result<Vec<PlayHistory>> {
+    let request = CLIENT
+        .get(format!("{API}/me/player/recently-played"))
+        .query(&[("limit", "50")])
+        .bearer_auth(access_token);
+    Ok(send("me/player/recently-played", request)
+        .await?
+        .json::<Paging<PlayHistory>>()
+        .await?
+        .items)
+}
+
+/// Creates a private playlist owned by `user_id`.
+pub async fn create_playlist(
+    access_token: &str,
+    user_id: &str,
+    name: &str,
+    description: &str,
+) -> anyhow::Result<Playlist> {
+    let request = CLIENT
+        .post(format!("{API}/users/{}/playlists", segment(user_id)))
+        .bearer_auth(access_token)
+        .json(&json!({ "name": name, "description": description, "public": false }));
+    Ok(send("users/{id}/playlists", request).await?.json().await?)
+}
+
+/// Albums and singles of an artist. Compilations and appearances on other artists' releases are
+/// left out.
+pub fn artist_albums(
+    access_token: &str,
+    artist_id: &str,
+) -> impl Stream<Item = anyhow::Result<Album>> {
+    paginate(
+        "artists/{id}/albums",
+        access_token,
+        format!(
+            "{API}/artists/{}/albums?include_groups=album,single&limit=50",
+            segment(artist_id)
+        ),
+    )
+}
+
+/// Every track of each of `album_ids`, following the track pages of long albums.
+#[cfg(feature = "library")]
+pub async fn album_tracks(access_token: &str, album_ids: &[String]) -> anyhow::Result<Vec<Track>> {
+    #[derive(Deserialize)]
+    struct Response {
+        albums: Vec<Option<FullAlbum>>,
+    }
+
+    chunked(album_ids, 20, |ids| async move {
+        let request = CLIENT
+            .get(format!("{API}/albums"))
+            .query(&[("ids", ids)])
+            .bearer_auth(access_token);
+        let response: Response = send("albums", request).await?.json().await?;
+        let mut tracks = Vec::new();
+        for album in response.albums.into_iter().flatten() {
+            tracks.extend(album.tracks.items);
+            if let Some(next) = album.tracks.next {
+                let rest: Vec<Track> = paginate("albums/{id}/tracks", access_token, next)
+                    .try_collect()
+                    .await?;
+                tracks.extend(rest);
+            }
+        }
+        Ok(tracks)
+    })
+    .await
+}
+
+/// Full track objects of `track_ids`, fetched 50 at a time.
+pub async fn tracks(access_token: &str, track_ids: &[String]) -> anyhow::Result<Vec<Track>> {
+    #[derive(Deserialize)]
+    struct Response {
+        tracks: Vec<Option<Track>>,
+    }
+
+    chunked(track_ids, 50, |ids| async move {
+        let request = CLIENT
+            .get(format!("{API}/tracks"))
+            .query(&[("ids", ids)])
+            .bearer_auth(access_token);
+        let response: Response = send("tracks", request).await?.json().await?;
+        Ok(response.tracks.into_iter().flatten().collect())
+    })
+    .await
+}
+
+/// A single track. With a `market`, Spotify relinks it to the release playable there, if any.
+#[cfg(feature = "library")]
+pub async fn track(
+    access_token: &str,
+    track_id: &str,
+    market: Option<&str>,
+) -> anyhow::Result<Track> {
+    let mut request = CLIENT
+        .get(format!("{API}/tracks/{}", segment(track_id)))
+        .bearer_auth(access_token);
+    if let Some(market) = market {
+        request = request.query(&[("market", market)]);
+    }
+    Ok(send("tracks/{id}", request).await?.json().await?)
+}
+
+/// The track playing right now. Paused playback counts as nothing playing.
+pub async fn currently_playing(access_token: &str) -> anyhow::Result<Option<Track>> {
+    let request = CLIENT
+        .get(format!("{API}/me/player/currently-playing"))
+        .bearer_auth(access_token);
+    let response = send("me/player/currently-playing", request).await?;
+    if response.status() == reqwest::StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    let playing: CurrentlyPlaying = response.json().await?;
+    Ok(playing.item.filter(|_| playing.is_playing))
+}
+
+/// Playback on the user's active device, or `None` when no device is active.
+pub async fn playback_state(access_token: &str) -> anyhow::Result<Option<PlaybackState>> {
+    let request = CLIENT
+        .get(format!("{API}/me/player"))
+        .bearer_auth(access_token);
+    let response = send("me/player", request).await?;
+    if response.status() == reqwest::StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    Ok(Some(response.json().await?))
+}
+
+/// Pauses playback on the active device.
+#[cfg(feature = "player")]
+pub async fn pause(access_token: &str) -> anyhow::Result<()> {
+    let request = CLIENT
+        .put(format!("{API}/me/player/pause"))
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/pause", request).await?;
+    Ok(())
+}
+
+/// Resumes playback on the active device.
+#[cfg(feature = "player")]
+pub async fn resume(access_token: &str) -> anyhow::Result<()> {
+    let request = CLIENT
+        .put(format!("{API}/me/player/play"))
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/play", request).await?;
+    Ok(())
+}
+
+/// Seeks to `position_ms` into the track playing on the active device.
+#[cfg(feature = "player")]
+pub async fn seek(access_token: &str, position_ms: u64) -> anyhow::Result<()> {
+    let request = CLIENT
+        .put(format!("{API}/me/player/seek"))
+        .query(&[("position_ms", position_ms)])
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/seek", request).await?;
+    Ok(())
+}
+
+/// The queue of the active device. Spotify returns up to 20 upcoming items.
+#[cfg(feature = "player")]
+pub async fn queue(access_token: &str) -> anyhow::Result<Queue> {
+    #[derive(Deserialize)]
+    struct Response {
+        currently_playing: Option<serde_json::Value>,
+        queue: Vec<serde_json::Value>,
+    }
+
+    let request = CLIENT
+        .get(format!("{API}/me/player/queue"))
+        .bearer_auth(access_token);
+    let response: Response = send("me/player/queue", request).await?.json().await?;
+    // Items are tracks or episodes, told apart by their `type`; only tracks have analyses.
+    let as_track = |item: serde_json::Value| {
+        (item["type"] == "track")
+            .then(|| serde_json::from_value(item))
+            .transpose()
+    };
+    Ok(Queue {
+        currently_playing: response
+            .currently_playing
+            .map(as_track)
+            .transpose()?
+            .flatten(),
+        queue: response
+            .queue
+            .into_iter()
+            .filter_map(|item| as_track(item).transpose())
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// The audio analysis of a track, or `None` if Spotify has none for it.
+#[cfg(feature = "player")]
+pub async fn audio_analysis(
+    access_token: &str,
+    track_id: &str,
+) -> anyhow::Result<Option<AudioAnalysis>> {
+    let request = CLIENT
+        .get(format!("{API}/audio-analysis/{}", segment(track_id)))
+        .bearer_auth(access_token);
+    match send("audio-analysis/{id}", request).await {
+        Ok(response) => Ok(Some(response.json().await?)),
+        Err(e)
+            if e.downcast_ref::<reqwest::Error>()
+                .and_then(reqwest::Error::status)
+                == Some(reqwest::StatusCode::NOT_FOUND) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Sets the volume of the active device, from 0 to 100.
+pub async fn set_volume(access_token: &str, volume_percent: u32) -> anyhow::Result<()> {
+    let request = CLIENT
+        .put(format!("{API}/me/player/volume"))
+        .query(&[("volume_percent", volume_percent)])
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/volume", request).await?;
+    Ok(())
+}
+
+/// Skips to the next track on the active device.
+#[cfg(feature = "player")]
+pub async fn skip_to_next(access_token: &str) -> anyhow::Result<()> {
+    let request = CLIENT
+        .post(format!("{API}/me/player/next"))
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/next", request).await?;
+    Ok(())
+}
+
+/// Skips to the previous track on the active device.
+#[cfg(feature = "player")]
+pub async fn skip_to_previous(access_token: &str) -> anyhow::Result<()> {
+    let request = CLIENT
+        .post(format!("{API}/me/player/previous"))
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/previous", request).await?;
+    Ok(())
+}
+
+/// Adds a track to the end of the queue of the active device.
+#[cfg(feature = "player")]
+pub async fn add_to_queue(access_token: &str, track_id: &str) -> anyhow::Result<()> {
+    let request = CLIENT
+        .post(format!("{API}/me/player/queue"))
+        .query(&[("uri", format!("spotify:track:{track_id}"))])
+        .header(reqwest::header::CONTENT_LENGTH, 0)
+        .bearer_auth(access_token);
+    send("me/player/queue", request).await?;
+    Ok(())
+}
+
+/// Saves a track to the user's library, or removes it from there.
+#[cfg(feature = "library")]
+pub async fn set_saved(access_token: &str, track_id: &str, saved: bool) -> anyhow::Result<()> {
+    let url = format!("{API}/me/tracks");
+    let request = if saved {
+        CLIENT.put(url)
+    } else {
+        CLIENT.delete(url)
+    }
+    .query(&[("ids", track_id)])
+    .header(reqwest::header::CONTENT_LENGTH, 0)
+    .bearer_auth(access_token);
+    send("me/tracks", request).await?;
+    Ok(())
+}
+
+/// Follows an artist, or stops following them.
+#[cfg(feature = "library")]
+pub async fn set_following(
+    access_token: &str,
+    artist_id: &str,
+    following: bool,
+) -> anyhow::Result<()> {
+    let url = format!("{API}/me/following");
+    let request = if following {
+        CLIENT.put(url)
+    } else {
+        CLIENT.delete(url)
+    }
+    .query(&[("type", "artist"), ("ids", artist_id)])
+    .header(reqwest::header::CONTENT_LENGTH, 0)
+    .bearer_auth(access_token);
+    send("me/following", request).await?;
+    Ok(())
+}
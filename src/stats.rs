@@ -0,0 +1,655 @@
+//! Stats computed from the collected listening history. Days are counted in UTC, which is all
+//! history knows about when plays happened.
+//!
+//! Milestones are worked out from history every time rather than stored, so they also cover
+//! imported plays. The collector announces those its new plays reach, see [`newly_reached`].
+
+#[cfg(feature = "stats")]
+use askama_axum::Template;
+#[cfg(feature = "stats")]
+use chrono::Datelike;
+use chrono::{DateTime, NaiveDate, Utc};
+#[cfg(feature = "stats")]
+use itertools::Itertools;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    digest,
+    spotify::Track,
+    webhooks::{self, Event, EventKind},
+    AppStateInner,
+};
+#[cfg(feature = "stats")]
+use crate::{
+    library::GenreCache,
+    spotify::SimpleArtist,
+    templates::{Layout, Page},
+};
+
+/// Entries in each top list of a year in review.
+#[cfg(feature = "stats")]
+const TOP: usize = 10;
+/// Artists whose genres make up the top genres. Looking up every artist of a year would take
+/// many Spotify calls for the long tail, which barely moves the ranking.
+#[cfg(feature = "stats")]
+const GENRE_ARTISTS: usize = 50;
+/// Play counts that are milestones, overall and per artist.
+const PLAY_MILESTONES: &[usize] = &[100, 500, 1000, 5000, 10_000, 50_000];
+/// Streak lengths that are milestones, in days.
+const STREAK_MILESTONES: &[u32] = &[7, 30, 100, 365];
+
+/// Consecutive days with at least one play.
+#[cfg(feature = "stats")]
+#[derive(Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Streak {
+    pub days: u32,
+    /// `YYYY-MM-DD`, both empty when `days` is 0.
+    pub from: String,
+    pub to: String,
+}
+
+#[cfg(feature = "stats")]
+impl Streak {
+    fn new(from: NaiveDate, to: NaiveDate) -> Self {
+        Self {
+            days: u32::try_from((to - from).num_days() + 1).unwrap_or(u32::MAX),
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+}
+
+/// Every streak in `days`, which has to be sorted, oldest first.
+#[cfg(feature = "stats")]
+pub fn streaks(days: impl IntoIterator<Item = NaiveDate>) -> Vec<Streak> {
+    let mut streaks = Vec::new();
+    let mut current: Option<(NaiveDate, NaiveDate)> = None;
+    for day in days {
+        current = match current {
+            Some((from, to)) if day <= to => Some((from, to)),
+            Some((from, to)) if to.succ_opt() == Some(day) => Some((from, day)),
+            Some((from, to)) => {
+                streaks.push(Streak::new(from, to));
+                Some((day, day))
+            }
+            None => Some((day, day)),
+        };
+    }
+    streaks.extend(current.map(|(from, to)| Streak::new(from, to)));
+    streaks
+}
+
+/// The longest of `streaks`, the earliest of equally long ones. Zero days when there's none.
+#[cfg(feature = "stats")]
+pub fn longest(streaks: &[Streak]) -> Streak {
+    streaks
+        .iter()
+        .rev()
+        .max_by_key(|streak| streak.days)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The streak still going, which is the one that includes today or yesterday: today's plays may
+/// just not have happened yet. Zero days when there's none.
+#[cfg(feature = "stats")]
+pub fn current_streak(streaks: &[Streak], today: NaiveDate) -> Streak {
+    let alive = [today, today.pred_opt().unwrap_or(today)].map(|day| day.to_string());
+    streaks
+        .last()
+        .filter(|streak| alive.contains(&streak.to))
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MilestoneKind {
+    /// The `plays`th play overall.
+    Plays { plays: usize },
+    /// The `plays`th play of an artist.
+    ArtistPlays {
+        artist_id: String,
+        artist: String,
+        plays: usize,
+    },
+    /// The first streak of `days` days.
+    Streak { days: u32 },
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Milestone {
+    #[serde(flatten)]
+    pub kind: MilestoneKind,
+    /// The play that reached it.
+    pub reached_at: DateTime<Utc>,
+}
+
+impl std::fmt::Display for Milestone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            MilestoneKind::Plays { plays } => write!(f, "your {plays}th play"),
+            MilestoneKind::ArtistPlays { artist, plays, .. } => {
+                write!(f, "your {plays}th play of {artist}")
+            }
+            MilestoneKind::Streak { days } => write!(f, "{days} days of listening in a row"),
+        }
+    }
+}
+
+/// Every milestone reached in `plays`, which are oldest first, in the order they were reached.
+pub fn milestones(plays: &[(DateTime<Utc>, Track)]) -> Vec<Milestone> {
+    let mut reached = Vec::new();
+    let mut by_artist: HashMap<&str, usize> = HashMap::new();
+    // The last day played, and the length of the streak up to it.
+    let mut streak: Option<(NaiveDate, u32)> = None;
+    let mut longest = 0;
+    for (i, (played_at, track)) in plays.iter().enumerate() {
+        let mut reach = |kind| {
+            reached.push(Milestone {
+                kind,
+                reached_at: *played_at,
+            });
+        };
+        if PLAY_MILESTONES.contains(&(i + 1)) {
+            reach(MilestoneKind::Plays { plays: i + 1 });
+        }
+        for artist in &track.artists {
+            let plays = by_artist.entry(&artist.id).or_default();
+            *plays += 1;
+            if PLAY_MILESTONES.contains(plays) {
+                reach(MilestoneKind::ArtistPlays {
+                    artist_id: artist.id.clone(),
+                    artist: artist.name.clone(),
+                    plays: *plays,
+                });
+            }
+        }
+        let day = played_at.date_naive();
+        let days = match streak {
+            Some((last, days)) if last == day => days,
+            Some((last, days)) if last.succ_opt() == Some(day) => days + 1,
+            _ => 1,
+        };
+        streak = Some((day, days));
+        if days > longest {
+            longest = days;
+            if STREAK_MILESTONES.contains(&days) {
+                reach(MilestoneKind::Streak { days });
+            }
+        }
+    }
+    reached
+}
+
+/// The milestones in `after` that aren't in `before`, the milestones of the same history before
+/// and after plays were added.
+pub fn newly_reached(before: &[Milestone], after: Vec<Milestone>) -> Vec<Milestone> {
+    let before: HashSet<&MilestoneKind> = before.iter().map(|milestone| &milestone.kind).collect();
+    after
+        .into_iter()
+        .filter(|milestone| !before.contains(&milestone.kind))
+        .collect()
+}
+
+/// The milestones of the user's history, when anyone is told about them: a webhook of theirs
+/// wants them, or they get them by email. `None` otherwise, or when history fails to load.
+pub async fn announced(state: &AppStateInner, user_id: &str) -> Option<Vec<Milestone>> {
+    let hooked = match state.webhooks.owned_by(user_id).await {
+        Ok(webhooks) => webhooks
+            .iter()
+            .any(|webhook| webhook.events.contains(&EventKind::Milestone)),
+        Err(e) => {
+            tracing::warn!("Failed to list webhooks: {e:#}");
+            false
+        }
+    };
+    let emailed = match state.notifications.get(user_id).await {
+        Ok(prefs) => prefs.is_some_and(|prefs| prefs.milestones),
+        Err(e) => {
+            tracing::warn!("Failed to read notification settings: {e:#}");
+            false
+        }
+    };
+    if !hooked && !emailed {
+        return None;
+    }
+    match state.history.plays(user_id).await {
+        Ok(plays) => Some(milestones(&plays)),
+        Err(e) => {
+            tracing::warn!("Failed to load history for milestones: {e:#}");
+            None
+        }
+    }
+}
+
+/// Tells the user about `reached` through their webhooks and by email.
+pub async fn announce(state: &Arc<AppStateInner>, user_id: &str, reached: &[Milestone]) {
+    for milestone in reached {
+        let event = Event::Milestone {
+            milestone: milestone.clone(),
+        };
+        webhooks::dispatch(state, user_id, &event).await;
+    }
+    if let Err(e) = digest::send_milestone_alert(state, user_id, reached).await {
+        tracing::warn!("Failed to email milestones: {e:#}");
+    }
+}
+
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+pub struct TopArtist {
+    pub id: String,
+    pub name: String,
+    pub plays: usize,
+    pub minutes: u64,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+pub struct TopTrack {
+    pub id: String,
+    pub uri: String,
+    pub name: String,
+    pub artists: String,
+    pub plays: usize,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+pub struct TopGenre {
+    pub genre: String,
+    /// Plays of the artists tagged with it.
+    pub plays: usize,
+}
+
+/// A user's year of listening, as JSON or as a page.
+#[cfg(feature = "stats")]
+#[derive(Template, Serialize)]
+#[template(path = "wrapped.html")]
+pub struct Wrapped {
+    pub year: i32,
+    pub plays: usize,
+    /// Listening time, counting each play as the whole track.
+    pub minutes: u64,
+    pub tracks: usize,
+    pub artists: usize,
+    pub days_listened: usize,
+    pub longest_streak: Streak,
+    /// Most played first.
+    pub top_artists: Vec<TopArtist>,
+    pub top_tracks: Vec<TopTrack>,
+    pub top_genres: Vec<TopGenre>,
+    /// Filled in by the page, empty in the API.
+    pub layout: Layout,
+}
+
+#[cfg(feature = "stats")]
+impl Page for Wrapped {
+    const PATH: &'static str = "wrapped.html";
+}
+
+/// The year in review of `year` out of `plays`, every play of a user. Genres are looked up with
+/// `access_token`.
+#[cfg(feature = "stats")]
+pub async fn wrapped(
+    genres: &GenreCache,
+    access_token: &str,
+    year: i32,
+    plays: Vec<(DateTime<Utc>, Track)>,
+) -> anyhow::Result<Wrapped> {
+    let plays: Vec<(DateTime<Utc>, Track)> = plays
+        .into_iter()
+        .filter(|(played_at, _)| played_at.year() == year)
+        .collect();
+
+    let artists = top_artists(&plays);
+    let artist_count = artists.len();
+    let top_genres = top_genres(genres, access_token, &artists)
+        .await?
+        .into_iter()
+        .take(TOP)
+        .collect();
+    let top_tracks = top_tracks(&plays);
+    let track_count = top_tracks.len();
+
+    let days: Vec<NaiveDate> = plays
+        .iter()
+        .map(|(played_at, _)| played_at.date_naive())
+        .dedup()
+        .collect();
+    let longest_streak = longest(&streaks(days.iter().copied()));
+
+    Ok(Wrapped {
+        year,
+        plays: plays.len(),
+        minutes: plays
+            .iter()
+            .map(|(_, track)| track.duration_ms)
+            .sum::<u64>()
+            / 60_000,
+        tracks: track_count,
+        artists: artist_count,
+        days_listened: days.len(),
+        longest_streak,
+        top_artists: artists.into_iter().take(TOP).collect(),
+        top_tracks: top_tracks.into_iter().take(TOP).collect(),
+        top_genres,
+        layout: Layout::default(),
+    })
+}
+
+/// Every artist in `plays`, most played first.
+#[cfg(feature = "stats")]
+fn top_artists(plays: &[(DateTime<Utc>, Track)]) -> Vec<TopArtist> {
+    // Plays and milliseconds listened of each artist.
+    let mut artists: HashMap<&str, (&SimpleArtist, usize, u64)> = HashMap::new();
+    for (_, track) in plays {
+        for artist in &track.artists {
+            let entry = artists.entry(&artist.id).or_insert((artist, 0, 0));
+            entry.1 += 1;
+            entry.2 += track.duration_ms;
+        }
+    }
+    artists
+        .into_values()
+        .map(|(artist, plays, ms)| TopArtist {
+            id: artist.id.clone(),
+            name: artist.name.clone(),
+            plays,
+            minutes: ms / 60_000,
+        })
+        .sorted_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.name.cmp(&b.name)))
+        .collect()
+}
+
+/// Every track in `plays`, most played first.
+#[cfg(feature = "stats")]
+fn top_tracks(plays: &[(DateTime<Utc>, Track)]) -> Vec<TopTrack> {
+    plays
+        .iter()
+        .map(|(_, track)| track)
+        .into_group_map_by(|track| track.id.as_str())
+        .into_values()
+        .map(|played| TopTrack {
+            id: played[0].id.clone(),
+            uri: played[0].uri.clone(),
+            name: played[0].name.clone(),
+            artists: played[0].artists.iter().map(|a| &a.name).join(", "),
+            plays: played.len(),
+        })
+        .sorted_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.name.cmp(&b.name)))
+        .collect()
+}
+
+/// The genres of the first [`GENRE_ARTISTS`] of `artists`, by the plays of the artists tagged
+/// with each, most played first.
+#[cfg(feature = "stats")]
+async fn top_genres(
+    genres: &GenreCache,
+    access_token: &str,
+    artists: &[TopArtist],
+) -> anyhow::Result<Vec<TopGenre>> {
+    let artists = &artists[..artists.len().min(GENRE_ARTISTS)];
+    let tagged = genres
+        .resolve(
+            access_token,
+            artists.iter().map(|artist| artist.id.as_str()),
+        )
+        .await?;
+    Ok(artists
+        .iter()
+        .flat_map(|artist| {
+            tagged
+                .get(&artist.id)
+                .into_iter()
+                .flatten()
+                .map(|genre| (genre, artist.plays))
+        })
+        .into_grouping_map()
+        .sum()
+        .into_iter()
+        .map(|(genre, plays)| TopGenre {
+            genre: genre.clone(),
+            plays,
+        })
+        .sorted_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.genre.cmp(&b.genre)))
+        .collect())
+}
+
+/// Something both users of a [`Comparison`] played, with how often each did.
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+pub struct Shared {
+    pub id: String,
+    pub name: String,
+    /// Plays by the first user, then by the second.
+    pub plays: [usize; 2],
+}
+
+/// How the listening of two users overlaps, out of their whole histories.
+#[cfg(feature = "stats")]
+#[derive(Serialize)]
+pub struct Comparison {
+    /// Share of the artists either played that both did, from 0 to 1.
+    pub artist_overlap: f64,
+    /// Likewise for tracks.
+    pub track_overlap: f64,
+    /// Most played by both first, by the fewer plays of the two.
+    pub shared_artists: Vec<Shared>,
+    pub shared_tracks: Vec<Shared>,
+    /// Genres among the top ones of both, most played by both first.
+    pub shared_genres: Vec<String>,
+}
+
+/// Compares the histories of two users, `a` and `b`. Genres are looked up with `access_token`.
+#[cfg(feature = "stats")]
+pub async fn compare(
+    genres: &GenreCache,
+    access_token: &str,
+    a: &[(DateTime<Utc>, Track)],
+    b: &[(DateTime<Utc>, Track)],
+) -> anyhow::Result<Comparison> {
+    let (artists_a, artists_b) = (top_artists(a), top_artists(b));
+    let (tracks_a, tracks_b) = (top_tracks(a), top_tracks(b));
+    let (genres_a, genres_b) = (
+        top_genres(genres, access_token, &artists_a).await?,
+        top_genres(genres, access_token, &artists_b).await?,
+    );
+
+    let (artist_overlap, shared_artists) = shared(
+        artists_a.iter().map(|x| (&x.id, &x.name, x.plays)),
+        artists_b.iter().map(|x| (&x.id, &x.name, x.plays)),
+    );
+    let (track_overlap, shared_tracks) = shared(
+        tracks_a.iter().map(|x| (&x.id, &x.name, x.plays)),
+        tracks_b.iter().map(|x| (&x.id, &x.name, x.plays)),
+    );
+    let (_, shared_genres) = shared(
+        genres_a
+            .iter()
+            .take(TOP)
+            .map(|x| (&x.genre, &x.genre, x.plays)),
+        genres_b
+            .iter()
+            .take(TOP)
+            .map(|x| (&x.genre, &x.genre, x.plays)),
+    );
+    Ok(Comparison {
+        artist_overlap,
+        track_overlap,
+        shared_artists: shared_artists.into_iter().take(TOP).collect(),
+        shared_tracks: shared_tracks.into_iter().take(TOP).collect(),
+        shared_genres: shared_genres.into_iter().map(|genre| genre.name).collect(),
+    })
+}
+
+/// The overlap of `a` and `b`, ID, name and plays of things each user played, as the share of
+/// them both played, and those both played, most played by both first.
+#[cfg(feature = "stats")]
+#[allow(clippy::cast_precision_loss)]
+fn shared<'a>(
+    a: impl Iterator<Item = (&'a String, &'a String, usize)>,
+    b: impl Iterator<Item = (&'a String, &'a String, usize)>,
+) -> (f64, Vec<Shared>) {
+    let b: HashMap<&String, usize> = b.map(|(id, _, plays)| (id, plays)).collect();
+    let mut either = b.len();
+    let mut both = Vec::new();
+    for (id, name, plays) in a {
+        match b.get(id) {
+            Some(&theirs) => both.push(Shared {
+                id: id.clone(),
+                name: name.clone(),
+                plays: [plays, theirs],
+            }),
+            None => either += 1,
+        }
+    }
+    both.sort_by(|x, y| {
+        let fewer = |shared: &Shared| shared.plays[0].min(shared.plays[1]);
+        fewer(y).cmp(&fewer(x)).then_with(|| x.name.cmp(&y.name))
+    });
+    let overlap = if either == 0 {
+        0.0
+    } else {
+        both.len() as f64 / either as f64
+    };
+    (overlap, both)
+}
+
+/// URIs of a playlist mixing the listening of two users, `a` and `b`: the tracks both played
+/// most first, then the top tracks of each in turn, `len` at most.
+#[cfg(feature = "stats")]
+pub fn blend(
+    a: &[(DateTime<Utc>, Track)],
+    b: &[(DateTime<Utc>, Track)],
+    len: usize,
+) -> Vec<String> {
+    let (tracks_a, tracks_b) = (top_tracks(a), top_tracks(b));
+    let (_, both) = shared(
+        tracks_a.iter().map(|x| (&x.uri, &x.name, x.plays)),
+        tracks_b.iter().map(|x| (&x.uri, &x.name, x.plays)),
+    );
+    let mut seen = HashSet::new();
+    both.into_iter()
+        .map(|shared| shared.id)
+        .chain(
+            tracks_a
+                .into_iter()
+                .map(|track| track.uri)
+                .interleave(tracks_b.into_iter().map(|track| track.uri)),
+        )
+        .filter(|uri| seen.insert(uri.clone()))
+        .take(len)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: &str, artist: &str) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "uri": format!("spotify:track:{id}"),
+            "duration_ms": 60_000,
+            "artists": [{ "id": artist, "name": artist }],
+        }))
+        .unwrap()
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .and_then(|date| date.and_hms_opt(hour, 0, 0))
+            .unwrap()
+            .and_utc()
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn finds_the_longest_and_current_streaks() {
+        let days =
+            [1, 2, 2, 3, 5, 6, 9, 10, 11].map(|day| NaiveDate::from_ymd_opt(2024, 1, day).unwrap());
+        let streaks = streaks(days);
+        let lengths: Vec<u32> = streaks.iter().map(|streak| streak.days).collect();
+        assert_eq!(lengths, [3, 2, 3]);
+        // The earliest of equally long streaks.
+        assert_eq!(longest(&streaks).from, "2024-01-01");
+        assert!(longest(&[]) == Streak::default());
+
+        let day = |day| NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        assert_eq!(current_streak(&streaks, day(11)).from, "2024-01-09");
+        assert_eq!(current_streak(&streaks, day(12)).days, 3);
+        assert_eq!(current_streak(&streaks, day(13)).days, 0);
+    }
+
+    #[test]
+    fn reaches_each_milestone_once() {
+        let mut plays: Vec<_> = (0..99)
+            .map(|i| (at(1, 0), track(&i.to_string(), "a")))
+            .collect();
+        // A day off, then six days in a row.
+        for day in 3..=8 {
+            plays.push((at(day, 12), track("t", "b")));
+        }
+        let before = milestones(&plays);
+        assert!(matches!(
+            before.as_slice(),
+            [Milestone {
+                kind: MilestoneKind::Plays { plays: 100 },
+                ..
+            }]
+        ));
+        assert_eq!(before[0].reached_at, at(3, 12));
+
+        plays.push((at(9, 12), track("t", "b")));
+        // Listening twice on the same day doesn't lengthen the streak.
+        plays.push((at(9, 13), track("t", "b")));
+        let after = milestones(&plays);
+        let reached = newly_reached(&before, after);
+        assert_eq!(reached.len(), 1);
+        assert_eq!(reached[0].kind, MilestoneKind::Streak { days: 7 });
+        assert_eq!(reached[0].reached_at, at(9, 12));
+        assert_eq!(reached[0].to_string(), "7 days of listening in a row");
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn blends_shared_tracks_first() {
+        let ann = [
+            (at(1, 0), track("shared", "a")),
+            (at(1, 1), track("shared", "a")),
+            (at(1, 2), track("ann", "a")),
+            (at(1, 3), track("ann", "a")),
+            (at(1, 4), track("ann", "a")),
+        ];
+        let bob = [
+            (at(1, 0), track("shared", "a")),
+            (at(1, 1), track("bob", "b")),
+        ];
+        assert_eq!(
+            blend(&ann, &bob, 10),
+            [
+                "spotify:track:shared",
+                "spotify:track:ann",
+                "spotify:track:bob"
+            ]
+        );
+        assert_eq!(blend(&ann, &bob, 1), ["spotify:track:shared"]);
+
+        let artists = top_artists(&ann);
+        assert_eq!(artists.len(), 1);
+        assert_eq!((artists[0].plays, artists[0].minutes), (5, 5));
+        let (overlap, both) = shared(
+            top_artists(&ann).iter().map(|x| (&x.id, &x.name, x.plays)),
+            top_artists(&bob).iter().map(|x| (&x.id, &x.name, x.plays)),
+        );
+        assert!((overlap - 0.5).abs() < f64::EPSILON);
+        assert_eq!(both[0].plays, [5, 1]);
+    }
+}
@@ -0,0 +1,210 @@
+//! Rendering of the HTML templates, by one of two [`Engine`]s picked at build time. Builds use
+//! the askama templates compiled into the binary by default. With the `runtime-templates`
+//! feature, the same files are instead read from `TEMPLATE_DIR` and rendered with minijinja on
+//! every request, so a deployment can change its markup, or a developer edit it, without a
+//! recompile. Without `TEMPLATE_DIR`, they're read from `templates/` in the working directory,
+//! which is the source tree's under `cargo run`, as the `dev-templates` feature is meant for.
+
+use askama_axum::Template;
+use axum::{
+    extract::FromRequestParts,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{cookie_manager::Flash, session::Session, AppStateInner};
+
+/// A template that can be rendered either way. `PATH` must match the askama `path` attribute.
+/// Pages extending the layout also have a `layout` field, the [`Layout`] of the request.
+pub trait Page: Template + Serialize {
+    const PATH: &'static str;
+}
+
+/// What the layout shows around every page: the instance theme, whether someone is logged in for
+/// the nav bar, and the flash message left by the last request. Taken by page handlers as an
+/// extractor. Children mark the section of the nav bar they belong to by filling its block,
+/// `player_current`, `library_current`, `stats_current` or `settings_current`, with
+/// `aria-current="page"`.
+#[derive(Serialize, Default, Debug)]
+pub struct Layout {
+    /// The stylesheet of the instance theme from [`crate::themes::ThemeStore::stylesheet`],
+    /// empty for none.
+    pub theme: String,
+    /// The Spotify user logged in, empty when logged out.
+    pub user_id: String,
+    /// See [`crate::cookie_manager`], empty for none.
+    pub flash: String,
+}
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppStateInner>> for Layout {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &Arc<AppStateInner>,
+    ) -> Result<Self, Self::Rejection> {
+        let user_id = Session::from_request_parts(parts, state)
+            .await
+            .map(|session| session.user_id)
+            .unwrap_or_default();
+        Ok(Self {
+            theme: state.themes.stylesheet(),
+            user_id,
+            flash: parts
+                .extensions
+                .get::<Flash>()
+                .map(|Flash(message)| message.clone())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Turns pages into HTML.
+pub trait Engine: Send + Sync {
+    fn render<P: Page>(&self, page: &P) -> anyhow::Result<String>;
+}
+
+/// The templates compiled into the binary.
+#[cfg(not(feature = "runtime-templates"))]
+pub struct Askama;
+
+#[cfg(not(feature = "runtime-templates"))]
+impl Engine for Askama {
+    fn render<P: Page>(&self, page: &P) -> anyhow::Result<String> {
+        Ok(page.render()?)
+    }
+}
+
+/// The templates in a directory, read again for every render so edits show up immediately.
+#[cfg(feature = "runtime-templates")]
+pub struct Minijinja {
+    pub dir: std::path::PathBuf,
+}
+
+#[cfg(feature = "runtime-templates")]
+impl Engine for Minijinja {
+    fn render<P: Page>(&self, page: &P) -> anyhow::Result<String> {
+        let mut env = minijinja::Environment::new();
+        env.set_loader(minijinja::path_loader(&self.dir));
+        Ok(env.get_template(P::PATH)?.render(page)?)
+    }
+}
+
+#[cfg(feature = "runtime-templates")]
+const DEFAULT_DIR: &str = "templates";
+
+#[cfg(feature = "runtime-templates")]
+static ENGINE: once_cell::sync::OnceCell<Minijinja> = once_cell::sync::OnceCell::new();
+
+/// Where runtime templates are read from, `TEMPLATE_DIR` or `templates/`.
+#[cfg(feature = "runtime-templates")]
+pub fn init(dir: Option<std::path::PathBuf>) {
+    let dir = dir.unwrap_or_else(|| DEFAULT_DIR.into());
+    tracing::info!("Rendering templates from {}", dir.display());
+    let _ = ENGINE.set(Minijinja { dir });
+}
+
+#[cfg(not(feature = "runtime-templates"))]
+#[allow(clippy::needless_pass_by_value)]
+pub fn init(dir: Option<std::path::PathBuf>) {
+    if dir.is_some() {
+        tracing::warn!("TEMPLATE_DIR is set, but this build only has its compiled-in templates");
+    }
+}
+
+#[cfg(feature = "runtime-templates")]
+fn engine() -> &'static impl Engine {
+    ENGINE.get_or_init(|| Minijinja {
+        dir: DEFAULT_DIR.into(),
+    })
+}
+
+#[cfg(not(feature = "runtime-templates"))]
+const fn engine() -> &'static impl Engine {
+    &Askama
+}
+
+/// Takes the page by value, which handlers build just for this.
+#[allow(clippy::needless_pass_by_value)]
+pub fn render<P: Page>(page: P) -> Response {
+    match engine().render(&page) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to render {}: {e:#}", P::PATH);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")).into_response()
+        }
+    }
+}
+
+/// The representation a client asked for through its `Accept` header. Browsers get the HTML
+/// page, API clients get the same data as JSON, without a second route tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    /// Picks whichever of `text/html` and `application/json` has the higher `q` value, with ties
+    /// and absent headers going to HTML.
+    pub fn from_accept(accept: &str) -> Self {
+        let quality = |wanted: &str| {
+            accept
+                .split(',')
+                .filter_map(|range| {
+                    let mut params = range.split(';').map(str::trim);
+                    let media = params.next()?;
+                    let matches = media == wanted
+                        || media == "*/*"
+                        || media
+                            .strip_suffix("/*")
+                            .is_some_and(|ty| wanted.starts_with(ty));
+                    if !matches {
+                        return None;
+                    }
+                    let q = params
+                        .find_map(|p| p.strip_prefix("q="))
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .unwrap_or(1.0);
+                    // An exact match outranks a wildcard of the same quality.
+                    Some((q, media == wanted))
+                })
+                .fold(
+                    (0.0, false),
+                    |best, cur| if cur > best { cur } else { best },
+                )
+        };
+        if quality("application/json") > quality("text/html") {
+            Self::Json
+        } else {
+            Self::Html
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .headers
+            .get(axum::http::header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map_or(Self::Html, Self::from_accept))
+    }
+}
+
+/// Renders `page` as HTML or JSON depending on `format`.
+pub fn respond<P: Page>(format: Format, page: P) -> Response {
+    match format {
+        Format::Html => render(page),
+        Format::Json => axum::Json(page).into_response(),
+    }
+}
@@ -0,0 +1,297 @@
+//! Instance themes: CSS bundles uploaded by admins under `/admin/themes` and kept in `THEME_DIR`,
+//! one per file. The one selected with `/admin/theme` is loaded by every page after the default
+//! styles, so it only has to override what it changes.
+//!
+//! Bundles are served from `/assets/themes/:name.css`. Pages link to the selected one with the
+//! hash of its content in the query, so browsers can cache it for good and still pick up a new
+//! upload on their next page.
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, sync::Arc, sync::RwLock};
+use tokio::sync::Mutex;
+
+use crate::{token, AppStateInner};
+
+/// Largest bundle accepted, in bytes.
+pub const MAX_SIZE: usize = 512 * 1024;
+/// Longest theme name accepted.
+const MAX_NAME_LEN: usize = 32;
+/// File in `THEME_DIR` holding the name of the selected theme.
+const SELECTED_FILE: &str = "selected";
+
+struct Selected {
+    name: String,
+    /// Where pages load it from, with its hash.
+    url: String,
+}
+
+/// Whether `name` can name a theme: lowercase letters, digits and dashes.
+pub fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Theme {
+    pub name: String,
+    /// Size of the bundle in bytes.
+    pub size: u64,
+    pub selected: bool,
+}
+
+pub struct ThemeStore {
+    dir: PathBuf,
+    /// Held while changing bundles or the selection, so a selection never points at a bundle
+    /// being replaced.
+    writes: Mutex<()>,
+    /// The selected theme, read by every page through [`Self::stylesheet`].
+    selected: RwLock<Option<Selected>>,
+}
+
+impl ThemeStore {
+    /// Uses `dir`, selecting the theme that was selected when the instance last ran.
+    pub fn load(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("couldn't create THEME_DIR {}", dir.display()))?;
+        let store = Self {
+            dir,
+            writes: Mutex::default(),
+            selected: RwLock::default(),
+        };
+        let name = match std::fs::read_to_string(store.dir.join(SELECTED_FILE)) {
+            Ok(name) if valid_name(name.trim()) => name.trim().to_owned(),
+            Ok(name) => anyhow::bail!("bad theme name {name:?} in {SELECTED_FILE}"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e.into()),
+        };
+        match std::fs::read(store.path(&name)) {
+            Ok(css) => {
+                tracing::info!("Using the {name} theme");
+                store.set_selected(Some(selection(name, &css)));
+            }
+            Err(e) => tracing::warn!("Not using the selected {name} theme: {e}"),
+        }
+        Ok(store)
+    }
+
+    /// The stylesheet of the selected theme, empty when there's none, for the layout of pages.
+    pub fn stylesheet(&self) -> String {
+        self.selected
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .as_ref()
+            .map(|selected| selected.url.clone())
+            .unwrap_or_default()
+    }
+
+    /// The name of the selected theme, if any.
+    pub fn selected(&self) -> Option<String> {
+        self.selected
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .as_ref()
+            .map(|selected| selected.name.clone())
+    }
+
+    fn set_selected(&self, selected: Option<Selected>) {
+        *self
+            .selected
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = selected;
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.css"))
+    }
+
+    /// The bundle of `name`, if there's one.
+    async fn read(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(name)).await {
+            Ok(css) => Ok(Some(css)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Every theme, by name.
+    pub async fn list(&self) -> anyhow::Result<Vec<Theme>> {
+        let current = self.selected();
+        let mut themes = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(name) = file_name
+                .to_str()
+                .and_then(|file_name| file_name.strip_suffix(".css"))
+                .filter(|name| valid_name(name))
+            else {
+                continue;
+            };
+            themes.push(Theme {
+                name: name.to_owned(),
+                size: entry.metadata().await?.len(),
+                selected: current.as_deref() == Some(name),
+            });
+        }
+        themes.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(themes)
+    }
+
+    /// Adds or replaces the bundle of `name`, which must be [valid](valid_name). Pages pick up
+    /// a replaced bundle of the selected theme straight away.
+    pub async fn save(&self, name: &str, css: &str) -> anyhow::Result<()> {
+        let _writes = self.writes.lock().await;
+        // Written aside and renamed, so the bundle is never served half-written.
+        let partial = self.dir.join(format!(".{name}.css.partial"));
+        tokio::fs::write(&partial, css).await?;
+        tokio::fs::rename(&partial, self.path(name)).await?;
+        if self.selected().as_deref() == Some(name) {
+            self.set_selected(Some(selection(name.to_owned(), css.as_bytes())));
+        }
+        Ok(())
+    }
+
+    /// Removes the bundle of `name`, going back to the default styles if it was selected, and
+    /// returning whether there was one.
+    pub async fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let _writes = self.writes.lock().await;
+        if self.selected().as_deref() == Some(name) {
+            self.write_selection(None).await?;
+        }
+        match tokio::fs::remove_file(self.path(name)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Selects `name` for every page, or goes back to the default styles with `None`, returning
+    /// whether there's such a theme.
+    pub async fn select(&self, name: Option<&str>) -> anyhow::Result<bool> {
+        let _writes = self.writes.lock().await;
+        let Some(name) = name else {
+            self.write_selection(None).await?;
+            return Ok(true);
+        };
+        if !valid_name(name) {
+            return Ok(false);
+        }
+        let Some(css) = self.read(name).await? else {
+            return Ok(false);
+        };
+        self.write_selection(Some(selection(name.to_owned(), &css)))
+            .await?;
+        Ok(true)
+    }
+
+    /// Remembers the selection for the next start, then applies it.
+    async fn write_selection(&self, selection: Option<Selected>) -> anyhow::Result<()> {
+        let path = self.dir.join(SELECTED_FILE);
+        match &selection {
+            Some(selection) => tokio::fs::write(path, &selection.name).await?,
+            None => match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        self.set_selected(selection);
+        Ok(())
+    }
+}
+
+fn selection(name: String, css: &[u8]) -> Selected {
+    let hash = token::hex(&Sha256::digest(css)[..8]);
+    Selected {
+        url: format!("/assets/themes/{name}.css?v={hash}"),
+        name,
+    }
+}
+
+/// Nested under `/assets`, with the other static files.
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new().route("/themes/:file", get(bundle))
+}
+
+#[derive(Deserialize)]
+struct BundleQuery {
+    /// The hash pages link with, which makes the response cacheable for good.
+    v: Option<String>,
+}
+
+async fn bundle(
+    State(s): State<Arc<AppStateInner>>,
+    Path(file): Path<String>,
+    Query(query): Query<BundleQuery>,
+) -> Response {
+    let Some(name) = file.strip_suffix(".css").filter(|name| valid_name(name)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let css = match s.themes.read(name).await {
+        Ok(Some(css)) => css,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read the {name} theme: {e:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let cache_control = if query.v.is_some() {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        css,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir() -> PathBuf {
+        std::env::temp_dir().join(format!("blid-themes-{}", token::generate(8)))
+    }
+
+    #[tokio::test]
+    async fn selects_themes_per_store() {
+        let (dir, other_dir) = (dir(), dir());
+        let store = ThemeStore::load(dir.clone()).unwrap();
+        let other = ThemeStore::load(other_dir.clone()).unwrap();
+        store.save("dark", "body { color: white }").await.unwrap();
+        assert!(!store.select(Some("light")).await.unwrap());
+        assert!(store.select(Some("dark")).await.unwrap());
+        assert_eq!(store.selected().as_deref(), Some("dark"));
+        let url = store.stylesheet();
+        assert!(url.starts_with("/assets/themes/dark.css?v="));
+        assert_eq!(other.selected(), None);
+        assert_eq!(other.stylesheet(), "");
+
+        // A new upload changes the link, and the selection outlives the store.
+        store.save("dark", "body { color: grey }").await.unwrap();
+        assert_ne!(store.stylesheet(), url);
+        let reloaded = ThemeStore::load(dir.clone()).unwrap();
+        assert_eq!(reloaded.stylesheet(), store.stylesheet());
+
+        assert!(store.remove("dark").await.unwrap());
+        assert_eq!(store.selected(), None);
+        assert!(ThemeStore::load(dir.clone()).unwrap().selected().is_none());
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(other_dir);
+    }
+}
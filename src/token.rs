@@ -0,0 +1,177 @@
+use base64::prelude::*;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::hash::{Hash, Hasher};
+
+/// Entropy used for the OAuth `state` parameter, which only lives for the length of a login.
+pub const STATE_BYTES: usize = 16;
+/// Entropy used for session IDs, which are long-lived bearer credentials.
+pub const SESSION_BYTES: usize = 32;
+/// Entropy used for API keys, which live until revoked.
+pub const API_KEY_BYTES: usize = 32;
+
+/// Generates a URL- and cookie-safe token from `entropy_bytes` bytes read from the OS CSPRNG.
+pub fn generate(entropy_bytes: usize) -> String {
+    let mut buf = vec![0; entropy_bytes];
+    OsRng.fill_bytes(&mut buf);
+    BASE64_URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        })
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The raw session ID handed to the browser in the `session_id` cookie. It never gets stored
+/// server-side; the session store is keyed by its [`SessionHash`] instead.
+#[derive(Clone)]
+pub struct SessionId(String);
+
+impl SessionId {
+    pub fn generate() -> Self {
+        Self(generate(SESSION_BYTES))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn hash(&self) -> SessionHash {
+        SessionHash::of(&self.0)
+    }
+}
+
+impl From<&str> for SessionId {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+/// SHA-256 of a [`SessionId`], used as the key in the session store.
+///
+/// A leaked store, or a debug dump of it, can't be replayed as cookies. Equality is constant-time
+/// so that the final comparison of a lookup doesn't leak how much of a guess was correct.
+#[derive(Clone, Copy, Eq)]
+pub struct SessionHash([u8; 32]);
+
+impl SessionHash {
+    /// Hashes any bearer credential. API keys are stored by this too.
+    pub fn of(secret: &str) -> Self {
+        Self(Sha256::digest(secret.as_bytes()).into())
+    }
+
+    /// Lowercase hex, for stores that key by string and for session snapshots.
+    pub fn to_hex(self) -> String {
+        hex(&self.0)
+    }
+
+    /// Reads back [`Self::to_hex`].
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(Self(bytes))
+    }
+}
+
+impl PartialEq for SessionHash {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Hash for SessionHash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl std::fmt::Debug for SessionHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SessionHash(")?;
+        for b in &self.0[..4] {
+            write!(f, "{b:02x}")?;
+        }
+        write!(f, "…)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn length_follows_entropy() {
+        // Unpadded base64 takes four characters for every three bytes, rounded up.
+        for (bytes, len) in [(STATE_BYTES, 22), (SESSION_BYTES, 43), (API_KEY_BYTES, 43)] {
+            assert_eq!(generate(bytes).len(), len);
+            assert_eq!(
+                BASE64_URL_SAFE_NO_PAD
+                    .decode(generate(bytes))
+                    .unwrap()
+                    .len(),
+                bytes
+            );
+        }
+        assert_eq!(generate(0), "");
+    }
+
+    #[test]
+    fn url_and_cookie_safe() {
+        for _ in 0..1000 {
+            let token = generate(SESSION_BYTES);
+            assert!(
+                token
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn bits_are_evenly_distributed() {
+        const SAMPLES: usize = 2000;
+        let mut ones = [0_usize; SESSION_BYTES * 8];
+        let mut seen = HashSet::new();
+        for _ in 0..SAMPLES {
+            let token = generate(SESSION_BYTES);
+            let bytes = BASE64_URL_SAFE_NO_PAD.decode(&token).unwrap();
+            for (bit, count) in ones.iter_mut().enumerate() {
+                *count += usize::from((bytes[bit / 8] >> (bit % 8)) & 1 == 1);
+            }
+            assert!(seen.insert(token));
+        }
+        // Each bit is set in half the samples, give or take 22 for one standard deviation. Allowing
+        // seven of them, all 256 bits leave a failure to chance about once in a billion runs.
+        for (bit, count) in ones.iter().enumerate() {
+            assert!((846..=1154).contains(count), "bit {bit} set {count} times");
+        }
+    }
+
+    #[test]
+    fn hash_survives_hex() {
+        let hash = SessionId::generate().hash();
+        assert_eq!(SessionHash::from_hex(&hash.to_hex()), Some(hash));
+        assert_eq!(SessionHash::from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,88 @@
+//! Undo for the playlist edits the app makes: dedupe, reorder and running a rule. Before one of
+//! them changes a playlist, its tracks are kept, and `POST /api/playlists/:id/undo` puts them back
+//! for a while afterwards. Only the last edit of each playlist can be undone, and only while the
+//! playlist is still as the app left it, so changes made since elsewhere are never reverted.
+//!
+//! Spotify's API can't add local files, so those aren't restored.
+
+use std::{collections::HashMap, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::spotify::PlaylistItem;
+
+/// How long an edit can be undone.
+const WINDOW: Duration = Duration::from_hours(1);
+
+#[derive(Clone)]
+pub struct Undoable {
+    /// The tracks before the edit, in order.
+    pub uris: Vec<String>,
+    /// The snapshot the edit produced, which the playlist must still be at to be reverted.
+    pub snapshot_id: String,
+    at: Instant,
+}
+
+#[derive(Default)]
+pub struct UndoStore {
+    /// By user ID and playlist ID.
+    edits: RwLock<HashMap<(String, String), Undoable>>,
+}
+
+impl UndoStore {
+    /// Remembers `items`, what a playlist held before an edit that produced `snapshot_id`,
+    /// replacing what could be undone before.
+    pub async fn record(
+        &self,
+        user_id: &str,
+        playlist_id: &str,
+        items: &[PlaylistItem],
+        snapshot_id: String,
+    ) {
+        let uris = items
+            .iter()
+            .filter_map(|item| item.track.as_ref())
+            .filter(|track| !track.uri.starts_with("spotify:local:"))
+            .map(|track| track.uri.clone())
+            .collect();
+        let mut edits = self.edits.write().await;
+        edits.retain(|_, edit| edit.at.elapsed() < WINDOW);
+        edits.insert(
+            (user_id.to_owned(), playlist_id.to_owned()),
+            Undoable {
+                uris,
+                snapshot_id,
+                at: Instant::now(),
+            },
+        );
+    }
+
+    /// The last edit of a playlist, if it can still be undone.
+    pub async fn get(&self, user_id: &str, playlist_id: &str) -> Option<Undoable> {
+        self.edits
+            .read()
+            .await
+            .get(&(user_id.to_owned(), playlist_id.to_owned()))
+            .filter(|edit| edit.at.elapsed() < WINDOW)
+            .cloned()
+    }
+
+    /// Forgets the last edit of a playlist, once undone.
+    pub async fn remove(&self, user_id: &str, playlist_id: &str) {
+        self.edits
+            .write()
+            .await
+            .remove(&(user_id.to_owned(), playlist_id.to_owned()));
+    }
+
+    /// Forgets every edit of `user_id`, returning how many there were.
+    pub async fn remove_owned_by(&self, user_id: &str) -> usize {
+        let mut edits = self.edits.write().await;
+        let before = edits.len();
+        edits.retain(|(owner, _), _| owner != user_id);
+        before - edits.len()
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.edits.try_read().ok().map(|e| e.len())
+    }
+}
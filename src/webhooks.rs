@@ -0,0 +1,1061 @@
+//! Webhooks: users register URLs to be told when the track they're playing changes, or when one
+//! of the playlists they watch does. A worker polls Spotify on behalf of every user with webhooks
+//! and a session, and delivers a JSON payload per event, retrying failures with exponential
+//! backoff.
+//!
+//! Payloads are signed with the webhook's secret, which is only shown when the webhook is
+//! created: `X-Blid-Signature` is `sha256=` followed by the hex HMAC-SHA256 of the body.
+//!
+//! Since anyone signed in can pick the URL and read back how its endpoint answered, deliveries
+//! only go to public addresses: the host is checked when a webhook is registered, again before
+//! every attempt, and the delivery client only connects to public addresses it resolved itself.
+//!
+//! Webhooks and their delivery logs are kept in memory by default, or in the database with a SQL
+//! feature and `DATABASE_URL` set. What was last seen for each user, to tell changes apart, stays
+//! in the memory of the instance polling.
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{Mutex, RwLock};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    geoip::Location,
+    spotify::{self, Album, SpotifyToken, Track},
+    stats::Milestone,
+    token, AppStateInner,
+};
+
+/// How often playback and watched playlists are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Attempts made to deliver an event before giving up on it.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after it.
+const FIRST_RETRY: Duration = Duration::from_secs(5);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries kept in the log of each webhook.
+const LOG_LEN: usize = 50;
+
+/// Deliveries go to arbitrary hosts, so they don't share the Spotify client and its pool.
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("webhook client configuration is valid")
+});
+
+/// Whether deliveries may go to `ip`: not this machine, its networks, or anything only reachable
+/// from them, like cloud metadata services on link-local addresses.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // 0.0.0.0/8, "this network".
+                || a == 0
+                // 100.64.0.0/10, carrier-grade NAT.
+                || (a == 100 && b & 0xc0 == 64)
+                // 198.18.0.0/15, benchmarking.
+                || (a == 198 && b & 0xfe == 18))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            // 2002::/16, 6to4, which carries an IPv4 address in its next 32 bits.
+            if segments[0] == 0x2002 {
+                let [a, b] = segments[1].to_be_bytes();
+                let [c, d] = segments[2].to_be_bytes();
+                return is_public(IpAddr::V4([a, b, c, d].into()));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // ::/96, IPv4-compatible, which routers may still send to the IPv4 address.
+                || segments[..6] == [0; 6]
+                // 64:ff9b::/96 and 64:ff9b:1::/48, NAT64, which reaches any IPv4 address,
+                // private ones included.
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                || segments[..3] == [0x64, 0xff9b, 1])
+        }
+    }
+}
+
+/// The addresses `host` resolves to, failing unless they are all public.
+async fn public_addrs(host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if addrs.is_empty() {
+        anyhow::bail!("{host} has no addresses");
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        anyhow::bail!(
+            "{host} resolves to {}, which is not a public address",
+            addr.ip()
+        );
+    }
+    Ok(addrs)
+}
+
+/// Checks that `url` is an http(s) URL whose host is public, as far as DNS says right now.
+pub async fn check_destination(url: &str) -> anyhow::Result<()> {
+    let url = reqwest::Url::parse(url)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("url must be an http(s) URL");
+    }
+    let Some(host) = url.host_str() else {
+        anyhow::bail!("url must be an http(s) URL");
+    };
+    // IPv6 hosts keep their brackets.
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        if !is_public(ip) {
+            anyhow::bail!("{ip} is not a public address");
+        }
+        return Ok(());
+    }
+    public_addrs(host, url.port_or_known_default().unwrap_or(80))
+        .await
+        .map(drop)
+}
+
+/// Resolves names for [`CLIENT`], so a name that turned private since it was checked can't be
+/// connected to.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs = public_addrs(&host, 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    TrackChange,
+    PlaylistChange,
+    NewRelease,
+    Milestone,
+    UnusualLogin,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<EventKind>,
+    /// Playlists whose changes trigger `playlist_change` events.
+    pub playlist_ids: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub secret: String,
+    #[serde(skip)]
+    pub owner: String,
+}
+
+impl Webhook {
+    fn wants(&self, event: &Event) -> bool {
+        self.events.contains(&event.kind())
+            && match event {
+                Event::TrackChange { .. }
+                | Event::NewRelease { .. }
+                | Event::Milestone { .. }
+                | Event::UnusualLogin { .. } => true,
+                Event::PlaylistChange { playlist_id, .. } => {
+                    self.playlist_ids.contains(playlist_id)
+                }
+            }
+    }
+
+    fn sign(&self, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body);
+        format!("sha256={}", token::hex(&mac.finalize().into_bytes()))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// `track` is `None` when playback stopped.
+    TrackChange { track: Option<Track> },
+    PlaylistChange {
+        playlist_id: String,
+        snapshot_id: String,
+    },
+    /// Found by [`crate::releases`].
+    NewRelease { artist: String, album: Album },
+    /// Reached by plays the history collector found. See [`crate::stats`].
+    Milestone { milestone: Milestone },
+    /// A login from a place unusual for the user. See [`crate::logins`].
+    UnusualLogin { location: Location },
+}
+
+impl Event {
+    const fn kind(&self) -> EventKind {
+        match self {
+            Self::TrackChange { .. } => EventKind::TrackChange,
+            Self::PlaylistChange { .. } => EventKind::PlaylistChange,
+            Self::NewRelease { .. } => EventKind::NewRelease,
+            Self::Milestone { .. } => EventKind::Milestone,
+            Self::UnusualLogin { .. } => EventKind::UnusualLogin,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    delivery_id: &'a str,
+    webhook_id: &'a str,
+    occurred_at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Delivery {
+    pub id: String,
+    pub event: EventKind,
+    pub created_at: DateTime<Utc>,
+    pub attempts: u32,
+    /// Status of the last attempt, when the endpoint answered at all.
+    pub status: Option<u16>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+/// What was last seen for a user, to tell changes apart. Nothing fires for the first
+/// observation of anything.
+#[derive(Default)]
+struct Watch {
+    /// `None` until the first observation, `Some(None)` while nothing plays.
+    #[allow(clippy::option_option)]
+    track_id: Option<Option<String>>,
+    snapshot_ids: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct WebhookStore {
+    stored: Stored,
+    /// Of the users this instance polls for.
+    watches: Mutex<HashMap<String, Watch>>,
+}
+
+/// Webhooks and their delivery logs, in memory by default, or in the database with a SQL feature
+/// and `DATABASE_URL` set, in which case only the instance holding the workers lease polls for
+/// events.
+enum Stored {
+    Memory {
+        webhooks: RwLock<HashMap<String, Webhook>>,
+        deliveries: RwLock<HashMap<String, VecDeque<Delivery>>>,
+    },
+    #[cfg(feature = "sql")]
+    Sql(db::Pool),
+}
+
+impl Default for Stored {
+    fn default() -> Self {
+        Self::Memory {
+            webhooks: RwLock::default(),
+            deliveries: RwLock::default(),
+        }
+    }
+}
+
+impl WebhookStore {
+    #[cfg(feature = "sql")]
+    pub fn in_database(pool: db::Pool) -> Self {
+        Self {
+            stored: Stored::Sql(pool),
+            watches: Mutex::default(),
+        }
+    }
+
+    pub async fn insert(
+        &self,
+        owner: String,
+        url: String,
+        events: Vec<EventKind>,
+        playlist_ids: Vec<String>,
+    ) -> anyhow::Result<Webhook> {
+        let webhook = Webhook {
+            id: token::generate(8),
+            url,
+            events,
+            playlist_ids,
+            created_at: Utc::now(),
+            secret: token::generate(32),
+            owner,
+        };
+        match &self.stored {
+            Stored::Memory { webhooks, .. } => {
+                webhooks
+                    .write()
+                    .await
+                    .insert(webhook.id.clone(), webhook.clone());
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                sqlx::query(
+                    "INSERT INTO webhooks \
+                     (id, owner, url, events, playlist_ids, secret, created_at) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                )
+                .bind(&webhook.id)
+                .bind(&webhook.owner)
+                .bind(&webhook.url)
+                .bind(serde_json::to_string(&webhook.events)?)
+                .bind(serde_json::to_string(&webhook.playlist_ids)?)
+                .bind(&webhook.secret)
+                .bind(webhook.created_at.timestamp())
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(webhook)
+    }
+
+    pub async fn owned_by(&self, owner: &str) -> anyhow::Result<Vec<Webhook>> {
+        match &self.stored {
+            Stored::Memory { webhooks, .. } => Ok(webhooks
+                .read()
+                .await
+                .values()
+                .filter(|webhook| webhook.owner == owner)
+                .cloned()
+                .collect()),
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let rows: Vec<WebhookRow> = sqlx::query_as(
+                    "SELECT id, owner, url, events, playlist_ids, secret, created_at \
+                     FROM webhooks WHERE owner = $1 ORDER BY created_at",
+                )
+                .bind(owner)
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter().map(from_row).collect()
+            }
+        }
+    }
+
+    pub async fn remove(&self, owner: &str, id: &str) -> anyhow::Result<bool> {
+        match &self.stored {
+            Stored::Memory {
+                webhooks,
+                deliveries,
+            } => {
+                let mut webhooks = webhooks.write().await;
+                if webhooks
+                    .get(id)
+                    .is_none_or(|webhook| webhook.owner != owner)
+                {
+                    return Ok(false);
+                }
+                webhooks.remove(id);
+                deliveries.write().await.remove(id);
+                Ok(true)
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let deleted = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND owner = $2")
+                    .bind(id)
+                    .bind(owner)
+                    .execute(&mut *tx)
+                    .await?;
+                if deleted.rows_affected() == 0 {
+                    return Ok(false);
+                }
+                sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Removes every webhook of `owner` along with its delivery log, returning how many there
+    /// were.
+    pub async fn remove_owned_by(&self, owner: &str) -> anyhow::Result<usize> {
+        self.watches.lock().await.remove(owner);
+        match &self.stored {
+            Stored::Memory {
+                webhooks,
+                deliveries,
+            } => {
+                let mut webhooks = webhooks.write().await;
+                let removed: Vec<String> = webhooks
+                    .values()
+                    .filter(|webhook| webhook.owner == owner)
+                    .map(|webhook| webhook.id.clone())
+                    .collect();
+                let mut deliveries = deliveries.write().await;
+                for id in &removed {
+                    webhooks.remove(id);
+                    deliveries.remove(id);
+                }
+                Ok(removed.len())
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query(
+                    "DELETE FROM webhook_deliveries \
+                     WHERE webhook_id IN (SELECT id FROM webhooks WHERE owner = $1)",
+                )
+                .bind(owner)
+                .execute(&mut *tx)
+                .await?;
+                let deleted = sqlx::query("DELETE FROM webhooks WHERE owner = $1")
+                    .bind(owner)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(usize::try_from(deleted.rows_affected())?)
+            }
+        }
+    }
+
+    /// The delivery log of a webhook, newest first, or `None` if `owner` has no such webhook.
+    pub async fn deliveries(&self, owner: &str, id: &str) -> anyhow::Result<Option<Vec<Delivery>>> {
+        match &self.stored {
+            Stored::Memory {
+                webhooks,
+                deliveries,
+            } => {
+                if webhooks
+                    .read()
+                    .await
+                    .get(id)
+                    .is_none_or(|webhook| webhook.owner != owner)
+                {
+                    return Ok(None);
+                }
+                Ok(Some(
+                    deliveries
+                        .read()
+                        .await
+                        .get(id)
+                        .map(|log| log.iter().cloned().collect())
+                        .unwrap_or_default(),
+                ))
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let found: Option<String> =
+                    sqlx::query_scalar("SELECT id FROM webhooks WHERE id = $1 AND owner = $2")
+                        .bind(id)
+                        .bind(owner)
+                        .fetch_optional(pool)
+                        .await?;
+                if found.is_none() {
+                    return Ok(None);
+                }
+                let rows: Vec<DeliveryRow> = sqlx::query_as(
+                    "SELECT id, event, created_at, attempts, status, error, delivered \
+                     FROM webhook_deliveries WHERE webhook_id = $1 \
+                     ORDER BY created_at DESC, id DESC",
+                )
+                .bind(id)
+                .fetch_all(pool)
+                .await?;
+                Ok(Some(
+                    rows.into_iter()
+                        .map(delivery_from_row)
+                        .collect::<anyhow::Result<_>>()?,
+                ))
+            }
+        }
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        match &self.stored {
+            Stored::Memory { webhooks, .. } => webhooks.try_read().ok().map(|w| w.len()),
+            #[cfg(feature = "sql")]
+            Stored::Sql(_) => None,
+        }
+    }
+
+    /// Whether every instance sees the same webhooks, so only one of them should poll for them.
+    const fn is_shared(&self) -> bool {
+        match self.stored {
+            Stored::Memory { .. } => false,
+            #[cfg(feature = "sql")]
+            Stored::Sql(_) => true,
+        }
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Webhook>> {
+        match &self.stored {
+            Stored::Memory { webhooks, .. } => {
+                Ok(webhooks.read().await.values().cloned().collect())
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let rows: Vec<WebhookRow> = sqlx::query_as(
+                    "SELECT id, owner, url, events, playlist_ids, secret, created_at FROM webhooks",
+                )
+                .fetch_all(pool)
+                .await?;
+                rows.into_iter().map(from_row).collect()
+            }
+        }
+    }
+
+    /// Adds or updates a delivery in the log of its webhook. Returns `false` if the webhook was
+    /// deleted in the meantime.
+    async fn log(&self, webhook_id: &str, delivery: &Delivery) -> anyhow::Result<bool> {
+        match &self.stored {
+            Stored::Memory {
+                webhooks,
+                deliveries,
+            } => {
+                let webhooks = webhooks.read().await;
+                if !webhooks.contains_key(webhook_id) {
+                    return Ok(false);
+                }
+                let mut deliveries = deliveries.write().await;
+                let log = deliveries.entry(webhook_id.to_owned()).or_default();
+                if let Some(logged) = log.iter_mut().find(|logged| logged.id == delivery.id) {
+                    *logged = delivery.clone();
+                } else {
+                    log.push_front(delivery.clone());
+                    log.truncate(LOG_LEN);
+                }
+                Ok(true)
+            }
+            #[cfg(feature = "sql")]
+            Stored::Sql(pool) => {
+                let mut tx = pool.begin().await?;
+                let found: Option<String> =
+                    sqlx::query_scalar("SELECT id FROM webhooks WHERE id = $1")
+                        .bind(webhook_id)
+                        .fetch_optional(&mut *tx)
+                        .await?;
+                if found.is_none() {
+                    return Ok(false);
+                }
+                sqlx::query(
+                    "INSERT INTO webhook_deliveries \
+                     (id, webhook_id, event, created_at, attempts, status, error, delivered) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+                     ON CONFLICT (id) DO UPDATE \
+                     SET attempts = $5, status = $6, error = $7, delivered = $8",
+                )
+                .bind(&delivery.id)
+                .bind(webhook_id)
+                .bind(serde_json::to_string(&delivery.event)?)
+                .bind(delivery.created_at.timestamp())
+                .bind(i64::from(delivery.attempts))
+                .bind(i64::from(delivery.status.unwrap_or(0)))
+                .bind(delivery.error.clone().unwrap_or_default())
+                .bind(i64::from(delivery.delivered))
+                .execute(&mut *tx)
+                .await?;
+                let oldest_kept: Option<(i64, String)> = sqlx::query_as(
+                    "SELECT created_at, id FROM webhook_deliveries WHERE webhook_id = $1 \
+                     ORDER BY created_at DESC, id DESC LIMIT 1 OFFSET $2",
+                )
+                .bind(webhook_id)
+                .bind(i64::try_from(LOG_LEN)?)
+                .fetch_optional(&mut *tx)
+                .await?;
+                if let Some((created_at, id)) = oldest_kept {
+                    sqlx::query(
+                        "DELETE FROM webhook_deliveries WHERE webhook_id = $1 \
+                         AND (created_at < $2 OR (created_at = $2 AND id <= $3))",
+                    )
+                    .bind(webhook_id)
+                    .bind(created_at)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// ID, owner, URL, events and playlist IDs as JSON, secret, and creation time.
+#[cfg(feature = "sql")]
+type WebhookRow = (String, String, String, String, String, String, i64);
+
+#[cfg(feature = "sql")]
+fn from_row(
+    (id, owner, url, events, playlist_ids, secret, created_at): WebhookRow,
+) -> anyhow::Result<Webhook> {
+    Ok(Webhook {
+        id,
+        url,
+        events: serde_json::from_str(&events)?,
+        playlist_ids: serde_json::from_str(&playlist_ids)?,
+        created_at: DateTime::from_timestamp(created_at, 0)
+            .ok_or_else(|| anyhow::anyhow!("bad time {created_at}"))?,
+        secret,
+        owner,
+    })
+}
+
+/// ID, event kind as JSON, creation time, attempts, status or 0, error or empty, and 1 if it was
+/// delivered.
+#[cfg(feature = "sql")]
+type DeliveryRow = (String, String, i64, i64, i64, String, i64);
+
+#[cfg(feature = "sql")]
+fn delivery_from_row(
+    (id, event, created_at, attempts, status, error, delivered): DeliveryRow,
+) -> anyhow::Result<Delivery> {
+    Ok(Delivery {
+        id,
+        event: serde_json::from_str(&event)?,
+        created_at: DateTime::from_timestamp(created_at, 0)
+            .ok_or_else(|| anyhow::anyhow!("bad time {created_at}"))?,
+        attempts: u32::try_from(attempts)?,
+        status: Some(u16::try_from(status)?).filter(|status| *status != 0),
+        error: Some(error).filter(|error| !error.is_empty()),
+        delivered: delivered != 0,
+    })
+}
+
+pub fn spawn_worker(state: Arc<AppStateInner>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Webhooks kept in memory are this instance's own, so it polls for them whoever
+            // leads.
+            if state.webhooks.is_shared() && !state.leader.holds() {
+                continue;
+            }
+            let mut webhooks = match state.webhooks.all().await {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    tracing::error!("Failed to list webhooks: {e:#}");
+                    continue;
+                }
+            };
+            if webhooks.is_empty() {
+                continue;
+            }
+            let tokens: HashMap<String, SpotifyToken> = match state.sessions.all().await {
+                Ok(sessions) => sessions
+                    .into_iter()
+                    .map(|session| (session.user_id, session.token))
+                    .collect(),
+                Err(e) => {
+                    tracing::error!("Failed to list sessions for webhooks: {e:#}");
+                    continue;
+                }
+            };
+            webhooks.sort_by(|a, b| a.owner.cmp(&b.owner));
+            for owner_webhooks in webhooks.chunk_by(|a, b| a.owner == b.owner) {
+                let owner = &owner_webhooks[0].owner;
+                let Some(token) = tokens.get(owner) else {
+                    continue;
+                };
+                let events = match poll(&state, owner, &token.access_token, owner_webhooks).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        tracing::warn!("Failed to check for webhook events: {e:#}");
+                        continue;
+                    }
+                };
+                for event in events {
+                    for webhook in owner_webhooks.iter().filter(|w| w.wants(&event)) {
+                        tokio::spawn(deliver(state.clone(), webhook.clone(), event.clone()));
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// What changed for `owner` since the last poll.
+async fn poll(
+    state: &AppStateInner,
+    owner: &str,
+    access_token: &str,
+    webhooks: &[Webhook],
+) -> anyhow::Result<Vec<Event>> {
+    let wants_track = webhooks
+        .iter()
+        .any(|w| w.events.contains(&EventKind::TrackChange));
+    let playlist_ids: HashSet<&String> = webhooks
+        .iter()
+        .filter(|w| w.events.contains(&EventKind::PlaylistChange))
+        .flat_map(|w| &w.playlist_ids)
+        .collect();
+
+    let track = if wants_track {
+        Some(spotify::currently_playing(access_token).await?)
+    } else {
+        None
+    };
+    let mut snapshot_ids = Vec::with_capacity(playlist_ids.len());
+    for playlist_id in playlist_ids {
+        let snapshot_id = spotify::playlist_snapshot_id(access_token, playlist_id).await?;
+        snapshot_ids.push((playlist_id.clone(), snapshot_id));
+    }
+
+    let mut events = Vec::new();
+    let mut watches = state.webhooks.watches.lock().await;
+    let watch = watches.entry(owner.to_owned()).or_default();
+    if let Some(track) = track {
+        let track_id = track.as_ref().map(|t| t.id.clone());
+        let previous = watch.track_id.replace(track_id.clone());
+        if previous.is_some_and(|previous| previous != track_id) {
+            events.push(Event::TrackChange { track });
+        }
+    }
+    for (playlist_id, snapshot_id) in snapshot_ids {
+        let previous = watch
+            .snapshot_ids
+            .insert(playlist_id.clone(), snapshot_id.clone());
+        if previous.is_some_and(|previous| previous != snapshot_id) {
+            events.push(Event::PlaylistChange {
+                playlist_id,
+                snapshot_id,
+            });
+        }
+    }
+    Ok(events)
+}
+
+/// Delivers an event found outside of [`poll`] to the webhooks of `owner` that want it.
+pub async fn dispatch(state: &Arc<AppStateInner>, owner: &str, event: &Event) {
+    let webhooks = match state.webhooks.owned_by(owner).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            tracing::error!("Failed to list webhooks: {e:#}");
+            return;
+        }
+    };
+    for webhook in webhooks {
+        if webhook.wants(event) {
+            tokio::spawn(deliver(state.clone(), webhook, event.clone()));
+        }
+    }
+}
+
+async fn deliver(state: Arc<AppStateInner>, webhook: Webhook, event: Event) {
+    let mut delivery = Delivery {
+        id: token::generate(8),
+        event: event.kind(),
+        created_at: Utc::now(),
+        attempts: 0,
+        status: None,
+        error: None,
+        delivered: false,
+    };
+    let body = match serde_json::to_vec(&Payload {
+        delivery_id: &delivery.id,
+        webhook_id: &webhook.id,
+        occurred_at: delivery.created_at,
+        event: &event,
+    }) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to serialize webhook payload: {e}");
+            return;
+        }
+    };
+    let signature = webhook.sign(&body);
+
+    let mut backoff = FIRST_RETRY;
+    loop {
+        delivery.attempts += 1;
+        if let Err(e) = check_destination(&webhook.url).await {
+            delivery.status = None;
+            delivery.error = Some(format!("{e:#}"));
+            if let Err(e) = state.webhooks.log(&webhook.id, &delivery).await {
+                tracing::error!("Failed to log webhook delivery: {e:#}");
+            }
+            break;
+        }
+        let result = CLIENT
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Blid-Signature", &signature)
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) => {
+                delivery.status = Some(response.status().as_u16());
+                delivery.delivered = response.status().is_success();
+                delivery.error = (!delivery.delivered)
+                    .then(|| format!("endpoint answered {}", response.status()));
+            }
+            Err(e) => {
+                delivery.status = None;
+                delivery.error = Some(e.to_string());
+            }
+        }
+        match state.webhooks.log(&webhook.id, &delivery).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            // Not logging it isn't a reason to stop delivering it.
+            Err(e) => tracing::error!("Failed to log webhook delivery: {e:#}"),
+        }
+        if delivery.delivered || delivery.attempts >= MAX_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    if !delivery.delivered {
+        tracing::info!(
+            "Giving up on webhook delivery {} after {} attempts",
+            delivery.id,
+            delivery.attempts
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::dns::Resolve;
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "0.1.2.3",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd12:3456::1",
+            "::ffff:10.0.0.1",
+            "ff02::1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "64:ff9b::93.184.215.14",
+            "64:ff9b::10.0.0.1",
+            "64:ff9b:1::1",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "::10.0.0.1",
+            "::93.184.215.14",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "93.184.215.14",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2002:5db8:d70e::1",
+            "2606:2800:220:1::1",
+            "::ffff:1.1.1.1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn destinations_are_checked_by_what_they_resolve_to() {
+        assert!(check_destination("http://localhost:8080/").await.is_err());
+        assert!(check_destination("http://[fe80::1]/").await.is_err());
+        assert!(check_destination("ftp://93.184.215.14/").await.is_err());
+        assert!(check_destination("https://93.184.215.14/").await.is_ok());
+        assert!(PublicResolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .is_err());
+    }
+
+    /// A webhook whose host turned private after it was registered is given up on without
+    /// connecting.
+    #[tokio::test]
+    async fn deliveries_to_internal_addresses_are_refused() {
+        let state = AppStateInner::for_tests().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook = state
+            .webhooks
+            .insert(
+                "ann".to_owned(),
+                format!("http://{}/", listener.local_addr().unwrap()),
+                vec![EventKind::TrackChange],
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        deliver(
+            state.clone(),
+            webhook.clone(),
+            Event::TrackChange { track: None },
+        )
+        .await;
+
+        let log = state
+            .webhooks
+            .deliveries("ann", &webhook.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].attempts, 1);
+        assert!(!log[0].delivered);
+        assert_eq!(log[0].status, None);
+        assert!(log[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("not a public address"));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), listener.accept())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn signs_the_events_it_wants() {
+        let mut webhook = WebhookStore::default()
+            .insert(
+                "ann".to_owned(),
+                "https://example.com/".to_owned(),
+                vec![EventKind::PlaylistChange],
+                vec!["p".to_owned()],
+            )
+            .await
+            .unwrap();
+        let change = |playlist_id: &str| Event::PlaylistChange {
+            playlist_id: playlist_id.to_owned(),
+            snapshot_id: String::new(),
+        };
+        assert!(webhook.wants(&change("p")));
+        assert!(!webhook.wants(&change("q")));
+        assert!(!webhook.wants(&Event::TrackChange { track: None }));
+
+        webhook.secret = "key".to_owned();
+        assert_eq!(
+            webhook.sign(b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    async fn keeps_webhooks_and_their_logs(store: &WebhookStore) {
+        let webhook = store
+            .insert(
+                "ann".to_owned(),
+                "https://example.com/".to_owned(),
+                vec![EventKind::TrackChange, EventKind::PlaylistChange],
+                vec!["p".to_owned()],
+            )
+            .await
+            .unwrap();
+        let owned = store.owned_by("ann").await.unwrap();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].events, webhook.events);
+        assert_eq!(owned[0].playlist_ids, ["p"]);
+        assert_eq!(owned[0].secret, webhook.secret);
+        assert!(store.owned_by("bob").await.unwrap().is_empty());
+        assert!(store
+            .deliveries("bob", &webhook.id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut delivery = Delivery {
+            id: "first".to_owned(),
+            event: EventKind::TrackChange,
+            created_at: Utc::now(),
+            attempts: 1,
+            status: None,
+            error: Some("timed out".to_owned()),
+            delivered: false,
+        };
+        assert!(store.log(&webhook.id, &delivery).await.unwrap());
+        delivery.attempts = 2;
+        delivery.status = Some(204);
+        delivery.error = None;
+        delivery.delivered = true;
+        assert!(store.log(&webhook.id, &delivery).await.unwrap());
+        for i in 0..LOG_LEN {
+            let later = Delivery {
+                id: format!("later-{i:02}"),
+                created_at: delivery.created_at + chrono::Duration::seconds(1),
+                ..delivery.clone()
+            };
+            store.log(&webhook.id, &later).await.unwrap();
+        }
+        let log = store.deliveries("ann", &webhook.id).await.unwrap().unwrap();
+        assert_eq!(log.len(), LOG_LEN);
+        assert!(log.iter().all(|logged| logged.id != "first"));
+        assert_eq!(log[0].status, Some(204));
+        assert_eq!(log[0].error, None);
+
+        assert!(!store.remove("bob", &webhook.id).await.unwrap());
+        assert!(store.remove("ann", &webhook.id).await.unwrap());
+        assert!(!store.log(&webhook.id, &delivery).await.unwrap());
+        store
+            .insert(
+                "ann".to_owned(),
+                "https://example.com/".to_owned(),
+                vec![EventKind::NewRelease],
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(store.remove_owned_by("ann").await.unwrap(), 1);
+        assert!(store.all().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_webhooks_and_their_logs_in_memory() {
+        keeps_webhooks_and_their_logs(&WebhookStore::default()).await;
+    }
+
+    #[cfg(feature = "sqlite-store")]
+    #[tokio::test]
+    async fn keeps_webhooks_and_their_logs_in_the_database() {
+        let path = std::env::temp_dir().join(format!("blid-webhooks-{}.db", token::generate(8)));
+        let pool = db::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db::migrate(&pool).await.unwrap();
+        keeps_webhooks_and_their_logs(&WebhookStore::in_database(pool)).await;
+        let _ = std::fs::remove_file(path);
+    }
+}
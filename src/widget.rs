@@ -0,0 +1,208 @@
+//! An embeddable now-playing widget. A user who enables it gets a snippet to paste into any page:
+//! a script tag loading `/widget.js`, which polls the public `/w/:slug/now-playing.json` of that
+//! user and renders what they're listening to.
+//!
+//! Like public feeds, widgets are opt-in and addressed by an unguessable slug. The JSON is public
+//! and allowed from any origin, and cached briefly so busy pages don't turn into Spotify calls.
+//! Slugs are kept like other settings, see [`crate::slugs`].
+
+use askama_axum::Template;
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use itertools::Itertools;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+#[cfg(feature = "sql")]
+use crate::db;
+use crate::{
+    art,
+    session::PageSession,
+    slugs::SlugStore,
+    spotify,
+    templates::{self, Format, Layout, Page},
+    AppError, AppStateInner,
+};
+
+const CACHE_TTL: Duration = Duration::from_secs(15);
+
+const SCRIPT: &str = include_str!("../assets/widget.js");
+
+#[derive(Serialize, Clone)]
+struct WidgetTrack {
+    name: String,
+    artists: String,
+    album: String,
+    /// Empty when there is none.
+    artwork: String,
+    url: String,
+}
+
+/// What the widget shows. `track` is `None` when nothing is playing.
+#[derive(Serialize, Clone)]
+struct WidgetState {
+    track: Option<WidgetTrack>,
+}
+
+#[derive(Default)]
+pub struct WidgetStore {
+    slugs: SlugStore,
+    cache: RwLock<HashMap<String, (Instant, WidgetState)>>,
+}
+
+impl WidgetStore {
+    /// Keeps slugs in the database instead of in memory.
+    #[cfg(feature = "sql")]
+    pub fn in_database(self, pool: db::Pool) -> Self {
+        Self {
+            slugs: SlugStore::in_database(pool, "widgets"),
+            ..self
+        }
+    }
+
+    /// Enables the widget of a user, returning its slug. Enabling it again keeps the same slug.
+    pub async fn enable(&self, user_id: &str) -> anyhow::Result<String> {
+        self.slugs.enable(user_id).await
+    }
+
+    /// Disables the widget of a user, returning whether it was enabled.
+    pub async fn disable(&self, user_id: &str) -> anyhow::Result<bool> {
+        let Some(slug) = self.slugs.disable(user_id).await? else {
+            return Ok(false);
+        };
+        self.cache.write().await.remove(&slug);
+        Ok(true)
+    }
+
+    pub async fn slug(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        self.slugs.slug(user_id).await
+    }
+
+    pub fn len_hint(&self) -> Option<usize> {
+        self.slugs.len_hint()
+    }
+
+    /// What the widget with this slug shows, or `None` if no widget has it.
+    async fn state(
+        &self,
+        state: &AppStateInner,
+        slug: &str,
+    ) -> anyhow::Result<Option<WidgetState>> {
+        if let Some((at, widget)) = self.cache.read().await.get(slug) {
+            if at.elapsed() < CACHE_TTL {
+                return Ok(Some(widget.clone()));
+            }
+        }
+        let Some(user_id) = self.slugs.owner(slug).await? else {
+            return Ok(None);
+        };
+        // Any live session of the user will do; without one there's no token to ask Spotify
+        // with, and the widget shows nothing.
+        let session = state
+            .sessions
+            .all()
+            .await?
+            .into_iter()
+            .find(|session| session.user_id == user_id);
+        let track = match session {
+            Some(session) => spotify::currently_playing(&session.token.access_token).await?,
+            None => None,
+        };
+        let widget = WidgetState {
+            track: track.map(|track| WidgetTrack {
+                artists: track.artists.iter().map(|a| &a.name).join(", "),
+                // Absolute, since the widget is shown on other sites.
+                artwork: track
+                    .album
+                    .images
+                    .last()
+                    .map(|image| art::local_url(&state.public_url, &image.url))
+                    .unwrap_or_default(),
+                album: track.album.name,
+                url: format!("https://open.spotify.com/track/{}", track.id),
+                name: track.name,
+            }),
+        };
+        self.cache
+            .write()
+            .await
+            .insert(slug.to_owned(), (Instant::now(), widget.clone()));
+        Ok(Some(widget))
+    }
+}
+
+/// The snippet that embeds a widget, to paste into a page.
+pub fn snippet(public_url: &str, slug: &str) -> String {
+    format!("<script src=\"{public_url}/widget.js\" data-slug=\"{slug}\" async></script>")
+}
+
+pub fn router() -> Router<Arc<AppStateInner>> {
+    Router::new()
+        .route("/widget.js", get(script))
+        .route("/w/:slug/now-playing.json", get(now_playing))
+        .route("/settings/widget", get(settings))
+}
+
+async fn script() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/javascript; charset=utf-8"),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+        ],
+        SCRIPT,
+    )
+}
+
+async fn now_playing(State(s): State<Arc<AppStateInner>>, Path(slug): Path<String>) -> Response {
+    match s.widgets.state(&s, &slug).await {
+        Ok(Some(widget)) => (
+            [
+                (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+                (header::CACHE_CONTROL, "public, max-age=15"),
+            ],
+            Json(widget),
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::warn!("Failed to get what a widget's user is playing: {e:#}");
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "widget_settings.html")]
+struct WidgetSettingsTemplate {
+    /// Empty while the widget is disabled.
+    snippet: String,
+    layout: Layout,
+}
+
+impl Page for WidgetSettingsTemplate {
+    const PATH: &'static str = "widget_settings.html";
+}
+
+async fn settings(
+    PageSession(session): PageSession,
+    State(s): State<Arc<AppStateInner>>,
+    layout: Layout,
+    format: Format,
+) -> Result<Response, AppError> {
+    let snippet = s
+        .widgets
+        .slug(&session.user_id)
+        .await?
+        .map(|slug| snippet(&s.public_url, &slug))
+        .unwrap_or_default();
+    Ok(templates::respond(
+        format,
+        WidgetSettingsTemplate { snippet, layout },
+    ))
+}